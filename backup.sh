@@ -17,6 +17,7 @@ restore() {
 
 if [ "$CMD" == "restore" ]; then
     export IC_URL=http://localhost:8080
+    dfx canister call taggr begin_restore "$(cat $DIR/digest.txt)"
     PAGE=0
     while true; do
         for _ in {1..10}; do
@@ -27,7 +28,8 @@ if [ "$CMD" == "restore" ]; then
         wait
         if [ "$(stat -f%z $FILE)" == "18" ]; then break; fi
     done
-    dfx canister call taggr stable_to_heap
+    dfx canister call taggr restore_progress
+    dfx canister call taggr stable_to_heap "$(cat $DIR/digest.txt)"
     exit 0
 fi
 
@@ -38,6 +40,7 @@ fetch() {
 }
 
 git rev-parse HEAD > $DIR/commit.txt
+dfx canister --network ic call --query taggr backup_digest > $DIR/digest.txt
 
 PAGE=0
 while true; do
@@ -105,11 +105,10 @@ pub fn load() {
         Default::default(),
         domains.join("\n").as_bytes().to_vec(),
     );
-
-    ic_cdk::api::set_certified_data(&labeled_hash(LABEL, &asset_hashes().root_hash()));
 }
 
-fn add_asset(paths: &[&str], headers: Headers, bytes: Vec<u8>) {
+/// Adds or replaces the asset under the given paths and updates the certified data accordingly.
+pub fn add_asset(paths: &[&str], headers: Headers, bytes: Vec<u8>) {
     let mut hasher = Sha256::new();
     hasher.update(&bytes);
     let hash = hasher.finalize().into();
@@ -117,6 +116,7 @@ fn add_asset(paths: &[&str], headers: Headers, bytes: Vec<u8>) {
         asset_hashes().insert(path.as_bytes().to_vec(), hash);
         assets().insert(path.to_string(), (headers.clone(), bytes.clone()));
     }
+    ic_cdk::api::set_certified_data(&labeled_hash(LABEL, &asset_hashes().root_hash()));
 }
 
 pub fn asset_certified(path: &str) -> Option<(Headers, ByteBuf)> {
@@ -1,6 +1,34 @@
 //! Dev methods used for testing only.
 
 use super::*;
+use candid::CandidType;
+
+// The target and the progress of the running backup restore.
+#[derive(Default)]
+struct Restore {
+    heap_len: Option<u64>,
+    expected_hash: Option<String>,
+    pages: BTreeSet<u64>,
+    highest_offset: u64,
+}
+
+#[derive(CandidType)]
+struct RestoreProgress {
+    /// End of the highest page written so far.
+    highest_offset: u64,
+    /// Number of bytes of the backup to be restored, known once the restore was started via
+    /// `begin_restore` and the first page was written.
+    total_len: Option<u64>,
+    /// Pages below the total length that were not written yet.
+    missing_pages: Vec<u64>,
+    /// Whether all pages of the backup were written; the integrity of the restored heap is only
+    /// checked by `stable_to_heap`, as hashing it is too expensive for a query.
+    complete: bool,
+}
+
+thread_local! {
+    static RESTORE: RefCell<Restore> = Default::default();
+}
 
 #[update]
 // This method needs to be triggered to test an upgrade locally.
@@ -79,11 +107,78 @@ fn stable_mem_write(input: Vec<(u64, ByteBuf)>) {
             api::stable::stable64_grow(delta).unwrap_or_else(|_| panic!("couldn't grow memory"));
         }
         api::stable::stable64_write(offset, buffer);
+        RESTORE.with(|cell| {
+            let mut restore = cell.borrow_mut();
+            restore.pages.insert(*page);
+            restore.highest_offset = restore.highest_offset.max(offset + buffer.len() as u64);
+        });
     }
 }
 
 #[update]
-// Backup restore method.
-fn stable_to_heap() {
+// Starts a backup restore of a heap with the length and the SHA256 digest reported by
+// `backup_digest`.
+fn begin_restore(heap_len: u64, expected_hash: String) {
+    RESTORE.with(|cell| {
+        cell.replace(Restore {
+            heap_len: Some(heap_len),
+            expected_hash: Some(expected_hash),
+            ..Default::default()
+        })
+    });
+}
+
+#[query]
+// Reports the progress of the backup restore, so that the operator can re-upload missing pages.
+fn restore_progress() -> RestoreProgress {
+    RESTORE.with(|cell| {
+        let restore = cell.borrow();
+        // the backup ends with the heap, whose offset is stored in the first page
+        let total_len = restore
+            .heap_len
+            .filter(|_| restore.pages.contains(&0))
+            .map(|heap_len| memory::heap_address().0 + heap_len);
+        let missing_pages = total_len
+            .map(|total_len| {
+                (0..(total_len + BACKUP_PAGE_SIZE as u64 - 1) / BACKUP_PAGE_SIZE as u64)
+                    .filter(|page| !restore.pages.contains(page))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let complete = total_len
+            .map(|total_len| missing_pages.is_empty() && restore.highest_offset >= total_len)
+            .unwrap_or_default();
+        RestoreProgress {
+            highest_offset: restore.highest_offset,
+            total_len,
+            missing_pages,
+            complete,
+        }
+    })
+}
+
+#[update]
+// Backup restore method. The restored heap is only loaded if its length and SHA256 digest match
+// the values provided by the operator.
+fn stable_to_heap(expected_len: u64, expected_hash: String) -> Result<(), String> {
+    let target = RESTORE.with(|cell| {
+        let restore = cell.borrow();
+        (restore.heap_len, restore.expected_hash.clone())
+    });
+    if let (Some(heap_len), Some(hash)) = target {
+        if heap_len != expected_len || hash != expected_hash {
+            return Err("the digest doesn't match the one the restore was started with".into());
+        }
+    }
+    let (len, hash) = memory::stable_heap_digest();
+    if len != expected_len || hash != expected_hash {
+        let err = format!(
+            "backup integrity check failed: expected {} bytes with hash {}, found {} bytes with hash {}",
+            expected_len, expected_hash, len, hash
+        );
+        mutate(|state| state.logger.error(&err));
+        return Err(err);
+    }
     stable_to_heap_core();
+    Ok(())
 }
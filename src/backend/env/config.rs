@@ -1,10 +1,8 @@
-use std::collections::BTreeMap;
-
 use crate::token::Token;
 
 use super::{Cycles, Karma};
 use candid::CandidType;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 pub const ICP_CYCLES_PER_XDR: u64 = 1_000_000_000_000;
 
@@ -30,15 +28,58 @@ pub struct Config {
     pub proposal_approval_threshold: u16,
     pub proposal_controversy_threashold: u16,
     pub proposal_rejection_penalty: Cycles,
+    // proposals cancelled within this window and before any votes get their creation costs
+    // refunded
+    pub proposal_refund_window_minutes: u64,
+    // cycles held from proposers until the proposal is concluded; can be changed via proposals
+    pub proposal_deposit: Cycles,
+    pub max_proposal_deposit: Cycles,
 
     pub min_cycle_balance_main: u64,
+    // balance of the main canister below which stalwarts get alerted
+    pub low_cycle_balance_threshold: u64,
+    // number of hourly balance samples used to estimate the burn rate
+    pub cycle_balance_samples: usize,
 
     pub max_bucket_size: u64,
 
+    // the wasm32 heap limit of the main canister and its fill level reported as degraded
+    pub max_heap_size: u64,
+    pub heap_warning_percentage: u64,
+
+    // deflate heap snapshots written to the stable memory
+    pub compress_heap_snapshots: bool,
+
     pub max_posts_per_hour: u8,
     pub max_comments_per_hour: u8,
 
+    // default duration of the restrictions of new accounts, can be changed via proposals
+    pub new_user_cooldown_hours: u64,
+    // limit of posts and comments per hour of users in the cooldown
+    pub new_user_max_posts_per_hour: u8,
+
+    pub max_posts_per_minute: usize,
+    pub max_reactions_per_minute: usize,
+    pub trusted_rate_limit_multiplier: usize,
+    pub stalwart_rate_limit_multiplier: usize,
+
     pub feed_page_size: usize,
+    pub max_feed_page_size: usize,
+    pub reactor_sample_size: usize,
+    pub trending_tags_window_days: u64,
+    pub max_user_search_results: usize,
+    pub max_users_per_batch: usize,
+    pub max_export_scan: u64,
+    pub max_idempotency_key_length: usize,
+    pub max_idempotency_keys: usize,
+    pub idempotency_window_minutes: u64,
+
+    pub max_posts_in_heap: usize,
+    pub cold_post_age_days: u64,
+    pub cold_post_engagement_floor: usize,
+    pub max_mentions_per_post: usize,
+    pub max_feed_notifications_per_hour: u32,
+    pub max_held_notifications: usize,
 
     pub reporting_penalty_post: Cycles,
     pub reporting_penalty_misbehaviour: Cycles,
@@ -53,11 +94,18 @@ pub struct Config {
     pub post_cost: Cycles,
     pub tag_cost: Cycles,
     pub blob_cost: Cycles,
+    pub blob_upload_deadline_hours: u64,
     pub poll_cost: Cycles,
     pub realm_cost: Cycles,
 
+    pub min_realm_name: usize,
     pub max_realm_name: usize,
+    pub reserved_realm_names: &'static [&'static str],
+    pub max_reserved_realm_names: usize,
+    pub max_realm_post_cost: Cycles,
     pub max_realm_logo_len: usize,
+    pub max_realms_per_user: usize,
+    pub max_realms: Option<usize>,
 
     pub realm_cleanup_penalty: Cycles,
 
@@ -67,41 +115,110 @@ pub struct Config {
     pub inactivity_duration_weeks: u64,
 
     pub voting_reward: Cycles,
+    pub max_voting_reward: Cycles,
 
     // top x percentage of users selected as stalwarts
     pub stalwart_percentage: usize,
     pub min_stalwart_activity_weeks: u8,
     pub min_stalwart_account_age_weeks: u8,
+    pub min_stalwart_karma: Karma,
     pub stalwart_moderation_reward: Cycles,
 
     // percentage of stalwarts needed to confirm a report
     pub report_confirmation_percentage: u16,
 
+    // total karma of reporters and confirming stalwarts hiding the reported content until the
+    // report is resolved
+    pub report_hiding_karma_threshold: Karma,
+
+    pub report_appeal_window_days: u64,
+
     pub hot_post_engagement_percentage: f32,
 
     pub max_post_length: usize,
     pub max_tag_length: usize,
     pub max_user_info_length: usize,
+    pub max_tip_message_length: usize,
+    pub min_tip_e8s: u64,
+    pub max_tip_recipients: usize,
+    // share of every tip sent to the treasury
+    pub tip_fee_percentage: u64,
+
+    pub max_leaderboard_entries: usize,
+    pub max_karma_history_entries: usize,
+    pub max_cycles_history_entries: usize,
+    pub max_proposal_events: usize,
+    // failed ICP transfers of proposals are retried in the chores until this many attempts
+    pub max_icp_transfer_attempts: u32,
     pub max_blob_size_bytes: usize,
+    pub max_blobs_per_post: usize,
 
     pub min_cycles_for_inviting: Cycles,
     pub invites_budget_cycles: Cycles,
+    pub max_invite_uses: u32,
+    pub max_invite_follows: usize,
+    // remaining uses of all active invites of a user
+    pub max_outstanding_invites: Option<u32>,
+
+    pub account_deletion_grace_period_days: u64,
+    pub post_deletion_grace_period_minutes: u64,
 
     pub online_activity_minutes: u64,
 
     pub revenue_share_activity_weeks: u64,
     pub voting_power_activity_weeks: u64,
+    pub voting_power_decay_weeks: u64,
+    pub max_voting_power_decay_weeks: u64,
 
-    pub reactions: &'static [(u16, i64)],
-
-    pub min_positive_reaction_id: u16,
+    // id, emoji, karma change of the author, cycles paid by the reactor
+    pub reactions: &'static [(u16, &'static str, Karma, Cycles)],
+    pub max_reactions: usize,
+    pub max_reaction_karma: Karma,
+    pub max_reacted_posts: usize,
+    pub impression_window_hours: u64,
 
     pub reaction_fee: Cycles,
 
     pub max_funding_amount: u64,
 
+    pub mint_window_days: u64,
+    pub max_minted_per_window_percentage: u64,
+
+    // vesting of rewards; unvested tokens are forfeited once the receiver stays inactive for
+    // `vesting_forfeiture_inactivity_weeks`
+    pub max_vesting_weeks: u64,
+    pub vesting_forfeiture_inactivity_weeks: u64,
+
+    // ban proposals
+    pub max_ban_days: u64,
+    pub stalwart_ban_approval_threshold: u16,
+
+    // minting ratio samples kept for the history
+    pub max_minting_ratio_samples: usize,
+
+    // devices registered by a user
+    pub max_devices: usize,
+    pub max_device_label_length: usize,
+
     pub post_deletion_penalty_factor: u32,
 
+    pub max_user_languages: usize,
+
+    pub min_handle_length: usize,
+    pub max_handle_length: usize,
+    // handles nobody can register, can be changed via proposals
+    pub reserved_handles: &'static [&'static str],
+    pub max_reserved_handles: usize,
+    pub handle_change_cooldown_days: u64,
+    // old handles stay assigned to the user for this period after a rename
+    pub old_handle_hold_days: u64,
+
+    pub max_webhook_url_length: usize,
+    pub max_webhook_deliveries_per_hour: u32,
+    pub max_webhook_deliveries_per_run: usize,
+    pub max_webhook_attempts: u32,
+    pub max_webhook_response_bytes: u64,
+
     #[serde(with = "string")]
     pub neuron_id: u64,
 }
@@ -152,10 +269,15 @@ pub const CONFIG: &Config = &Config {
     proposal_approval_threshold: 66,
     proposal_controversy_threashold: 10,
     proposal_rejection_penalty: 500,
+    proposal_refund_window_minutes: 30,
+    proposal_deposit: 100,
+    max_proposal_deposit: 1000,
 
     total_supply: 100_000_000,
 
     min_cycle_balance_main: 2 * ICP_CYCLES_PER_XDR,
+    low_cycle_balance_threshold: ICP_CYCLES_PER_XDR,
+    cycle_balance_samples: 24,
 
     num_hot_posts: 10000,
 
@@ -166,6 +288,10 @@ pub const CONFIG: &Config = &Config {
     #[cfg(not(any(test, feature = "dev")))]
     report_confirmation_percentage: 20,
 
+    report_hiding_karma_threshold: 1000,
+
+    report_appeal_window_days: 7,
+
     trusted_user_min_karma: 25,
     trusted_user_min_age_weeks: 4,
 
@@ -175,32 +301,118 @@ pub const CONFIG: &Config = &Config {
 
     max_bucket_size: 1024 * 1024 * 1024 * 48, // 48Gb
 
+    max_heap_size: 1024 * 1024 * 1024 * 4, // 4Gb
+    heap_warning_percentage: 80,
+
+    compress_heap_snapshots: true,
+
     #[cfg(feature = "dev")]
     max_posts_per_hour: 15,
     #[cfg(not(feature = "dev"))]
     max_posts_per_hour: 3,
     max_comments_per_hour: 15,
 
+    new_user_cooldown_hours: 24,
+    new_user_max_posts_per_hour: 2,
+
+    // tests perform many actions at the same timestamp
+    #[cfg(test)]
+    max_posts_per_minute: 1000,
+    #[cfg(not(test))]
+    max_posts_per_minute: 3,
+    #[cfg(test)]
+    max_reactions_per_minute: 1000,
+    #[cfg(not(test))]
+    max_reactions_per_minute: 10,
+    trusted_rate_limit_multiplier: 2,
+    stalwart_rate_limit_multiplier: 4,
+
     feed_page_size: 10,
+    max_feed_page_size: 100,
+    reactor_sample_size: 20,
+    trending_tags_window_days: 7,
+    max_user_search_results: 20,
+    max_users_per_batch: 100,
+    max_export_scan: 20_000,
+    max_idempotency_key_length: 64,
+    max_idempotency_keys: 20,
+    idempotency_window_minutes: 10,
+
+    max_posts_in_heap: 20_000,
+    cold_post_age_days: 180,
+    cold_post_engagement_floor: 5,
+    max_mentions_per_post: 10,
+    max_feed_notifications_per_hour: 10,
+    max_held_notifications: 200,
 
     reporting_penalty_post: 200,
     reporting_penalty_misbehaviour: 1000,
 
     min_cycles_for_inviting: 50,
     invites_budget_cycles: 300,
+    max_invite_uses: 100,
+    max_invite_follows: 20,
+    max_outstanding_invites: Some(100),
+
+    account_deletion_grace_period_days: 30,
+    post_deletion_grace_period_minutes: 10,
 
     post_cost: 2,
     tag_cost: 3,
     blob_cost: 10,
+    blob_upload_deadline_hours: 6,
     poll_cost: 3,
     realm_cost: 1000,
 
+    min_realm_name: 2,
     max_realm_name: 12,
+    reserved_realm_names: &[
+        "all",
+        "admin",
+        "dao",
+        "help",
+        "moderator",
+        "official",
+        "support",
+        "system",
+        "taggr",
+    ],
+    max_reserved_realm_names: 1000,
+    max_realm_post_cost: 50,
     max_realm_logo_len: 16 * 1024,
+    max_realms_per_user: 3,
+    max_realms: None,
 
     post_deletion_penalty_factor: 10,
 
+    max_user_languages: 5,
+
+    min_handle_length: 2,
+    max_handle_length: 16,
+    reserved_handles: &[
+        "all",
+        "stalwarts",
+        "dao",
+        "admin",
+        "administrator",
+        "moderator",
+        "support",
+        "taggr",
+        "dfinity",
+        "system",
+    ],
+    max_reserved_handles: 1000,
+    handle_change_cooldown_days: 30,
+    old_handle_hold_days: 14,
+
+    max_webhook_url_length: 256,
+    max_webhook_deliveries_per_hour: 30,
+    max_webhook_deliveries_per_run: 50,
+    max_webhook_attempts: 5,
+    max_webhook_response_bytes: 4096,
+
     voting_reward: 5,
+    max_voting_reward: 100,
 
     response_reward: 1,
 
@@ -208,10 +420,13 @@ pub const CONFIG: &Config = &Config {
     inactivity_duration_weeks: 26,
     revenue_share_activity_weeks: 2,
     voting_power_activity_weeks: 8,
+    voting_power_decay_weeks: 8,
+    max_voting_power_decay_weeks: 104,
 
     stalwart_percentage: 3,
     min_stalwart_activity_weeks: 6,
     min_stalwart_account_age_weeks: 26,
+    min_stalwart_karma: 501,
     stalwart_moderation_reward: 20,
 
     hot_post_engagement_percentage: 0.01,
@@ -219,27 +434,88 @@ pub const CONFIG: &Config = &Config {
     max_post_length: 15000,
     max_tag_length: 30,
     max_user_info_length: 500,
+    max_tip_message_length: 140,
+    min_tip_e8s: 1_000_000,
+    max_tip_recipients: 10,
+    tip_fee_percentage: 0,
+
+    max_leaderboard_entries: 1000,
+    max_karma_history_entries: 200,
+    max_cycles_history_entries: 200,
+    max_proposal_events: 1000,
+    max_icp_transfer_attempts: 5,
     max_blob_size_bytes: 460800,
+    max_blobs_per_post: 8,
 
     online_activity_minutes: 10 * 60000000000_u64,
 
-    reactions: &[(1, -3), (100, 10), (50, 5), (51, 5), (10, 1)],
-
-    min_positive_reaction_id: 10,
+    reactions: &[
+        (1, "👎", -3, 3),
+        (100, "⭐", 10, 10),
+        (50, "🔥", 5, 5),
+        (51, "😂", 5, 5),
+        (10, "❤️", 1, 1),
+    ],
+    max_reactions: 16,
+    max_reaction_karma: 100,
+    max_reacted_posts: 1000,
+    impression_window_hours: 24,
 
     reaction_fee: 1,
 
     max_funding_amount: 2_000_000, // at ratio 1:1
 
+    mint_window_days: 7,
+    max_minted_per_window_percentage: 5,
+
+    max_vesting_weeks: 208,
+    vesting_forfeiture_inactivity_weeks: 12,
+
+    max_ban_days: 365,
+    stalwart_ban_approval_threshold: 80,
+
+    max_minting_ratio_samples: 180,
+
+    max_devices: 10,
+    max_device_label_length: 32,
+
     neuron_id: 16737374299031693047,
 };
 
-pub fn reaction_karma() -> BTreeMap<u16, Karma> {
+/// An entry of the reaction catalog.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Reaction {
+    pub id: u16,
+    pub emoji: String,
+    pub karma: Karma,
+    pub positive: bool,
+    /// Cycles paid by the reactor without the fee; catalogs adopted before costs were
+    /// introduced have no costs set, in which case the reactor pays the absolute karma.
+    #[serde(default)]
+    pub cost: Cycles,
+}
+
+impl Reaction {
+    pub fn cost(&self) -> Cycles {
+        if self.cost == 0 {
+            self.karma.unsigned_abs()
+        } else {
+            self.cost
+        }
+    }
+}
+
+/// Returns the catalog the platform started with, used until governance adopts a new one.
+pub fn default_reactions() -> Vec<Reaction> {
     CONFIG
         .reactions
         .iter()
-        .fold(BTreeMap::default(), |mut acc, (id, karma)| {
-            acc.insert(*id, *karma);
-            acc
+        .map(|(id, emoji, karma, cost)| Reaction {
+            id: *id,
+            emoji: emoji.to_string(),
+            karma: *karma,
+            positive: *karma > 0,
+            cost: *cost,
         })
+        .collect()
 }
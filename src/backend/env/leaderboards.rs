@@ -0,0 +1,177 @@
+use super::user::UserId;
+use super::*;
+
+#[derive(Clone, Copy, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub enum Metric {
+    /// Tokens received from other users through ledger transfers.
+    TipsReceived,
+    /// Sum of all karma increases of the user.
+    KarmaGained,
+    /// Positive reactions received on posts created within the window.
+    Reactions,
+}
+
+const METRICS: &[Metric] = &[Metric::TipsReceived, Metric::KarmaGained, Metric::Reactions];
+const WINDOWS: &[TopWindow] = &[TopWindow::Day, TopWindow::Week, TopWindow::Month];
+
+#[derive(Serialize)]
+pub struct Entry {
+    pub user_id: UserId,
+    pub handle: String,
+    pub value: u64,
+}
+
+/// Rankings of users recomputed in the hourly chores.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Leaderboards {
+    boards: BTreeMap<(Metric, TopWindow), Vec<(UserId, u64)>>,
+}
+
+impl Leaderboards {
+    pub fn compute(state: &State, now: u64) -> Self {
+        let mut boards = BTreeMap::default();
+        for metric in METRICS {
+            for window in WINDOWS {
+                let since = now.saturating_sub(window.duration());
+                let mut board = match metric {
+                    Metric::TipsReceived => tips_received(state, since),
+                    Metric::KarmaGained => karma_gained(state, since),
+                    Metric::Reactions => reactions(state, since),
+                }
+                .into_iter()
+                .filter(|(_, value)| *value > 0)
+                .collect::<Vec<_>>();
+                board.sort_unstable_by(|(id_a, a), (id_b, b)| b.cmp(a).then(id_a.cmp(id_b)));
+                board.truncate(CONFIG.max_leaderboard_entries);
+                boards.insert((*metric, *window), board);
+            }
+        }
+        Self { boards }
+    }
+
+    pub fn page(
+        &self,
+        state: &State,
+        metric: Metric,
+        window: TopWindow,
+        page: usize,
+    ) -> Vec<Entry> {
+        self.boards
+            .get(&(metric, window))
+            .into_iter()
+            .flatten()
+            .skip(page * CONFIG.feed_page_size)
+            .take(CONFIG.feed_page_size)
+            .filter_map(|(user_id, value)| {
+                state.users.get(user_id).map(|user| Entry {
+                    user_id: *user_id,
+                    handle: user.name.clone(),
+                    value: *value,
+                })
+            })
+            .collect()
+    }
+}
+
+fn tips_received(state: &State, since: u64) -> BTreeMap<UserId, u64> {
+    let user_id = |account: &Account| {
+        account
+            .subaccount
+            .is_none()
+            .then(|| state.principal_to_user(account.owner))
+            .flatten()
+            .map(|user| user.id)
+    };
+    let mut values = BTreeMap::default();
+    for transaction in state
+        .ledger
+        .iter()
+        .rev()
+        .take_while(|transaction| transaction.timestamp >= since)
+    {
+        // only transfers between two users count as tips
+        if let (Some(_), Some(receiver)) = (user_id(&transaction.from), user_id(&transaction.to)) {
+            *values.entry(receiver).or_default() += transaction.amount;
+        }
+    }
+    values
+}
+
+fn karma_gained(state: &State, since: u64) -> BTreeMap<UserId, u64> {
+    state
+        .users
+        .values()
+        .map(|user| {
+            let gained = user
+                .accounting
+                .iter()
+                .take_while(|(timestamp, _, _, _)| *timestamp >= since)
+                .filter(|(_, kind, delta, _)| kind == "KRM" && *delta > 0)
+                .map(|(_, _, delta, _)| *delta as u64)
+                .sum();
+            (user.id, gained)
+        })
+        .collect()
+}
+
+fn reactions(state: &State, since: u64) -> BTreeMap<UserId, u64> {
+    let catalog = state.reactions();
+    let mut values = BTreeMap::default();
+    for post in state
+        .last_posts(None, true)
+        .take_while(|post| post.timestamp() >= since)
+        .filter(|post| !post.is_deleted())
+    {
+        *values.entry(post.user).or_default() += post.positive_reactions(&catalog) as u64;
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        env::tests::{create_user, pr},
+        STATE,
+    };
+
+    #[test]
+    fn test_leaderboards() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+
+            for i in 1..=3 {
+                let id = create_user(state, pr(i));
+                state.users.get_mut(&id).unwrap().accounting.clear();
+            }
+            let now = time();
+            let post_id =
+                Post::create(state, "Test".to_string(), &[], pr(1), now, None, None, None).unwrap();
+            assert_eq!(state.react(pr(2), post_id, 50, now), Ok(()));
+            assert_eq!(state.react(pr(3), post_id, 10, now), Ok(()));
+            state
+                .principal_to_user_mut(pr(2))
+                .unwrap()
+                .change_karma(-5, "test");
+
+            let leaderboards = Leaderboards::compute(state, now);
+            let board = |metric| {
+                leaderboards
+                    .page(state, metric, TopWindow::Day, 0)
+                    .into_iter()
+                    .map(|entry| (entry.handle, entry.value))
+                    .collect::<Vec<_>>()
+            };
+            let name = pr(1).to_string().replace('-', "");
+
+            assert_eq!(board(Metric::Reactions), vec![(name.clone(), 2)]);
+            // the author received karma from both reactions, karma decreases are ignored
+            assert_eq!(board(Metric::KarmaGained), vec![(name, 6)]);
+            assert!(board(Metric::TipsReceived).is_empty());
+            assert!(leaderboards
+                .page(state, Metric::Reactions, TopWindow::Day, 1)
+                .is_empty());
+        })
+    }
+}
@@ -1,7 +1,10 @@
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
 use ic_cdk::api::stable::{stable64_grow, stable64_read, stable64_size, stable64_write};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::{cell::RefCell, collections::BTreeMap, fmt::Display, rc::Rc};
+use sha2::{Digest, Sha256};
+use std::{cell::RefCell, collections::BTreeMap, fmt::Display, io::Write, rc::Rc};
 
+use super::config::CONFIG;
 use super::post::PostId;
 
 #[derive(Serialize, Deserialize)]
@@ -45,6 +48,14 @@ pub struct Memory {
 // We leave the first 16 bytes recerved for the heap coordinates (offset + length)
 const INITIAL_OFFSET: u64 = 16;
 
+// Chunk size used for hashing the heap stored in the stable memory.
+const DIGEST_CHUNK_SIZE: u64 = 1024 * 1024;
+
+// Every heap snapshot starts with a format byte. Snapshots written before the format byte was
+// introduced start directly with a CBOR map, whose first byte never collides with these values.
+const SNAPSHOT_FORMAT_RAW: u8 = 0;
+const SNAPSHOT_FORMAT_DEFLATE: u8 = 1;
+
 impl Api {
     pub fn write<T: Serialize>(&mut self, value: &T) -> Result<(u64, u64), String> {
         let buffer: Vec<u8> = serde_cbor::to_vec(value).expect("couldn't serialize");
@@ -114,7 +125,7 @@ impl Memory {
 pub fn heap_to_stable(state: &mut super::State) {
     state.memory.pack();
     let offset = state.memory.api.boundary();
-    let bytes = serde_cbor::to_vec(&state).expect("couldn't serialize the state");
+    let bytes = encode_heap(state);
     let len = bytes.len() as u64;
     if offset + len > (stable64_size() << 16) {
         stable64_grow((len >> 16) + 1).expect("couldn't grow memory");
@@ -122,6 +133,54 @@ pub fn heap_to_stable(state: &mut super::State) {
     stable64_write(offset, &bytes);
     stable64_write(0, &offset.to_be_bytes());
     stable64_write(8, &len.to_be_bytes());
+    state.backup_digest = (len, digest(&bytes));
+}
+
+fn encode_heap(state: &super::State) -> Vec<u8> {
+    let bytes = serde_cbor::to_vec(state).expect("couldn't serialize the state");
+    if !CONFIG.compress_heap_snapshots {
+        let mut snapshot = Vec::with_capacity(bytes.len() + 1);
+        snapshot.push(SNAPSHOT_FORMAT_RAW);
+        snapshot.extend_from_slice(&bytes);
+        return snapshot;
+    }
+    let mut encoder = DeflateEncoder::new(vec![SNAPSHOT_FORMAT_DEFLATE], Compression::fast());
+    encoder
+        .write_all(&bytes)
+        .expect("couldn't compress the state");
+    encoder.finish().expect("couldn't compress the state")
+}
+
+fn decode_heap(bytes: &[u8]) -> super::State {
+    match bytes.first() {
+        Some(&SNAPSHOT_FORMAT_RAW) => serde_cbor::from_slice(&bytes[1..]),
+        Some(&SNAPSHOT_FORMAT_DEFLATE) => serde_cbor::from_reader(DeflateDecoder::new(&bytes[1..])),
+        // legacy snapshots without the format byte
+        _ => serde_cbor::from_slice(bytes),
+    }
+    .expect("couldn't deserialize the state")
+}
+
+fn digest(bytes: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+    format!("{:x}", hasher.finalize())
+}
+
+// Returns the length and the SHA256 digest of the serialized heap currently stored in the
+// stable memory. The heap is read in chunks to avoid allocating it twice.
+pub fn stable_heap_digest() -> (u64, String) {
+    let (offset, len) = heap_address();
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; DIGEST_CHUNK_SIZE.min(len) as usize];
+    let mut position = 0;
+    while position < len {
+        let chunk_size = DIGEST_CHUNK_SIZE.min(len - position) as usize;
+        stable64_read(offset + position, &mut buf[..chunk_size]);
+        hasher.update(&buf[..chunk_size]);
+        position += chunk_size as u64;
+    }
+    (len, format!("{:x}", hasher.finalize()))
 }
 
 pub fn heap_address() -> (u64, u64) {
@@ -137,8 +196,9 @@ pub fn heap_address() -> (u64, u64) {
 pub fn stable_to_heap() -> super::State {
     let (offset, len) = heap_address();
     ic_cdk::println!("Reading heap from coordinates: {:?}", (offset, len),);
-    let api = Api::default();
-    let mut state: super::State = api.read(offset, len);
+    let mut bytes = vec![0; len as usize];
+    stable64_read(offset, &mut bytes);
+    let mut state = decode_heap(&bytes);
     state.memory.unpack();
     state
 }
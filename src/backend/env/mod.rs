@@ -1,17 +1,20 @@
 use self::canisters::{upgrade_main_canister, NNSVote};
 use self::invoices::{parse_account, user_icp_account, Invoice, USER_ICP_SUBACCOUNT};
-use self::post::{archive_cold_posts, Extension, Poll, Post, PostId};
+use self::post::{
+    archive_cold_posts, ContentWarning, DeletedBy, Extension, Poll, Post, PostChanges, PostEvent,
+    PostExport, PostId, PostsSince, ReactionCharge,
+};
 use self::proposals::{Payload, Status};
 use self::reports::Report;
 use self::token::account;
-use self::user::{Notification, Predicate};
+use self::user::{Notification, NotificationType, Predicate};
 use crate::env::invoices::principal_to_subaccount;
 use crate::env::user::CyclesDelta;
 use crate::proposals::Proposal;
 use crate::token::{Account, Token, Transaction};
 use crate::{assets, mutate, read};
 use candid::Principal;
-use config::{reaction_karma, CONFIG, ICP_CYCLES_PER_XDR};
+use config::{default_reactions, Reaction, CONFIG, ICP_CYCLES_PER_XDR};
 use ic_cdk::api::stable::stable64_size;
 use ic_cdk::api::{self, canister_balance};
 use ic_ledger_types::{AccountIdentifier, Memo, Tokens};
@@ -21,18 +24,25 @@ use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use sha2::{Digest, Sha256};
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
-use user::{User, UserId};
+use std::ops::RangeInclusive;
+use user::{
+    handle_skeleton, ContentWarnings, Device, DoNotDisturb, Engagement, HandlePolicy, User, UserId,
+};
 
 pub mod canisters;
 pub mod config;
 pub mod invoices;
+pub mod leaderboards;
 pub mod memory;
 pub mod post;
 pub mod proposals;
+pub mod rate_limits;
 pub mod reports;
+pub mod search;
 pub mod storage;
 pub mod token;
 pub mod user;
+pub mod webhooks;
 
 pub type Cycles = u64;
 pub type Karma = i64;
@@ -52,6 +62,132 @@ pub struct NNSProposal {
     pub summary: String,
 }
 
+/// Sort order of a feed.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub enum FeedMode {
+    /// Posts engaging at least `hot_post_engagement_percentage` of users through positive
+    /// reactions and comments, without a negative reaction balance, ordered by their last engagement.
+    Hot,
+    /// Posts ordered from the newest to the oldest.
+    New,
+    /// Posts created within the time window, ordered by the number of positive reactions.
+    Top(TopWindow),
+}
+
+/// Order of sibling comments in a tree.
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+pub enum TreeSort {
+    /// Comments ordered from the oldest to the newest.
+    #[default]
+    Chronological,
+    /// Comments ordered by their net reaction karma, the newer comment wins on ties.
+    Top,
+}
+
+#[derive(Clone, Copy, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub enum TopWindow {
+    Day,
+    Week,
+    Month,
+}
+
+impl TopWindow {
+    pub fn duration(&self) -> u64 {
+        match self {
+            TopWindow::Day => DAY,
+            TopWindow::Week => WEEK,
+            TopWindow::Month => 30 * DAY,
+        }
+    }
+}
+
+/// Invite code charged from the inviter's invites budget or cycles whenever it's used.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(from = "StoredInvite")]
+pub struct Invite {
+    pub inviter: UserId,
+    pub cycles: Cycles,
+    pub expires_at: Option<u64>,
+    pub max_uses: u32,
+    pub uses: u32,
+    // realm joined and users followed by the invited user
+    pub realm: Option<String>,
+    pub follow: Vec<UserId>,
+}
+
+// Invites created before the expiry and usage limits were stored as `(inviter, cycles)` tuples.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredInvite {
+    Legacy(UserId, Cycles),
+    Current {
+        inviter: UserId,
+        cycles: Cycles,
+        expires_at: Option<u64>,
+        max_uses: u32,
+        uses: u32,
+        #[serde(default)]
+        realm: Option<String>,
+        #[serde(default)]
+        follow: Vec<UserId>,
+    },
+}
+
+impl From<StoredInvite> for Invite {
+    fn from(invite: StoredInvite) -> Self {
+        match invite {
+            StoredInvite::Legacy(inviter, cycles) => Invite {
+                inviter,
+                cycles,
+                expires_at: None,
+                max_uses: 1,
+                uses: 0,
+                realm: None,
+                follow: Default::default(),
+            },
+            StoredInvite::Current {
+                inviter,
+                cycles,
+                expires_at,
+                max_uses,
+                uses,
+                realm,
+                follow,
+            } => Invite {
+                inviter,
+                cycles,
+                expires_at,
+                max_uses,
+                uses,
+                realm,
+                follow,
+            },
+        }
+    }
+}
+
+impl Invite {
+    fn expired(&self, now: u64) -> bool {
+        self.expires_at
+            .map(|timestamp| timestamp < now)
+            .unwrap_or_default()
+    }
+
+    fn exhausted(&self) -> bool {
+        self.uses >= self.max_uses
+    }
+
+    pub fn validate(&self, now: u64) -> Result<(), String> {
+        if self.expired(now) {
+            return Err("invite expired".into());
+        }
+        if self.exhausted() {
+            return Err("invite has no uses left".into());
+        }
+        Ok(())
+    }
+}
+
 #[derive(Default, Deserialize, Serialize)]
 pub struct SearchResult {
     pub id: PostId,
@@ -59,6 +195,8 @@ pub struct SearchResult {
     pub generic_id: String,
     pub result: String,
     pub relevant: String,
+    // the field matching the search term, e.g. `username`, `tag` or `body`
+    pub matched: String,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -68,7 +206,7 @@ pub struct Event {
     pub message: String,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Stats {
     team_tokens: HashMap<UserId, Token>,
     emergency_release: String,
@@ -82,6 +220,7 @@ pub struct Stats {
     burned_cycles_total: Cycles,
     total_revenue_shared: u64,
     total_rewards_shared: u64,
+    total_tip_fees: u64,
     posts: usize,
     comments: usize,
     account: String,
@@ -99,7 +238,108 @@ pub struct Stats {
     module_hash: String,
     canister_id: Principal,
     circulating_supply: u64,
+    minting_ratio_history: Vec<(u64, u64, Token)>,
+    mint_headroom: Token,
     meta: String,
+    // timestamp of the snapshot computation
+    #[serde(default)]
+    computed_at: u64,
+}
+
+/// Cheap liveness signals for external monitors.
+#[derive(Serialize)]
+pub struct Health {
+    pub canister_cycle_balance: u64,
+    pub heap_size: u64,
+    pub max_heap_size: u64,
+    pub users: usize,
+    pub posts: PostId,
+    pub last_hourly_chores: u64,
+    // subsystems needing attention: `cycles`, `heap`, `storage` or `chores`
+    pub degraded: Vec<&'static str>,
+}
+
+#[derive(Default, Serialize)]
+pub struct InactivityPenalties {
+    pub karma: BTreeMap<UserId, Karma>,
+    pub cycles: BTreeMap<UserId, Cycles>,
+}
+
+#[derive(Serialize)]
+pub struct EmergencyReleaseStatus {
+    /// SHA-256 hash of the candidate binary.
+    pub hash: String,
+    /// Voting power of all confirmations.
+    pub votes: Token,
+    /// Voting power needed to execute the emergency upgrade.
+    pub required: Token,
+    pub confirmed_by_caller: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RewardPreview {
+    /// Karma collected since the last minting.
+    pub karma_to_reward: u64,
+    pub minting_ratio: u64,
+    /// Tokens (incl. decimals) the karma would be converted to at the current ratio.
+    pub projected_tokens: Token,
+    pub trusted: bool,
+    /// False if the rewards will be applied to the negative karma instead of being minted.
+    pub eligible: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StalwartRequirement {
+    pub name: &'static str,
+    pub current: i64,
+    pub required: i64,
+    pub met: bool,
+}
+
+/// Progress of the user towards the stalwart status, which gets recomputed in weekly chores.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StalwartStatus {
+    pub stalwart: bool,
+    pub requirements: Vec<StalwartRequirement>,
+    /// Estimated time in nanoseconds until all requirements are met, assuming the user stays
+    /// active every week; not set if a requirement can't be met by waiting.
+    pub time_to_eligibility: Option<u64>,
+}
+
+/// Preview of the side effects of the next chores run.
+#[derive(Default, Serialize)]
+pub struct ChoresPlan {
+    pub weekly: bool,
+    pub daily: bool,
+    pub hourly: bool,
+    // proposals concluded by the daily evaluation with the shrinking voting power
+    pub proposals_to_conclude: Vec<(u32, Status)>,
+    pub bans_to_lift: Vec<UserId>,
+    // posts with an expired deletion grace period
+    pub posts_to_delete: Vec<PostId>,
+    pub inactivity_penalties: InactivityPenalties,
+    // tokens minted from the karma earned since the last minting
+    pub tokens_to_mint: BTreeMap<UserId, Token>,
+}
+
+/// Rules for new realm names, exposed to clients for the validation before the submission.
+#[derive(Serialize)]
+pub struct RealmNamePolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub allowed_chars: String,
+    pub reserved: Vec<String>,
+}
+
+/// Returns true if the character is allowed in realm names.
+pub fn realm_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+/// Maps the realm name to a canonical form, in which visually similar names are equal, e.g.
+/// `DAO-NEWS` and `DA0_NEWS`.
+fn realm_name_skeleton(name: &str) -> String {
+    handle_skeleton(&name.replace(['_', '-'], ""))
 }
 
 #[derive(Default, Serialize, Deserialize)]
@@ -111,6 +351,39 @@ pub struct Realm {
     theme: String,
     pub num_posts: u64,
     pub num_members: u64,
+    #[serde(default)]
+    pub pinned: Option<PostId>,
+    // ICP (in e8s) tipped on the posts of the realm
+    #[serde(default)]
+    pub tips: u64,
+    // realm reputation voters need for their votes to count in the reputation-weighted results
+    // of realm polls
+    #[serde(default)]
+    pub min_voting_reputation: u64,
+    // cycles charged on top of the post costs for every post in the realm; they go to the realm
+    // treasury
+    #[serde(default)]
+    pub post_cost: Cycles,
+    // cycles the controllers can grant to the realm members
+    #[serde(default)]
+    pub treasury: Cycles,
+    // no new members are admitted while the realm has this many members
+    #[serde(default)]
+    pub max_members: Option<u64>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct RealmStats {
+    pub members: u64,
+    pub posts: u64,
+    /// Posts and comments created within the last 7 days.
+    pub posts_last_week: u64,
+    /// Posts and comments created within the last 30 days.
+    pub posts_last_month: u64,
+    /// Distinct authors of posts and comments created within the last 30 days.
+    pub active_authors: u64,
+    /// ICP (in e8s) tipped on the posts of the realm.
+    pub tips: u64,
 }
 
 #[derive(Default, Serialize, Deserialize)]
@@ -129,7 +402,7 @@ pub struct State {
     pub last_hourly_chores: u64,
     pub logger: Logger,
     pub hot: VecDeque<PostId>,
-    pub invites: BTreeMap<String, (UserId, Cycles)>,
+    pub invites: BTreeMap<String, Invite>,
     pub realms: BTreeMap<String, Realm>,
 
     #[serde(skip)]
@@ -150,6 +423,10 @@ pub struct State {
     #[serde(skip)]
     pub last_upgrade: u64,
 
+    // Length and SHA256 digest of the last heap snapshot written to the stable memory.
+    #[serde(skip)]
+    pub backup_digest: (u64, String),
+
     #[serde(skip)]
     pub emergency_binary: Vec<u8>,
     #[serde(skip)]
@@ -162,6 +439,118 @@ pub struct State {
     pub last_nns_proposal: u64,
 
     pub root_posts: usize,
+
+    #[serde(default)]
+    pub webhooks: webhooks::Webhooks,
+
+    #[serde(default)]
+    pub search_index: search::SearchIndex,
+
+    // reaction catalog adopted by governance; the default catalog is used while it's empty
+    #[serde(default)]
+    pub reaction_catalog: Vec<Reaction>,
+
+    // new user cooldown adopted by governance; the configured default is used while it's not set
+    #[serde(default)]
+    pub new_user_cooldown_hours: Option<u64>,
+
+    // voting reward adopted by governance; the configured default is used while it's not set
+    #[serde(default)]
+    pub voting_reward: Option<Cycles>,
+
+    // additional reserved handles adopted by governance; they're always merged with the configured
+    // defaults
+    #[serde(default)]
+    pub reserved_handles: Option<Vec<String>>,
+
+    // additional reserved realm names adopted by governance; they're always merged with the
+    // configured defaults
+    #[serde(default)]
+    pub reserved_realm_names: Option<Vec<String>>,
+
+    // proposal deposit adopted by governance; the configured default is used while it's not set
+    #[serde(default)]
+    pub proposal_deposit: Option<Cycles>,
+
+    // the latest changes of all posts for the indexer sync
+    #[serde(default)]
+    pub post_changes: PostChanges,
+
+    // weeks of inactivity after which the voting power starts to decay and reaches zero, adopted
+    // by governance; the configured defaults are used while it's not set
+    #[serde(default)]
+    pub voting_power_decay: Option<(u64, u64)>,
+
+    #[serde(default)]
+    pub leaderboards: leaderboards::Leaderboards,
+
+    // activity stats of realms recomputed in hourly chores
+    #[serde(default)]
+    realm_stats: BTreeMap<String, RealmStats>,
+
+    // (timestamp, balance) samples of the main canister's cycle balance taken in hourly chores
+    #[serde(default)]
+    cycle_balance_samples: VecDeque<(u64, u64)>,
+    #[serde(default)]
+    low_cycle_balance_alerted: bool,
+
+    // timestamps of the last counted impressions per (viewer, post) pair
+    #[serde(default)]
+    recent_impressions: BTreeMap<(UserId, PostId), u64>,
+    // impressions counted since the last flush; they're added to the posts in the hourly chores
+    #[serde(default)]
+    pending_impressions: BTreeMap<PostId, u32>,
+
+    #[serde(skip)]
+    pub rate_limits: rate_limits::RateLimits,
+
+    #[serde(default)]
+    pub vesting_grants: Vec<proposals::VestingGrant>,
+
+    // number of times the karma was collected for minting; reactions can only be refunded within
+    // the epoch they were made in
+    #[serde(default)]
+    minting_epoch: u64,
+
+    // (timestamp, minting ratio, circulating supply) samples taken in daily chores
+    #[serde(default)]
+    minting_ratio_history: VecDeque<(u64, u64, Token)>,
+
+    // start of the current minting window and the tokens minted within it
+    #[serde(default)]
+    minted_in_window: (u64, Token),
+
+    // (proposal id, recipient account, e8s) of executed ICP transfer proposals
+    #[serde(default)]
+    pub pending_icp_transfers: Vec<(u32, String, u64)>,
+    // failed attempts of pending ICP transfers by proposal id
+    #[serde(default)]
+    pub icp_transfer_attempts: BTreeMap<u32, u32>,
+
+    // tag -> users with notified feeds containing the tag; rebuilt on upgrades, so entries of
+    // feeds unfollowed since then can be stale and every match is checked against the user's feeds
+    #[serde(skip)]
+    feed_subscribers: BTreeMap<String, BTreeSet<UserId>>,
+
+    // ICP (in e8s) collected as tip fees
+    #[serde(default)]
+    pub total_tip_fees: u64,
+
+    // transitions of proposals to final statuses, oldest first
+    #[serde(default)]
+    pub proposal_events: VecDeque<proposals::StatusChange>,
+
+    // stats computed in the chores and served until the next computation
+    #[serde(skip)]
+    stats_snapshot: Option<Stats>,
+
+    // post id -> (deadline, blobs of the post which couldn't be stored yet)
+    #[serde(default)]
+    pub pending_blobs: BTreeMap<PostId, (u64, Vec<(String, Blob)>)>,
+
+    // post id -> versions of the post to be hashed once the deletion becomes permanent
+    #[serde(default)]
+    pub pending_deletions: BTreeMap<PostId, Vec<String>>,
 }
 
 #[derive(Default, Deserialize, Serialize)]
@@ -232,6 +621,65 @@ impl State {
         }
     }
 
+    pub fn emergency_binary_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.emergency_binary);
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Sets a new emergency binary. Replacing a binary which was already confirmed by someone
+    /// requires the hash of the replaced binary, so that accumulated votes aren't discarded by
+    /// accident or by a single griefing stalwart unnoticed.
+    pub fn set_emergency_release(
+        &mut self,
+        caller: Principal,
+        binary: Vec<u8>,
+        replaced_hash: Option<String>,
+    ) -> Result<(), String> {
+        let user = self.principal_to_user(caller).ok_or("no user found")?;
+        if !user.stalwart {
+            return Err("only stalwarts can set emergency releases".into());
+        }
+        if binary.is_empty() {
+            return Err("empty binary".into());
+        }
+        if !self.emergency_votes.is_empty()
+            && replaced_hash.as_ref() != Some(&self.emergency_binary_hash())
+        {
+            return Err(
+                "the current binary has votes, please confirm its replacement with its hash".into(),
+            );
+        }
+        let name = user.name.clone();
+        let discarded_votes = self.emergency_votes.len();
+        self.emergency_binary = binary;
+        self.emergency_votes.clear();
+        self.logger.info(format!(
+            "@{} set a new emergency binary, discarding {} votes 🚨",
+            name, discarded_votes
+        ));
+        Ok(())
+    }
+
+    /// Returns the progress of the emergency release or nothing if no binary is set.
+    pub fn emergency_release_status(
+        &self,
+        caller: Principal,
+        now: u64,
+    ) -> Option<EmergencyReleaseStatus> {
+        if self.emergency_binary.is_empty() {
+            return None;
+        }
+        let required =
+            (self.active_voting_power(now) * CONFIG.proposal_approval_threshold as u64 + 99) / 100;
+        Some(EmergencyReleaseStatus {
+            hash: self.emergency_binary_hash(),
+            votes: self.emergency_votes.values().sum(),
+            required,
+            confirmed_by_caller: self.emergency_votes.contains_key(&caller),
+        })
+    }
+
     pub fn execute_pending_emergency_upgrade(&mut self, force: bool) -> bool {
         if self.emergency_binary.is_empty() {
             return false;
@@ -278,22 +726,46 @@ impl State {
         Ok(())
     }
 
-    pub fn active_voting_power(&self, time: u64) -> Token {
+    /// Returns the voting power of the user holding the balance. The voting power is the full
+    /// balance while the user was active within the first decay period, then it decreases
+    /// linearly with the time of inactivity and reaches zero at the decay horizon. If both are
+    /// equal, the voting power drops to zero at once.
+    pub fn effective_voting_power(&self, user: &User, balance: Token, time: u64) -> Token {
+        let (start, horizon) = self.voting_power_decay();
+        let inactivity = time.saturating_sub(user.last_activity);
+        if inactivity < start * WEEK {
+            return balance;
+        }
+        if inactivity >= horizon * WEEK {
+            return 0;
+        }
+        (balance as u128 * (horizon * WEEK - inactivity) as u128
+            / ((horizon - start) * WEEK) as u128) as Token
+    }
+
+    /// Returns the effective voting power of all users holding tokens, unless it's zero.
+    pub fn voting_powers(&self, time: u64) -> Vec<(UserId, Token)> {
         self.balances
             .iter()
             .filter_map(|(acc, balance)| {
-                self.principal_to_user(acc.owner).and_then(|user| {
-                    user.active_within_weeks(time, CONFIG.voting_power_activity_weeks)
-                        .then_some(*balance)
-                })
+                self.principal_to_user(acc.owner)
+                    .map(|user| (user.id, self.effective_voting_power(user, *balance, time)))
             })
+            .filter(|(_, voting_power)| *voting_power > 0)
+            .collect()
+    }
+
+    pub fn active_voting_power(&self, time: u64) -> Token {
+        self.voting_powers(time)
+            .into_iter()
+            .map(|(_, voting_power)| voting_power)
             .sum()
     }
 
     fn spend_to_user_karma<T: ToString>(&mut self, id: UserId, amount: Cycles, log: T) {
         let user = self.users.get_mut(&id).expect("no user found");
         user.change_karma(amount as Karma, log.to_string());
-        if amount > CONFIG.voting_reward {
+        if amount > self.voting_reward() {
             self.logger.info(format!(
                 "Spent `{}` cycles on @{}'s karma for {}.",
                 amount,
@@ -351,7 +823,7 @@ impl State {
         };
         if result.is_ok() {
             if let Some(message) = notification {
-                receiver.notify(message);
+                receiver.notify_with_type(NotificationType::Tip, message);
             }
         }
         result
@@ -359,6 +831,7 @@ impl State {
 
     pub fn load(&mut self) {
         assets::load();
+        self.refresh_stats(time());
         match token::balances_from_ledger(&self.ledger) {
             Ok(value) => self.balances = value,
             Err(err) => self.logger.log(
@@ -366,3150 +839,8605 @@ impl State {
                 "CRITICAL".into(),
             ),
         }
+        for user in self.users.values_mut() {
+            user.backfill_karma_history();
+            user.backfill_cycles_history();
+        }
+        for proposal in self.proposals.iter_mut() {
+            proposal.backfill_outcome();
+            proposal.backfill_voting_rewards();
+        }
+        self.backfill_engagement();
+        self.backfill_post_changes();
+        self.index_feed_subscribers();
         self.last_upgrade = time();
         self.last_hourly_chores = time();
     }
 
-    pub fn hot_posts(&self, realm: Option<String>, page: usize) -> Vec<Post> {
-        self.hot
-            .iter()
-            .filter_map(|post_id| Post::get(self, post_id))
-            .filter(|post| realm.is_none() || post.realm == realm)
-            .skip(page * CONFIG.feed_page_size)
-            .take(CONFIG.feed_page_size)
-            .cloned()
-            .collect()
+    fn index_feed_subscribers(&mut self) {
+        self.feed_subscribers.clear();
+        for user in self.users.values() {
+            for tag in user.notified_feeds.iter().flatten() {
+                self.feed_subscribers
+                    .entry(tag.clone())
+                    .or_default()
+                    .insert(user.id);
+            }
+        }
     }
 
-    pub fn toggle_realm_membership(&mut self, principal: Principal, name: String) -> bool {
-        if !self.realms.contains_key(&name) {
-            return false;
+    // Logs all existing posts as created, if no post changes were recorded yet. Only the post ids
+    // are used, so that the archive doesn't need to be decoded during the upgrade.
+    fn backfill_post_changes(&mut self) {
+        if !self.post_changes.is_empty() {
+            return;
         }
-        let user = match self.principal_to_user_mut(principal) {
-            Some(user) => user,
-            _ => return false,
-        };
-        if user.realms.contains(&name) {
-            user.realms.retain(|realm| realm != &name);
-            self.realms
-                .get_mut(&name)
-                .expect("no realm found")
-                .num_members -= 1;
-            return false;
+        for post_id in 0..self.next_post_id {
+            self.post_changes.record(post_id, PostEvent::Created);
         }
-        user.realms.push(name.clone());
-        self.realms
-            .get_mut(&name)
-            .expect("no realm found")
-            .num_members += 1;
-        true
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub fn edit_realm(
-        &mut self,
-        principal: Principal,
-        name: String,
-        logo: String,
-        label_color: String,
-        theme: String,
-        description: String,
-        controllers: Vec<UserId>,
-    ) -> Result<(), String> {
-        let user_id = self
-            .principal_to_user_mut(principal)
-            .ok_or("no user found")?
-            .id;
-        let realm = self.realms.get_mut(&name).ok_or("no realm found")?;
-        if !realm.controllers.contains(&user_id) {
-            return Err("not authorized".into());
+    // Computes the engagement totals of all users from their posts, if no totals were recorded yet.
+    // This runs during the upgrade, so only the posts in the heap are counted: decoding the whole
+    // archive could exceed the instruction limit and bloat the post cache.
+    fn backfill_engagement(&mut self) {
+        if self
+            .users
+            .values()
+            .any(|user| user.engagement != Default::default())
+        {
+            return;
         }
-        if controllers.is_empty() {
-            return Err("no controllers specified".into());
+        let mut totals: BTreeMap<UserId, Engagement> = Default::default();
+        for post in self.posts.values() {
+            let engagement = totals.entry(post.user).or_default();
+            if post.parent.is_none() {
+                engagement.posts += 1;
+            } else {
+                engagement.comments += 1;
+            }
+            engagement.reactions_received += post
+                .reactions
+                .values()
+                .map(|users| users.len() as u64)
+                .sum::<u64>();
+            for (i, (_, amount)) in post.tips.iter().enumerate() {
+                let shares = match post.tip_shares.get(&i) {
+                    Some(shares) => shares.clone(),
+                    None => vec![(post.user, *amount)],
+                };
+                for (recipient_id, e8s) in shares {
+                    *totals
+                        .entry(recipient_id)
+                        .or_default()
+                        .tips_received
+                        .entry("ICP".into())
+                        .or_default() += e8s;
+                }
+            }
         }
-        if !logo.is_empty() {
-            realm.logo = logo;
+        for (user_id, engagement) in totals {
+            if let Some(user) = self.users.get_mut(&user_id) {
+                user.engagement = engagement;
+            }
         }
-        realm.description = description;
-        realm.controllers = controllers;
-        realm.label_color = label_color;
-        realm.theme = theme;
-        Ok(())
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub fn create_realm(
-        &mut self,
-        principal: Principal,
-        name: String,
-        logo: String,
-        label_color: String,
-        theme: String,
-        description: String,
-        controllers: Vec<UserId>,
-    ) -> Result<(), String> {
-        if controllers.is_empty() {
-            return Err("no controllers specified".into());
+    fn compute_realm_stats(&mut self, now: u64) {
+        let mut stats: BTreeMap<String, RealmStats> = Default::default();
+        let mut authors: BTreeMap<&String, BTreeSet<UserId>> = Default::default();
+        for post in self
+            .last_posts(None, true)
+            .take_while(|post| post.timestamp() + 30 * DAY > now)
+        {
+            let name = match &post.realm {
+                Some(name) => name,
+                None => continue,
+            };
+            let realm_stats = stats.entry(name.clone()).or_default();
+            realm_stats.posts_last_month += 1;
+            if post.timestamp() + WEEK > now {
+                realm_stats.posts_last_week += 1;
+            }
+            authors.entry(name).or_default().insert(post.user);
         }
-
-        if name.len() > CONFIG.max_realm_name {
-            return Err("realm name too long".into());
+        for (name, authors) in authors {
+            if let Some(realm_stats) = stats.get_mut(name) {
+                realm_stats.active_authors = authors.len() as u64;
+            }
         }
+        self.realm_stats = stats;
+    }
 
-        if name
-            .chars()
-            .any(|c| !char::is_alphanumeric(c) && c != '_' && c != '-')
+    /// Returns the stats of the realm, where the activity numbers are as of the last hourly
+    /// chores.
+    pub fn realm_stats(&self, name: &str) -> Result<RealmStats, String> {
+        let realm = self.realms.get(name).ok_or("no realm found")?;
+        Ok(RealmStats {
+            members: realm.num_members,
+            posts: realm.num_posts,
+            tips: realm.tips,
+            ..self.realm_stats.get(name).cloned().unwrap_or_default()
+        })
+    }
+
+    /// Recomputes the stats snapshot and publishes it as a certified asset, so that clients can
+    /// verify its integrity.
+    pub fn refresh_stats(&mut self, now: u64) {
+        let stats = self.compute_stats(now);
+        assets::add_asset(
+            &["/api/stats.json"],
+            vec![
+                ("Content-Type".to_string(), "application/json".to_string()),
+                (
+                    "Cache-Control".to_string(),
+                    "public, max-age=3600".to_string(),
+                ),
+            ],
+            serde_json::to_vec(&stats).expect("couldn't serialize stats"),
+        );
+        self.stats_snapshot = Some(stats);
+    }
+
+    pub fn force_stats_refresh(&mut self, principal: Principal, now: u64) -> Result<(), String> {
+        if !self
+            .principal_to_user(principal)
+            .map(|user| user.stalwart)
+            .unwrap_or_default()
         {
-            return Err("realm name should be an alpha-numeric string".into());
+            return Err("only stalwarts can refresh the stats".into());
         }
+        self.refresh_stats(now);
+        Ok(())
+    }
 
-        if name.chars().all(|c| char::is_ascii_digit(&c)) {
-            return Err("realm name should have at least on character".into());
+    /// Returns the health of the canister without computing anything expensive. Chores are
+    /// considered stalled if the hourly chores didn't run for two hours and the storage is
+    /// degraded if all buckets are full.
+    pub fn health(&self, canister_cycle_balance: u64, heap_size: u64, now: u64) -> Health {
+        let mut degraded = Vec::new();
+        if canister_cycle_balance < CONFIG.low_cycle_balance_threshold {
+            degraded.push("cycles");
         }
-
-        if CONFIG.name.to_lowercase() == name.to_lowercase() || self.realms.contains_key(&name) {
-            return Err("realm name taken".into());
+        if heap_size * 100 >= CONFIG.max_heap_size * CONFIG.heap_warning_percentage {
+            degraded.push("heap");
         }
+        if !self.storage.buckets.is_empty()
+            && self
+                .storage
+                .buckets
+                .values()
+                .all(|size| *size >= CONFIG.max_bucket_size)
+        {
+            degraded.push("storage");
+        }
+        if self.last_hourly_chores + 2 * HOUR < now {
+            degraded.push("chores");
+        }
+        Health {
+            canister_cycle_balance,
+            heap_size,
+            max_heap_size: CONFIG.max_heap_size,
+            users: self.users.len(),
+            posts: self.next_post_id,
+            last_hourly_chores: self.last_hourly_chores,
+            degraded,
+        }
+    }
 
-        let user = self
-            .principal_to_user(principal)
-            .ok_or("no user found")?
-            .clone();
-
-        self.charge(user.id, CONFIG.realm_cost, "realm creation".to_string())
-            .map_err(|err| {
-                format!(
-                    "couldn't charge {} cycles for realm creation: {}",
-                    CONFIG.realm_cost, err
-                )
-            })?;
+    /// Returns the snapshot of the stats computed in the last chores with the cheap live values
+    /// updated.
+    pub fn stats(&self, now: u64) -> Stats {
+        let mut stats = match &self.stats_snapshot {
+            Some(stats) => stats.clone(),
+            None => self.compute_stats(now),
+        };
+        stats.canister_cycle_balance = canister_balance();
+        stats.burned_cycles = self.burned_cycles;
+        stats.last_upgrade = self.last_upgrade;
+        stats.state_size = stable64_size() << 16;
+        stats
+    }
 
-        self.realms.insert(
-            name.clone(),
-            Realm {
-                logo,
-                description,
-                controllers,
-                label_color,
-                theme,
-                ..Default::default()
-            },
-        );
-
-        self.logger.info(format!(
-            "@{} created realm [{1}](/#/realm/{1}) 🎭",
-            user.name, name
-        ));
-
-        Ok(())
-    }
-
-    pub async fn tip(principal: Principal, post_id: PostId, amount: String) -> Result<(), String> {
-        let result: Result<_, String> = read(|state| {
-            let tipper = state.principal_to_user(principal).ok_or("no user found")?;
-            let tipper_id = tipper.id;
-            let tipper_name = tipper.name.clone();
-            let author_id = Post::get(state, &post_id).ok_or("post not found")?.user;
-            let recipient = state
-                .users
-                .get(&author_id)
-                .ok_or("no user found")?
-                .account
-                .clone();
-            Ok((recipient, tipper_name, author_id, tipper_id))
-        });
-        let (recipient, tipper_name, author_id, tipper_id) = result?;
-        let tip = State::icp_transfer(principal, recipient, &amount).await?;
-        mutate(|state| {
-            Post::mutate(state, &post_id, |post| {
-                post.watchers.insert(tipper_id);
-                post.tips.push((tipper_id, tip.e8s()));
-                Ok(())
-            })?;
-            state
-                .users
-                .get_mut(&author_id)
-                .expect("user not found")
-                .notify_about_post(
-                    format!(
-                        "@{} tipped you with `{}` ICP for your post",
-                        tipper_name, amount,
-                    ),
-                    post_id,
-                );
-            Ok(())
-        })
+    pub fn hot_posts(
+        &self,
+        realm: Option<String>,
+        languages: &[String],
+        hide_flagged: bool,
+        page: usize,
+        page_size: usize,
+    ) -> Vec<Post> {
+        self.feed(
+            FeedMode::Hot,
+            realm,
+            languages,
+            hide_flagged,
+            page,
+            page_size,
+            time(),
+        )
     }
 
-    fn new_user(&mut self, principal: Principal, timestamp: u64, name: String) -> UserId {
-        let id = self.new_user_id();
-        let mut user = User::new(principal, id, timestamp, name);
-        user.notify(format!("**Welcome!** 🎉 Use #{} as your personal blog, micro-blog or a photo blog. Use #hashtags to connect with others. Make sure you understand [how {0} works](/#/whitepaper). And finally, [say hello](#/new) and start earning karma!", CONFIG.name));
-        self.principals.insert(principal, user.id);
-        self.logger
-            .info(format!("@{} joined {} 🚀", &user.name, CONFIG.name));
-        self.users.insert(user.id, user);
-        id
+    /// Returns a page of root posts ordered according to the feed mode. Posts in languages other
+    /// than the given ones are skipped, unless the list is empty; posts with content warnings are
+    /// skipped if `hide_flagged` is set.
+    #[allow(clippy::too_many_arguments)]
+    pub fn feed(
+        &self,
+        mode: FeedMode,
+        realm: Option<String>,
+        languages: &[String],
+        hide_flagged: bool,
+        page: usize,
+        page_size: usize,
+        now: u64,
+    ) -> Vec<Post> {
+        let pinned = realm
+            .as_deref()
+            .and_then(|name| self.realm_pinned_post(name));
+        let posts: Box<dyn Iterator<Item = &Post>> = match mode {
+            FeedMode::Hot => Box::new(
+                self.hot
+                    .iter()
+                    .filter_map(|post_id| Post::get(self, post_id))
+                    .filter(|post| !post.hidden() && (realm.is_none() || post.realm == realm)),
+            ),
+            FeedMode::New => self.last_posts(realm, false),
+            FeedMode::Top(window) => {
+                let since = now.saturating_sub(window.duration());
+                let mut posts = self
+                    .last_posts(realm, false)
+                    .take_while(|post| post.timestamp() >= since)
+                    .collect::<Vec<_>>();
+                let catalog = self.reactions();
+                // the sort is stable, so posts with equally many reactions stay newest first
+                posts.sort_by_key(|post| std::cmp::Reverse(post.positive_reactions(&catalog)));
+                Box::new(posts.into_iter())
+            }
+        };
+        let posts = Box::new(posts.filter(move |post| {
+            post.in_languages(languages) && !post.hidden_by_warning(hide_flagged)
+        }));
+        self.with_pinned(pinned, posts)
+            .skip(page * page_size)
+            .take(page_size)
+            .cloned()
+            .collect()
     }
 
-    pub async fn create_user(
+    /// Pins the post to the top of the realm if a realm is specified or to the top of the
+    /// caller's profile otherwise. Pinning no post removes the pin.
+    pub fn pin_post(
+        &mut self,
         principal: Principal,
-        name: String,
-        invite: Option<String>,
+        post_id: Option<PostId>,
+        realm: Option<String>,
     ) -> Result<(), String> {
-        let invited = mutate(|state| {
-            state.validate_username(&name)?;
-            if let Some(user) = state.principal_to_user(principal) {
-                return Err(format!("principal already assigned to user @{}", user.name));
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        if let Some(id) = post_id {
+            let post = Post::get(self, &id).ok_or("no post found")?;
+            if post.hidden() {
+                return Err("post deleted".into());
             }
-            if let Some((inviter_id, cycles)) = invite.and_then(|code| state.invites.remove(&code))
-            {
-                let inviter = state.users.get_mut(&inviter_id).ok_or("no user found")?;
-                let new_user_id = if inviter.invites_budget > cycles {
-                    inviter.invites_budget = inviter.invites_budget.saturating_sub(cycles);
-                    state.spend(cycles, "user invite");
-                    state.new_user(principal, time(), name.clone())
-                } else if inviter.cycles() > cycles {
-                    let new_user_id = state.new_user(principal, time(), name.clone());
-                    state
-                        .cycle_transfer(
-                            inviter_id,
-                            new_user_id,
-                            cycles,
-                            0,
-                            Destination::Cycles,
-                            "claimed by invited user",
-                            None,
-                        )
-                        .unwrap_or_else(|err| panic!("couldn't use the invite: {}", err));
-                    new_user_id
-                } else {
-                    return Err("inviter has not enough cycles".into());
-                };
-                let user = state.users.get_mut(&new_user_id).expect("no user found");
-                user.invited_by = Some(inviter_id);
-                if let Some(inviter) = state.users.get_mut(&inviter_id) {
-                    inviter.notify(format!(
-                        "Your invite was used by @{}! Thanks for helping #{} grow! 🤗",
-                        name, CONFIG.name
-                    ));
+            if post.parent.is_some() {
+                return Err("only root posts can be pinned".into());
+            }
+            match &realm {
+                Some(_) if post.realm != realm => {
+                    return Err("post doesn't belong to the realm".into())
                 }
-                return Ok(true);
+                None if post.user != user_id => return Err("not authorized".into()),
+                _ => {}
             }
-            Ok(false)
-        })?;
-
-        if invited {
-            return Ok(());
         }
+        match realm {
+            Some(name) => {
+                let realm = self.realms.get_mut(&name).ok_or("no realm found")?;
+                if !realm.controllers.contains(&user_id) {
+                    return Err("not authorized".into());
+                }
+                realm.pinned = post_id;
+            }
+            None => self.users.get_mut(&user_id).expect("no user found").pinned = post_id,
+        }
+        Ok(())
+    }
 
-        if let Ok(Invoice { paid: true, .. }) = State::mint_cycles(principal, 0).await {
-            mutate(|state| state.new_user(principal, time(), name));
-            // After the user has beed created, transfer cycles.
-            return State::mint_cycles(principal, 0).await.map(|_| ());
+    // Removes the post from the profile of its author and from its realm if it was pinned there.
+    fn unpin_post(&mut self, post_id: PostId) {
+        let (user_id, realm) = match Post::get(self, &post_id) {
+            Some(post) => (post.user, post.realm.clone()),
+            None => return,
+        };
+        if let Some(user) = self.users.get_mut(&user_id) {
+            if user.pinned == Some(post_id) {
+                user.pinned = None;
+            }
         }
+        if let Some(realm) = realm.and_then(|name| self.realms.get_mut(&name)) {
+            if realm.pinned == Some(post_id) {
+                realm.pinned = None;
+            }
+        }
+    }
 
-        Err("payment missing or the invite is invalid".to_string())
+    /// Puts the pinned post in front of the given posts, so that it comes first regardless of
+    /// its age and doesn't appear a second time further down.
+    pub fn with_pinned<'a>(
+        &'a self,
+        pinned: Option<PostId>,
+        posts: Box<dyn Iterator<Item = &'a Post> + 'a>,
+    ) -> Box<dyn Iterator<Item = &'a Post> + 'a> {
+        let pinned = pinned
+            .and_then(|id| Post::get(self, &id))
+            .filter(|post| !post.hidden());
+        let pinned_id = pinned.map(|post| post.id);
+        Box::new(
+            pinned
+                .into_iter()
+                .chain(posts.filter(move |post| Some(post.id) != pinned_id)),
+        )
     }
 
-    pub fn invites(&self, principal: Principal) -> Vec<(String, Cycles)> {
-        self.principal_to_user(principal)
-            .map(|user| {
-                self.invites
-                    .iter()
-                    .filter(|(_, (user_id, _))| user_id == &user.id)
-                    .map(|(code, (_, cycles))| (code.clone(), *cycles))
-                    .collect::<Vec<_>>()
+    /// Returns the post pinned to the realm if it's still part of the realm.
+    pub fn realm_pinned_post(&self, name: &str) -> Option<PostId> {
+        self.realms
+            .get(name)
+            .and_then(|realm| realm.pinned)
+            .filter(|id| {
+                Post::get(self, id)
+                    .map(|post| post.realm.as_deref() == Some(name))
+                    .unwrap_or_default()
             })
+    }
+
+    /// Follows or unfollows the realm, so that its posts appear in the personal feed of the user
+    /// without joining it. Returns true if the realm is followed.
+    pub fn toggle_following_realm(&mut self, principal: Principal, name: String) -> bool {
+        if !self.realms.contains_key(&name) {
+            return false;
+        }
+        self.principal_to_user_mut(principal)
+            .map(|user| user.toggle_following_realm(name))
             .unwrap_or_default()
     }
 
-    pub fn create_invite(&mut self, principal: Principal, cycles: Cycles) -> Result<(), String> {
-        let min_cycles = CONFIG.min_cycles_for_inviting;
+    /// Joins or leaves the realm. Returns true if the user joined the realm.
+    pub fn toggle_realm_membership(
+        &mut self,
+        principal: Principal,
+        name: String,
+    ) -> Result<bool, String> {
+        let realm = self.realms.get(&name).ok_or("no realm found")?;
+        let full = realm
+            .max_members
+            .map_or(false, |max_members| realm.num_members >= max_members);
         let user = self
             .principal_to_user_mut(principal)
             .ok_or("no user found")?;
-        if cycles < min_cycles {
-            return Err(format!(
-                "smallest invite must contain {} cycles",
-                min_cycles
-            ));
+        if user.realms.contains(&name) {
+            user.realms.retain(|realm| realm != &name);
+            self.realms
+                .get_mut(&name)
+                .expect("no realm found")
+                .num_members -= 1;
+            return Ok(false);
         }
-        if user.cycles() < cycles {
-            return Err("not enough cycles".into());
+        if full {
+            return Err(format!("realm {} has reached its member limit", name));
         }
-        if !user.trusted() {
-            return Err("bootcamp users cannot invite others".into());
+        user.realms.push(name.clone());
+        self.realms
+            .get_mut(&name)
+            .expect("no realm found")
+            .num_members += 1;
+        Ok(true)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn edit_realm(
+        &mut self,
+        principal: Principal,
+        name: String,
+        logo: String,
+        label_color: String,
+        theme: String,
+        description: String,
+        controllers: Vec<UserId>,
+    ) -> Result<(), String> {
+        let user_id = self
+            .principal_to_user_mut(principal)
+            .ok_or("no user found")?
+            .id;
+        let realm = self.realms.get_mut(&name).ok_or("no realm found")?;
+        if !realm.controllers.contains(&user_id) {
+            return Err("not authorized".into());
         }
-        let mut hasher = Sha256::new();
-        hasher.update(principal.as_slice());
-        hasher.update(time().to_be_bytes());
-        let code = format!("{:x}", hasher.finalize())[..10].to_string();
-        let user_id = user.id;
-        self.invites.insert(code, (user_id, cycles));
+        if controllers.is_empty() {
+            return Err("no controllers specified".into());
+        }
+        if !logo.is_empty() {
+            realm.logo = logo;
+        }
+        realm.description = description;
+        realm.controllers = controllers;
+        realm.label_color = label_color;
+        realm.theme = theme;
         Ok(())
     }
 
-    fn critical<T: ToString>(&mut self, message: T) {
-        self.logger
-            .log(&message.to_string(), "CRITICAL".to_string());
-        self.users.values_mut().for_each(|user| {
-            user.notify(format!("CRITICAL SYSTEM ERROR: {}", message.to_string()))
-        });
+    pub fn set_realm_min_voting_reputation(
+        &mut self,
+        principal: Principal,
+        name: &str,
+        min_voting_reputation: u64,
+    ) -> Result<(), String> {
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        let realm = self.realms.get_mut(name).ok_or("no realm found")?;
+        if !realm.controllers.contains(&user_id) {
+            return Err("not authorized".into());
+        }
+        realm.min_voting_reputation = min_voting_reputation;
+        Ok(())
     }
 
-    fn notify_with_predicate<T: AsRef<str>>(
+    /// Sets the member limit of the realm. Members above a lowered limit stay in the realm.
+    pub fn set_realm_max_members(
         &mut self,
-        filter: &dyn Fn(&User) -> bool,
-        message: T,
-        predicate: Predicate,
-    ) {
-        self.users
-            .values_mut()
-            .filter(|u| filter(u))
-            .for_each(|u| u.notify_with_params(&message, Some(predicate.clone())));
+        principal: Principal,
+        name: &str,
+        max_members: Option<u64>,
+    ) -> Result<(), String> {
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        let realm = self.realms.get_mut(name).ok_or("no realm found")?;
+        if !realm.controllers.contains(&user_id) {
+            return Err("not authorized".into());
+        }
+        if max_members == Some(0) {
+            return Err("the member limit must be positive".into());
+        }
+        realm.max_members = max_members;
+        Ok(())
     }
 
-    pub fn denotify_users(&mut self, filter: &dyn Fn(&User) -> bool) {
-        for (user_id, mut inbox) in self
-            .users
-            .values_mut()
-            .filter(|u| filter(u))
-            .map(|u| (u.id, u.inbox.clone()))
-            .collect::<Vec<_>>()
-            .into_iter()
-        {
-            inbox.retain(|_, n| {
-                if let Notification::Conditional(_, predicate) = n {
-                    return match predicate {
-                        Predicate::UserReportOpen(user_id) => self
-                            .users
-                            .get(user_id)
-                            .and_then(|p| p.report.as_ref().map(|r| !r.closed))
-                            .unwrap_or_default(),
-                        Predicate::ReportOpen(post_id) => Post::get(self, post_id)
-                            .and_then(|p| p.report.as_ref().map(|r| !r.closed))
-                            .unwrap_or_default(),
-                        Predicate::Proposal(post_id) => self
-                            .proposals
-                            .iter()
-                            .last()
-                            .map(|p| p.status == Status::Open && p.post_id == *post_id)
-                            .unwrap_or_default(),
-                    };
+    pub fn set_realm_post_cost(
+        &mut self,
+        principal: Principal,
+        name: &str,
+        post_cost: Cycles,
+    ) -> Result<(), String> {
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        let realm = self.realms.get_mut(name).ok_or("no realm found")?;
+        if !realm.controllers.contains(&user_id) {
+            return Err("not authorized".into());
+        }
+        if post_cost > CONFIG.max_realm_post_cost {
+            return Err(format!(
+                "realm post cost can't be higher than {} cycles",
+                CONFIG.max_realm_post_cost
+            ));
+        }
+        realm.post_cost = post_cost;
+        Ok(())
+    }
+
+    /// Transfers cycles from the realm treasury to a realm member.
+    pub fn grant_from_realm_treasury(
+        &mut self,
+        principal: Principal,
+        name: &str,
+        receiver: UserId,
+        amount: Cycles,
+    ) -> Result<(), String> {
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        let realm = self.realms.get_mut(name).ok_or("no realm found")?;
+        if !realm.controllers.contains(&user_id) {
+            return Err("not authorized".into());
+        }
+        if amount == 0 || amount > realm.treasury {
+            return Err("invalid grant amount".into());
+        }
+        let receiver = self.users.get_mut(&receiver).ok_or("no receiver found")?;
+        if !receiver.realms.iter().any(|realm| realm == name) {
+            return Err("grants can only be made to realm members".into());
+        }
+        receiver.change_cycles(
+            amount,
+            CyclesDelta::Plus,
+            format!("grant from realm {}", name),
+        )?;
+        receiver.notify(format!(
+            "You received a grant of `{}` cycles from the realm {}",
+            amount, name
+        ));
+        let receiver_name = receiver.name.clone();
+        realm.treasury -= amount;
+        self.logger.info(format!(
+            "Realm {} granted `{}` cycles to @{}.",
+            name, amount, receiver_name
+        ));
+        Ok(())
+    }
+
+    /// Returns the reputation of all users who participated in the realm: the number of their
+    /// posts and comments in the realm plus the positive reactions received on them. Unlike the
+    /// karma, it's only used by realm-local mechanisms. Only the posts in the heap are counted,
+    /// so that the archive is never decoded.
+    pub fn realm_reputation(&self, name: &str) -> BTreeMap<UserId, u64> {
+        let catalog = self.reactions();
+        let mut reputation = BTreeMap::default();
+        for post in self
+            .last_posts(Some(name.to_string()), true)
+            .take_while(|post| !post.archived)
+            .filter(|post| !post.is_deleted())
+        {
+            *reputation.entry(post.user).or_default() +=
+                1 + post.positive_reactions(&catalog) as u64;
+        }
+        reputation
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_realm(
+        &mut self,
+        principal: Principal,
+        name: String,
+        logo: String,
+        label_color: String,
+        theme: String,
+        description: String,
+        controllers: Vec<UserId>,
+    ) -> Result<(), String> {
+        if controllers.is_empty() {
+            return Err("no controllers specified".into());
+        }
+
+        self.validate_realm_name(&name)?;
+
+        let user = self
+            .principal_to_user(principal)
+            .ok_or("no user found")?
+            .clone();
+
+        self.check_new_user_cooldown(&user, time(), "create realms")?;
+
+        // otherwise the realm quota could be bypassed by listing other users as controllers
+        if !controllers.contains(&user.id) {
+            return Err("the realm creator must be a controller".into());
+        }
+
+        if self.realm_quota(user.id) == 0 {
+            return Err(match CONFIG.max_realms {
+                Some(max_realms) if self.realms.len() >= max_realms => {
+                    format!("the limit of {} realms is reached", max_realms)
                 }
-                true
+                _ => format!(
+                    "users can't control more than {} realms",
+                    CONFIG.max_realms_per_user
+                ),
             });
-            self.users.get_mut(&user_id).expect("no user found").inbox = inbox;
         }
+
+        self.charge(user.id, CONFIG.realm_cost, "realm creation".to_string())
+            .map_err(|err| {
+                format!(
+                    "couldn't charge {} cycles for realm creation: {}",
+                    CONFIG.realm_cost, err
+                )
+            })?;
+
+        self.realms.insert(
+            name.clone(),
+            Realm {
+                logo,
+                description,
+                controllers,
+                label_color,
+                theme,
+                ..Default::default()
+            },
+        );
+
+        self.logger.info(format!(
+            "@{} created realm [{1}](/#/realm/{1}) 🎭",
+            user.name, name
+        ));
+
+        Ok(())
     }
 
-    pub fn search(&self, mut term: String) -> Vec<SearchResult> {
-        const SNIPPET_LEN: usize = 100;
-        term = term.to_lowercase();
-        let snippet = |body: &str, i: usize| {
-            if body.len() < SNIPPET_LEN {
-                body.to_string()
-            } else {
-                body.chars()
-                    .skip(i.saturating_sub(SNIPPET_LEN / 2))
-                    .skip_while(|c| c.is_alphanumeric())
-                    .take(SNIPPET_LEN)
-                    .skip_while(|c| c.is_alphanumeric())
-                    .collect::<String>()
-            }
-            .replace('\n', " ")
-        };
-        self.users
-            .iter()
-            .filter_map(|(id, User { name, about, .. })| {
-                if format!("@{} {0} {} {}", name, id, about)
-                    .to_lowercase()
-                    .contains(&term)
-                {
-                    return Some(SearchResult {
-                        id: *id,
-                        relevant: about.clone(),
-                        result: "user".to_string(),
-                        ..Default::default()
-                    });
-                }
-                None
-            })
-            .chain(self.realms.iter().filter_map(|(id, realm)| {
-                if id.to_lowercase().contains(&term) {
-                    return Some(SearchResult {
-                        generic_id: id.clone(),
-                        relevant: snippet(realm.description.as_str(), 0),
-                        result: "realm".to_string(),
-                        ..Default::default()
-                    });
-                }
-                if let Some(i) = realm.description.to_lowercase().find(&term) {
-                    return Some(SearchResult {
-                        generic_id: id.clone(),
-                        relevant: snippet(realm.description.as_str(), i),
-                        result: "realm".to_string(),
-                        ..Default::default()
-                    });
-                }
-                None
-            }))
-            .chain(
-                self.recent_tags(None, 500)
-                    .into_iter()
-                    .filter_map(|(tag, _)| {
-                        if format!("#{} {0}", tag).to_lowercase().contains(&term) {
-                            return Some(SearchResult {
-                                relevant: tag,
-                                result: "tag".to_string(),
-                                ..Default::default()
-                            });
-                        }
-                        None
-                    }),
-            )
-            .chain(
-                self.last_posts(None, true)
-                    .filter_map(|Post { id, body, user, .. }| {
-                        if id.to_string() == term {
-                            return Some(SearchResult {
-                                id: *id,
-                                user_id: *user,
-                                relevant: snippet(body, 0),
-                                result: "post".to_string(),
-                                ..Default::default()
-                            });
-                        }
-                        let search_body = body.to_lowercase();
-                        if let Some(i) = search_body.find(&term) {
-                            return Some(SearchResult {
-                                id: *id,
-                                user_id: *user,
-                                relevant: snippet(body, i),
-                                result: "post".to_string(),
-                                ..Default::default()
-                            });
-                        }
-                        None
-                    }),
-            )
-            .take(100)
-            .collect()
+    pub async fn tip(
+        principal: Principal,
+        post_id: PostId,
+        amount: String,
+        message: Option<String>,
+    ) -> Result<(), String> {
+        let message = message
+            .map(|message| sanitize_tip_message(&message))
+            .transpose()?
+            .filter(|message| !message.is_empty());
+        let (e8s, fee) = split_tip(&amount)?;
+        let author_id = read(|state| Post::get(state, &post_id).map(|post| post.user))
+            .ok_or("post not found")?;
+        State::send_tips(principal, post_id, vec![(author_id, e8s, fee)], message).await
     }
 
-    async fn top_up() {
-        let children = read(|state| state.storage.buckets.keys().cloned().collect::<Vec<_>>());
+    /// Splits the tip of the post among several recipients, e.g. co-authors or bounty hunters.
+    /// The shares must add up to the tipped amount and every share is sent separately.
+    pub async fn tip_split(
+        principal: Principal,
+        post_id: PostId,
+        amount: String,
+        shares: Vec<(UserId, String)>,
+        message: Option<String>,
+    ) -> Result<(), String> {
+        let message = message
+            .map(|message| sanitize_tip_message(&message))
+            .transpose()?
+            .filter(|message| !message.is_empty());
+        let shares = read(|state| state.validate_tip_split(principal, post_id, &amount, &shares))?;
+        State::send_tips(principal, post_id, shares, message).await
+    }
 
-        // top up the main canister
-        let balance = canister_balance();
-        let target_balance =
-            CONFIG.min_cycle_balance_main + children.len() as u64 * ICP_CYCLES_PER_XDR;
-        if balance < target_balance {
-            let xdrs = target_balance / ICP_CYCLES_PER_XDR;
-            // subtract weekly burned cycles to reduce the revenue
-            mutate(|state| state.spend(xdrs * 1000, "canister top up"));
-            match invoices::topup_with_icp(&api::id(), xdrs).await {
-                Err(err) => mutate(|state| {
-                    state.critical(format!(
-                    "FAILED TO TOP UP THE MAIN CANISTER — {}'S FUNCTIONALITY IS ENDANGERED: {:?}",
-                    CONFIG.name.to_uppercase(),
-                    err
-                ))
-                }),
-                Ok(_cycles) => mutate(|state| {
-                    state.logger.info(format!(
-                        "The main canister was topped up with cycles (balance was `{}`, now `{}`).",
-                        balance,
-                        canister_balance()
-                    ))
-                }),
-            }
+    /// Validates the split of a tip and returns the recipients with the e8s and fees of their
+    /// shares.
+    fn validate_tip_split(
+        &self,
+        principal: Principal,
+        post_id: PostId,
+        amount: &str,
+        shares: &[(UserId, String)],
+    ) -> Result<Vec<(UserId, u64, u64)>, String> {
+        let tipper_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        Post::get(self, &post_id).ok_or("post not found")?;
+        if shares.is_empty() {
+            return Err("no recipients specified".into());
         }
-
-        // top up all children canisters
-        let mut topped_up = Vec::new();
-        for canister_id in children {
-            match crate::canisters::top_up(canister_id, ICP_CYCLES_PER_XDR).await {
-                Ok(true) => topped_up.push(canister_id),
-                Err(err) => mutate(|state| state.critical(err)),
-                _ => {}
+        if shares.len() > CONFIG.max_tip_recipients {
+            return Err(format!(
+                "not more than {} recipients are allowed",
+                CONFIG.max_tip_recipients
+            ));
+        }
+        let total = parse_icp_amount(amount)?.e8s();
+        let mut recipients = BTreeSet::new();
+        let mut split = Vec::new();
+        for (recipient_id, share) in shares {
+            if *recipient_id == tipper_id {
+                return Err("you can't tip yourself".into());
+            }
+            if !self.users.contains_key(recipient_id) {
+                return Err(format!("no user with id {} found", recipient_id));
             }
+            if !recipients.insert(recipient_id) {
+                return Err(format!("user {} is listed more than once", recipient_id));
+            }
+            let (e8s, fee) = split_tip(share)?;
+            split.push((*recipient_id, e8s, fee));
         }
-        if !topped_up.is_empty() {
-            mutate(|state| {
-                state.logger.info(format!(
-                    "Topped up canisters: {:?}.",
-                    topped_up
-                        .into_iter()
-                        .map(|p| p.to_string())
-                        .collect::<Vec<_>>()
-                ))
-            })
+        if split.iter().map(|(_, e8s, _)| e8s).sum::<u64>() != total {
+            return Err("the shares don't add up to the tipped amount".into());
         }
+        Ok(split)
     }
 
-    pub fn collect_revenue(&mut self, now: u64, e8s_for_one_xdr: u64) -> HashMap<UserId, u64> {
-        let burned_cycles = self.burned_cycles;
-        if burned_cycles <= 0 {
-            return Default::default();
+    // Transfers the fees of a tip to the treasury and the shares to their recipients, records the
+    // tips on the post and notifies the recipients. The balance is checked and the fees are
+    // transferred first, so that no share is sent without its fee. If a share transfer fails,
+    // the already sent shares are still recorded.
+    async fn send_tips(
+        principal: Principal,
+        post_id: PostId,
+        shares: Vec<(UserId, u64, u64)>,
+        message: Option<String>,
+    ) -> Result<(), String> {
+        let result: Result<_, String> = read(|state| {
+            let tipper = state.principal_to_user(principal).ok_or("no user found")?;
+            let accounts = shares
+                .iter()
+                .map(|(recipient_id, _, _)| {
+                    state
+                        .users
+                        .get(recipient_id)
+                        .map(|user| user.account.clone())
+                        .ok_or("no user found")
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok((tipper.id, tipper.name.clone(), accounts))
+        });
+        let (tipper_id, tipper_name, accounts) = result?;
+        State::claim_user_icp(principal).await?;
+        let fee = shares.iter().map(|(_, _, fee)| fee).sum::<u64>();
+        #[cfg(not(test))]
+        {
+            // the fees are deducted from the shares, so the tipped amount covers all transfers
+            // except for the ledger fee of the fee transfer
+            let total = shares.iter().map(|(_, e8s, _)| e8s).sum::<u64>()
+                + if fee > 0 { invoices::fee() } else { 0 };
+            if invoices::account_balance_of_principal(principal)
+                .await
+                .e8s()
+                < total
+            {
+                return Err(format!(
+                    "not enough ICP: the tip requires {} ICP",
+                    e8s_to_icp(total)
+                ));
+            }
         }
-        let active_user_balances = self
-            .balances
-            .iter()
-            .filter_map(|(acc, balance)| {
-                let user = self.principal_to_user(acc.owner)?;
-                if user.active_within_weeks(now, CONFIG.revenue_share_activity_weeks) {
-                    return Some((user.id, *balance));
+        if fee > 0 {
+            // the ledger fee is added, so that the treasury receives the full fee
+            State::icp_transfer_e8s(
+                principal,
+                &invoices::main_account().to_string(),
+                Tokens::from_e8s(fee + invoices::fee()),
+            )
+            .await
+            .map_err(|err| format!("couldn't transfer the tip fee: {}", err))?;
+            mutate(|state| state.total_tip_fees += fee);
+        }
+        let mut tips = Vec::new();
+        let mut failure = None;
+        for ((recipient_id, e8s, share_fee), account) in shares.into_iter().zip(accounts) {
+            match State::icp_transfer_e8s(principal, &account, Tokens::from_e8s(e8s - share_fee))
+                .await
+            {
+                Ok(tip) => tips.push((recipient_id, tip.e8s())),
+                Err(err) => {
+                    failure = Some(err);
+                    break;
                 }
-                None
-            })
-            .collect::<Vec<_>>();
-        let supply_of_active_users: u64 = active_user_balances
-            .iter()
-            .map(|(_, balance)| balance)
-            .sum();
-        for (user_id, _) in active_user_balances.iter() {
-            let user = self.users.get_mut(user_id).expect("no user found");
-            if user.trusted() {
-                user.invites_budget = user.invites_budget.max(CONFIG.invites_budget_cycles);
             }
         }
-        active_user_balances
-            .into_iter()
-            .map(|(user_id, balance)| {
-                let revenue_share =
-                    burned_cycles as f64 * balance as f64 / supply_of_active_users as f64;
-                let e8s = (revenue_share / CONFIG.native_cycles_per_xdr as f64
-                    * e8s_for_one_xdr as f64) as u64;
-                (user_id, e8s)
-            })
-            .collect()
+        if tips.is_empty() {
+            return Err(failure.unwrap_or_default());
+        }
+        mutate(|state| {
+            let total = tips.iter().map(|(_, e8s)| e8s).sum::<u64>();
+            let author_id = Post::mutate(state, &post_id, |post| {
+                post.watchers.insert(tipper_id);
+                let index = post.tips.len();
+                if let Some(message) = &message {
+                    post.tip_messages.insert(index, message.clone());
+                }
+                if tips
+                    .iter()
+                    .any(|(recipient_id, _)| *recipient_id != post.user)
+                {
+                    post.tip_shares.insert(index, tips.clone());
+                }
+                post.tips.push((tipper_id, total));
+                Ok(post.user)
+            })?;
+            if let Some(realm) = Post::get(state, &post_id)
+                .and_then(|post| post.realm.clone())
+                .and_then(|name| state.realms.get_mut(&name))
+            {
+                realm.tips += total;
+            }
+            for (recipient_id, e8s) in &tips {
+                let Some(recipient) = state.users.get_mut(recipient_id) else {
+                    continue;
+                };
+                *recipient
+                    .engagement
+                    .tips_received
+                    .entry("ICP".into())
+                    .or_default() += e8s;
+                recipient.notify_about_post(
+                    NotificationType::Tip,
+                    format!(
+                        "@{} tipped you with `{}` ICP {}{}",
+                        tipper_name,
+                        e8s_to_icp(*e8s),
+                        if *recipient_id == author_id {
+                            "for your post"
+                        } else {
+                            "as your share of a tip for the post"
+                        },
+                        message
+                            .as_ref()
+                            .map(|message| format!(": '{}'", message))
+                            .unwrap_or_default()
+                    ),
+                    post_id,
+                );
+            }
+            match failure {
+                Some(err) => Err(format!(
+                    "the tip was sent to {} recipients only: {}",
+                    tips.len(),
+                    err
+                )),
+                None => Ok(()),
+            }
+        })
     }
 
-    pub fn minting_ratio(&self) -> u64 {
-        let circulating_supply: Token = self.balances.values().sum();
-        let factor = (circulating_supply as f64 / CONFIG.total_supply as f64 * 10.0) as u64;
-        1 << factor
+    fn new_user(&mut self, principal: Principal, timestamp: u64, name: String) -> UserId {
+        let id = self.new_user_id();
+        let mut user = User::new(principal, id, timestamp, name);
+        user.notify(format!("**Welcome!** 🎉 Use #{} as your personal blog, micro-blog or a photo blog. Use #hashtags to connect with others. Make sure you understand [how {0} works](/#/whitepaper). And finally, [say hello](#/new) and start earning karma!", CONFIG.name));
+        self.principals.insert(principal, user.id);
+        self.logger
+            .info(format!("@{} joined {} 🚀", &user.name, CONFIG.name));
+        self.users.insert(user.id, user);
+        id
     }
 
-    pub fn mint(&mut self, rewards: HashMap<UserId, u64>) {
-        let mut minted_tokens = 0;
-        let mut minters = Vec::new();
-        let base = 10_u64.pow(CONFIG.token_decimals as u32);
-        let ratio = self.minting_ratio();
-        let circulating_supply: Token = self.balances.values().sum();
-        if circulating_supply < CONFIG.total_supply {
-            for (user_id, user_karma) in rewards {
-                let user = match self.users.get_mut(&user_id) {
-                    Some(user) => user,
-                    _ => continue,
+    pub async fn create_user(
+        principal: Principal,
+        name: String,
+        invite: Option<String>,
+    ) -> Result<(), String> {
+        let invited = mutate(|state| {
+            state.validate_username(&name)?;
+            if let Some(user) = state.principal_to_user(principal) {
+                return Err(format!("principal already assigned to user @{}", user.name));
+            }
+            if let Some((code, invite)) = invite.and_then(|code| {
+                state
+                    .invites
+                    .get(&code)
+                    .cloned()
+                    .map(|invite| (code, invite))
+            }) {
+                invite.validate(time())?;
+                let (inviter_id, cycles) = (invite.inviter, invite.cycles);
+                let inviter = state.users.get_mut(&inviter_id).ok_or("no user found")?;
+                let new_user_id = if inviter.invites_budget > cycles {
+                    inviter.invites_budget = inviter.invites_budget.saturating_sub(cycles);
+                    state.spend(cycles, "user invite");
+                    state.new_user(principal, time(), name.clone())
+                } else if inviter.cycles() > cycles {
+                    let new_user_id = state.new_user(principal, time(), name.clone());
+                    state
+                        .cycle_transfer(
+                            inviter_id,
+                            new_user_id,
+                            cycles,
+                            0,
+                            Destination::Cycles,
+                            "claimed by invited user",
+                            None,
+                        )
+                        .unwrap_or_else(|err| panic!("couldn't use the invite: {}", err));
+                    new_user_id
+                } else {
+                    return Err("inviter has not enough cycles".into());
                 };
-                let acc = account(user.principal);
-                let minted = user_karma / ratio * base;
-                if minted == 0 {
-                    continue;
+                // exhausted invites are removed in the daily chores
+                if let Some(invite) = state.invites.get_mut(&code) {
+                    invite.uses += 1;
                 }
-                user.notify(format!(
-                    "{} minted `{}` ${} tokens for you! 💎",
-                    CONFIG.name,
-                    minted / base,
-                    CONFIG.token_symbol,
-                ));
-                minters.push(format!("`{}` to @{}", minted / base, user.name));
-                crate::token::mint(self, acc, minted);
-                minted_tokens += minted / base;
-            }
-
-            // Mint team tokens
-            for user in [0, 305]
-                .iter()
-                .filter_map(|id| self.users.get(id).cloned())
-                .collect::<Vec<_>>()
-            {
-                let acc = account(user.principal);
-                let vested = match self.team_tokens.get_mut(&user.id) {
-                    Some(balance) if *balance > 0 => {
-                        // 1% of circulating supply is vesting.
-                        let vested = (circulating_supply / 100).min(*balance);
-                        // We use 14% because 1% will vest and we want to stay below 15%.
-                        let cap = (circulating_supply * 14) / 100;
-                        // Vesting is allowed if the total voting power of the team member stays below
-                        // 15% of the current supply, or if 2/3 of total supply is minted.
-                        if self.balances.get(&acc).copied().unwrap_or_default() <= cap
-                            || circulating_supply * 3 > CONFIG.total_supply * 2
-                        {
-                            *balance -= vested;
-                            Some((vested, *balance))
-                        } else {
-                            None
-                        }
+                let user = state.users.get_mut(&new_user_id).expect("no user found");
+                user.invited_by = Some(inviter_id);
+                // a full realm doesn't block the onboarding
+                if let Some(realm) = invite.realm {
+                    let _ = state.toggle_realm_membership(principal, realm);
+                }
+                for followee_id in invite.follow {
+                    if state.users.contains_key(&followee_id) {
+                        state.toggle_following_user(principal, followee_id);
                     }
-                    _ => None,
-                };
-                if let Some((vested, remaining_balance)) = vested {
-                    crate::token::mint(self, acc, vested);
-                    self.logger.info(format!(
-                        "Minted `{}` team tokens for @{} (still vesting: `{}`).",
-                        vested / 100,
-                        user.name,
-                        remaining_balance / 100
+                }
+                if let Some(inviter) = state.users.get_mut(&inviter_id) {
+                    inviter.notify(format!(
+                        "Your invite was used by @{}! Thanks for helping #{} grow! 🤗",
+                        name, CONFIG.name
                     ));
                 }
+                return Ok(true);
             }
+            Ok(false)
+        })?;
+
+        if invited {
+            return Ok(());
         }
-        if minters.is_empty() {
-            self.logger.info("no tokens were minted".to_string());
-        } else {
-            self.logger.info(format!(
-                "{} minted `{}` ${} tokens 💎 from the earned karma at the ratio `{}:1` as follows: {}",
-                CONFIG.name,
-                minted_tokens,
-                CONFIG.token_symbol,
-                ratio,
-                minters.join(", ")
-            ));
+
+        if let Ok(Invoice { paid: true, .. }) = State::mint_cycles(principal, 0).await {
+            mutate(|state| state.new_user(principal, time(), name));
+            // After the user has beed created, transfer cycles.
+            return State::mint_cycles(principal, 0).await.map(|_| ());
         }
+
+        Err("payment missing or the invite is invalid".to_string())
     }
 
-    pub fn collect_new_karma(&mut self) -> HashMap<UserId, u64> {
-        for user in self.users.values_mut() {
-            user.accounting.clear();
-        }
-        self.users
-            .values_mut()
-            .filter(|u| u.karma_to_reward() > 0)
-            .filter_map(|user| {
-                let karma = user.karma_to_reward();
-                let _ = user.top_up_cycles_from_rewards();
-                if user.karma() < 0 {
-                    user.apply_rewards();
-                    return None;
-                }
-                Some((user.id, karma))
+    /// Returns the codes of user's invites with their cycles, remaining uses and the time left
+    /// until their expiry.
+    pub fn invites(
+        &self,
+        principal: Principal,
+        now: u64,
+    ) -> Vec<(String, Cycles, u32, Option<u64>)> {
+        self.principal_to_user(principal)
+            .map(|user| {
+                self.invites
+                    .iter()
+                    .filter(|(_, invite)| invite.inviter == user.id)
+                    .map(|(code, invite)| {
+                        (
+                            code.clone(),
+                            invite.cycles,
+                            invite.max_uses.saturating_sub(invite.uses),
+                            invite
+                                .expires_at
+                                .map(|timestamp| timestamp.saturating_sub(now)),
+                        )
+                    })
+                    .collect::<Vec<_>>()
             })
-            .collect()
+            .unwrap_or_default()
     }
 
-    pub async fn icp_transfer(
+    /// Returns the number of invite uses the user can still create, if limited. Expired invites
+    /// don't count against the limit.
+    pub fn invite_quota(&self, user_id: UserId, now: u64) -> Option<u32> {
+        CONFIG.max_outstanding_invites.map(|max| {
+            max.saturating_sub(
+                self.invites
+                    .values()
+                    .filter(|invite| invite.inviter == user_id && !invite.expired(now))
+                    .map(|invite| invite.max_uses.saturating_sub(invite.uses))
+                    .sum(),
+            )
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_invite(
+        &mut self,
         principal: Principal,
-        recipient: String,
-        amount: &str,
-    ) -> Result<Tokens, String> {
-        State::claim_user_icp(principal).await?;
+        cycles: Cycles,
+        max_uses: Option<u32>,
+        expires_at: Option<u64>,
+        realm: Option<String>,
+        mut follow: Vec<UserId>,
+        now: u64,
+    ) -> Result<(), String> {
+        // following is toggled on redemption, so every user must be listed only once
+        follow.sort_unstable();
+        follow.dedup();
+        if let Some(name) = &realm {
+            if !self.realms.contains_key(name) {
+                return Err(format!("realm {} not found", name));
+            }
+        }
+        if follow.len() > CONFIG.max_invite_follows {
+            return Err(format!(
+                "invites can't follow more than {} users",
+                CONFIG.max_invite_follows
+            ));
+        }
+        if let Some(id) = follow.iter().find(|id| !self.users.contains_key(id)) {
+            return Err(format!("user {} not found", id));
+        }
+        let min_cycles = CONFIG.min_cycles_for_inviting;
+        let user = self
+            .principal_to_user_mut(principal)
+            .ok_or("no user found")?;
+        if cycles < min_cycles {
+            return Err(format!(
+                "smallest invite must contain {} cycles",
+                min_cycles
+            ));
+        }
+        if user.cycles() < cycles {
+            return Err("not enough cycles".into());
+        }
+        if !user.trusted() {
+            return Err("bootcamp users cannot invite others".into());
+        }
+        let max_uses = max_uses.unwrap_or(1);
+        if max_uses == 0 || max_uses > CONFIG.max_invite_uses {
+            return Err(format!(
+                "invites can be used between 1 and {} times",
+                CONFIG.max_invite_uses
+            ));
+        }
+        if expires_at.map(|timestamp| timestamp <= now) == Some(true) {
+            return Err("expiry must be in the future".into());
+        }
+        let user_id = user.id;
+        if let Some(quota) = self.invite_quota(user_id, now) {
+            if max_uses > quota {
+                return Err(format!(
+                    "you can create invites for {} more users only",
+                    quota
+                ));
+            }
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(principal.as_slice());
+        hasher.update(now.to_be_bytes());
+        let code = format!("{:x}", hasher.finalize())[..10].to_string();
+        self.invites.insert(
+            code,
+            Invite {
+                inviter: user_id,
+                cycles,
+                expires_at,
+                max_uses,
+                uses: 0,
+                realm,
+                follow,
+            },
+        );
+        Ok(())
+    }
 
-        fn parse(amount: &str) -> Result<Tokens, String> {
-            let parse = |s: &str| {
-                s.parse::<u64>()
-                    .map_err(|err| format!("Couldn't parse as u64: {:?}", err))
-            };
-            match &amount.split('.').collect::<Vec<_>>().as_slice() {
-                [icpts] => Ok(Tokens::from_e8s(parse(icpts)? * 10_u64.pow(8))),
-                [icpts, e8s] => {
-                    let mut e8s = e8s.to_string();
-                    while e8s.len() < 8 {
-                        e8s.push('0');
-                    }
-                    let e8s = &e8s[..8];
-                    Ok(Tokens::from_e8s(
-                        parse(icpts)? * 10_u64.pow(8) + parse(e8s)?,
-                    ))
+    // Removes expired and exhausted invites. Invites are only charged when used, so there are no
+    // cycles to return to inviters; the unused invites only stop counting against their quotas.
+    fn clean_up_invites(&mut self, now: u64) {
+        let (stale, active): (BTreeMap<_, _>, BTreeMap<_, _>) = std::mem::take(&mut self.invites)
+            .into_iter()
+            .partition(|(_, invite)| invite.expired(now) || invite.exhausted());
+        self.invites = active;
+        for (code, invite) in stale {
+            if invite.uses < invite.max_uses {
+                if let Some(inviter) = self.users.get_mut(&invite.inviter) {
+                    inviter.notify(format!(
+                        "Your invite `{}` expired after being used {} out of {} times.",
+                        code.to_uppercase(),
+                        invite.uses,
+                        invite.max_uses
+                    ));
                 }
-                _ => Err(format!("Can't parse amount {}", amount)),
             }
         }
+    }
 
-        let amount = parse(amount)?;
-        invoices::transfer(
-            parse_account(&recipient)?,
-            amount,
-            Memo(1),
-            Some(principal_to_subaccount(&principal)),
-        )
-        .await
-        .map(|_| amount)
+    fn critical<T: ToString>(&mut self, message: T) {
+        self.logger
+            .log(&message.to_string(), "CRITICAL".to_string());
+        self.users.values_mut().for_each(|user| {
+            user.notify(format!("CRITICAL SYSTEM ERROR: {}", message.to_string()))
+        });
     }
 
-    async fn distribute_icp(
-        e8s_for_one_xdr: u64,
-        karma: &HashMap<UserId, u64>,
-        revenue: HashMap<UserId, u64>,
-    ) {
-        let treasury_balance = invoices::main_account_balance().await.e8s();
-        let debt = mutate(|state| {
-            let rewards = karma
-                .iter()
-                .map(|(id, karma)| {
-                    (
-                        id,
-                        (*karma as f64 / CONFIG.native_cycles_per_xdr as f64
-                            * e8s_for_one_xdr as f64) as u64,
-                    )
-                })
-                .collect::<HashMap<_, _>>();
-            let total_payout =
-                rewards.values().copied().sum::<u64>() + revenue.values().copied().sum::<u64>();
-            // We stop distributions if the treasury balance falls below the minimum balance.
-            let minimal_treasury_balance = CONFIG.min_treasury_balance_xdrs * e8s_for_one_xdr;
-            if treasury_balance < total_payout || treasury_balance < minimal_treasury_balance {
-                state
-                    .logger
-                    .info("Treasury balance is too low; skipping the payouts...");
-                return 0;
-            }
-            let mut payments = Vec::default();
-            let bootcampers = state
-                .users
-                .values()
-                .filter_map(|u| (!u.trusted()).then_some(u.id))
-                .collect::<HashSet<_>>();
-            let mut total_rewards = 0;
-            let mut total_revenue = 0;
-            for user in state.users.values_mut() {
-                let user_reward = rewards.get(&user.id).copied().unwrap_or_default();
-                // If the user was active and earned reward, mint tokens for them.
-                if user_reward > 0 {
-                    user.apply_rewards();
-                }
-                let mut user_revenue = revenue.get(&user.id).copied().unwrap_or_default();
-                let _ = user.top_up_cycles_from_revenue(&mut user_revenue, e8s_for_one_xdr);
-                let e8s = user_reward + user_revenue;
-                if e8s < invoices::fee() * 100 {
-                    continue;
-                }
-                user.treasury_e8s += e8s;
-                total_rewards += user_reward;
-                total_revenue += user_revenue;
-                payments.push(format!("`{}` to @{}", e8s_to_icp(e8s), &user.name));
-                user.notify(format!(
-                    "You received `{}` ICP as rewards and `{}` ICP as revenue! 💸",
-                    e8s_to_icp(user_reward),
-                    e8s_to_icp(user_revenue)
-                ));
-            }
-            state.spend(state.burned_cycles as Cycles, "revenue distribution");
-            state.burned_cycles_total += state.burned_cycles as Cycles;
-            state.total_rewards_shared += total_rewards;
-            state.total_revenue_shared += total_revenue;
-            state.logger.info(format!(
-                "Paid out `{}` ICP as rewards and `{}` ICP as revenue as follows: {}",
-                e8s_to_icp(total_rewards),
-                e8s_to_icp(total_revenue),
-                payments.join(", ")
-            ));
-            let mut graduation_list = Vec::new();
-            for user in state
-                .users
-                .values_mut()
-                .filter_map(|u| (u.trusted() && bootcampers.contains(&u.id)).then_some(u))
-            {
-                graduation_list.push(format!("@{}", user.name));
-                user.notify(
-                    "Congratulation! 🎉 You graduated from the bootcamp and became a trusted user!",
-                );
-            }
-            if !graduation_list.is_empty() {
-                state.logger.info(format!(
-                    "These users graduated from the bootcamp 🎉: {}",
-                    graduation_list.join(", ")
-                ));
-            }
-            total_rewards + total_revenue
-        });
+    fn notify_with_predicate<T: AsRef<str>>(
+        &mut self,
+        filter: &dyn Fn(&User) -> bool,
+        message: T,
+        predicate: Predicate,
+    ) {
+        self.users
+            .values_mut()
+            .filter(|u| filter(u))
+            .for_each(|u| u.notify_with_params(&message, Some(predicate.clone())));
+    }
 
-        if let Err(err) =
-            invoices::transfer(user_icp_account(), Tokens::from_e8s(debt), Memo(4545), None).await
+    pub fn denotify_users(&mut self, filter: &dyn Fn(&User) -> bool) {
+        for (user_id, mut inbox) in self
+            .users
+            .values_mut()
+            .filter(|u| filter(u))
+            .map(|u| (u.id, u.inbox.clone()))
+            .collect::<Vec<_>>()
+            .into_iter()
         {
-            mutate(|state| {
-                state.logger.error(format!(
-                    "user ICPs couldn't be transferred from the registry: {err}"
-                ))
+            inbox.retain(|_, n| {
+                if let Notification::Conditional(_, predicate) = n {
+                    return match predicate {
+                        Predicate::UserReportOpen(user_id) => self
+                            .users
+                            .get(user_id)
+                            .and_then(|p| p.report.as_ref().map(|r| !r.closed))
+                            .unwrap_or_default(),
+                        Predicate::ReportOpen(post_id) => Post::get(self, post_id)
+                            .and_then(|p| p.report.as_ref().map(|r| !r.closed))
+                            .unwrap_or_default(),
+                        Predicate::Proposal(post_id) => self
+                            .proposals
+                            .iter()
+                            .last()
+                            .map(|p| p.status == Status::Open && p.post_id == *post_id)
+                            .unwrap_or_default(),
+                    };
+                }
+                true
             });
+            self.users.get_mut(&user_id).expect("no user found").inbox = inbox;
         }
     }
 
-    fn conclude_polls(&mut self, now: u64) {
-        for post_id in self.pending_polls.clone() {
-            match Post::conclude_poll(self, &post_id, now) {
-                // The poll didn't end yet.
-                Ok(false) => {}
-                // The poll has ended, so it can be removed from pending ones.
-                _ => {
-                    self.pending_polls.remove(&post_id);
-                }
-            };
-        }
+    /// Returns non-deleted posts created within the time range, ordered by time.
+    pub fn posts_in_range(&self, range: RangeInclusive<u64>, page: usize) -> Vec<Post> {
+        let (start, end) = (
+            self.first_post_since(*range.start()),
+            self.first_post_since(range.end().saturating_add(1)),
+        );
+        (start..end)
+            .filter_map(|id| Post::get(self, &id))
+            .filter(|post| !post.hidden() && range.contains(&post.timestamp()))
+            .skip(page * CONFIG.feed_page_size)
+            .take(CONFIG.feed_page_size)
+            .cloned()
+            .collect()
     }
 
-    fn daily_chores(now: u64) {
-        mutate(|state| {
-            for proposal_id in state
-                .proposals
-                .iter()
-                .filter_map(|p| (p.status == Status::Open).then_some(p.id))
-                .collect::<Vec<_>>()
-            {
-                if let Err(err) = proposals::execute_proposal(state, proposal_id, now) {
-                    state
-                        .logger
-                        .error(format!("couldn't execute last proposal: {:?}", err));
-                }
+    // Returns the id of the first post created at or after the timestamp. Since post ids are
+    // assigned in the order of creation, this is a binary search.
+    fn first_post_since(&self, timestamp: u64) -> PostId {
+        let (mut low, mut high) = (0, self.next_post_id);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match Post::get(self, &mid) {
+                Some(post) if post.timestamp() < timestamp => low = mid + 1,
+                _ => high = mid,
             }
+        }
+        low
+    }
 
-            if !state.emergency_binary.is_empty() {
-                state.logger.info("An emergency release is pending! 🚨");
+    /// Searches users, realms, tags and posts. If the search is scoped to a realm, an author or a
+    /// time range, only posts are searched.
+    pub fn search(
+        &self,
+        mut term: String,
+        page: usize,
+        realm: Option<String>,
+        author: Option<UserId>,
+        range: RangeInclusive<u64>,
+    ) -> Vec<SearchResult> {
+        const SNIPPET_LEN: usize = 100;
+        const PAGE_SIZE: usize = 100;
+        term = term.to_lowercase();
+        let snippet = |body: &str, i: usize| {
+            if body.len() < SNIPPET_LEN {
+                body.to_string()
+            } else {
+                body.chars()
+                    .skip(i.saturating_sub(SNIPPET_LEN / 2))
+                    .skip_while(|c| c.is_alphanumeric())
+                    .take(SNIPPET_LEN)
+                    .skip_while(|c| c.is_alphanumeric())
+                    .collect::<String>()
             }
+            .replace('\n', " ")
+        };
+        let scoped = realm.is_some() || author.is_some() || range != (0..=u64::MAX);
+        let tokens = search::tokenize(&term);
+        self.users
+            .iter()
+            .filter(|_| !scoped)
+            .filter_map(|(id, User { name, about, .. })| {
+                let matched = if format!("@{} {0} {}", name, id)
+                    .to_lowercase()
+                    .contains(&term)
+                {
+                    "username"
+                } else if about.to_lowercase().contains(&term) {
+                    "about"
+                } else {
+                    return None;
+                };
+                Some(SearchResult {
+                    id: *id,
+                    relevant: about.clone(),
+                    result: "user".to_string(),
+                    matched: matched.to_string(),
+                    ..Default::default()
+                })
+            })
+            .chain(
+                self.realms
+                    .iter()
+                    .filter(|_| !scoped)
+                    .filter_map(|(id, realm)| {
+                        if id.to_lowercase().contains(&term) {
+                            return Some(SearchResult {
+                                generic_id: id.clone(),
+                                relevant: snippet(realm.description.as_str(), 0),
+                                result: "realm".to_string(),
+                                matched: "name".to_string(),
+                                ..Default::default()
+                            });
+                        }
+                        if let Some(i) = realm.description.to_lowercase().find(&term) {
+                            return Some(SearchResult {
+                                generic_id: id.clone(),
+                                relevant: snippet(realm.description.as_str(), i),
+                                result: "realm".to_string(),
+                                matched: "description".to_string(),
+                                ..Default::default()
+                            });
+                        }
+                        None
+                    }),
+            )
+            .chain(
+                if scoped {
+                    Default::default()
+                } else {
+                    self.recent_tags(None, 500)
+                }
+                .into_iter()
+                .filter_map(|(tag, _)| {
+                    if format!("#{} {0}", tag).to_lowercase().contains(&term) {
+                        return Some(SearchResult {
+                            relevant: tag,
+                            result: "tag".to_string(),
+                            matched: "tag".to_string(),
+                            ..Default::default()
+                        });
+                    }
+                    None
+                }),
+            )
+            .chain(
+                term.parse::<PostId>()
+                    .ok()
+                    .into_iter()
+                    .chain(
+                        self.search_index
+                            .search(&term)
+                            .into_iter()
+                            .filter(|id| id.to_string() != term),
+                    )
+                    .filter_map(|id| Post::get(self, &id))
+                    .filter(|post| {
+                        !post.hidden()
+                            && (realm.is_none() || post.realm == realm)
+                            && author.map(|id| post.user == id).unwrap_or(true)
+                            && range.contains(&post.timestamp())
+                    })
+                    .map(|post| {
+                        let search_body = post.body.to_lowercase();
+                        let i = tokens
+                            .iter()
+                            .find_map(|token| search_body.find(token.as_str()))
+                            .unwrap_or_default();
+                        let matched = if post.id.to_string() == term {
+                            "id"
+                        } else if post
+                            .tags
+                            .iter()
+                            .flat_map(|tag| search::tokenize(tag))
+                            .any(|token| tokens.contains(&token))
+                        {
+                            "tag"
+                        } else {
+                            "body"
+                        };
+                        SearchResult {
+                            id: post.id,
+                            user_id: post.user,
+                            relevant: snippet(&post.body, i),
+                            result: "post".to_string(),
+                            matched: matched.to_string(),
+                            ..Default::default()
+                        }
+                    }),
+            )
+            .skip(page * PAGE_SIZE)
+            .take(PAGE_SIZE)
+            .collect()
+    }
 
-            if let Err(err) = state.archive_cold_data() {
-                state
-                    .logger
-                    .error(format!("couldn't archive cold data: {:?}", err));
+    async fn top_up() {
+        let children = read(|state| state.storage.buckets.keys().cloned().collect::<Vec<_>>());
+
+        // top up the main canister
+        let balance = canister_balance();
+        let target_balance =
+            CONFIG.min_cycle_balance_main + children.len() as u64 * ICP_CYCLES_PER_XDR;
+        if balance < target_balance {
+            let xdrs = target_balance / ICP_CYCLES_PER_XDR;
+            // subtract weekly burned cycles to reduce the revenue
+            mutate(|state| state.spend(xdrs * 1000, "canister top up"));
+            match invoices::topup_with_icp(&api::id(), xdrs).await {
+                Err(err) => mutate(|state| {
+                    state.critical(format!(
+                    "FAILED TO TOP UP THE MAIN CANISTER — {}'S FUNCTIONALITY IS ENDANGERED: {:?}",
+                    CONFIG.name.to_uppercase(),
+                    err
+                ))
+                }),
+                Ok(_cycles) => mutate(|state| {
+                    state.logger.info(format!(
+                        "The main canister was topped up with cycles (balance was `{}`, now `{}`).",
+                        balance,
+                        canister_balance()
+                    ))
+                }),
             }
+        }
 
-            state.recompute_stalwarts(now);
-        })
+        // top up all children canisters
+        let mut topped_up = Vec::new();
+        for canister_id in children {
+            match crate::canisters::top_up(canister_id, ICP_CYCLES_PER_XDR).await {
+                Ok(true) => topped_up.push(canister_id),
+                Err(err) => mutate(|state| state.critical(err)),
+                _ => {}
+            }
+        }
+        if !topped_up.is_empty() {
+            mutate(|state| {
+                state.logger.info(format!(
+                    "Topped up canisters: {:?}.",
+                    topped_up
+                        .into_iter()
+                        .map(|p| p.to_string())
+                        .collect::<Vec<_>>()
+                ))
+            })
+        }
+    }
+
+    pub fn collect_revenue(&mut self, now: u64, e8s_for_one_xdr: u64) -> HashMap<UserId, u64> {
+        let burned_cycles = self.burned_cycles;
+        if burned_cycles <= 0 {
+            return Default::default();
+        }
+        let active_user_balances = self
+            .balances
+            .iter()
+            .filter_map(|(acc, balance)| {
+                let user = self.principal_to_user(acc.owner)?;
+                if user.active_within_weeks(now, CONFIG.revenue_share_activity_weeks) {
+                    return Some((user.id, *balance));
+                }
+                None
+            })
+            .collect::<Vec<_>>();
+        let supply_of_active_users: u64 = active_user_balances
+            .iter()
+            .map(|(_, balance)| balance)
+            .sum();
+        for (user_id, _) in active_user_balances.iter() {
+            let user = self.users.get_mut(user_id).expect("no user found");
+            if user.trusted() {
+                user.invites_budget = user.invites_budget.max(CONFIG.invites_budget_cycles);
+            }
+        }
+        active_user_balances
+            .into_iter()
+            .map(|(user_id, balance)| {
+                let revenue_share =
+                    burned_cycles as f64 * balance as f64 / supply_of_active_users as f64;
+                let e8s = (revenue_share / CONFIG.native_cycles_per_xdr as f64
+                    * e8s_for_one_xdr as f64) as u64;
+                (user_id, e8s)
+            })
+            .collect()
+    }
+
+    pub fn minting_ratio(&self) -> u64 {
+        let circulating_supply: Token = self.balances.values().sum();
+        let factor = (circulating_supply as f64 / CONFIG.total_supply as f64 * 10.0) as u64;
+        1 << factor
+    }
+
+    fn sample_minting_ratio(&mut self, now: u64) {
+        let circulating_supply: Token = self.balances.values().sum();
+        self.minting_ratio_history
+            .push_back((now, self.minting_ratio(), circulating_supply));
+        while self.minting_ratio_history.len() > CONFIG.max_minting_ratio_samples {
+            self.minting_ratio_history.pop_front();
+        }
+    }
+
+    /// Returns the tokens (incl. decimals) which can still be minted within the current minting
+    /// window without exceeding the global minting cap.
+    pub fn mint_headroom(&self, now: u64) -> Token {
+        let cap = CONFIG.total_supply * CONFIG.max_minted_per_window_percentage / 100;
+        let (window_start, minted) = self.minted_in_window;
+        if window_start + CONFIG.mint_window_days * DAY <= now {
+            return cap;
+        }
+        cap.saturating_sub(minted)
+    }
+
+    pub fn record_minted_tokens(&mut self, tokens: Token, now: u64) {
+        let (window_start, minted) = &mut self.minted_in_window;
+        if *window_start + CONFIG.mint_window_days * DAY <= now {
+            *window_start = now;
+            *minted = 0;
+        }
+        *minted += tokens;
+    }
+
+    /// Converts the karma rewards into token amounts at the current minting ratio.
+    pub fn tokens_to_mint(&self, rewards: &HashMap<UserId, u64>) -> BTreeMap<UserId, Token> {
+        let circulating_supply: Token = self.balances.values().sum();
+        if circulating_supply >= CONFIG.total_supply {
+            return Default::default();
+        }
+        let base = 10_u64.pow(CONFIG.token_decimals as u32);
+        let ratio = self.minting_ratio();
+        rewards
+            .iter()
+            .filter(|(user_id, _)| self.users.contains_key(user_id))
+            .map(|(user_id, karma)| (*user_id, karma / ratio * base))
+            .filter(|(_, minted)| *minted > 0)
+            .collect()
+    }
+
+    pub fn mint(&mut self, rewards: HashMap<UserId, u64>) {
+        let mut minted_tokens = 0;
+        let mut minters = Vec::new();
+        let base = 10_u64.pow(CONFIG.token_decimals as u32);
+        let ratio = self.minting_ratio();
+        let circulating_supply: Token = self.balances.values().sum();
+        if circulating_supply < CONFIG.total_supply {
+            let mut tokens_to_mint = self.tokens_to_mint(&rewards);
+            let total: Token = tokens_to_mint.values().sum();
+            let headroom = self.mint_headroom(time());
+            // all rewards get pro-rated if the minting cap would be exceeded
+            if total > headroom {
+                for minted in tokens_to_mint.values_mut() {
+                    *minted = (*minted as u128 * headroom as u128 / total as u128) as Token;
+                }
+                self.logger.info(format!(
+                    "The minting cap allows only `{}` of `{}` ${} tokens to be minted this week.",
+                    headroom / base,
+                    total / base,
+                    CONFIG.token_symbol
+                ));
+            }
+            for (user_id, minted) in tokens_to_mint {
+                if minted == 0 {
+                    continue;
+                }
+                let user = match self.users.get_mut(&user_id) {
+                    Some(user) => user,
+                    _ => continue,
+                };
+                let acc = account(user.principal);
+                user.notify(format!(
+                    "{} minted `{}` ${} tokens for you! 💎",
+                    CONFIG.name,
+                    minted / base,
+                    CONFIG.token_symbol,
+                ));
+                minters.push(format!("`{}` to @{}", minted / base, user.name));
+                crate::token::mint(self, acc, minted);
+                minted_tokens += minted / base;
+            }
+
+            // Mint team tokens
+            for user in [0, 305]
+                .iter()
+                .filter_map(|id| self.users.get(id).cloned())
+                .collect::<Vec<_>>()
+            {
+                let acc = account(user.principal);
+                let headroom = self.mint_headroom(time());
+                let vested = match self.team_tokens.get_mut(&user.id) {
+                    Some(balance) if *balance > 0 && headroom > 0 => {
+                        // 1% of circulating supply is vesting.
+                        let vested = (circulating_supply / 100).min(*balance).min(headroom);
+                        // We use 14% because 1% will vest and we want to stay below 15%.
+                        let cap = (circulating_supply * 14) / 100;
+                        // Vesting is allowed if the total voting power of the team member stays below
+                        // 15% of the current supply, or if 2/3 of total supply is minted.
+                        if self.balances.get(&acc).copied().unwrap_or_default() <= cap
+                            || circulating_supply * 3 > CONFIG.total_supply * 2
+                        {
+                            *balance -= vested;
+                            Some((vested, *balance))
+                        } else {
+                            None
+                        }
+                    }
+                    _ => None,
+                };
+                if let Some((vested, remaining_balance)) = vested {
+                    crate::token::mint(self, acc, vested);
+                    self.logger.info(format!(
+                        "Minted `{}` team tokens for @{} (still vesting: `{}`).",
+                        vested / 100,
+                        user.name,
+                        remaining_balance / 100
+                    ));
+                }
+            }
+        }
+        if minters.is_empty() {
+            self.logger.info("no tokens were minted".to_string());
+        } else {
+            self.logger.info(format!(
+                "{} minted `{}` ${} tokens 💎 from the earned karma at the ratio `{}:1` as follows: {}",
+                CONFIG.name,
+                minted_tokens,
+                CONFIG.token_symbol,
+                ratio,
+                minters.join(", ")
+            ));
+        }
+    }
+
+    /// Returns the karma rewards of all users, which will be converted to tokens in the next
+    /// minting.
+    pub fn karma_to_mint(&self) -> HashMap<UserId, u64> {
+        self.users
+            .values()
+            // users with negative karma get their rewards applied to the karma instead
+            .filter(|user| user.karma_to_reward() > 0 && user.karma() >= 0)
+            .map(|user| (user.id, user.karma_to_reward()))
+            .collect()
+    }
+
+    /// Returns the karma collected by the user since the last minting and the tokens it would be
+    /// converted to at the current minting ratio.
+    pub fn reward_preview(&self, principal: Principal) -> Option<RewardPreview> {
+        let user = self.principal_to_user(principal)?;
+        let eligible = user.karma() >= 0;
+        let projected_tokens = if eligible {
+            self.tokens_to_mint(&std::iter::once((user.id, user.karma_to_reward())).collect())
+                .remove(&user.id)
+                .unwrap_or_default()
+        } else {
+            0
+        };
+        Some(RewardPreview {
+            karma_to_reward: user.karma_to_reward(),
+            minting_ratio: self.minting_ratio(),
+            projected_tokens,
+            trusted: user.trusted(),
+            eligible,
+        })
+    }
+
+    pub fn stalwart_status(&self, principal: Principal, now: u64) -> Option<StalwartStatus> {
+        let user = self.principal_to_user(principal)?;
+        let requirement = |name, current: i64, required: i64| StalwartRequirement {
+            name,
+            current,
+            required,
+            met: current >= required,
+        };
+        let age_weeks = (now.saturating_sub(user.timestamp) / WEEK) as i64;
+        let seats = self.users.len() * CONFIG.stalwart_percentage / 100;
+        let karma_rank = self
+            .users
+            .values()
+            .filter(|other| other.karma() > user.karma())
+            .count()
+            + 1;
+        let requirements = vec![
+            requirement("trusted", user.trusted() as i64, 1),
+            requirement("no open report", user.report.is_none() as i64, 1),
+            requirement("not banned", !user.banned(now) as i64, 1),
+            requirement(
+                "account age weeks",
+                age_weeks,
+                CONFIG.min_stalwart_account_age_weeks as i64,
+            ),
+            requirement(
+                "active weeks",
+                user.active_weeks as i64,
+                CONFIG.min_stalwart_activity_weeks as i64,
+            ),
+            requirement("karma", user.karma(), CONFIG.min_stalwart_karma),
+            // the seats are taken by the eligible users with the highest karma
+            StalwartRequirement {
+                name: "karma rank",
+                current: karma_rank as i64,
+                required: seats as i64,
+                met: karma_rank <= seats,
+            },
+        ];
+        let time_to_eligibility = requirements
+            .iter()
+            .filter(|requirement| !requirement.met)
+            .try_fold(0, |eta: u64, requirement| match requirement.name {
+                "account age weeks" => Some(eta.max(
+                    (user.timestamp + requirement.required as u64 * WEEK).saturating_sub(now),
+                )),
+                "active weeks" => {
+                    Some(eta.max((requirement.required - requirement.current) as u64 * WEEK))
+                }
+                "not banned" => user
+                    .banned_until
+                    .map(|until| eta.max(until.saturating_sub(now))),
+                _ => None,
+            });
+        Some(StalwartStatus {
+            stalwart: user.stalwart,
+            requirements,
+            time_to_eligibility,
+        })
+    }
+
+    pub fn collect_new_karma(&mut self) -> HashMap<UserId, u64> {
+        let karma = self.karma_to_mint();
+        self.minting_epoch += 1;
+        for user in self.users.values_mut() {
+            user.accounting.clear();
+            if user.karma_to_reward() == 0 {
+                continue;
+            }
+            let _ = user.top_up_cycles_from_rewards();
+            if user.karma() < 0 {
+                user.apply_rewards();
+            }
+        }
+        karma
+    }
+
+    pub async fn icp_transfer(
+        principal: Principal,
+        recipient: String,
+        amount: &str,
+    ) -> Result<Tokens, String> {
+        State::icp_transfer_e8s(principal, &recipient, parse_icp_amount(amount)?).await
+    }
+
+    async fn icp_transfer_e8s(
+        principal: Principal,
+        recipient: &str,
+        amount: Tokens,
+    ) -> Result<Tokens, String> {
+        State::claim_user_icp(principal).await?;
+        invoices::transfer(
+            parse_account(recipient)?,
+            amount,
+            Memo(1),
+            Some(principal_to_subaccount(&principal)),
+        )
+        .await
+        .map(|_| amount)
+    }
+
+    async fn distribute_icp(
+        e8s_for_one_xdr: u64,
+        karma: &HashMap<UserId, u64>,
+        revenue: HashMap<UserId, u64>,
+    ) {
+        let treasury_balance = invoices::main_account_balance().await.e8s();
+        let debt = mutate(|state| {
+            let rewards = karma
+                .iter()
+                .map(|(id, karma)| {
+                    (
+                        id,
+                        (*karma as f64 / CONFIG.native_cycles_per_xdr as f64
+                            * e8s_for_one_xdr as f64) as u64,
+                    )
+                })
+                .collect::<HashMap<_, _>>();
+            let total_payout =
+                rewards.values().copied().sum::<u64>() + revenue.values().copied().sum::<u64>();
+            // We stop distributions if the treasury balance falls below the minimum balance.
+            let minimal_treasury_balance = CONFIG.min_treasury_balance_xdrs * e8s_for_one_xdr;
+            if treasury_balance < total_payout || treasury_balance < minimal_treasury_balance {
+                state
+                    .logger
+                    .info("Treasury balance is too low; skipping the payouts...");
+                return 0;
+            }
+            let mut payments = Vec::default();
+            let bootcampers = state
+                .users
+                .values()
+                .filter_map(|u| (!u.trusted()).then_some(u.id))
+                .collect::<HashSet<_>>();
+            let mut total_rewards = 0;
+            let mut total_revenue = 0;
+            for user in state.users.values_mut() {
+                let user_reward = rewards.get(&user.id).copied().unwrap_or_default();
+                // If the user was active and earned reward, mint tokens for them.
+                if user_reward > 0 {
+                    user.apply_rewards();
+                }
+                let mut user_revenue = revenue.get(&user.id).copied().unwrap_or_default();
+                let _ = user.top_up_cycles_from_revenue(&mut user_revenue, e8s_for_one_xdr);
+                let e8s = user_reward + user_revenue;
+                if e8s < invoices::fee() * 100 {
+                    continue;
+                }
+                user.treasury_e8s += e8s;
+                total_rewards += user_reward;
+                total_revenue += user_revenue;
+                payments.push(format!("`{}` to @{}", e8s_to_icp(e8s), &user.name));
+                user.notify(format!(
+                    "You received `{}` ICP as rewards and `{}` ICP as revenue! 💸",
+                    e8s_to_icp(user_reward),
+                    e8s_to_icp(user_revenue)
+                ));
+            }
+            state.spend(state.burned_cycles as Cycles, "revenue distribution");
+            state.burned_cycles_total += state.burned_cycles as Cycles;
+            state.total_rewards_shared += total_rewards;
+            state.total_revenue_shared += total_revenue;
+            state.logger.info(format!(
+                "Paid out `{}` ICP as rewards and `{}` ICP as revenue as follows: {}",
+                e8s_to_icp(total_rewards),
+                e8s_to_icp(total_revenue),
+                payments.join(", ")
+            ));
+            let mut graduation_list = Vec::new();
+            for user in state
+                .users
+                .values_mut()
+                .filter_map(|u| (u.trusted() && bootcampers.contains(&u.id)).then_some(u))
+            {
+                graduation_list.push(format!("@{}", user.name));
+                user.notify(
+                    "Congratulation! 🎉 You graduated from the bootcamp and became a trusted user!",
+                );
+            }
+            if !graduation_list.is_empty() {
+                state.logger.info(format!(
+                    "These users graduated from the bootcamp 🎉: {}",
+                    graduation_list.join(", ")
+                ));
+            }
+            total_rewards + total_revenue
+        });
+
+        if let Err(err) =
+            invoices::transfer(user_icp_account(), Tokens::from_e8s(debt), Memo(4545), None).await
+        {
+            mutate(|state| {
+                state.logger.error(format!(
+                    "user ICPs couldn't be transferred from the registry: {err}"
+                ))
+            });
+        }
+    }
+
+    fn conclude_polls(&mut self, now: u64) {
+        for post_id in self.pending_polls.clone() {
+            match Post::conclude_poll(self, &post_id, now) {
+                // The poll didn't end yet.
+                Ok(false) => {}
+                // The poll has ended, so it can be removed from pending ones.
+                _ => {
+                    self.pending_polls.remove(&post_id);
+                }
+            };
+        }
+    }
+
+    fn daily_chores(now: u64) {
+        mutate(|state| {
+            for proposal_id in state
+                .proposals
+                .iter()
+                .filter_map(|p| (p.status == Status::Open).then_some(p.id))
+                .collect::<Vec<_>>()
+            {
+                if let Err(err) = proposals::execute_proposal(state, proposal_id, now, true) {
+                    state
+                        .logger
+                        .error(format!("couldn't execute last proposal: {:?}", err));
+                }
+            }
+
+            if !state.emergency_binary.is_empty() {
+                state.logger.info("An emergency release is pending! 🚨");
+            }
+
+            if let Err(err) = state.archive_cold_data(now) {
+                state
+                    .logger
+                    .error(format!("couldn't archive cold data: {:?}", err));
+            }
+
+            state.recompute_stalwarts(now);
+
+            state.clean_up_invites(now);
+
+            state.free_deleted_handles(now);
+
+            proposals::release_vested_tokens(state, now);
+
+            state.lift_bans(now);
+
+            state.sample_minting_ratio(now);
+        })
+    }
+
+    fn archive_cold_data(&mut self, now: u64) -> Result<(), String> {
+        // pending impressions can't be added to posts after they were archived
+        self.flush_impressions();
+        archive_cold_posts(self, CONFIG.max_posts_in_heap, now)
+    }
+
+    async fn handle_nns_proposals(now: u64) {
+        // Vote on proposals if pending ones exist
+        for (proposal_id, post_id) in read(|state| state.pending_nns_proposals.clone()) {
+            if let Some(Extension::Poll(poll)) = read(|state| {
+                Post::get(state, &post_id).and_then(|post| post.extension.as_ref().cloned())
+            }) {
+                // The poll is still pending.
+                if read(|state| state.pending_polls.contains(&post_id)) {
+                    continue;
+                }
+
+                let adopted = poll.weighted_by_karma.get(&0).copied().unwrap_or_default();
+                let rejected = poll.weighted_by_karma.get(&1).copied().unwrap_or_default();
+                if let Err(err) = canisters::vote_on_nns_proposal(
+                    proposal_id,
+                    if adopted > rejected {
+                        NNSVote::Adopt
+                    } else {
+                        NNSVote::Reject
+                    },
+                )
+                .await
+                {
+                    mutate(|state| {
+                        state.logger.error(format!(
+                            "couldn't vote on NNS proposal {}: {}",
+                            proposal_id, err
+                        ))
+                    });
+                };
+            }
+            mutate(|state| state.pending_nns_proposals.remove(&post_id));
+        }
+
+        // fetch new proposals
+        let last_known_proposal_id = read(|state| state.last_nns_proposal);
+        let proposals = match canisters::fetch_proposals().await {
+            Ok(value) => value,
+            Err(err) => {
+                mutate(|state| {
+                    state
+                        .logger
+                        .error(format!("couldn't fetch proposals: {}", err))
+                });
+                Default::default()
+            }
+        };
+
+        for proposal in proposals
+            .into_iter()
+            .filter(|proposal| proposal.id > last_known_proposal_id)
+        {
+            // Vote only on proposals with topics network economics, governance, SNS & replica-management.
+            if [3, 4, 13, 14].contains(&proposal.topic) {
+                let post = format!(
+                    "# #NNS-Proposal [{0}](https://dashboard.internetcomputer.org/proposal/{0})\n## {1}\n",
+                    proposal.id, proposal.title,
+                ) + &format!(
+                    "Proposer: [{0}](https://dashboard.internetcomputer.org/neuron/{0})\n\n\n\n{1}",
+                    proposal.proposer, proposal.summary
+                );
+
+                match mutate(|state| {
+                    state.last_nns_proposal = state.last_nns_proposal.max(proposal.id);
+                    Post::create(
+                        state,
+                        post,
+                        Default::default(),
+                        id(),
+                        now,
+                        None,
+                        Some("NNS-GOV".into()),
+                        Some(Extension::Poll(Poll {
+                            deadline: 72,
+                            options: vec!["ACCEPT".into(), "REJECT".into()],
+                            ..Default::default()
+                        })),
+                    )
+                }) {
+                    Ok(post_id) => {
+                        mutate(|state| state.pending_nns_proposals.insert(proposal.id, post_id));
+                        continue;
+                    }
+                    Err(err) => {
+                        mutate(|state| {
+                            state
+                                .logger
+                                .error(format!("couldn't create a NNS proposal post: {:?}", err))
+                        });
+                    }
+                };
+
+                if let Err(err) =
+                    canisters::vote_on_nns_proposal(proposal.id, NNSVote::Reject).await
+                {
+                    mutate(|state| {
+                        state.logger.error(format!(
+                            "couldn't vote on NNS proposal {}: {}",
+                            proposal.id, err
+                        ))
+                    });
+                };
+            }
+        }
+    }
+
+    async fn hourly_chores(now: u64) {
+        mutate(|state| {
+            // Automatically dump the heap to the stable memory. This should be the first
+            // opearation to avoid blocking of the backup by a panic in other parts of the routine.
+            memory::heap_to_stable(state);
+
+            state.conclude_polls(now);
+
+            state.leaderboards = leaderboards::Leaderboards::compute(state, now);
+
+            state.compute_realm_stats(now);
+
+            for user in state.users.values_mut() {
+                user.release_held_notifications(now);
+            }
+
+            state.rate_limits.clean_up(now);
+
+            state.flush_impressions();
+            let window = CONFIG.impression_window_hours * HOUR;
+            state
+                .recent_impressions
+                .retain(|_, timestamp| *timestamp + window > now);
+        });
+
+        State::top_up().await;
+
+        mutate(|state| state.check_cycle_balance(canister_balance(), now));
+
+        State::handle_nns_proposals(now).await;
+    }
+
+    /// Tracks the burn rate of the main canister and alerts stalwarts once its balance drops
+    /// below the configured threshold.
+    fn check_cycle_balance(&mut self, balance: u64, now: u64) {
+        self.cycle_balance_samples.push_back((now, balance));
+        while self.cycle_balance_samples.len() > CONFIG.cycle_balance_samples {
+            self.cycle_balance_samples.pop_front();
+        }
+        if balance >= CONFIG.low_cycle_balance_threshold {
+            self.low_cycle_balance_alerted = false;
+            return;
+        }
+        if self.low_cycle_balance_alerted {
+            return;
+        }
+        self.low_cycle_balance_alerted = true;
+        let projection = match time_to_empty(&self.cycle_balance_samples) {
+            Some(duration) => format!(
+                "it will run out of cycles in about {} hours",
+                duration / HOUR
+            ),
+            None => "the burn rate is unknown yet".to_string(),
+        };
+        let message = format!(
+            "The main canister's cycle balance dropped to `{}` cycles; {}. Please top it up!",
+            balance, projection
+        );
+        self.logger.log(&message, "CRITICAL".to_string());
+        self.users
+            .values_mut()
+            .filter(|user| user.stalwart)
+            .for_each(|user| user.notify(&message));
+    }
+
+    /// Returns what the next chores run would do at the given time without changing the state.
+    pub fn plan_chores(&self, now: u64) -> ChoresPlan {
+        let weekly = self.last_weekly_chores + WEEK < now;
+        let daily = self.last_daily_chores + DAY < now;
+        let mut plan = ChoresPlan {
+            weekly,
+            daily,
+            hourly: self.last_hourly_chores + HOUR < now,
+            posts_to_delete: self.posts_to_delete(now),
+            ..Default::default()
+        };
+        if daily {
+            plan.proposals_to_conclude = self
+                .proposals
+                .iter()
+                .filter(|proposal| proposal.status == Status::Open)
+                .filter_map(|proposal| {
+                    proposal
+                        .tally(self, now, true)
+                        .3
+                        .map(|status| (proposal.id, status))
+                })
+                .collect();
+            plan.bans_to_lift = self
+                .users
+                .values()
+                .filter(|user| user.banned_until.is_some() && !user.banned(now))
+                .map(|user| user.id)
+                .collect();
+        }
+        if weekly {
+            plan.inactivity_penalties = self.inactivity_penalties(now);
+            // minting is skipped while proposals are open
+            if self.proposals.iter().all(|p| p.status != Status::Open) {
+                plan.tokens_to_mint = self.tokens_to_mint(&self.karma_to_mint());
+            }
+        }
+        plan
+    }
+
+    pub async fn chores(now: u64) {
+        // This should always be the first operation executed in the chores routine so
+        // that the upgrades are never blocked by a panic in any other routine.
+        if mutate(|state| {
+            state.execute_pending_upgrade(false) || state.execute_pending_emergency_upgrade(false)
+        }) {
+            return;
+        }
+
+        let plan = read(|state| state.plan_chores(now));
+        if plan.weekly {
+            State::weekly_chores(now).await;
+            mutate(|state| state.last_weekly_chores += WEEK);
+        }
+        if plan.daily {
+            State::daily_chores(now);
+            mutate(|state| state.last_daily_chores += DAY);
+        }
+        if plan.hourly {
+            State::hourly_chores(now).await;
+            mutate(|state| state.last_hourly_chores += HOUR);
+        }
+
+        if !plan.posts_to_delete.is_empty() {
+            mutate(|state| state.delete_pending_posts(now));
+        }
+
+        proposals::execute_icp_transfers().await;
+
+        post::retry_pending_blobs(now).await;
+
+        webhooks::deliver(now).await;
+
+        mutate(|state| state.refresh_stats(now));
+    }
+
+    async fn weekly_chores(now: u64) {
+        mutate(|state| state.clean_up(now));
+
+        // We only mint and distribute if no open proposals exists
+        if read(|state| state.proposals.iter().all(|p| p.status != Status::Open)) {
+            let karma = match invoices::get_xdr_in_e8s().await {
+                Ok(e8s_for_one_xdr) => {
+                    let (karma, revenues) = mutate(|state| {
+                        (
+                            state.collect_new_karma(),
+                            state.collect_revenue(time(), e8s_for_one_xdr),
+                        )
+                    });
+                    State::distribute_icp(e8s_for_one_xdr, &karma, revenues).await;
+                    karma
+                }
+                Err(err) => {
+                    mutate(|state| {
+                        state
+                            .logger
+                            .error(format!("Couldn't fetch ICP/XDR rate: {:?}", err))
+                    });
+                    return;
+                }
+            };
+            mutate(|state| state.mint(karma));
+        } else {
+            mutate(|state| {
+                state
+                    .logger
+                    .info("Skipping minting & distributions due to open proposals")
+            });
+        }
+    }
+
+    /// Returns the karma and cycles penalties of all inactive users.
+    pub fn inactivity_penalties(&self, now: u64) -> InactivityPenalties {
+        let inactive_user_balance_threshold = CONFIG.inactivity_penalty * 4;
+        let mut penalties = InactivityPenalties::default();
+        for user in self
+            .users
+            .values()
+            .filter(|user| !user.active_within_weeks(now, CONFIG.inactivity_duration_weeks))
+        {
+            if user.karma() > 0 {
+                penalties.karma.insert(
+                    user.id,
+                    (CONFIG.inactivity_penalty as Karma).min(user.karma()),
+                );
+            }
+            if user.cycles() > inactive_user_balance_threshold {
+                penalties.cycles.insert(
+                    user.id,
+                    CONFIG
+                        .inactivity_penalty
+                        .min(user.cycles() - inactive_user_balance_threshold),
+                );
+            }
+        }
+        penalties
+    }
+
+    fn clean_up(&mut self, now: u64) {
+        let penalties = self.inactivity_penalties(now);
+        for user in self.users.values_mut() {
+            if user.active_within_weeks(now, 1) {
+                user.active_weeks += 1;
+            } else {
+                user.active_weeks = 0;
+            }
+            let inactive = !user.active_within_weeks(now, CONFIG.inactivity_duration_weeks);
+            if inactive || user.is_bot() {
+                user.clear_notifications(Vec::new())
+            }
+            if let Some(penalty) = penalties.karma.get(&user.id) {
+                user.change_karma(-penalty, "inactivity_penalty".to_string());
+            }
+        }
+        let mut inactive_users = 0;
+        let mut cycles_total = 0;
+        for (id, costs) in penalties.cycles {
+            if let Err(err) = self.charge(id, costs, "inactivity penalty".to_string()) {
+                self.logger
+                    .error(format!("Couldn't charge inactivity penalty: {:?}", err));
+            } else {
+                cycles_total += costs;
+                inactive_users += 1;
+            }
+        }
+        self.logger.info(format!(
+            "Charged `{}` inactive users with `{}` cycles.",
+            inactive_users, cycles_total
+        ));
+
+        self.accounting.clean_up();
+    }
+
+    /// Reconciles the stalwarts with the eligibility criteria: eligible users with the highest
+    /// karma take the available seats, stalwarts who don't qualify anymore are demoted. Every
+    /// change is logged and announced to the affected user.
+    fn recompute_stalwarts(&mut self, now: u64) {
+        let mut users = self.users.values_mut().collect::<Vec<_>>();
+        users.sort_unstable_by_key(|a| std::cmp::Reverse(a.karma()));
+
+        let mut stalwart_seats = users.len() * CONFIG.stalwart_percentage / 100;
+        let mut left = Vec::new();
+        let mut joined = Vec::new();
+        let mut demote = |u: &mut User, reason: &str| {
+            u.stalwart = false;
+            u.notify(format!(
+                "You are not a {} stalwart anymore ({}).",
+                CONFIG.name, reason
+            ));
+            left.push(format!("@{} ({})", u.name, reason));
+        };
+        for u in users {
+            let disqualification = if u.is_bot() {
+                Some("bot")
+            } else if !u.trusted() {
+                Some("untrusted")
+            } else if u.report.is_some() {
+                Some("report")
+            } else if u.banned(now) {
+                Some("ban")
+            } else if now.saturating_sub(u.timestamp)
+                < WEEK * CONFIG.min_stalwart_account_age_weeks as u64
+            {
+                Some("account age")
+            } else {
+                None
+            };
+            if let Some(reason) = disqualification {
+                if u.stalwart {
+                    demote(u, reason);
+                }
+                continue;
+            }
+            match (
+                u.stalwart,
+                u.active_weeks >= CONFIG.min_stalwart_activity_weeks as u32,
+                u.karma() >= CONFIG.min_stalwart_karma,
+                stalwart_seats,
+            ) {
+                // User is qualified but seats left or they lost karma
+                (true, true, true, 0) | (true, _, false, _) => demote(u, "karma"),
+                // A user is qualified and is already a stalwart and seats available
+                (true, true, true, _) => {
+                    stalwart_seats = stalwart_seats.saturating_sub(1);
+                }
+                // A user is a stalwart but became inactive
+                (true, false, _, _) => demote(u, "inactivity"),
+                // A user is not a stalwart, but qualified and there are seats left
+                (false, true, true, seats) if seats > 0 => {
+                    u.stalwart = true;
+                    joined.push(format!("@{}", u.name));
+                    stalwart_seats = stalwart_seats.saturating_sub(1);
+                    u.notify(format!(
+                        "Congratulations! You are a {} stalwart now!",
+                        CONFIG.name
+                    ));
+                }
+                _ => {}
+            };
+        }
+
+        if joined.is_empty() && left.is_empty() {
+            return;
+        }
+
+        self.logger.info(format!(
+            "Stalwart election ⚔️: {} joined; {} have left; `{}` seats vacant.",
+            if joined.is_empty() {
+                "no new users".to_string()
+            } else {
+                joined.join(", ")
+            },
+            if left.is_empty() {
+                "no users".to_string()
+            } else {
+                left.join(", ")
+            },
+            stalwart_seats
+        ));
+    }
+
+    // Check if user has some unclaimed e8s in the Treasury and transfers them to user's account.
+    async fn claim_user_icp(principal: Principal) -> Result<(), String> {
+        let user = match read(|state| state.principal_to_user(principal).cloned()) {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+        if user.treasury_e8s > 0 {
+            invoices::transfer(
+                parse_account(&user.account)?,
+                Tokens::from_e8s(user.treasury_e8s),
+                Memo(777),
+                Some(USER_ICP_SUBACCOUNT),
+            )
+            .await?;
+            mutate(|state| {
+                if let Some(user) = state.users.get_mut(&user.id) {
+                    user.treasury_e8s = 0
+                }
+            });
+        }
+        Ok(())
+    }
+
+    pub async fn mint_cycles(principal: Principal, kilo_cycles: u64) -> Result<Invoice, String> {
+        State::claim_user_icp(principal).await?;
+
+        let invoice = match Invoices::outstanding(&principal, kilo_cycles).await {
+            Ok(val) => val,
+            Err(err) => {
+                if kilo_cycles == 0 {
+                    mutate(|state| {
+                        state
+                            .logger
+                            .error(&format!("couldn't generate invoice: {:?}", err))
+                    });
+                }
+                return Err(err);
+            }
+        };
+
+        mutate(|state| {
+            if invoice.paid {
+                if let Some(user) = state.principal_to_user_mut(principal) {
+                    user.change_cycles(
+                        ((invoice.paid_e8s as f64 / invoice.e8s as f64)
+                            * CONFIG.native_cycles_per_xdr as f64)
+                            as Cycles,
+                        CyclesDelta::Plus,
+                        "top up with ICP".to_string(),
+                    )?;
+                    let user_name = user.name.clone();
+                    state.accounting.close(&principal);
+                    state.logger.info(format!(
+                        "@{} minted cycles for `{}` ICP 💰",
+                        user_name,
+                        e8s_to_icp(invoice.paid_e8s)
+                    ));
+                }
+            }
+            Ok(invoice)
+        })
+    }
+
+    pub fn clear_notifications(&mut self, principal: Principal, ids: Vec<String>) {
+        if let Some(user) = self.principal_to_user_mut(principal) {
+            user.clear_notifications(ids)
+        }
+    }
+
+    pub fn set_webhook(&mut self, principal: Principal, url: Option<String>) -> Result<(), String> {
+        if let Some(url) = &url {
+            webhooks::validate_url(url)?;
+        }
+        let user = self
+            .principal_to_user_mut(principal)
+            .ok_or("no user found")?;
+        if url.is_none() {
+            user.outbox.clear();
+        }
+        user.webhook = url;
+        Ok(())
+    }
+
+    /// Returns the next page of the user's posts in the ascending order, starting with the post
+    /// id `cursor`. At most `CONFIG.max_export_scan` post ids are scanned per page, so a page
+    /// can be empty without being the final one.
+    pub fn export_posts(&self, principal: Principal, cursor: PostId) -> Result<PostExport, String> {
+        let user_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        let end = cursor
+            .saturating_add(CONFIG.max_export_scan)
+            .min(self.next_post_id);
+        let mut posts = Vec::new();
+        let mut next = cursor;
+        for id in cursor..end {
+            next = id + 1;
+            if let Some(post) = Post::get(self, &id).filter(|post| post.user == user_id) {
+                posts.push(post.export());
+                if posts.len() >= CONFIG.max_feed_page_size {
+                    break;
+                }
+            }
+        }
+        Ok(PostExport {
+            posts,
+            next_cursor: (next < self.next_post_id).then_some(next),
+        })
+    }
+
+    /// Returns up to `limit` post changes following the given sequence number in the order they
+    /// happened, including deleted posts as tombstones, so that external indexers can sync all
+    /// creations, edits and deletions linearly and resume from the returned cursor. Without a
+    /// cursor the sync starts with the first change.
+    pub fn posts_since(&self, cursor: Option<u64>, limit: usize) -> PostsSince {
+        let mut last_seq = cursor;
+        let posts = self
+            .post_changes
+            .since(cursor, limit.min(CONFIG.max_feed_page_size))
+            .filter_map(|(seq, post_id, event)| {
+                last_seq = Some(seq);
+                Post::get(self, &post_id).map(|post| {
+                    // posts deleted before the log was introduced are only logged as created
+                    let event = if post.is_deleted() {
+                        PostEvent::Deleted
+                    } else {
+                        event
+                    };
+                    // impressions are private to the author and realm controllers
+                    let mut post = post.clone();
+                    post.impressions = None;
+                    (event, post)
+                })
+            })
+            .collect();
+        PostsSince {
+            posts,
+            cursor: last_seq,
+            high_water_mark: self.post_changes.last_seq(),
+        }
+    }
+
+    pub fn log_post_change(&mut self, post_id: PostId, event: PostEvent) {
+        self.post_changes.record(post_id, event);
+    }
+
+    /// Returns a page of posts the user reacted to, newest reactions first, optionally only
+    /// those with the given reaction. Deleted and hidden posts are skipped.
+    pub fn reacted_posts(
+        &self,
+        principal: Principal,
+        reaction: Option<u16>,
+        page: usize,
+    ) -> Vec<&Post> {
+        let Some(user) = self.principal_to_user(principal) else {
+            return Default::default();
+        };
+        user.reacted_posts
+            .iter()
+            .filter(|(_, id)| reaction.map(|reaction| reaction == *id).unwrap_or(true))
+            .filter_map(|(post_id, _)| Post::get(self, post_id))
+            .filter(|post| !post.hidden())
+            .skip(page * CONFIG.feed_page_size)
+            .take(CONFIG.feed_page_size)
+            .collect()
+    }
+
+    /// Returns the id of the post recently created by the caller with the given idempotency key,
+    /// so that retried requests don't create duplicates.
+    pub fn post_by_idempotency_key(
+        &self,
+        principal: Principal,
+        key: &str,
+        now: u64,
+    ) -> Result<Option<PostId>, String> {
+        if key.len() > CONFIG.max_idempotency_key_length {
+            return Err("idempotency key is too long".into());
+        }
+        let controller_id = principal.to_string();
+        Ok(self
+            .principal_to_user(principal)
+            // bots post through their controllers
+            .or_else(|| {
+                self.users
+                    .values()
+                    .find(|user| user.controllers.contains(&controller_id))
+            })
+            .and_then(|user| user.post_by_idempotency_key(key, now)))
+    }
+
+    pub fn remember_idempotency_key(&mut self, post_id: PostId, key: String, now: u64) {
+        if let Some(user) = Post::get(self, &post_id)
+            .map(|post| post.user)
+            .and_then(|user_id| self.users.get_mut(&user_id))
+        {
+            user.remember_idempotency_key(key, post_id, now);
+        }
+    }
+
+    pub fn set_do_not_disturb(
+        &mut self,
+        principal: Principal,
+        do_not_disturb: DoNotDisturb,
+        now: u64,
+    ) -> Result<(), String> {
+        do_not_disturb.validate()?;
+        let user = self
+            .principal_to_user_mut(principal)
+            .ok_or("no user found")?;
+        user.do_not_disturb = do_not_disturb;
+        user.release_held_notifications(now);
+        Ok(())
+    }
+
+    /// Returns true if an impression of the post by the viewer would be counted: own posts,
+    /// archived posts and posts of authors who didn't opt in are not counted.
+    pub fn counts_impressions(&self, viewer_id: UserId, post: &Post) -> bool {
+        post.user != viewer_id
+            && !post.archived
+            && self
+                .users
+                .get(&post.user)
+                .map_or(false, |author| author.impressions_enabled)
+    }
+
+    /// Returns the impressions of the post including the ones not flushed yet.
+    pub fn impressions(&self, post: &Post) -> Option<u32> {
+        match (post.impressions, self.pending_impressions.get(&post.id)) {
+            (impressions, None) => impressions,
+            (impressions, Some(pending)) => Some(impressions.unwrap_or_default() + pending),
+        }
+    }
+
+    /// Counts an impression of every given post by the caller, unless it's not counted at all or
+    /// the caller has already seen the post within the impression window. The counts are only
+    /// buffered and get added to the posts in the chores.
+    pub fn record_impressions(
+        &mut self,
+        principal: Principal,
+        ids: Vec<PostId>,
+        now: u64,
+    ) -> Result<(), String> {
+        if ids.len() > CONFIG.max_feed_page_size {
+            return Err("too many posts".into());
+        }
+        let viewer_id = self.principal_to_user(principal).ok_or("no user found")?.id;
+        let window = CONFIG.impression_window_hours * HOUR;
+        for post_id in ids {
+            // only posts from the heap are looked up, so that the archive is never decoded
+            let Some(post) = self.posts.get(&post_id) else {
+                continue;
+            };
+            let seen = self
+                .recent_impressions
+                .get(&(viewer_id, post_id))
+                .map(|timestamp| timestamp + window > now)
+                .unwrap_or_default();
+            if seen || !self.counts_impressions(viewer_id, post) {
+                continue;
+            }
+            self.recent_impressions.insert((viewer_id, post_id), now);
+            let pending = self.pending_impressions.entry(post_id).or_default();
+            *pending = pending.saturating_add(1);
+        }
+        Ok(())
+    }
+
+    // Adds the buffered impressions to the posts. Impressions of posts that are not in the heap
+    // anymore are dropped.
+    fn flush_impressions(&mut self) {
+        for (post_id, count) in std::mem::take(&mut self.pending_impressions) {
+            if let Some(post) = self.posts.get_mut(&post_id) {
+                let impressions = post.impressions.get_or_insert(0);
+                *impressions = impressions.saturating_add(count);
+            }
+        }
+    }
+
+    pub fn set_impressions_enabled(
+        &mut self,
+        principal: Principal,
+        enabled: bool,
+    ) -> Result<(), String> {
+        self.principal_to_user_mut(principal)
+            .ok_or("no user found")?
+            .impressions_enabled = enabled;
+        Ok(())
+    }
+
+    pub fn set_content_warnings(
+        &mut self,
+        principal: Principal,
+        preference: ContentWarnings,
+    ) -> Result<(), String> {
+        self.principal_to_user_mut(principal)
+            .ok_or("no user found")?
+            .content_warnings = preference;
+        Ok(())
+    }
+
+    pub fn set_languages(
+        &mut self,
+        principal: Principal,
+        mut languages: Vec<String>,
+    ) -> Result<(), String> {
+        if languages.len() > CONFIG.max_user_languages {
+            return Err(format!(
+                "not more than {} languages are allowed",
+                CONFIG.max_user_languages
+            ));
+        }
+        for language in &languages {
+            post::validate_language(language)?;
+        }
+        // the order is kept, because the first language is the default one of new posts
+        let mut seen = BTreeSet::new();
+        languages.retain(|language| seen.insert(language.clone()));
+        self.principal_to_user_mut(principal)
+            .ok_or("no user found")?
+            .languages = languages;
+        Ok(())
+    }
+
+    /// Renames the user keeping the user id. The old handle stays assigned to the user for a
+    /// while, so that it can't be used for impersonation right away.
+    pub fn change_username(
+        &mut self,
+        principal: Principal,
+        name: String,
+        now: u64,
+    ) -> Result<(), String> {
+        let user = self.principal_to_user(principal).ok_or("no user found")?;
+        if let Some((_, timestamp)) = user.previous_names.last() {
+            if timestamp + CONFIG.handle_change_cooldown_days * DAY > now {
+                return Err(format!(
+                    "handles can be changed only once in {} days",
+                    CONFIG.handle_change_cooldown_days
+                ));
+            }
+        }
+        self.validate_handle(&name, Some(user.id))?;
+        let user = self
+            .principal_to_user_mut(principal)
+            .ok_or("no user found")?;
+        let old_name = std::mem::replace(&mut user.name, name.clone());
+        user.previous_names.push((old_name.clone(), now));
+        self.logger
+            .info(format!("@{} is now known as @{}.", old_name, name));
+        Ok(())
+    }
+
+    pub fn clear_notifications_of_type(&mut self, principal: Principal, kind: NotificationType) {
+        if let Some(user) = self.principal_to_user_mut(principal) {
+            user.clear_notifications_of_type(kind)
+        }
+    }
+
+    pub fn reserved_handles(&self) -> Vec<String> {
+        let mut handles: Vec<String> = CONFIG
+            .reserved_handles
+            .iter()
+            .map(|handle| handle.to_string())
+            .chain(self.reserved_handles.iter().flatten().cloned())
+            .collect();
+        handles.sort_unstable();
+        handles.dedup();
+        handles
+    }
+
+    pub fn reserved_realm_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = CONFIG
+            .reserved_realm_names
+            .iter()
+            .map(|name| name.to_string())
+            .chain(self.reserved_realm_names.iter().flatten().cloned())
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
+    pub fn realm_name_policy(&self) -> RealmNamePolicy {
+        RealmNamePolicy {
+            min_length: CONFIG.min_realm_name,
+            max_length: CONFIG.max_realm_name,
+            allowed_chars: "latin letters, digits, _ and -".into(),
+            reserved: self.reserved_realm_names(),
+        }
+    }
+
+    pub fn validate_realm_name(&self, name: &str) -> Result<(), String> {
+        if name.len() < CONFIG.min_realm_name {
+            return Err("realm name too short".into());
+        }
+        if name.len() > CONFIG.max_realm_name {
+            return Err("realm name too long".into());
+        }
+        if !name.chars().all(realm_name_char) {
+            return Err("realm name should be an alpha-numeric string".into());
+        }
+        if name.chars().all(|c| char::is_ascii_digit(&c)) {
+            return Err("realm name should have at least on character".into());
+        }
+        if CONFIG.name.to_lowercase() == name.to_lowercase()
+            || self
+                .realms
+                .keys()
+                .any(|realm| realm.to_lowercase() == name.to_lowercase())
+        {
+            return Err("realm name taken".into());
+        }
+        let skeleton = realm_name_skeleton(name);
+        if self
+            .reserved_realm_names()
+            .iter()
+            .any(|reserved| realm_name_skeleton(reserved) == skeleton)
+        {
+            return Err("reserved realm name".into());
+        }
+        if let Some(realm) = self
+            .realms
+            .keys()
+            .find(|realm| realm_name_skeleton(realm) == skeleton)
+        {
+            return Err(format!("too similar to realm {}", realm));
+        }
+        Ok(())
+    }
+
+    pub fn handle_policy(&self) -> HandlePolicy {
+        HandlePolicy {
+            min_length: CONFIG.min_handle_length,
+            max_length: CONFIG.max_handle_length,
+            allowed_chars: "latin letters and digits, starting with a letter".into(),
+            reserved: self.reserved_handles(),
+        }
+    }
+
+    pub fn validate_username(&self, name: &str) -> Result<(), String> {
+        self.validate_handle(name, None)
+    }
+
+    // Validates the handle for the given user, whose own current and previous handles are not
+    // considered taken.
+    fn validate_handle(&self, name: &str, user_id: Option<UserId>) -> Result<(), String> {
+        let name = name.to_lowercase();
+        let others = || {
+            self.users
+                .values()
+                .filter(move |user| Some(user.id) != user_id)
+        };
+        if others().any(|user| user.name.to_lowercase() == name) {
+            return Err("taken".into());
+        }
+        let now = time();
+        if others().any(|user| user.holds_previous_name(&name, now)) {
+            return Err("taken".into());
+        }
+        if name.len() < CONFIG.min_handle_length || name.len() > CONFIG.max_handle_length {
+            return Err(format!(
+                "should be between {} and {} characters",
+                CONFIG.min_handle_length, CONFIG.max_handle_length
+            ));
+        }
+        if !name
+            .chars()
+            .all(|c| char::is_ascii(&c) && char::is_alphanumeric(c))
+        {
+            return Err("should be a latin alpha-numeric string".into());
+        }
+        if name
+            .chars()
+            .next()
+            .map(|c| !char::is_ascii_alphabetic(&c))
+            .unwrap_or_default()
+        {
+            return Err("first character can't be a number".into());
+        }
+        if name.chars().all(|c| char::is_ascii_digit(&c)) {
+            return Err("should have at least one character".into());
+        }
+        let skeleton = handle_skeleton(&name);
+        if self
+            .reserved_handles()
+            .iter()
+            .any(|handle| handle_skeleton(handle) == skeleton)
+        {
+            return Err("reserved handle".into());
+        }
+        if let Some(user) = self
+            .users
+            .values()
+            .find(|user| handle_skeleton(&user.name) == skeleton)
+        {
+            return Err(format!("too similar to @{}", user.name));
+        }
+        Ok(())
+    }
+
+    /// Returns posts authored by any of the given users (or by anyone if no users are given) and
+    /// tagged with all of the given tags, or with any of them if `match_any` is set. Tags are
+    /// matched case-insensitively and an empty tag list matches all posts. Every post is returned
+    /// at most once and the results are ordered from the newest to the oldest post.
+    pub fn posts_by_tags(
+        &self,
+        realm: Option<String>,
+        tags: Vec<String>,
+        users: Vec<UserId>,
+        match_any: bool,
+        page: usize,
+    ) -> Vec<Post> {
+        let query: HashSet<_> = tags.into_iter().map(|tag| tag.to_lowercase()).collect();
+        self.last_posts(realm, true)
+            .filter(|post| {
+                let post_tags = post
+                    .tags
+                    .iter()
+                    .map(|tag| tag.to_lowercase())
+                    .collect::<HashSet<_>>();
+                let tags_match = if match_any && !query.is_empty() {
+                    !post_tags.is_disjoint(&query)
+                } else {
+                    post_tags.is_superset(&query)
+                };
+                (users.is_empty() || users.contains(&post.user)) && tags_match
+            })
+            .skip(page * CONFIG.feed_page_size)
+            .take(CONFIG.feed_page_size)
+            .cloned()
+            .collect()
+    }
+
+    pub fn last_posts<'a>(
+        &'a self,
+        realm: Option<String>,
+        with_comments: bool,
+    ) -> Box<dyn Iterator<Item = &'a Post> + 'a> {
+        let iter = {
+            let last_id = self.next_post_id.saturating_sub(1);
+            Box::new((0..=last_id).rev())
+        }
+        .filter_map(move |i| Post::get(self, &i))
+        .filter(move |post| !post.hidden() && (with_comments || post.parent.is_none()));
+        match realm {
+            None => Box::new(iter),
+            id => Box::new(iter.filter(move |post| post.realm.as_ref() == id.as_ref())),
+        }
+    }
+
+    pub fn recent_tags(&self, realm: Option<String>, n: u64) -> Vec<(String, u64)> {
+        // normalized hashtag -> (user spelled hashtag, occurences)
+        let mut tags: HashMap<String, (String, u64)> = Default::default();
+        let mut tags_found = 0;
+        'OUTER: for post in self
+            .last_posts(realm, true)
+            .take_while(|post| !post.archived)
+        {
+            for tag in &post.tags {
+                let (_, counter) = tags.entry(tag.to_lowercase()).or_insert((tag.clone(), 0));
+                // We only count tags occurences on root posts, if they have comments or reactions
+                if post.parent.is_some() || post.reactions.is_empty() && post.children.is_empty() {
+                    continue;
+                }
+                *counter += 1;
+                if *counter == 2 {
+                    tags_found += 1;
+                }
+            }
+            if tags_found >= n {
+                break 'OUTER;
+            }
+        }
+        tags.into_iter()
+            .filter_map(|(_, (tag, count))| (count > 1).then_some((tag, count)))
+            .collect()
+    }
+
+    /// Returns up to `n` tags used most frequently in the realm's posts within the trending
+    /// window, ordered by their frequency.
+    pub fn realm_tags(&self, realm: String, n: usize, now: u64) -> Vec<(String, u64)> {
+        // normalized hashtag -> (user spelled hashtag, occurences)
+        let mut tags: HashMap<String, (String, u64)> = Default::default();
+        let since = now.saturating_sub(CONFIG.trending_tags_window_days * DAY);
+        // the cutoff is applied to all posts, so that the posts of a quiet realm don't make the
+        // iteration walk past the window
+        for post in self
+            .last_posts(None, true)
+            .take_while(|post| !post.archived && post.timestamp() >= since)
+            .filter(|post| post.realm.as_ref() == Some(&realm))
+        {
+            for tag in &post.tags {
+                tags.entry(tag.to_lowercase()).or_insert((tag.clone(), 0)).1 += 1;
+            }
+        }
+        let mut tags = tags.into_values().collect::<Vec<_>>();
+        tags.sort_unstable_by(|(tag_a, a), (tag_b, b)| b.cmp(a).then(tag_a.cmp(tag_b)));
+        tags.truncate(n);
+        tags
+    }
+
+    /// Returns an iterator of posts from the root post to the post `id`.
+    pub fn thread(&self, id: PostId) -> Box<dyn Iterator<Item = PostId>> {
+        let mut result = Vec::new();
+        let mut curr = id;
+        while let Some(Post { id, parent, .. }) = Post::get(self, &curr) {
+            result.push(*id);
+            if let Some(parent_id) = parent {
+                curr = *parent_id
+            } else {
+                break;
+            }
+        }
+        Box::new(result.into_iter().rev())
+    }
+
+    /// Returns the ids of all comments below the post in depth-first order, so that every
+    /// comment directly follows its parent or its preceding sibling's subtree.
+    pub fn tree(&self, id: PostId, sort: TreeSort) -> Vec<PostId> {
+        let catalog = self.reactions();
+        let score = |post: &Post| {
+            // reactions on deleted comments don't count, so that their replies don't get
+            // promoted or buried by the content that is gone
+            if post.is_deleted() {
+                0
+            } else {
+                post.reaction_karma(&catalog)
+            }
+        };
+        let mut result = Vec::new();
+        let mut stack = vec![id];
+        while let Some(post_id) = stack.pop() {
+            if post_id != id {
+                result.push(post_id);
+            }
+            let mut children = match Post::get(self, &post_id) {
+                Some(post) => post
+                    .children
+                    .iter()
+                    .filter_map(|id| Post::get(self, id))
+                    .collect::<Vec<_>>(),
+                None => continue,
+            };
+            match sort {
+                TreeSort::Chronological => children.sort_by_key(|post| post.id),
+                TreeSort::Top => children.sort_by(|a, b| {
+                    score(b)
+                        .cmp(&score(a))
+                        .then(b.timestamp().cmp(&a.timestamp()))
+                        .then(b.id.cmp(&a.id))
+                }),
+            }
+            // the stack is processed from the end
+            stack.extend(children.into_iter().rev().map(|post| post.id));
+        }
+        result
+    }
+
+    pub fn user(&self, handle: &str) -> Option<&User> {
+        handle
+            .parse::<u64>()
+            .ok()
+            .and_then(|id| self.users.get(&id))
+            .or_else(|| {
+                self.users
+                    .values()
+                    .find(|user| user.name.to_lowercase() == handle.to_lowercase())
+            })
+            // recently renamed users are still found by their old handles
+            .or_else(|| {
+                let now = time();
+                self.users
+                    .values()
+                    .find(|user| user.holds_previous_name(handle, now))
+            })
+    }
+
+    /// Returns users with handles starting with the prefix (case-insensitive) as `(id, handle,
+    /// karma)` tuples ordered by karma and recent activity. Banned and deleted users are skipped.
+    pub fn search_users(
+        &self,
+        prefix: &str,
+        limit: usize,
+        now: u64,
+    ) -> Vec<(UserId, String, Karma)> {
+        let prefix = prefix.trim_start_matches('@').to_lowercase();
+        if prefix.is_empty() {
+            return Default::default();
+        }
+        let mut users = self
+            .users
+            .values()
+            .filter(|user| {
+                user.deleted_at.is_none()
+                    && !user.banned(now)
+                    && user.name.to_lowercase().starts_with(&prefix)
+            })
+            .collect::<Vec<_>>();
+        users.sort_unstable_by(|a, b| {
+            b.karma()
+                .cmp(&a.karma())
+                .then(b.last_activity.cmp(&a.last_activity))
+                .then(a.id.cmp(&b.id))
+        });
+        users
+            .into_iter()
+            .take(limit.min(CONFIG.max_user_search_results))
+            .map(|user| (user.id, user.name.clone(), user.karma()))
+            .collect()
+    }
+
+    /// Returns the total number of followers (or followees) of the user and the given page of
+    /// them as `(id, handle, karma)` tuples.
+    pub fn follow_list(
+        &self,
+        handle: &str,
+        followers: bool,
+        page: usize,
+    ) -> Result<(usize, Vec<(UserId, String, Karma)>), String> {
+        let user = self.user(handle).ok_or("no user found")?;
+        let ids = if followers {
+            &user.followers
+        } else {
+            &user.followees
+        };
+        Ok((
+            ids.len(),
+            ids.iter()
+                .skip(page * CONFIG.feed_page_size)
+                .take(CONFIG.feed_page_size)
+                .filter_map(|id| self.users.get(id))
+                .map(|user| (user.id, user.name.clone(), user.karma()))
+                .collect(),
+        ))
+    }
+
+    /// Deletes the account of the user: all posts are redacted, all relations to realms and
+    /// other users are removed and the profile is erased. The handle stays reserved for the
+    /// grace period.
+    pub fn delete_account(
+        &mut self,
+        principal: Principal,
+        confirmation: String,
+        now: u64,
+    ) -> Result<(), String> {
+        let user = self
+            .principal_to_user(principal)
+            .ok_or("no user found")?
+            .clone();
+        if user.name != confirmation {
+            return Err("please confirm the deletion with your handle".into());
+        }
+        // any device of the user can hold tokens
+        if user.devices().iter().any(|device| {
+            self.balances
+                .get(&account(device.principal))
+                .copied()
+                .unwrap_or_default()
+                > 0
+        }) {
+            return Err("please transfer your tokens before deleting the account".into());
+        }
+        if user.treasury_e8s > 0 {
+            return Err("please withdraw your ICP rewards before deleting the account".into());
+        }
+
+        // pending deletions become permanent right away
+        for post_id in self
+            .pending_deletions
+            .keys()
+            .filter(|id| Post::get(self, id).map(|post| post.user) == Some(user.id))
+            .copied()
+            .collect::<Vec<_>>()
+        {
+            self.finalize_pending_deletion(post_id)?;
+        }
+        for (post_id, body) in user
+            .posts(self)
+            .filter(|post| !post.is_deleted())
+            .map(|post| (post.id, post.body.clone()))
+            .collect::<Vec<_>>()
+        {
+            self.erase_post(post_id, vec![body])?;
+        }
+        let mut proposals = std::mem::take(&mut self.proposals);
+        for proposal in proposals
+            .iter_mut()
+            .filter(|p| p.proposer == user.id && p.status == Status::Open)
+        {
+            proposal.status = Status::Cancelled;
+            proposals::log_status_change(
+                &mut self.proposal_events,
+                proposal.id,
+                Status::Cancelled,
+                now,
+            );
+            proposals::settle_deposit(self, proposal, false);
+        }
+        self.proposals = proposals;
+
+        for name in &user.realms {
+            if let Some(realm) = self.realms.get_mut(name) {
+                realm.num_members = realm.num_members.saturating_sub(1);
+            }
+        }
+        for realm in self.realms.values_mut() {
+            realm.controllers.retain(|id| id != &user.id);
+        }
+        for followee_id in &user.followees {
+            if let Some(followee) = self.users.get_mut(followee_id) {
+                followee.followers.remove(&user.id);
+            }
+        }
+        for follower_id in &user.followers {
+            if let Some(follower) = self.users.get_mut(follower_id) {
+                follower.followees.remove(&user.id);
+            }
+        }
+        self.invites.retain(|_, invite| invite.inviter != user.id);
+
+        // the remaining cycles are burned, so that they don't vanish from the accounting
+        let cycles = self
+            .users
+            .get(&user.id)
+            .map(|user| user.cycles())
+            .unwrap_or_default();
+        if cycles > 0 {
+            self.charge(user.id, cycles, "account deletion")?;
+        }
+        self.users
+            .get_mut(&user.id)
+            .expect("no user found")
+            .redact(now);
+        self.principals.retain(|_, id| id != &user.id);
+        self.logger
+            .info(format!("@{} deleted their account.", user.name));
+        Ok(())
+    }
+
+    fn lift_bans(&mut self, now: u64) {
+        for user in self
+            .users
+            .values_mut()
+            .filter(|user| user.banned_until.is_some() && !user.banned(now))
+        {
+            user.banned_until = None;
+            user.notify("Your ban was lifted.");
+            self.logger
+                .info(format!("The ban of @{} was lifted.", user.name));
+        }
+    }
+
+    // Releases handles of deleted accounts after the grace period.
+    fn free_deleted_handles(&mut self, now: u64) {
+        for user in self.users.values_mut().filter(|user| {
+            user.deleted_at
+                .map(|timestamp| timestamp + CONFIG.account_deletion_grace_period_days * DAY < now)
+                .unwrap_or_default()
+                && user.name != format!("deleted_{}", user.id)
+        }) {
+            // underscores are not allowed in handles, so this name never collides with new users
+            user.name = format!("deleted_{}", user.id);
+        }
+    }
+
+    pub async fn change_principal(
+        principal: Principal,
+        new_principal_str: String,
+    ) -> Result<(), String> {
+        #[allow(unused_variables)]
+        let account_identifier = mutate(|state| {
+            let new_principal =
+                Principal::from_text(new_principal_str).map_err(|e| e.to_string())?;
+            if state.principals.contains_key(&new_principal) {
+                return Err("principal already controls a user".to_string());
+            }
+            let user_id = *state
+                .principals
+                .get(&principal)
+                .ok_or("no principal found")?;
+            let user = state.users.get_mut(&user_id).expect("no user found");
+            if user.principal != principal {
+                return Err("only the primary principal can be changed".into());
+            }
+            for device in user.devices.iter_mut() {
+                if device.principal == principal {
+                    device.principal = new_principal;
+                }
+            }
+            user.principal = new_principal;
+            state.principals.remove(&principal);
+            state.principals.insert(new_principal, user_id);
+            for grant in state
+                .vesting_grants
+                .iter_mut()
+                .filter(|grant| grant.receiver == principal)
+            {
+                grant.receiver = new_principal;
+                grant.receiver_id = Some(user_id);
+            }
+            let user = state.users.get_mut(&user_id).expect("no user found");
+            let account_identifier =
+                AccountIdentifier::new(&id(), &principal_to_subaccount(&new_principal));
+            user.account = account_identifier.to_string();
+            let accounts = state
+                .balances
+                .keys()
+                .filter(|acc| acc.owner == principal)
+                .cloned()
+                .collect::<Vec<_>>();
+            for acc in accounts {
+                crate::token::move_funds(state, &acc, account(new_principal))
+                    .expect("couldn't transfer token funds");
+            }
+            Ok(account_identifier)
+        })?;
+        #[cfg(not(test))]
+        {
+            let balance = invoices::account_balance_of_principal(principal).await;
+            invoices::transfer(
+                account_identifier,
+                balance,
+                Memo(10101),
+                Some(principal_to_subaccount(&principal)),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Lets the given principal control the account of the caller as well.
+    pub fn add_device(
+        &mut self,
+        caller: Principal,
+        device: String,
+        label: String,
+        now: u64,
+    ) -> Result<(), String> {
+        let device = Principal::from_text(device).map_err(|e| e.to_string())?;
+        if device == Principal::anonymous() {
+            return Err("anonymous principal can't control a user".into());
+        }
+        if self.principals.contains_key(&device) {
+            return Err("principal already controls a user".into());
+        }
+        let label = label.trim().to_string();
+        if label.is_empty() || label.chars().count() > CONFIG.max_device_label_length {
+            return Err("invalid device label".into());
+        }
+        let user = self.principal_to_user_mut(caller).ok_or("no user found")?;
+        user.pending_devices.retain(|d| d.principal != device);
+        if user.devices().len() + user.pending_devices.len() >= CONFIG.max_devices {
+            return Err(format!(
+                "not more than {} devices allowed",
+                CONFIG.max_devices
+            ));
+        }
+        // the device only controls the account after its principal confirms it
+        user.pending_devices.push(Device {
+            principal: device,
+            label,
+            added: now,
+        });
+        Ok(())
+    }
+
+    /// Lets the caller confirm a device proposed by the given user.
+    pub fn confirm_device(&mut self, caller: Principal, user_id: UserId) -> Result<(), String> {
+        if self.principals.contains_key(&caller) {
+            return Err("principal already controls a user".into());
+        }
+        let user = self.users.get_mut(&user_id).ok_or("no user found")?;
+        let pos = user
+            .pending_devices
+            .iter()
+            .position(|d| d.principal == caller)
+            .ok_or("no pending device found")?;
+        let mut devices = user.devices();
+        if devices.len() >= CONFIG.max_devices {
+            return Err(format!(
+                "not more than {} devices allowed",
+                CONFIG.max_devices
+            ));
+        }
+        devices.push(user.pending_devices.remove(pos));
+        user.devices = devices;
+        self.principals.insert(caller, user_id);
+        Ok(())
+    }
+
+    pub fn remove_device(&mut self, caller: Principal, device: String) -> Result<(), String> {
+        let device = Principal::from_text(device).map_err(|e| e.to_string())?;
+        let user = self.principal_to_user_mut(caller).ok_or("no user found")?;
+        if user.pending_devices.iter().any(|d| d.principal == device) {
+            user.pending_devices.retain(|d| d.principal != device);
+            return Ok(());
+        }
+        let mut devices = user.devices();
+        if !devices.iter().any(|d| d.principal == device) {
+            return Err("no device found".into());
+        }
+        if devices.len() <= 1 {
+            return Err("the last device can't be removed".into());
+        }
+        if device == user.principal {
+            return Err("the primary principal can only be replaced with a new one".into());
+        }
+        devices.retain(|d| d.principal != device);
+        user.devices = devices;
+        self.principals.remove(&device);
+        Ok(())
+    }
+
+    pub fn principal_to_user(&self, principal: Principal) -> Option<&User> {
+        self.principals
+            .get(&principal)
+            .and_then(|id| self.users.get(id))
+    }
+
+    pub fn principal_to_user_mut(&mut self, principal: Principal) -> Option<&mut User> {
+        let id = self.principals.get(&principal)?;
+        self.users.get_mut(id)
+    }
+
+    fn new_user_id(&mut self) -> UserId {
+        let id = self.next_user_id;
+        self.next_user_id += 1;
+        id
+    }
+
+    fn new_post_id(&mut self) -> PostId {
+        let id = self.next_post_id;
+        self.next_post_id += 1;
+        id
+    }
+
+    /// Returns a page of log events, newest first, optionally only those of the given level.
+    pub fn logs(&self, level: Option<&str>, page: usize, page_size: usize) -> Vec<&Event> {
+        self.logger
+            .events
+            .iter()
+            .rev()
+            .filter(|event| {
+                level
+                    .map(|level| event.level.eq_ignore_ascii_case(level))
+                    .unwrap_or(true)
+            })
+            .skip(page * page_size)
+            .take(page_size)
+            .collect()
+    }
+
+    fn compute_stats(&self, now: u64) -> Stats {
+        let mut stalwarts = Vec::new();
+        let mut weekly_karma_leaders = Vec::new();
+        let mut bootcamp_users = 0;
+        let mut users_online = 0;
+        let mut invited_users = 0;
+        let mut active_users = 0;
+        let mut bots = Vec::new();
+        let mut cycles = 0;
+        for user in self.users.values() {
+            if user.stalwart {
+                stalwarts.push(user);
+            }
+            if !user.trusted() {
+                bootcamp_users += 1;
+            }
+            if now < user.last_activity + CONFIG.online_activity_minutes {
+                users_online += 1;
+            }
+            if user.is_bot() {
+                bots.push(user.id);
+            }
+            if user.invited_by.is_some() {
+                invited_users += 1;
+            }
+            if user.active_within_weeks(now, 1) {
+                active_users += 1;
+                if user.karma_to_reward() > 0 {
+                    weekly_karma_leaders.push((user.id, user.karma_to_reward()));
+                }
+            }
+            cycles += user.cycles();
+        }
+        stalwarts.sort_unstable_by_key(|u1| std::cmp::Reverse(u1.karma()));
+        weekly_karma_leaders.sort_unstable_by_key(|k| k.1);
+        weekly_karma_leaders = weekly_karma_leaders.into_iter().rev().take(12).collect();
+        let posts = self.root_posts;
+        let emergency_votes = self.emergency_votes.values().sum::<Token>() as f32
+            / self.active_voting_power(time()).max(1) as f32
+            * 100.0;
+        Stats {
+            emergency_release: format!(
+                "Binary set: {}, votes: {}% (required: {}%)",
+                !self.emergency_binary.is_empty(),
+                emergency_votes as u32,
+                CONFIG.proposal_approval_threshold
+            ),
+            team_tokens: self.team_tokens.clone(),
+            emergency_votes: self.emergency_votes.keys().cloned().collect(),
+            meta: format!("Memory health: {}", self.memory.health("MB")),
+            weekly_karma_leaders,
+            bootcamp_users,
+            module_hash: self.module_hash.clone(),
+            canister_id: ic_cdk::id(),
+            last_upgrade: self.last_upgrade,
+            last_weekly_chores: self.last_weekly_chores,
+            last_daily_chores: self.last_weekly_chores,
+            last_hourly_chores: self.last_weekly_chores,
+            canister_cycle_balance: canister_balance(),
+            users: self.users.len(),
+            posts,
+            comments: Post::count(self) - posts,
+            cycles,
+            burned_cycles: self.burned_cycles,
+            burned_cycles_total: self.burned_cycles_total,
+            total_revenue_shared: self.total_revenue_shared,
+            total_rewards_shared: self.total_rewards_shared,
+            total_tip_fees: self.total_tip_fees,
+            account: invoices::main_account().to_string(),
+            users_online,
+            stalwarts: stalwarts.into_iter().map(|u| u.id).collect(),
+            bots,
+            state_size: stable64_size() << 16,
+            invited_users,
+            active_users,
+            circulating_supply: self.balances.values().sum(),
+            minting_ratio_history: self.minting_ratio_history.iter().cloned().collect(),
+            mint_headroom: self.mint_headroom(now),
+            buckets: self
+                .storage
+                .buckets
+                .iter()
+                .map(|(id, size)| (id.to_string(), *size))
+                .collect(),
+            computed_at: now,
+        }
+    }
+
+    pub fn vote_on_report(
+        &mut self,
+        principal: Principal,
+        domain: String,
+        id: u64,
+        vote: bool,
+    ) -> Result<(), String> {
+        let user = self
+            .principal_to_user(principal)
+            .ok_or("no user found")?
+            .clone();
+        if !user.stalwart {
+            return Err("only stalwarts can vote on reports".into());
+        }
+        let stalwarts = self.users.values().filter(|u| u.stalwart).count();
+        let (user_id, report, penalty, subject) = match domain.as_str() {
+            "post" => {
+                let was_deleted = Post::get(self, &id).map(|post| post.is_deleted());
+                let (files, result) = Post::mutate(
+                    self,
+                    &id,
+                    |post| -> Result<(_, (UserId, Report, Cycles, String)), String> {
+                        let files = post.files.clone();
+                        post.vote_on_report(stalwarts, user.id, user.karma(), vote)?;
+                        // files are only removed if the report led to a deletion
+                        let removed = if post.files.is_empty() {
+                            files
+                        } else {
+                            Default::default()
+                        };
+                        let post_user = post.user;
+                        let post_report = post.report.clone().ok_or("no report")?;
+                        Ok((
+                            removed,
+                            (
+                                post_user,
+                                post_report,
+                                CONFIG.reporting_penalty_post,
+                                format!("post {}", id),
+                            ),
+                        ))
+                    },
+                )?;
+                self.storage.release_files(&files);
+                if was_deleted == Some(false)
+                    && Post::get(self, &id).map(|post| post.is_deleted()) == Some(true)
+                {
+                    self.log_post_change(id, PostEvent::Deleted);
+                }
+                result
+            }
+            "misbehaviour" => {
+                if user.id == id {
+                    return Err("votes on own reports are not accepted".into());
+                }
+                let report = self
+                    .users
+                    .get_mut(&id)
+                    .and_then(|u| u.report.as_mut())
+                    .expect("no user found");
+                report.vote(stalwarts, user.id, user.karma(), vote)?;
+                (
+                    id,
+                    report.clone(),
+                    CONFIG.reporting_penalty_misbehaviour,
+                    format!("user {}", id),
+                )
+            }
+            _ => return Err("unknown report type".into()),
+        };
+        // confirmed reports delete the post
+        if domain == "post"
+            && Post::get(self, &id)
+                .map(|post| post.is_deleted())
+                .unwrap_or_default()
+        {
+            self.search_index.remove(id);
+            self.unpin_post(id);
+        }
+        let mut report = report;
+        reports::finalize_report(self, &mut report, penalty, user_id, subject)?;
+        self.mutate_report(&domain, id, |stored| {
+            stored.charged = report.charged;
+            stored.rewards = report.rewards;
+            stored.revoked_stalwart = report.revoked_stalwart;
+            stored.revoked_active_weeks = report.revoked_active_weeks;
+            Ok(())
+        })
+    }
+
+    fn mutate_report<T>(
+        &mut self,
+        domain: &str,
+        id: u64,
+        f: impl FnOnce(&mut Report) -> Result<T, String>,
+    ) -> Result<T, String> {
+        match domain {
+            "post" => Post::mutate(self, &id, |post| {
+                f(post.report.as_mut().ok_or("no report found")?)
+            }),
+            "misbehaviour" => f(self
+                .users
+                .get_mut(&id)
+                .and_then(|user| user.report.as_mut())
+                .ok_or("no report found")?),
+            _ => Err("unknown report type".into()),
+        }
+    }
+
+    /// Lets stalwarts resolve a report by covering the reported post with a content warning
+    /// instead of deleting it.
+    pub fn add_content_warning(
+        &mut self,
+        principal: Principal,
+        post_id: PostId,
+        warning: ContentWarning,
+    ) -> Result<(), String> {
+        let user = self.principal_to_user(principal).ok_or("no user found")?;
+        if !user.stalwart {
+            return Err("only stalwarts can add content warnings".into());
+        }
+        let name = user.name.clone();
+        let author = Post::mutate(self, &post_id, |post| {
+            if post.report.is_none() {
+                return Err("only reported posts can get content warnings".into());
+            }
+            if post.content_warning.is_some() {
+                return Err("post already has a content warning".into());
+            }
+            post.content_warning = Some(warning);
+            Ok(post.user)
+        })?;
+        self.logger.info(format!(
+            "@{} added a content warning to post {}.",
+            name, post_id
+        ));
+        if let Some(author) = self.users.get_mut(&author) {
+            author.notify_about_post(
+                NotificationType::System,
+                "Your reported post got a content warning",
+                post_id,
+            );
+        }
+        Ok(())
+    }
+
+    pub fn appeal_report(
+        &mut self,
+        principal: Principal,
+        domain: String,
+        id: u64,
+        appeal: String,
+        now: u64,
+    ) -> Result<(), String> {
+        if appeal.is_empty() {
+            return Err("appeal is empty".into());
+        }
+        if appeal.len() > 1000 {
+            return Err("appeal too long".into());
+        }
+        let user = self
+            .principal_to_user(principal)
+            .ok_or("no user found")?
+            .clone();
+        let (penalized_user, subject, predicate) = match domain.as_str() {
+            "post" => (
+                Post::get(self, &id).ok_or("no post found")?.user,
+                format!("post {}", id),
+                Predicate::ReportOpen(id),
+            ),
+            "misbehaviour" => (id, format!("user {}", id), Predicate::UserReportOpen(id)),
+            _ => return Err("unknown report type".into()),
+        };
+        if penalized_user != user.id {
+            return Err("only the penalized user can appeal".into());
+        }
+        self.mutate_report(&domain, id, |report| report.appeal(appeal, now))?;
+        self.notify_with_predicate(
+            &|u| u.stalwart && u.id != user.id,
+            format!(
+                "@{} appealed the moderation decision on {}",
+                user.name, subject
+            ),
+            predicate,
+        );
+        self.logger.info(format!(
+            "@{} appealed the moderation decision on {}.",
+            user.name, subject
+        ));
+        Ok(())
+    }
+
+    pub fn vote_on_poll(
+        &mut self,
+        principal: Principal,
+        time: u64,
+        post_id: PostId,
+        vote: u16,
+    ) -> Result<(), String> {
+        let user = self
+            .principal_to_user(principal)
+            .ok_or_else(|| "no user found".to_string())?;
+        let (user_id, user_realms) = (user.id, user.realms.clone());
+        Post::mutate(self, &post_id, |post| {
+            post.watchers.insert(user_id);
+            post.vote_on_poll(user_id, user_realms.clone(), time, vote)
+        })
+    }
+
+    pub fn report(
+        &mut self,
+        principal: Principal,
+        domain: String,
+        id: u64,
+        reason: String,
+    ) -> Result<(), String> {
+        if reason.len() > 1000 {
+            return Err("reason too long".into());
+        }
+        let cycles_required = if domain == "post" {
+            CONFIG.reporting_penalty_post
+        } else {
+            CONFIG.reporting_penalty_misbehaviour
+        } / 2;
+        let user = match self.principal_to_user(principal) {
+            Some(user) if user.cycles() >= cycles_required => user.clone(),
+            _ => {
+                return Err(format!(
+                    "You need at least {} cycles for this report",
+                    cycles_required
+                ))
+            }
+        };
+        let mut report = Report {
+            reporter: user.id,
+            reason,
+            ..Default::default()
+        };
+        report.add_weight(user.karma());
+        let report = Some(report);
+
+        match domain.as_str() {
+            "post" => {
+                let post_user = Post::mutate(self, &id, |post| {
+                    if post.report.is_some() {
+                        return Err("this post is already reported".into());
+                    }
+                    post.report = report.clone();
+                    Ok(post.user)
+                })?;
+                let author_name = self
+                    .users
+                    .get(&post_user)
+                    .map(|user| user.name.clone())
+                    .unwrap_or_default();
+                self.notify_with_predicate(
+                    &|u| u.stalwart && u.id != user.id,
+                    format!("@{} reported this post by @{}", user.name, author_name),
+                    Predicate::ReportOpen(id),
+                );
+            }
+            "misbehaviour" => {
+                let misbehaving_user = self.users.get_mut(&id).ok_or("no user found")?;
+                if misbehaving_user.report.as_ref().map(|r| r.closed) == Some(true) {
+                    return Err("this user is already reported".into());
+                }
+                misbehaving_user.report = report;
+                let user_name = misbehaving_user.name.clone();
+                self.notify_with_predicate(
+                    &|u| u.stalwart && u.id != id,
+                    format!("@{} reported user @{}", user.name, user_name),
+                    Predicate::UserReportOpen(id),
+                );
+            }
+            _ => unimplemented!(),
+        }
+
+        Ok(())
+    }
+
+    /// Hides the post and schedules its deletion. The deletion becomes permanent in the chores
+    /// after the grace period and can be undone with `restore_post` until then.
+    pub fn delete_post(
+        &mut self,
+        principal: Principal,
+        post_id: PostId,
+        versions: Vec<String>,
+        now: u64,
+    ) -> Result<(), String> {
+        let post = Post::get(self, &post_id).ok_or("no post found")?;
+        if self.principal_to_user(principal).map(|user| user.id) != Some(post.user) {
+            return Err("not authorized".into());
+        }
+        if post.hidden() {
+            return Err("post deleted".into());
+        }
+        self.check_post_deletion_costs(post)?;
+
+        Post::mutate(self, &post_id, |post| {
+            post.deletion_scheduled = Some(now);
+            Ok(())
+        })?;
+        self.pending_deletions.insert(post_id, versions);
+        self.search_index.remove(post_id);
+        self.log_post_change(post_id, PostEvent::Deleted);
+        Ok(())
+    }
+
+    pub fn restore_post(&mut self, principal: Principal, post_id: PostId) -> Result<(), String> {
+        let post = Post::get(self, &post_id).ok_or("no post found")?;
+        if self.principal_to_user(principal).map(|user| user.id) != Some(post.user) {
+            return Err("not authorized".into());
+        }
+        if post.deletion_scheduled.is_none() || post.is_deleted() {
+            return Err("post deletion can't be undone".into());
+        }
+        self.cancel_post_deletion(post_id)
+    }
+
+    fn cancel_post_deletion(&mut self, post_id: PostId) -> Result<(), String> {
+        self.pending_deletions.remove(&post_id);
+        let body = Post::mutate(self, &post_id, |post| {
+            post.deletion_scheduled = None;
+            Ok(post.body.clone())
+        })?;
+        if !body.is_empty() {
+            self.search_index.add(post_id, &body);
+        }
+        self.log_post_change(post_id, PostEvent::Edited);
+        Ok(())
+    }
+
+    // Deletes a post scheduled for deletion free of charge, keeping the versions stored when the
+    // deletion was scheduled.
+    fn finalize_pending_deletion(&mut self, post_id: PostId) -> Result<(), String> {
+        let versions = self.pending_deletions.remove(&post_id).unwrap_or_default();
+        self.erase_post(post_id, versions)
+    }
+
+    // Deletes the post without any charges or refunds, e.g. on the deletion of the account.
+    fn erase_post(&mut self, post_id: PostId, versions: Vec<String>) -> Result<(), String> {
+        let files = Post::mutate(self, &post_id, |post| {
+            post.deletion_scheduled = None;
+            Ok(post.delete(versions, DeletedBy::Author))
+        })?;
+        self.storage.release_files(&files);
+        self.search_index.remove(post_id);
+        self.log_post_change(post_id, PostEvent::Deleted);
+        self.hot.retain(|id| id != &post_id);
+        self.pending_polls.remove(&post_id);
+        self.unpin_post(post_id);
+        Ok(())
+    }
+
+    fn posts_to_delete(&self, now: u64) -> Vec<PostId> {
+        self.pending_deletions
+            .keys()
+            .filter(|post_id| {
+                Post::get(self, post_id)
+                    .and_then(|post| post.deletion_scheduled)
+                    .map(|timestamp| {
+                        timestamp + CONFIG.post_deletion_grace_period_minutes * MINUTE <= now
+                    })
+                    .unwrap_or(true)
+            })
+            .copied()
+            .collect()
+    }
+
+    // Makes all deletions with an expired grace period permanent. If the author can't pay the
+    // deletion anymore, the post gets restored.
+    fn delete_pending_posts(&mut self, now: u64) {
+        for post_id in self.posts_to_delete(now) {
+            let versions = self.pending_deletions.remove(&post_id).unwrap_or_default();
+            let user_id = match Post::get(self, &post_id) {
+                Some(post) if !post.is_deleted() => post.user,
+                // the post was deleted by moderation in the meantime
+                Some(_) => {
+                    let _ = Post::mutate(self, &post_id, |post| {
+                        post.deletion_scheduled = None;
+                        Ok(())
+                    });
+                    continue;
+                }
+                None => continue,
+            };
+            if let Err(err) = self.delete_post_permanently(post_id, versions) {
+                let _ = self.cancel_post_deletion(post_id);
+                if let Some(user) = self.users.get_mut(&user_id) {
+                    user.notify(format!(
+                        "The deletion of your post {} failed and it was restored: {}",
+                        post_id, err
+                    ));
+                }
+            }
+        }
+    }
+
+    // Fails if the author doesn't have enough cycles to pay for the post deletion.
+    fn check_post_deletion_costs(&self, post: &Post) -> Result<(), String> {
+        let (_, costs) = self.post_deletion_costs(post);
+        if costs > self.users.get(&post.user).ok_or("no user found")?.cycles() {
+            return Err(format!(
+                "not enough cycles (this post requires {} cycles to be deleted)",
+                costs
+            ));
+        }
+        Ok(())
+    }
+
+    // Returns the reaction rewards to be refunded and the total costs of the post deletion.
+    fn post_deletion_costs<'a>(
+        &self,
+        post: &'a Post,
+    ) -> (Vec<(&'a BTreeSet<UserId>, Cycles)>, Cycles) {
+        let comments_tree_penalty =
+            post.tree_size as Cycles * CONFIG.post_deletion_penalty_factor as Cycles;
+        let catalog = self.reactions();
+        let reaction_costs = post
+            .reactions
+            .iter()
+            .filter_map(|(r_id, users)| {
+                let cost = catalog
+                    .get(r_id)
+                    .map(|reaction| reaction.karma)
+                    .unwrap_or_default();
+                (cost > 0).then_some((users, cost as Cycles))
+            })
+            .collect::<Vec<_>>();
+        let costs = CONFIG.post_cost
+            + reaction_costs.iter().map(|(_, cost)| *cost).sum::<u64>()
+            + comments_tree_penalty;
+        (reaction_costs, costs)
+    }
+
+    fn delete_post_permanently(
+        &mut self,
+        post_id: PostId,
+        versions: Vec<String>,
+    ) -> Result<(), String> {
+        let post = Post::get(self, &post_id).ok_or("no post found")?.clone();
+        self.check_post_deletion_costs(&post)?;
+        let comments_tree_penalty =
+            post.tree_size as Cycles * CONFIG.post_deletion_penalty_factor as Cycles;
+        let (reaction_costs, _) = self.post_deletion_costs(&post);
+
+        let mut karma_penalty = post.children.len() as Karma * CONFIG.response_reward as Karma;
+
+        // refund rewards
+        for (users, amount) in reaction_costs {
+            for user_id in users {
+                self.cycle_transfer(
+                    post.user,
+                    *user_id,
+                    amount,
+                    0,
+                    Destination::Cycles,
+                    format!("rewards refund after deletion of post {}", post.id),
+                    None,
+                )?;
+                karma_penalty += amount as Karma;
+            }
+        }
+
+        // penalize for comments tree destruction
+        self.charge(
+            post.user,
+            CONFIG.post_cost + comments_tree_penalty,
+            format!("deletion of post {}", post.id),
+        )?;
+
+        // subtract all rewards from karma
+        self.users
+            .get_mut(&post.user)
+            .expect("no user found")
+            .change_karma(-karma_penalty, format!("deletion of post {}", post.id));
+
+        self.hot.retain(|id| id != &post_id);
+
+        match &post.extension {
+            Some(Extension::Proposal(proposal_id)) => {
+                let mut proposals = std::mem::take(&mut self.proposals);
+                if let Some(proposal) = proposals.iter_mut().find(|p| &p.id == proposal_id) {
+                    if proposal.status == Status::Open {
+                        proposals::log_status_change(
+                            &mut self.proposal_events,
+                            proposal.id,
+                            Status::Cancelled,
+                            time(),
+                        );
+                        proposals::settle_deposit(self, proposal, false);
+                    }
+                    proposal.status = Status::Cancelled
+                }
+                self.proposals = proposals;
+            }
+            Some(Extension::Poll(_)) => {
+                self.pending_polls.remove(&post_id);
+            }
+            _ => {}
+        };
+
+        self.search_index.remove(post_id);
+        self.unpin_post(post_id);
+
+        let files = Post::mutate(self, &post_id, |post| {
+            post.deletion_scheduled = None;
+            Ok(post.delete(versions.clone(), DeletedBy::Author))
+        })?;
+        self.storage.release_files(&files);
+        self.log_post_change(post_id, PostEvent::Deleted);
+        Ok(())
+    }
+
+    /// Returns the cycles deposited by proposers until their proposals are concluded.
+    pub fn proposal_deposit(&self) -> Cycles {
+        self.proposal_deposit.unwrap_or(CONFIG.proposal_deposit)
+    }
+
+    /// Returns the cycles converted to the voter's karma for every vote on a proposal.
+    pub fn voting_reward(&self) -> Cycles {
+        self.voting_reward.unwrap_or(CONFIG.voting_reward)
+    }
+
+    /// Returns the weeks of inactivity after which the voting power starts to decay and after
+    /// which it reaches zero.
+    pub fn voting_power_decay(&self) -> (u64, u64) {
+        self.voting_power_decay.unwrap_or((
+            CONFIG.voting_power_activity_weeks,
+            CONFIG.voting_power_decay_weeks,
+        ))
+    }
+
+    /// Returns the end of the cooldown restricting the new user, unless the cooldown is over or
+    /// was waived because the user was invited by a stalwart.
+    pub fn new_user_cooldown_end(&self, user: &User, now: u64) -> Option<u64> {
+        let hours = self
+            .new_user_cooldown_hours
+            .unwrap_or(CONFIG.new_user_cooldown_hours);
+        let end = user.timestamp + hours * HOUR;
+        let invited_by_stalwart = user
+            .invited_by
+            .and_then(|id| self.users.get(&id))
+            .map_or(false, |inviter| inviter.stalwart);
+        (end > now && !invited_by_stalwart).then_some(end)
+    }
+
+    /// Fails with a message stating when the restriction lifts if the user is in the cooldown.
+    pub fn check_new_user_cooldown(
+        &self,
+        user: &User,
+        now: u64,
+        action: &str,
+    ) -> Result<(), String> {
+        match self.new_user_cooldown_end(user, now) {
+            Some(end) => Err(format!(
+                "new users can't {}, the restriction lifts in {} hours",
+                action,
+                (end - now + HOUR - 1) / HOUR
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the names of the realms controlled by the user.
+    pub fn controlled_realms(&self, user_id: UserId) -> Vec<String> {
+        self.realms
+            .iter()
+            .filter(|(_, realm)| realm.controllers.contains(&user_id))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Returns the number of realms the user can still create, limited by both the per-user and
+    /// the global realm caps.
+    pub fn realm_quota(&self, user_id: UserId) -> usize {
+        let quota = CONFIG
+            .max_realms_per_user
+            .saturating_sub(self.controlled_realms(user_id).len());
+        match CONFIG.max_realms {
+            Some(max_realms) => quota.min(max_realms.saturating_sub(self.realms.len())),
+            None => quota,
+        }
+    }
+
+    /// Returns the active reaction catalog indexed by reaction ids.
+    pub fn reactions(&self) -> BTreeMap<u16, Reaction> {
+        let catalog = if self.reaction_catalog.is_empty() {
+            default_reactions()
+        } else {
+            self.reaction_catalog.clone()
+        };
+        catalog
+            .into_iter()
+            .map(|mut reaction| {
+                reaction.cost = reaction.cost();
+                (reaction.id, reaction)
+            })
+            .collect()
+    }
+
+    pub fn react(
+        &mut self,
+        principal: Principal,
+        post_id: PostId,
+        reaction: u16,
+        time: u64,
+    ) -> Result<(), String> {
+        let catalog = self.reactions();
+        let (delta, cost): (i64, Cycles) = match catalog.get(&reaction) {
+            Some(reaction) => (reaction.karma, reaction.cost),
+            _ => return Err("unknown reaction".into()),
+        };
+        let user = self
+            .principal_to_user(principal)
+            .ok_or("no user for principal found")?
+            .clone();
+        let post = Post::get(self, &post_id).ok_or("post not found")?.clone();
+        if post.hidden() {
+            return Err("post deleted".into());
+        }
+        if user.banned(time) {
+            return Err("banned users can't react".into());
+        }
+        if post.user == user.id {
+            return Err("reactions to own posts are forbidden".into());
+        }
+        let previous_reaction = post
+            .reactions
+            .iter()
+            .find_map(|(id, users)| users.contains(&user.id).then_some(*id));
+        // reacting with the same reaction again removes it
+        let removal = previous_reaction == Some(reaction);
+        if !user.trusted() && delta < 0 && !removal {
+            return Err("bootcamp users can't downvote".into());
+        }
+        let rate_limit = rate_limits::limit(&user, rate_limits::Action::Reaction);
+        self.rate_limits
+            .check(user.id, rate_limits::Action::Reaction, rate_limit, time)?;
+
+        // the new charge is validated before the previous reaction is reverted, so that a failing
+        // charge can't leave the post without any reaction of the user
+        let affordable = if user.trusted() && delta < 0 {
+            cost.min(user.cycles()) > 0
+        } else {
+            cost + CONFIG.reaction_fee <= user.cycles()
+        };
+        if !removal && !affordable {
+            return Err("not enough cycles".into());
+        }
+
+        let user_id = user.id;
+        if let Some(previous_reaction) = previous_reaction {
+            self.revert_reaction(user_id, &post, previous_reaction)?;
+            if let Some(user) = self.users.get_mut(&user_id) {
+                user.forget_reaction(post_id);
+            }
+            if removal {
+                self.rate_limits
+                    .record(user_id, rate_limits::Action::Reaction, time);
+                return Ok(());
+            }
+        }
+        let user = self.users.get(&user_id).expect("no user found").clone();
+
+        let log = format!("reaction to post {}", post_id);
+        // cycles paid by the reactor without the fee, cycles burned from the author and the karma
+        // change of the author
+        let charges;
+        // If the user is untrusted, they can only upvote, but this does not affect author's karma.
+        if !user.trusted() {
+            self.charge(user.id, cost + CONFIG.reaction_fee, log)
+                .expect("coudln't charge user");
+            charges = (cost, 0, 0);
+        }
+        // If the user is trusted, they pay for upvotes with cycles converted to the author's karma,
+        // but burn their own cycles on down votes + cycles and karma of the author
+        else if delta < 0 {
+            let reactor_cycles = cost.min(user.cycles());
+            let author_cycles = delta
+                .unsigned_abs()
+                .min(self.users.get(&post.user).expect("no user found").cycles());
+            self.charge(user.id, reactor_cycles, log.clone())?;
+            self.users
+                .get_mut(&post.user)
+                .expect("user not found")
+                .change_karma(delta, log.clone());
+            if author_cycles > 0 {
+                self.charge(post.user, author_cycles, log)
+                    .expect("couldn't charge user");
+            }
+            charges = (reactor_cycles, author_cycles, delta);
+        } else {
+            self.users
+                .get_mut(&user.id)
+                .expect("no user found")
+                .change_cycles(cost + CONFIG.reaction_fee, CyclesDelta::Minus, &log)?;
+            // the part of the cost exceeding the author's karma gain is burned
+            self.burned_cycles += (cost + CONFIG.reaction_fee) as i64 - delta;
+            self.users
+                .get_mut(&post.user)
+                .expect("no user found")
+                .change_karma(delta, log);
+            post.make_hot(&mut self.hot, self.users.len(), user.id, &catalog);
+            charges = (cost, 0, delta);
+        }
+
+        self.rate_limits
+            .record(user.id, rate_limits::Action::Reaction, time);
+        let reactor = self
+            .principal_to_user_mut(principal)
+            .expect("no user for principal found");
+        reactor.last_activity = time;
+        reactor.record_reaction(post_id, reaction);
+        self.users
+            .get_mut(&post.user)
+            .expect("no user found")
+            .engagement
+            .reactions_received += 1;
+        // Reactions are only reported in the digest mode, where they can't flood the inbox.
+        if let Some(author) = self
+            .users
+            .get_mut(&post.user)
+            .filter(|author| author.digest_mode)
+        {
+            author.notify_about_post_event(
+                NotificationType::Reaction,
+                format!("@{} reacted to your post", user.name),
+                post_id,
+                post_id,
+                |n| format!("`{}` people reacted to your post", n),
+            );
+        }
+        let (reactor_cycles, author_cycles, author_karma) = charges;
+        let charge = ReactionCharge {
+            reactor_cycles,
+            author_cycles,
+            author_karma,
+            epoch: Some(self.minting_epoch),
+        };
+        Post::mutate(self, &post_id, |post| {
+            post.reactions.entry(reaction).or_default().insert(user_id);
+            post.reaction_charges.insert(user_id, charge);
+            Ok(())
+        })
+    }
+
+    // Removes the user's reaction from the post and reverts its cycle and karma changes except
+    // for the fee. Reactions from previous minting epochs, whose karma was already collected, and
+    // reactions created before the charges were recorded are removed without refunds.
+    fn revert_reaction(
+        &mut self,
+        user_id: UserId,
+        post: &Post,
+        reaction: u16,
+    ) -> Result<(), String> {
+        let log = format!("reaction to post {} removed", post.id);
+        if let Some(ReactionCharge {
+            reactor_cycles,
+            author_cycles,
+            author_karma,
+            ..
+        }) = post
+            .reaction_charges
+            .get(&user_id)
+            .copied()
+            .filter(|charge| charge.epoch == Some(self.minting_epoch))
+        {
+            if reactor_cycles > 0 {
+                self.users
+                    .get_mut(&user_id)
+                    .expect("no user found")
+                    .change_cycles(reactor_cycles, CyclesDelta::Plus, &log)?;
+            }
+            let author = self.users.get_mut(&post.user).expect("no user found");
+            if author_cycles > 0 {
+                author.change_cycles(author_cycles, CyclesDelta::Plus, &log)?;
+            }
+            if author_karma != 0 {
+                author.change_karma(-author_karma, &log);
+            }
+            // cycles of upvotes of trusted users were converted to the author's karma
+            if author_karma <= 0 {
+                self.burned_cycles -= (reactor_cycles + author_cycles) as i64;
+            } else {
+                self.burned_cycles -= reactor_cycles as i64 - author_karma;
+            }
+        }
+        if let Some(author) = self.users.get_mut(&post.user) {
+            author.engagement.reactions_received =
+                author.engagement.reactions_received.saturating_sub(1);
+        }
+        Post::mutate(self, &post.id, |post| {
+            if let Some(users) = post.reactions.get_mut(&reaction) {
+                users.remove(&user_id);
+                if users.is_empty() {
+                    post.reactions.remove(&reaction);
+                }
+            }
+            post.reaction_charges.remove(&user_id);
+            Ok(())
+        })
+    }
+
+    /// Enables or disables notifications about new posts matching the followed feed of the user.
+    /// Returns true if the notifications were enabled.
+    pub fn toggle_feed_notifications(
+        &mut self,
+        principal: Principal,
+        tags: Vec<String>,
+    ) -> Result<bool, String> {
+        let user = self
+            .principal_to_user_mut(principal)
+            .ok_or("no user found")?;
+        let enabled = user.toggle_feed_notifications(tags)?;
+        let user_id = user.id;
+        let tags = user
+            .notified_feeds
+            .iter()
+            .flatten()
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        self.feed_subscribers.retain(|tag, ids| {
+            if !tags.contains(tag) {
+                ids.remove(&user_id);
+            }
+            !ids.is_empty()
+        });
+        for tag in tags {
+            self.feed_subscribers
+                .entry(tag)
+                .or_default()
+                .insert(user_id);
+        }
+        Ok(enabled)
+    }
+
+    pub fn toggle_following_user(&mut self, principal: Principal, followee_id: UserId) -> bool {
+        let (added, (id, name)) = {
+            let user = match self.principal_to_user_mut(principal) {
+                Some(user) => user,
+                _ => return false,
+            };
+            (
+                if user.followees.contains(&followee_id) {
+                    user.followees.remove(&followee_id);
+                    false
+                } else {
+                    user.followees.insert(followee_id);
+                    true
+                },
+                (user.id, user.name.clone()),
+            )
+        };
+        let followee = self.users.get_mut(&followee_id).expect("User not found");
+        if added {
+            followee.followers.insert(id);
+            followee.notify(format!("@{} followed you", name));
+        } else {
+            followee.followers.remove(&id);
+        }
+        added
+    }
+}
+
+// Checks if any feed represents the superset for the given tag set.
+// The `strict` option requires the sets to be equal.
+fn covered_by_feeds(
+    feeds: &[BTreeSet<String>],
+    tags: &BTreeSet<String>,
+    strict: bool,
+) -> Option<usize> {
+    for (i, feed) in feeds.iter().enumerate() {
+        if strict && tags.len() != feed.len() {
+            continue;
+        }
+        if feed.iter().all(|tag| tags.contains(tag)) {
+            return Some(i);
+        }
+    }
+    None
+}
+
+// Projects the time until the balance runs out using the average burn rate between the balance
+// samples. Top-ups are not counted as negative burn.
+fn time_to_empty(samples: &VecDeque<(u64, u64)>) -> Option<u64> {
+    let (first, last) = (samples.front()?, samples.back()?);
+    let burned: u64 = samples
+        .iter()
+        .zip(samples.iter().skip(1))
+        .map(|((_, prev), (_, next))| prev.saturating_sub(*next))
+        .sum();
+    let elapsed = last.0.saturating_sub(first.0);
+    if burned == 0 || elapsed == 0 {
+        return None;
+    }
+    Some((last.1 as u128 * elapsed as u128 / burned as u128) as u64)
+}
+
+fn parse_icp_amount(amount: &str) -> Result<Tokens, String> {
+    let parse = |s: &str| {
+        s.parse::<u64>()
+            .map_err(|err| format!("Couldn't parse as u64: {:?}", err))
+    };
+    match &amount.split('.').collect::<Vec<_>>().as_slice() {
+        [icpts] => Ok(Tokens::from_e8s(parse(icpts)? * 10_u64.pow(8))),
+        [icpts, e8s] => {
+            let mut e8s = e8s.to_string();
+            while e8s.len() < 8 {
+                e8s.push('0');
+            }
+            let e8s = &e8s[..8];
+            Ok(Tokens::from_e8s(
+                parse(icpts)? * 10_u64.pow(8) + parse(e8s)?,
+            ))
+        }
+        _ => Err(format!("Can't parse amount {}", amount)),
+    }
+}
+
+// Returns the protocol fee of a tip. A non-zero fee is never smaller than the ledger fee, so
+// that its transfer to the treasury is worth the costs.
+fn tip_fee(e8s: u64, percentage: u64) -> u64 {
+    if percentage == 0 {
+        return 0;
+    }
+    (e8s * percentage / 100).max(invoices::fee())
+}
+
+// Splits the tipped amount into the e8s sent to the author and the protocol fee sent to the
+// treasury.
+fn split_tip(amount: &str) -> Result<(u64, u64), String> {
+    let e8s = parse_icp_amount(amount)?.e8s();
+    if e8s < CONFIG.min_tip_e8s {
+        return Err(format!(
+            "tips must be at least {} ICP",
+            e8s_to_icp(CONFIG.min_tip_e8s)
+        ));
+    }
+    Ok((e8s, tip_fee(e8s, CONFIG.tip_fee_percentage)))
+}
+
+// Collapses all whitespace of a tip message into single spaces and drops markdown code and
+// link markers, so that the message can be quoted in a notification.
+fn sanitize_tip_message(message: &str) -> Result<String, String> {
+    if message.chars().count() > CONFIG.max_tip_message_length {
+        return Err(format!(
+            "tip message is longer than {} characters",
+            CONFIG.max_tip_message_length
+        ));
+    }
+    Ok(message
+        .split_whitespace()
+        .map(|word| {
+            word.chars()
+                .filter(|c| !c.is_control() && !matches!(c, '`' | '[' | ']'))
+                .collect::<String>()
+        })
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+pub fn id() -> Principal {
+    #[cfg(test)]
+    return Principal::anonymous();
+    #[cfg(not(test))]
+    ic_cdk::id()
+}
+
+pub fn time() -> u64 {
+    #[cfg(test)]
+    return CONFIG.trusted_user_min_age_weeks * WEEK + 1;
+    #[cfg(not(test))]
+    api::time()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::STATE;
+    use post::{ExportedPost, Post};
+
+    pub fn pr(n: u8) -> Principal {
+        let v = vec![0, n];
+        Principal::from_slice(&v)
+    }
+
+    pub fn create_user(state: &mut State, p: Principal) -> UserId {
+        create_user_with_params(state, p, &p.to_string().replace('-', ""), true, 1000)
+    }
+
+    pub fn create_user_with_cycles(state: &mut State, p: Principal, cycles: Cycles) -> UserId {
+        create_user_with_params(state, p, &p.to_string().replace('-', ""), true, cycles)
+    }
+
+    pub fn create_untrusted_user(state: &mut State, p: Principal) -> UserId {
+        create_user_with_params(state, p, &p.to_string().replace('-', ""), false, 1000)
+    }
+
+    fn create_user_with_params(
+        state: &mut State,
+        p: Principal,
+        name: &str,
+        trusted: bool,
+        cycles: Cycles,
+    ) -> UserId {
+        // test users are created at timestamp 0 and act right away, so the cooldown of new users
+        // is waived unless the test sets it
+        state.new_user_cooldown_hours.get_or_insert(0);
+        let id = state.new_user(p, 0, name.to_string());
+        let u = state.users.get_mut(&id).unwrap();
+        u.change_cycles(cycles, CyclesDelta::Plus, "").unwrap();
+        if trusted {
+            u.change_karma(CONFIG.trusted_user_min_karma, "");
+            u.apply_rewards();
+        }
+        id
+    }
+
+    #[test]
+    fn test_poll_conclusion() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+
+            // create users each having trusted_user_min_karma + i*10, e.g.
+            // user 1: 35, user 2: 45, user 3: 55, etc...
+            let mut eligigble = HashMap::default();
+            for i in 1..11 {
+                let p = pr(i);
+                let id = create_user(state, p);
+                let user = state.users.get_mut(&id).unwrap();
+                // we create the same amount of new and hard karma so that we have both karma and
+                // balances after minting
+                user.change_karma(i as Karma * 10, "test");
+                user.apply_rewards();
+                user.change_karma(i as Karma * 10, "test");
+                assert_eq!(
+                    user.karma(),
+                    i as Karma * 10 + CONFIG.trusted_user_min_karma
+                );
+                assert!(user.trusted());
+                eligigble.insert(id, user.karma_to_reward());
+            }
+
+            // mint tokens
+            state.mint(eligigble);
+            assert_eq!(state.ledger.len(), 10);
+
+            let post_id = Post::create(
+                state,
+                "Test".to_string(),
+                &[],
+                pr(1),
+                0,
+                None,
+                None,
+                Some(Extension::Poll(Poll {
+                    options: vec!["A".into(), "B".into(), "C".into()],
+                    deadline: 72,
+                    ..Default::default()
+                })),
+            )
+            .unwrap();
+
+            let now = Post::mutate(state, &post_id, |post| {
+                let mut votes = BTreeMap::new();
+                votes.insert(0, vec![1, 2, 3].into_iter().collect());
+                votes.insert(1, vec![4, 5, 6].into_iter().collect());
+                votes.insert(2, vec![7, 8, 9].into_iter().collect());
+                if let Some(Extension::Poll(poll)) = post.extension.as_mut() {
+                    poll.votes = votes;
+                }
+                Ok(post.timestamp())
+            })
+            .unwrap();
+            assert_eq!(state.pending_polls.len(), 1);
+            state.conclude_polls(now + 24 * HOUR);
+            assert_eq!(state.pending_polls.len(), 1);
+            state.conclude_polls(now + 3 * 24 * HOUR);
+            assert_eq!(state.pending_polls.len(), 0);
+            if let Some(Extension::Poll(poll)) =
+                Post::get(state, &post_id).unwrap().extension.as_ref()
+            {
+                // Here we can see that by karma the difference is way smaller becasue values are
+                // normalized by the square root.
+                assert_eq!(*poll.weighted_by_karma.get(&0).unwrap(), 21);
+                assert_eq!(*poll.weighted_by_karma.get(&1).unwrap(), 26);
+                assert_eq!(*poll.weighted_by_karma.get(&2).unwrap(), 31);
+                assert_eq!(*poll.weighted_by_tokens.get(&0).unwrap(), 9000);
+                assert_eq!(*poll.weighted_by_tokens.get(&1).unwrap(), 18000);
+                assert_eq!(*poll.weighted_by_tokens.get(&2).unwrap(), 27000);
+            } else {
+                panic!("should be a poll")
+            }
+        });
+    }
+
+    #[actix_rt::test]
+    async fn test_principal_change() {
+        let u_id = STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+
+            let mut eligigble = HashMap::default();
+            for i in 1..3 {
+                let p = pr(i);
+                let id = create_user(state, p);
+                let user = state.users.get_mut(&id).unwrap();
+                user.change_karma(i as Karma * 111, "test");
+                assert_eq!(user.karma(), CONFIG.trusted_user_min_karma);
+                assert!(user.trusted());
+                eligigble.insert(id, user.karma_to_reward());
+            }
+
+            // mint tokens
+            state.mint(eligigble);
+            assert_eq!(state.ledger.len(), 2);
+            assert_eq!(*state.balances.get(&account(pr(1))).unwrap(), 11100);
+
+            let u_id = state.principal_to_user(pr(1)).unwrap().id;
+            u_id
+        });
+        let new_principal_str: String =
+            "yh4uw-lqajx-4dxcu-rwe6s-kgfyk-6dicz-yisbt-pjg7v-to2u5-morox-hae".into();
+        assert!(State::change_principal(pr(1), new_principal_str.clone())
+            .await
+            .is_ok());
+
+        mutate(|state| {
+            let principal = Principal::from_text(new_principal_str).unwrap();
+            assert_eq!(state.principal_to_user(principal).unwrap().id, u_id);
+            assert!(state.balances.get(&account(pr(1))).is_none());
+            assert_eq!(
+                *state.balances.get(&account(principal)).unwrap(),
+                11100 - CONFIG.transaction_fee
+            );
+            let user = state.users.get(&u_id).unwrap();
+            assert_eq!(user.principal, principal);
+            assert_eq!(
+                user.account,
+                AccountIdentifier::new(&id(), &principal_to_subaccount(&principal)).to_string()
+            );
+        })
+    }
+
+    #[test]
+    fn test_mint_cap() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let cap = CONFIG.total_supply * CONFIG.max_minted_per_window_percentage / 100;
+            assert_eq!(state.mint_headroom(time()), cap);
+
+            let mut rewards = HashMap::default();
+            rewards.insert(create_user(state, pr(1)), 20);
+            rewards.insert(create_user(state, pr(2)), 60);
+
+            // only 8 of 80 tokens can be minted, so all rewards are pro-rated
+            state.minted_in_window = (time() - DAY, cap - 800);
+            state.mint(rewards.clone());
+            assert_eq!(*state.balances.get(&account(pr(1))).unwrap(), 200);
+            assert_eq!(*state.balances.get(&account(pr(2))).unwrap(), 600);
+            assert_eq!(state.mint_headroom(time()), 0);
+
+            // nothing is minted until the window ends
+            state.mint(rewards);
+            assert_eq!(*state.balances.get(&account(pr(1))).unwrap(), 200);
+            let next_window = time() - DAY + CONFIG.mint_window_days * DAY;
+            assert_eq!(state.mint_headroom(next_window), cap);
+        })
+    }
+
+    #[test]
+    fn test_realm_change() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            state.realms.insert("TEST".into(), Realm::default());
+            state.realms.insert("TEST2".into(), Realm::default());
+
+            create_user(state, pr(0));
+            assert_eq!(
+                state.toggle_realm_membership(pr(0), "TEST".into()),
+                Ok(true)
+            );
+            assert_eq!(
+                state
+                    .users
+                    .values()
+                    .filter(|user| user.realms.contains(&"TEST".to_string()))
+                    .count(),
+                1
+            );
+
+            let post_id = Post::create(
+                state,
+                "Root".to_string(),
+                &[],
+                pr(0),
+                0,
+                None,
+                Some("TEST".into()),
+                None,
+            )
+            .unwrap();
+
+            let comment_1_id = Post::create(
+                state,
+                "Comment 1".to_string(),
+                &[],
+                pr(0),
+                0,
+                Some(post_id),
+                Some("TEST".into()),
+                None,
+            )
+            .unwrap();
+
+            Post::create(
+                state,
+                "Comment 2".to_string(),
+                &[],
+                pr(0),
+                0,
+                Some(comment_1_id),
+                Some("TEST".into()),
+                None,
+            )
+            .unwrap();
+
+            assert_eq!(realm_posts(state, "TEST").len(), 3);
+            assert_eq!(realm_posts(state, "TEST2").len(), 0);
+
+            crate::post::change_realm(state, post_id, Some("TEST2".into()));
+
+            assert_eq!(realm_posts(state, "TEST").len(), 0);
+            assert_eq!(realm_posts(state, "TEST2").len(), 3);
+        });
+    }
+
+    fn realm_posts(state: &State, name: &str) -> Vec<PostId> {
+        state
+            .last_posts(None, true)
+            .filter(|post| post.realm.as_ref() == Some(&name.to_string()))
+            .map(|post| post.id)
+            .collect::<Vec<_>>()
     }
 
-    fn archive_cold_data(&mut self) -> Result<(), String> {
-        let max_posts_in_heap = 20_000;
-        archive_cold_posts(self, max_posts_in_heap)
-    }
+    #[test]
+    fn test_post_deletion() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
 
-    async fn handle_nns_proposals(now: u64) {
-        // Vote on proposals if pending ones exist
-        for (proposal_id, post_id) in read(|state| state.pending_nns_proposals.clone()) {
-            if let Some(Extension::Poll(poll)) = read(|state| {
-                Post::get(state, &post_id).and_then(|post| post.extension.as_ref().cloned())
-            }) {
-                // The poll is still pending.
-                if read(|state| state.pending_polls.contains(&post_id)) {
-                    continue;
-                }
+            let id = create_user(state, pr(0));
+            let user = state.users.get_mut(&id).unwrap();
+            assert_eq!(user.karma_to_reward(), 0);
+            let upvoter_id = create_user(state, pr(1));
+            let user = state.users.get_mut(&upvoter_id).unwrap();
+            let upvoter_cycles = user.cycles();
+            user.change_karma(1000, "test");
+            assert!(user.trusted());
+            let uid = create_user(state, pr(2));
+            create_user(state, pr(3));
+            state
+                .users
+                .get_mut(&uid)
+                .unwrap()
+                .change_karma(1000, "test");
 
-                let adopted = poll.weighted_by_karma.get(&0).copied().unwrap_or_default();
-                let rejected = poll.weighted_by_karma.get(&1).copied().unwrap_or_default();
-                if let Err(err) = canisters::vote_on_nns_proposal(
-                    proposal_id,
-                    if adopted > rejected {
-                        NNSVote::Adopt
-                    } else {
-                        NNSVote::Reject
-                    },
-                )
-                .await
-                {
-                    mutate(|state| {
-                        state.logger.error(format!(
-                            "couldn't vote on NNS proposal {}: {}",
-                            proposal_id, err
-                        ))
-                    });
-                };
-            }
-            mutate(|state| state.pending_nns_proposals.remove(&post_id));
-        }
+            let post_id =
+                Post::create(state, "Test".to_string(), &[], pr(0), 0, None, None, None).unwrap();
 
-        // fetch new proposals
-        let last_known_proposal_id = read(|state| state.last_nns_proposal);
-        let proposals = match canisters::fetch_proposals().await {
-            Ok(value) => value,
-            Err(err) => {
-                mutate(|state| {
-                    state
-                        .logger
-                        .error(format!("couldn't fetch proposals: {}", err))
-                });
-                Default::default()
+            // Create 2 comments
+            let mut comment_id = 0;
+            for i in 1..=2 {
+                comment_id = Post::create(
+                    state,
+                    "Comment".to_string(),
+                    &[],
+                    pr(i),
+                    0,
+                    Some(post_id),
+                    None,
+                    None,
+                )
+                .unwrap();
             }
-        };
-
-        for proposal in proposals
-            .into_iter()
-            .filter(|proposal| proposal.id > last_known_proposal_id)
-        {
-            // Vote only on proposals with topics network economics, governance, SNS & replica-management.
-            if [3, 4, 13, 14].contains(&proposal.topic) {
-                let post = format!(
-                    "# #NNS-Proposal [{0}](https://dashboard.internetcomputer.org/proposal/{0})\n## {1}\n",
-                    proposal.id, proposal.title,
-                ) + &format!(
-                    "Proposer: [{0}](https://dashboard.internetcomputer.org/neuron/{0})\n\n\n\n{1}",
-                    proposal.proposer, proposal.summary
-                );
 
-                match mutate(|state| {
-                    state.last_nns_proposal = state.last_nns_proposal.max(proposal.id);
-                    Post::create(
-                        state,
-                        post,
-                        Default::default(),
-                        id(),
-                        now,
-                        None,
-                        Some("NNS-GOV".into()),
-                        Some(Extension::Poll(Poll {
-                            deadline: 72,
-                            options: vec!["ACCEPT".into(), "REJECT".into()],
-                            ..Default::default()
-                        })),
-                    )
-                }) {
-                    Ok(post_id) => {
-                        mutate(|state| state.pending_nns_proposals.insert(proposal.id, post_id));
-                        continue;
-                    }
-                    Err(err) => {
-                        mutate(|state| {
-                            state
-                                .logger
-                                .error(format!("couldn't create a NNS proposal post: {:?}", err))
-                        });
-                    }
-                };
+            let leaf = Post::create(
+                state,
+                "Leaf".to_string(),
+                &[],
+                pr(0),
+                0,
+                Some(comment_id),
+                None,
+                None,
+            )
+            .unwrap();
 
-                if let Err(err) =
-                    canisters::vote_on_nns_proposal(proposal.id, NNSVote::Reject).await
-                {
-                    mutate(|state| {
-                        state.logger.error(format!(
-                            "couldn't vote on NNS proposal {}: {}",
-                            proposal.id, err
-                        ))
-                    });
-                };
-            }
-        }
-    }
+            assert_eq!(Post::get(state, &post_id).unwrap().tree_size, 3);
+            assert_eq!(Post::get(state, &comment_id).unwrap().tree_size, 1);
+            assert_eq!(Post::get(state, &leaf).unwrap().tree_size, 0);
 
-    async fn hourly_chores(now: u64) {
-        mutate(|state| {
-            // Automatically dump the heap to the stable memory. This should be the first
-            // opearation to avoid blocking of the backup by a panic in other parts of the routine.
-            memory::heap_to_stable(state);
+            // React from both users
+            assert!(state.react(pr(1), post_id, 100, 0).is_ok());
+            assert!(state.react(pr(2), post_id, 50, 0).is_ok());
 
-            state.conclude_polls(now)
-        });
+            assert_eq!(
+                state.users.get(&id).unwrap().karma_to_reward(),
+                10 + 5 + 2 * CONFIG.response_reward
+            );
 
-        State::top_up().await;
+            assert_eq!(
+                state.users.get_mut(&upvoter_id).unwrap().cycles(),
+                // reward + fee + post creation
+                upvoter_cycles - 10 - 1 - 2
+            );
 
-        State::handle_nns_proposals(now).await;
-    }
+            let versions = vec!["a".into(), "b".into()];
+            assert_eq!(
+                state.delete_post(pr(1), post_id, versions.clone(), 0),
+                Err("not authorized".into())
+            );
 
-    pub async fn chores(now: u64) {
-        // This should always be the first operation executed in the chores routine so
-        // that the upgrades are never blocked by a panic in any other routine.
-        if mutate(|state| {
-            state.execute_pending_upgrade(false) || state.execute_pending_emergency_upgrade(false)
-        }) {
-            return;
-        }
+            state
+                .charge(id, state.users.get(&id).unwrap().cycles(), "")
+                .unwrap();
+            assert_eq!(
+                state.delete_post(pr(0), post_id, versions.clone(), 0),
+                Err("not enough cycles (this post requires 47 cycles to be deleted)".into())
+            );
 
-        let (last_hourly_chores, last_daily_chores, last_weekly_chores) = read(|state| {
-            (
-                state.last_hourly_chores,
-                state.last_daily_chores,
-                state.last_weekly_chores,
-            )
-        });
-        if last_weekly_chores + WEEK < now {
-            State::weekly_chores(now).await;
-            mutate(|state| state.last_weekly_chores += WEEK);
-        }
-        if last_daily_chores + DAY < now {
-            State::daily_chores(now);
-            mutate(|state| state.last_daily_chores += DAY);
-        }
-        if last_hourly_chores + HOUR < now {
-            State::hourly_chores(now).await;
-            mutate(|state| state.last_hourly_chores += HOUR);
-        }
-    }
+            state
+                .users
+                .get_mut(&id)
+                .unwrap()
+                .change_cycles(1000, CyclesDelta::Plus, "")
+                .unwrap();
 
-    async fn weekly_chores(now: u64) {
-        mutate(|state| state.clean_up(now));
+            assert_eq!(&Post::get(state, &0).unwrap().body, "Test");
+            assert_eq!(
+                state.delete_post(pr(0), post_id, versions.clone(), 0),
+                Ok(())
+            );
 
-        // We only mint and distribute if no open proposals exists
-        if read(|state| state.proposals.iter().all(|p| p.status != Status::Open)) {
-            let karma = match invoices::get_xdr_in_e8s().await {
-                Ok(e8s_for_one_xdr) => {
-                    let (karma, revenues) = mutate(|state| {
-                        (
-                            state.collect_new_karma(),
-                            state.collect_revenue(time(), e8s_for_one_xdr),
-                        )
-                    });
-                    State::distribute_icp(e8s_for_one_xdr, &karma, revenues).await;
-                    karma
-                }
-                Err(err) => {
-                    mutate(|state| {
-                        state
-                            .logger
-                            .error(format!("Couldn't fetch ICP/XDR rate: {:?}", err))
-                    });
-                    return;
-                }
-            };
-            mutate(|state| state.mint(karma));
-        } else {
-            mutate(|state| {
-                state
-                    .logger
-                    .info("Skipping minting & distributions due to open proposals")
-            });
-        }
-    }
+            // the post is hidden, but can be restored with all reactions and comments
+            assert_eq!(&Post::get(state, &0).unwrap().body, "Test");
+            assert!(state.last_posts(None, false).all(|post| post.id != post_id));
+            assert!(state.search_index.search("test").is_empty());
+            assert_eq!(
+                state.react(pr(1), post_id, 1, 0),
+                Err("post deleted".into())
+            );
+            assert_eq!(
+                state.restore_post(pr(1), post_id),
+                Err("not authorized".into())
+            );
+            assert_eq!(state.restore_post(pr(0), post_id), Ok(()));
+            assert_eq!(
+                state.restore_post(pr(0), post_id),
+                Err("post deletion can't be undone".into())
+            );
+            let post = Post::get(state, &post_id).unwrap();
+            assert_eq!(
+                post.reactions
+                    .values()
+                    .map(|users| users.len())
+                    .sum::<usize>(),
+                2
+            );
+            assert_eq!(post.children.len(), 2);
+            assert_eq!(state.last_posts(None, false).next().unwrap().id, post_id);
+            assert_eq!(state.search_index.search("test"), vec![post_id]);
 
-    fn clean_up(&mut self, now: u64) {
-        for user in self.users.values_mut() {
-            if user.active_within_weeks(now, 1) {
-                user.active_weeks += 1;
-            } else {
-                user.active_weeks = 0;
-            }
-            let inactive = !user.active_within_weeks(now, CONFIG.inactivity_duration_weeks);
-            if inactive || user.is_bot() {
-                user.clear_notifications(Vec::new())
-            }
-            if inactive && user.karma() > 0 {
-                user.change_karma(
-                    -(CONFIG.inactivity_penalty as Karma).min(user.karma()),
-                    "inactivity_penalty".to_string(),
-                );
-            }
-        }
-        let mut inactive_users = 0;
-        let mut cycles_total = 0;
-        let inactive_user_balance_threshold = CONFIG.inactivity_penalty * 4;
-        for (id, cycles) in self
-            .users
-            .values()
-            .filter(|user| {
-                !user.active_within_weeks(now, CONFIG.inactivity_duration_weeks)
-                    && user.cycles() > inactive_user_balance_threshold
-            })
-            .map(|u| (u.id, u.cycles()))
-            .collect::<Vec<_>>()
-        {
-            let costs = CONFIG
-                .inactivity_penalty
-                .min(cycles - inactive_user_balance_threshold);
-            if let Err(err) = self.charge(id, costs, "inactivity penalty".to_string()) {
-                self.logger
-                    .error(format!("Couldn't charge inactivity penalty: {:?}", err));
-            } else {
-                cycles_total += costs;
-                inactive_users += 1;
-            }
-        }
-        self.logger.info(format!(
-            "Charged `{}` inactive users with `{}` cycles.",
-            inactive_users, cycles_total
-        ));
+            // the deletion becomes permanent after the grace period
+            assert_eq!(
+                state.delete_post(pr(0), post_id, versions.clone(), 0),
+                Ok(())
+            );
+            let grace_period = CONFIG.post_deletion_grace_period_minutes * MINUTE;
+            assert!(state.posts_to_delete(grace_period - 1).is_empty());
+            assert_eq!(state.posts_to_delete(grace_period), vec![post_id]);
+            state.delete_pending_posts(grace_period);
+            assert!(state.pending_deletions.is_empty());
+            assert_eq!(Post::get(state, &0).unwrap().deletion_scheduled, None);
+            assert_eq!(
+                state.restore_post(pr(0), post_id),
+                Err("post deletion can't be undone".into())
+            );
+            assert_eq!(&Post::get(state, &0).unwrap().body, "");
+            assert_eq!(Post::get(state, &0).unwrap().hashes.len(), versions.len());
 
-        self.accounting.clean_up();
+            assert_eq!(
+                state.users.get(&upvoter_id).unwrap().cycles(),
+                // reward received back
+                upvoter_cycles - 10 - 1 - 2 + 10
+            );
+            assert_eq!(state.users.get(&id).unwrap().karma_to_reward(), 0);
+
+            assert_eq!(
+                state.react(pr(1), post_id, 1, 0),
+                Err("post deleted".into())
+            );
+        });
     }
 
-    fn recompute_stalwarts(&mut self, now: u64) {
-        let mut users = self.users.values_mut().collect::<Vec<_>>();
-        users.sort_unstable_by_key(|a| std::cmp::Reverse(a.karma()));
+    #[test]
+    fn test_new_user_cooldown() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            state.new_user_cooldown_hours = Some(24);
+            let id = create_user(state, pr(1));
 
-        let mut stalwart_seats = users.len() * CONFIG.stalwart_percentage / 100;
-        let mut left = Vec::new();
-        let mut joined = Vec::new();
-        for u in users {
-            if u.is_bot()
-                || !u.trusted()
-                || u.report.is_some()
-                || now.saturating_sub(u.timestamp)
-                    < WEEK * CONFIG.min_stalwart_account_age_weeks as u64
-            {
-                u.stalwart = false;
-                continue;
+            for i in 0..CONFIG.new_user_max_posts_per_hour as u64 {
+                assert!(
+                    Post::create(state, "Test".into(), &[], pr(1), i * MINUTE, None, None, None)
+                        .is_ok()
+                );
             }
-            match (
-                u.stalwart,
-                u.active_weeks >= CONFIG.min_stalwart_activity_weeks as u32,
-                u.karma() > CONFIG.proposal_rejection_penalty as Karma,
-                stalwart_seats,
-            ) {
-                // User is qualified but seats left or they lost karma
-                (true, true, true, 0) | (true, _, false, _) => {
-                    u.stalwart = false;
-                    left.push(format!("@{} (karma)", u.name));
-                }
-                // A user is qualified and is already a stalwart and seats available
-                (true, true, true, _) => {
-                    stalwart_seats = stalwart_seats.saturating_sub(1);
-                }
-                // A user is a stalwart but became inactive
-                (true, false, _, _) => {
-                    u.stalwart = false;
-                    left.push(format!("@{} (inactivity)", u.name));
-                }
-                // A user is not a stalwart, but qualified and there are seats left
-                (false, true, true, seats) if seats > 0 => {
-                    u.stalwart = true;
-                    joined.push(format!("@{}", u.name));
-                    stalwart_seats = stalwart_seats.saturating_sub(1);
-                    u.notify(format!(
-                        "Congratulations! You are a {} stalwart now!",
-                        CONFIG.name
-                    ));
-                }
-                _ => {}
-            };
-        }
-
-        if joined.is_empty() && left.is_empty() {
-            return;
-        }
+            assert_eq!(
+                Post::create(state, "Test".into(), &[], pr(1), 2 * MINUTE, None, None, None),
+                Err(format!(
+                    "not more than {} posts per hour are allowed for new users, the restriction lifts in 24 hours",
+                    CONFIG.new_user_max_posts_per_hour
+                ))
+            );
+            // the cooldown is over after 24 hours
+            assert!(Post::create(state, "Test".into(), &[], pr(1), DAY, None, None, None).is_ok());
 
-        self.logger.info(format!(
-            "Stalwart election ⚔️: {} joined; {} have left; `{}` seats vacant.",
-            if joined.is_empty() {
-                "no new users".to_string()
-            } else {
-                joined.join(", ")
-            },
-            if left.is_empty() {
-                "no users".to_string()
-            } else {
-                left.join(", ")
-            },
-            stalwart_seats
-        ));
-    }
+            let create_realm = |state: &mut State| {
+                state.create_realm(
+                    pr(1),
+                    "TEST".into(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    vec![id],
+                )
+            };
+            state.users.get_mut(&id).unwrap().timestamp = time() - HOUR;
+            assert_eq!(
+                create_realm(state),
+                Err("new users can't create realms, the restriction lifts in 23 hours".into())
+            );
 
-    // Check if user has some unclaimed e8s in the Treasury and transfers them to user's account.
-    async fn claim_user_icp(principal: Principal) -> Result<(), String> {
-        let user = match read(|state| state.principal_to_user(principal).cloned()) {
-            Some(user) => user,
-            None => return Ok(()),
-        };
-        if user.treasury_e8s > 0 {
-            invoices::transfer(
-                parse_account(&user.account)?,
-                Tokens::from_e8s(user.treasury_e8s),
-                Memo(777),
-                Some(USER_ICP_SUBACCOUNT),
-            )
-            .await?;
-            mutate(|state| {
-                if let Some(user) = state.users.get_mut(&user.id) {
-                    user.treasury_e8s = 0
-                }
-            });
-        }
-        Ok(())
+            // invites of stalwarts waive the cooldown
+            let inviter_id = create_user(state, pr(2));
+            state.users.get_mut(&inviter_id).unwrap().stalwart = true;
+            state.users.get_mut(&id).unwrap().invited_by = Some(inviter_id);
+            assert_eq!(create_realm(state), Ok(()));
+        })
     }
 
-    pub async fn mint_cycles(principal: Principal, kilo_cycles: u64) -> Result<Invoice, String> {
-        State::claim_user_icp(principal).await?;
+    #[test]
+    fn test_realm_quota() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let id = create_user_with_cycles(state, pr(1), 10 * CONFIG.realm_cost);
+            let create_realm = |state: &mut State, name: String| {
+                state.create_realm(
+                    pr(1),
+                    name,
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    vec![id],
+                )
+            };
 
-        let invoice = match Invoices::outstanding(&principal, kilo_cycles).await {
-            Ok(val) => val,
-            Err(err) => {
-                if kilo_cycles == 0 {
-                    mutate(|state| {
-                        state
-                            .logger
-                            .error(&format!("couldn't generate invoice: {:?}", err))
-                    });
-                }
-                return Err(err);
+            assert_eq!(state.realm_quota(id), CONFIG.max_realms_per_user);
+            for i in 0..CONFIG.max_realms_per_user {
+                assert_eq!(create_realm(state, format!("REALM{}", i)), Ok(()));
             }
-        };
+            assert_eq!(state.realm_quota(id), 0);
+            let cycles = state.users.get(&id).unwrap().cycles();
+            assert_eq!(
+                create_realm(state, "ONEMORE".into()),
+                Err(format!(
+                    "users can't control more than {} realms",
+                    CONFIG.max_realms_per_user
+                ))
+            );
+            // nothing was charged for the rejected realm
+            assert_eq!(state.users.get(&id).unwrap().cycles(), cycles);
 
-        mutate(|state| {
-            if invoice.paid {
-                if let Some(user) = state.principal_to_user_mut(principal) {
-                    user.change_cycles(
-                        ((invoice.paid_e8s as f64 / invoice.e8s as f64)
-                            * CONFIG.native_cycles_per_xdr as f64)
-                            as Cycles,
-                        CyclesDelta::Plus,
-                        "top up with ICP".to_string(),
-                    )?;
-                    let user_name = user.name.clone();
-                    state.accounting.close(&principal);
-                    state.logger.info(format!(
-                        "@{} minted cycles for `{}` ICP 💰",
-                        user_name,
-                        e8s_to_icp(invoice.paid_e8s)
-                    ));
-                }
-            }
-            Ok(invoice)
+            // the quota can't be bypassed by listing other users as controllers
+            let other_id = create_user(state, pr(2));
+            assert_eq!(
+                state.create_realm(
+                    pr(1),
+                    "ONEMORE".into(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    vec![other_id],
+                ),
+                Err("the realm creator must be a controller".into())
+            );
+
+            // giving up the control of a realm frees the quota
+            state.realms.get_mut("REALM0").unwrap().controllers.clear();
+            assert_eq!(state.realm_quota(id), 1);
+            assert_eq!(create_realm(state, "ONEMORE".into()), Ok(()));
         })
     }
 
-    pub fn clear_notifications(&mut self, principal: Principal, ids: Vec<String>) {
-        if let Some(user) = self.principal_to_user_mut(principal) {
-            user.clear_notifications(ids)
-        }
-    }
+    #[test]
+    fn test_stalwart_status() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            // enough users for one stalwart seat
+            for i in 0..34 {
+                create_user(state, pr(i));
+            }
+            let user = state.principal_to_user_mut(pr(0)).unwrap();
+            user.change_karma(1000, "test");
+            user.apply_rewards();
+            assert_eq!(state.stalwart_status(pr(100), 0), None);
 
-    pub fn validate_username(&self, name: &str) -> Result<(), String> {
-        let name = name.to_lowercase();
-        if self
-            .users
-            .values()
-            .any(|user| user.name.to_lowercase() == name)
-        {
-            return Err("taken".into());
-        }
-        if name.len() < 2 || name.len() > 16 {
-            return Err("should be between 2 and 16 characters".into());
-        }
-        if !name
-            .chars()
-            .all(|c| char::is_ascii(&c) && char::is_alphanumeric(c))
-        {
-            return Err("should be a latin alpha-numeric string".into());
-        }
-        if name
-            .chars()
-            .next()
-            .map(|c| !char::is_ascii_alphabetic(&c))
-            .unwrap_or_default()
-        {
-            return Err("first character can't be a number".into());
-        }
-        if name.chars().all(|c| char::is_ascii_digit(&c)) {
-            return Err("should have at least one character".into());
-        }
-        if ["all", "stalwarts", "dao"].contains(&name.as_str()) {
-            return Err("reserved handle".into());
-        }
-        Ok(())
+            let unmet = |status: &StalwartStatus| {
+                status
+                    .requirements
+                    .iter()
+                    .filter(|requirement| !requirement.met)
+                    .map(|requirement| requirement.name)
+                    .collect::<Vec<_>>()
+            };
+            let status = state.stalwart_status(pr(0), 10 * WEEK).unwrap();
+            assert_eq!(unmet(&status), vec!["account age weeks", "active weeks"]);
+            assert_eq!(status.time_to_eligibility, Some(16 * WEEK));
+
+            // karma can't be earned by waiting
+            let status = state.stalwart_status(pr(1), 10 * WEEK).unwrap();
+            assert!(unmet(&status).contains(&"karma"));
+            assert!(unmet(&status).contains(&"karma rank"));
+            assert_eq!(status.time_to_eligibility, None);
+
+            let user = state.principal_to_user_mut(pr(0)).unwrap();
+            user.active_weeks = CONFIG.min_stalwart_activity_weeks as u32;
+            let status = state.stalwart_status(pr(0), 26 * WEEK).unwrap();
+            assert!(unmet(&status).is_empty());
+            assert_eq!(status.time_to_eligibility, Some(0));
+        })
     }
 
-    pub fn posts_by_tags(
-        &self,
-        realm: Option<String>,
-        tags: Vec<String>,
-        users: Vec<UserId>,
-        page: usize,
-    ) -> Vec<Post> {
-        let query: HashSet<_> = tags.into_iter().map(|tag| tag.to_lowercase()).collect();
-        self.last_posts(realm, true)
-            .filter(|post| {
-                (users.is_empty() || users.contains(&post.user))
-                    && post
-                        .tags
-                        .iter()
-                        .map(|tag| tag.to_lowercase())
-                        .collect::<HashSet<_>>()
-                        .is_superset(&query)
+    #[test]
+    fn test_reward_preview() {
+        let mut state = State::default();
+        let id = create_user(&mut state, pr(1));
+        assert_eq!(state.reward_preview(pr(2)), None);
+
+        let user = state.users.get_mut(&id).unwrap();
+        user.change_karma(30, "test");
+        let base = 10_u64.pow(CONFIG.token_decimals as u32);
+        assert_eq!(
+            state.reward_preview(pr(1)),
+            Some(RewardPreview {
+                karma_to_reward: 30,
+                minting_ratio: 1,
+                projected_tokens: 30 * base,
+                trusted: false,
+                eligible: true,
             })
-            .skip(page * CONFIG.feed_page_size)
-            .take(CONFIG.feed_page_size)
-            .cloned()
-            .collect()
+        );
+        assert_eq!(
+            state.tokens_to_mint(&state.karma_to_mint()).get(&id),
+            Some(&(30 * base))
+        );
+
+        // rewards of users with negative karma are not minted
+        let user = state.users.get_mut(&id).unwrap();
+        user.apply_rewards();
+        user.change_karma(-100, "test");
+        user.change_karma(10, "test");
+        let preview = state.reward_preview(pr(1)).unwrap();
+        assert!(!preview.eligible);
+        assert_eq!(preview.projected_tokens, 0);
     }
 
-    pub fn last_posts<'a>(
-        &'a self,
-        realm: Option<String>,
-        with_comments: bool,
-    ) -> Box<dyn Iterator<Item = &'a Post> + 'a> {
-        let iter = {
-            let last_id = self.next_post_id.saturating_sub(1);
-            Box::new((0..=last_id).rev())
-        }
-        .filter_map(move |i| Post::get(self, &i))
-        .filter(move |post| !post.is_deleted() && (with_comments || post.parent.is_none()));
-        match realm {
-            None => Box::new(iter),
-            id => Box::new(iter.filter(move |post| post.realm.as_ref() == id.as_ref())),
+    #[test]
+    fn test_emergency_release_status() {
+        let mut state = State::default();
+        assert!(state.emergency_release_status(pr(0), 0).is_none());
+
+        for i in 0..3 {
+            create_user(&mut state, pr(i));
+            state.balances.insert(account(pr(i)), 100);
         }
+        state.emergency_binary = vec![1, 2, 3];
+        state.emergency_votes.insert(pr(1), 100);
+
+        let status = state.emergency_release_status(pr(1), 0).unwrap();
+        assert_eq!(status.hash, state.emergency_binary_hash());
+        assert_eq!(status.votes, 100);
+        assert_eq!(
+            status.required,
+            300 * CONFIG.proposal_approval_threshold as u64 / 100
+        );
+        assert!(status.confirmed_by_caller);
+        assert!(
+            !state
+                .emergency_release_status(pr(0), 0)
+                .unwrap()
+                .confirmed_by_caller
+        );
     }
 
-    pub fn recent_tags(&self, realm: Option<String>, n: u64) -> Vec<(String, u64)> {
-        // normalized hashtag -> (user spelled hashtag, occurences)
-        let mut tags: HashMap<String, (String, u64)> = Default::default();
-        let mut tags_found = 0;
-        'OUTER: for post in self
-            .last_posts(realm, true)
-            .take_while(|post| !post.archived)
-        {
-            for tag in &post.tags {
-                let (_, counter) = tags.entry(tag.to_lowercase()).or_insert((tag.clone(), 0));
-                // We only count tags occurences on root posts, if they have comments or reactions
-                if post.parent.is_some() || post.reactions.is_empty() && post.children.is_empty() {
-                    continue;
-                }
-                *counter += 1;
-                if *counter == 2 {
-                    tags_found += 1;
-                }
-            }
-            if tags_found >= n {
-                break 'OUTER;
-            }
+    #[test]
+    fn test_set_emergency_release() {
+        let mut state = State::default();
+        for i in 0..2 {
+            let id = create_user(&mut state, pr(i));
+            state.users.get_mut(&id).unwrap().stalwart = i == 0;
         }
-        tags.into_iter()
-            .filter_map(|(_, (tag, count))| (count > 1).then_some((tag, count)))
-            .collect()
+
+        assert_eq!(
+            state.set_emergency_release(pr(1), vec![1], None),
+            Err("only stalwarts can set emergency releases".into())
+        );
+        assert_eq!(
+            state.set_emergency_release(pr(0), vec![], None),
+            Err("empty binary".into())
+        );
+        assert_eq!(state.set_emergency_release(pr(0), vec![1], None), Ok(()));
+        // no votes yet, so the binary can be replaced freely
+        assert_eq!(state.set_emergency_release(pr(0), vec![2], None), Ok(()));
+
+        state.emergency_votes.insert(pr(1), 100);
+        let hash = state.emergency_binary_hash();
+        let err: Result<(), String> = Err(
+            "the current binary has votes, please confirm its replacement with its hash".into(),
+        );
+        assert_eq!(state.set_emergency_release(pr(0), vec![3], None), err);
+        assert_eq!(
+            state.set_emergency_release(pr(0), vec![3], Some("wrong".into())),
+            err
+        );
+        assert_eq!(state.emergency_binary, vec![2]);
+        assert_eq!(
+            state.set_emergency_release(pr(0), vec![3], Some(hash)),
+            Ok(())
+        );
+        assert_eq!(state.emergency_binary, vec![3]);
+        assert!(state.emergency_votes.is_empty());
+        assert!(state
+            .logger
+            .events
+            .iter()
+            .any(|event| event.message.contains("discarding 1 votes")));
     }
 
-    /// Returns an iterator of posts from the root post to the post `id`.
-    pub fn thread(&self, id: PostId) -> Box<dyn Iterator<Item = PostId>> {
-        let mut result = Vec::new();
-        let mut curr = id;
-        while let Some(Post { id, parent, .. }) = Post::get(self, &curr) {
-            result.push(*id);
-            if let Some(parent_id) = parent {
-                curr = *parent_id
-            } else {
-                break;
-            }
+    #[test]
+    fn test_search_users() {
+        let mut state = State::default();
+        for (i, name, karma) in [
+            (0, "alice", 10),
+            (1, "Alfred", 50),
+            (2, "bob", 100),
+            (3, "al", 10),
+        ] {
+            let id = create_user_with_params(&mut state, pr(i), name, true, 1000);
+            let user = state.users.get_mut(&id).unwrap();
+            user.change_karma(karma - user.karma(), "test");
+            user.apply_rewards();
+            user.last_activity = i as u64;
         }
-        Box::new(result.into_iter().rev())
+        let handles = |state: &State, prefix, limit| {
+            state
+                .search_users(prefix, limit, 0)
+                .into_iter()
+                .map(|(_, name, _)| name)
+                .collect::<Vec<_>>()
+        };
+
+        // ordered by karma first and by the last activity second
+        assert_eq!(handles(&state, "AL", 10), vec!["Alfred", "al", "alice"]);
+        assert_eq!(handles(&state, "@al", 1), vec!["Alfred"]);
+        assert!(handles(&state, "", 10).is_empty());
+
+        state.users.get_mut(&1).unwrap().banned_until = Some(1);
+        state.users.get_mut(&3).unwrap().deleted_at = Some(0);
+        assert_eq!(handles(&state, "al", 10), vec!["alice"]);
     }
 
-    pub fn user(&self, handle: &str) -> Option<&User> {
-        handle
-            .parse::<u64>()
-            .ok()
-            .and_then(|id| self.users.get(&id))
-            .or_else(|| {
-                self.users
-                    .values()
-                    .find(|user| user.name.to_lowercase() == handle.to_lowercase())
+    #[test]
+    fn test_export_posts() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            for i in 0..2 {
+                create_user(state, pr(i));
+            }
+            assert!(state.export_posts(pr(5), 0).is_err());
+
+            let root = Post::create(state, "Root".into(), &[], pr(0), 0, None, None, None).unwrap();
+            Post::create(state, "Other".into(), &[], pr(1), 0, None, None, None).unwrap();
+            let comment =
+                Post::create(state, "Reply".into(), &[], pr(0), 0, Some(root), None, None).unwrap();
+            Post::mutate(state, &root, |post| {
+                post.delete(vec!["hash".into()], DeletedBy::Author);
+                Ok(())
             })
+            .unwrap();
+
+            let export = state.export_posts(pr(0), 0).unwrap();
+            assert_eq!(export.next_cursor, None);
+            assert_eq!(
+                export.posts,
+                vec![
+                    ExportedPost {
+                        id: root,
+                        parent: None,
+                        realm: None,
+                        timestamp: 0,
+                        body: "".into(),
+                        files: vec![],
+                        deleted: true,
+                    },
+                    ExportedPost {
+                        id: comment,
+                        parent: Some(root),
+                        realm: None,
+                        timestamp: 0,
+                        body: "Reply".into(),
+                        files: vec![],
+                        deleted: false,
+                    }
+                ]
+            );
+
+            let export = state.export_posts(pr(0), comment).unwrap();
+            assert_eq!(export.posts.len(), 1);
+            assert_eq!(export.next_cursor, None);
+            let export = state.export_posts(pr(1), 0).unwrap();
+            assert_eq!(export.posts.len(), 1);
+            assert_eq!(export.posts[0].body, "Other");
+        })
     }
 
-    pub async fn change_principal(
-        principal: Principal,
-        new_principal_str: String,
-    ) -> Result<(), String> {
-        #[allow(unused_variables)]
-        let account_identifier = mutate(|state| {
-            let new_principal =
-                Principal::from_text(new_principal_str).map_err(|e| e.to_string())?;
-            if state.principals.contains_key(&new_principal) {
-                return Err("principal already controls a user".to_string());
-            }
-            let user_id = state
-                .principals
-                .remove(&principal)
-                .ok_or("no principal found")?;
-            state.principals.insert(new_principal, user_id);
-            let user = state.users.get_mut(&user_id).expect("no user found");
-            user.principal = new_principal;
-            let account_identifier =
-                AccountIdentifier::new(&id(), &principal_to_subaccount(&new_principal));
-            user.account = account_identifier.to_string();
-            let accounts = state
-                .balances
-                .keys()
-                .filter(|acc| acc.owner == principal)
-                .cloned()
-                .collect::<Vec<_>>();
-            for acc in accounts {
-                crate::token::move_funds(state, &acc, account(new_principal))
-                    .expect("couldn't transfer token funds");
+    #[actix_rt::test]
+    async fn test_posts_since() {
+        let ids = STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            create_user(state, pr(0));
+            assert!(state.posts_since(None, 10).posts.is_empty());
+            assert_eq!(state.posts_since(None, 10).high_water_mark, None);
+
+            let mut ids = Vec::new();
+            for body in vec!["first", "second", "third"] {
+                ids.push(
+                    Post::create(state, body.into(), &[], pr(0), 0, None, None, None).unwrap(),
+                );
             }
-            Ok(account_identifier)
-        })?;
-        #[cfg(not(test))]
-        {
-            let balance = invoices::account_balance_of_principal(principal).await;
-            invoices::transfer(
-                account_identifier,
-                balance,
-                Memo(10101),
-                Some(principal_to_subaccount(&principal)),
+
+            let page = state.posts_since(None, 2);
+            assert_eq!(page.cursor, Some(1));
+            assert_eq!(page.high_water_mark, Some(2));
+            assert_eq!(
+                page.posts
+                    .iter()
+                    .map(|(event, post)| (post.id, *event))
+                    .collect::<Vec<_>>(),
+                vec![(ids[0], PostEvent::Created), (ids[1], PostEvent::Created)]
+            );
+            ids
+        });
+
+        assert_eq!(
+            Post::edit(
+                ids[0],
+                "first, edited".into(),
+                vec![],
+                "patch".into(),
+                None,
+                pr(0),
+                time(),
             )
-            .await?;
-        }
-        Ok(())
+            .await,
+            Ok(())
+        );
+
+        mutate(|state| {
+            assert_eq!(state.delete_post(pr(0), ids[1], vec![], time()), Ok(()));
+
+            // the cursor of the first page only skips the already synced creations
+            let page = state.posts_since(Some(1), 10);
+            assert_eq!(page.cursor, Some(4));
+            assert_eq!(page.high_water_mark, Some(4));
+            assert_eq!(
+                page.posts
+                    .iter()
+                    .map(|(event, post)| (post.id, *event))
+                    .collect::<Vec<_>>(),
+                vec![
+                    (ids[2], PostEvent::Created),
+                    (ids[0], PostEvent::Edited),
+                    (ids[1], PostEvent::Deleted)
+                ]
+            );
+            assert_eq!(page.posts[1].1.body, "first, edited");
+
+            // a full sync only returns the latest change of each post
+            assert_eq!(state.posts_since(None, 10).posts.len(), 3);
+
+            let page = state.posts_since(Some(4), 10);
+            assert!(page.posts.is_empty());
+            assert_eq!(page.cursor, Some(4));
+
+            state.restore_post(pr(0), ids[1]).unwrap();
+            let page = state.posts_since(Some(4), 10);
+            assert_eq!(page.posts.len(), 1);
+            assert_eq!(page.posts[0].0, PostEvent::Edited);
+            assert_eq!(page.posts[0].1.id, ids[1]);
+        })
+    }
+
+    #[test]
+    fn test_validate_username() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let id = create_user(state, pr(0));
+            state.users.get_mut(&id).unwrap().name = "lord".into();
+
+            assert_eq!(state.validate_username("newbie"), Ok(()));
+            assert_eq!(state.validate_username("Lord"), Err("taken".into()));
+            assert_eq!(
+                state.validate_username("x"),
+                Err("should be between 2 and 16 characters".into())
+            );
+            assert_eq!(
+                state.validate_username("I0rd"),
+                Err("too similar to @lord".into())
+            );
+            assert_eq!(
+                state.validate_username("Adm1n"),
+                Err("reserved handle".into())
+            );
+            assert_eq!(
+                state.validate_username("rnoderator"),
+                Err("reserved handle".into())
+            );
+
+            state.reserved_handles = Some(vec!["newbie".into()]);
+            assert_eq!(
+                state.validate_username("newbie"),
+                Err("reserved handle".into())
+            );
+            assert_eq!(
+                state.validate_username("admin"),
+                Err("reserved handle".into())
+            );
+            let reserved = state.handle_policy().reserved;
+            assert!(reserved.contains(&"newbie".to_string()));
+            assert!(reserved.contains(&"admin".to_string()));
+        })
+    }
+
+    #[test]
+    fn test_validate_realm_name() {
+        let mut state = State::default();
+        state.realms.insert("DAO_NEWS".into(), Realm::default());
+
+        assert_eq!(state.validate_realm_name("CRAFT"), Ok(()));
+        assert_eq!(
+            state.validate_realm_name("X"),
+            Err("realm name too short".into())
+        );
+        assert_eq!(
+            state.validate_realm_name("THIS_NAME_IS_TOO_LONG"),
+            Err("realm name too long".into())
+        );
+        assert_eq!(
+            state.validate_realm_name("CAFÉ"),
+            Err("realm name should be an alpha-numeric string".into())
+        );
+        assert_eq!(
+            state.validate_realm_name("2024"),
+            Err("realm name should have at least on character".into())
+        );
+        assert_eq!(
+            state.validate_realm_name("dao_news"),
+            Err("realm name taken".into())
+        );
+        assert_eq!(
+            state.validate_realm_name("DA0-NEWS"),
+            Err("too similar to realm DAO_NEWS".into())
+        );
+        assert_eq!(
+            state.validate_realm_name("SUPP0RT"),
+            Err("reserved realm name".into())
+        );
+
+        state.reserved_realm_names = Some(vec!["craft".into()]);
+        assert_eq!(
+            state.validate_realm_name("CRAFT"),
+            Err("reserved realm name".into())
+        );
+        assert_eq!(
+            state.validate_realm_name("SUPPORT"),
+            Err("reserved realm name".into())
+        );
+        let reserved = state.realm_name_policy().reserved;
+        assert!(reserved.contains(&"craft".to_string()));
+        assert!(reserved.contains(&"support".to_string()));
+    }
+
+    #[test]
+    fn test_change_username() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let id = create_user(state, pr(0));
+            create_user(state, pr(1));
+            let now = time();
+            state.users.get_mut(&id).unwrap().name = "alice".into();
+
+            assert_eq!(
+                state.change_username(pr(2), "bob".into(), now),
+                Err("no user found".into())
+            );
+            assert_eq!(
+                state.change_username(pr(0), "admin".into(), now),
+                Err("reserved handle".into())
+            );
+            assert_eq!(state.change_username(pr(0), "bob".into(), now), Ok(()));
+            let user = state.users.get(&id).unwrap();
+            assert_eq!(user.name, "bob");
+            assert_eq!(user.previous_names, vec![("alice".to_string(), now)]);
+
+            // the old handle still resolves to the user and can't be taken
+            assert_eq!(state.user("Alice").map(|user| user.id), Some(id));
+            assert_eq!(state.validate_username("alice"), Err("taken".into()));
+            assert_eq!(
+                state.change_username(pr(0), "carol".into(), now + DAY),
+                Err(format!(
+                    "handles can be changed only once in {} days",
+                    CONFIG.handle_change_cooldown_days
+                ))
+            );
+
+            // the old handle is released after the hold period
+            let renamed = now - CONFIG.old_handle_hold_days * DAY;
+            state.users.get_mut(&id).unwrap().previous_names = vec![("alice".into(), renamed)];
+            assert_eq!(state.user("alice").map(|user| user.id), None);
+            assert_eq!(state.validate_username("alice"), Ok(()));
+            assert_eq!(state.change_username(pr(0), "carol".into(), now), Ok(()));
+            assert_eq!(state.users.get(&id).unwrap().previous_names.len(), 2);
+
+            // the user can take back their own held handle
+            assert_eq!(state.validate_username("bob"), Err("taken".into()));
+            assert_eq!(
+                state.change_username(
+                    pr(0),
+                    "bob".into(),
+                    now + CONFIG.handle_change_cooldown_days * DAY
+                ),
+                Ok(())
+            );
+        })
     }
 
-    pub fn principal_to_user(&self, principal: Principal) -> Option<&User> {
-        self.principals
-            .get(&principal)
-            .and_then(|id| self.users.get(id))
-    }
+    #[test]
+    fn test_posts_by_tags() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            for i in 0..3 {
+                create_user(state, pr(i));
+            }
+            let mut create = |body: &str, i| {
+                Post::create(state, body.into(), &[], pr(i), 0, None, None, None).unwrap()
+            };
+            let both = create("#Rust and #ic", 0);
+            let rust = create("#rust", 1);
+            let ic = create("#IC only", 2);
+            let untagged = create("no tags", 0);
 
-    pub fn principal_to_user_mut(&mut self, principal: Principal) -> Option<&mut User> {
-        let id = self.principals.get(&principal)?;
-        self.users.get_mut(id)
-    }
+            let query = |tags: &[&str], users: Vec<UserId>, match_any| {
+                let tags = tags.iter().map(|tag| tag.to_string()).collect();
+                state
+                    .posts_by_tags(None, tags, users, match_any, 0)
+                    .into_iter()
+                    .map(|post| post.id)
+                    .collect::<Vec<_>>()
+            };
 
-    fn new_user_id(&mut self) -> UserId {
-        let id = self.next_user_id;
-        self.next_user_id += 1;
-        id
+            // all tags have to match
+            assert_eq!(query(&["rust", "ic"], vec![], false), vec![both]);
+            assert_eq!(query(&["RUST"], vec![], false), vec![rust, both]);
+            // any tag matches, posts matching several tags are returned once
+            assert_eq!(query(&["rust", "ic"], vec![], true), vec![ic, rust, both]);
+            // posts of any listed user
+            assert_eq!(query(&["rust", "ic"], vec![0, 1], true), vec![rust, both]);
+            assert_eq!(query(&[], vec![0], false), vec![untagged, both]);
+            assert_eq!(query(&[], vec![0], true), vec![untagged, both]);
+            assert!(query(&["motoko"], vec![], true).is_empty());
+        })
     }
 
-    fn new_post_id(&mut self) -> PostId {
-        let id = self.next_post_id;
-        self.next_post_id += 1;
-        id
-    }
+    #[test]
+    fn test_engagement() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            for i in 0..3 {
+                create_user(state, pr(i));
+            }
+            let post_id =
+                Post::create(state, "Hello".into(), &[], pr(0), 0, None, None, None).unwrap();
+            Post::create(state, "World".into(), &[], pr(0), 0, None, None, None).unwrap();
+            Post::create(
+                state,
+                "Reply".into(),
+                &[],
+                pr(0),
+                0,
+                Some(post_id),
+                None,
+                None,
+            )
+            .unwrap();
+            assert_eq!(state.react(pr(1), post_id, 50, 0), Ok(()));
+            assert_eq!(state.react(pr(2), post_id, 10, 0), Ok(()));
+            // switching the reaction doesn't count twice
+            assert_eq!(state.react(pr(2), post_id, 50, 0), Ok(()));
+
+            let engagement = state.principal_to_user(pr(0)).unwrap().engagement.clone();
+            assert_eq!(
+                engagement,
+                Engagement {
+                    posts: 2,
+                    comments: 1,
+                    reactions_received: 2,
+                    tips_received: Default::default(),
+                }
+            );
+
+            // removing a reaction decreases the total
+            assert_eq!(state.react(pr(1), post_id, 50, 0), Ok(()));
+            assert_eq!(
+                state
+                    .principal_to_user(pr(0))
+                    .unwrap()
+                    .engagement
+                    .reactions_received,
+                1
+            );
 
-    pub fn logs(&self) -> &Vec<Event> {
-        &self.logger.events
+            // the totals of existing users are backfilled from their posts
+            Post::mutate(state, &post_id, |post| {
+                post.tips.push((1, 500));
+                // a split tip is credited to its recipients
+                post.tip_shares.insert(1, vec![(0, 200), (1, 100)]);
+                post.tips.push((2, 300));
+                Ok(())
+            })
+            .unwrap();
+            for user in state.users.values_mut() {
+                user.engagement = Default::default();
+            }
+            state.backfill_engagement();
+            let engagement = &state.principal_to_user(pr(0)).unwrap().engagement;
+            assert_eq!((engagement.posts, engagement.comments), (2, 1));
+            assert_eq!(engagement.reactions_received, 1);
+            assert_eq!(engagement.tips_received.get("ICP"), Some(&700));
+            assert_eq!(
+                state
+                    .principal_to_user(pr(1))
+                    .unwrap()
+                    .engagement
+                    .tips_received
+                    .get("ICP"),
+                Some(&100)
+            );
+        })
     }
 
-    pub fn stats(&self, now: u64) -> Stats {
-        let mut stalwarts = Vec::new();
-        let mut weekly_karma_leaders = Vec::new();
-        let mut bootcamp_users = 0;
-        let mut users_online = 0;
-        let mut invited_users = 0;
-        let mut active_users = 0;
-        let mut bots = Vec::new();
-        let mut cycles = 0;
-        for user in self.users.values() {
-            if user.stalwart {
-                stalwarts.push(user);
+    #[test]
+    fn test_idempotency_keys() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            for i in 0..2 {
+                create_user(state, pr(i));
             }
-            if !user.trusted() {
-                bootcamp_users += 1;
+            let key = "retry-1".to_string();
+            assert_eq!(state.post_by_idempotency_key(pr(0), &key, 0), Ok(None));
+            assert_eq!(
+                state.post_by_idempotency_key(pr(0), &"x".repeat(65), 0),
+                Err("idempotency key is too long".into())
+            );
+
+            let post_id =
+                Post::create(state, "Hello".into(), &[], pr(0), 0, None, None, None).unwrap();
+            state.remember_idempotency_key(post_id, key.clone(), 0);
+            assert_eq!(
+                state.post_by_idempotency_key(pr(0), &key, MINUTE),
+                Ok(Some(post_id))
+            );
+            // keys are scoped to the user
+            assert_eq!(state.post_by_idempotency_key(pr(1), &key, MINUTE), Ok(None));
+            // keys expire after the window
+            assert_eq!(
+                state.post_by_idempotency_key(
+                    pr(0),
+                    &key,
+                    CONFIG.idempotency_window_minutes * MINUTE
+                ),
+                Ok(None)
+            );
+
+            // only the most recent keys are kept
+            for i in 0..CONFIG.max_idempotency_keys {
+                state.remember_idempotency_key(post_id, format!("key-{}", i), MINUTE);
             }
-            if now < user.last_activity + CONFIG.online_activity_minutes {
-                users_online += 1;
+            assert_eq!(state.post_by_idempotency_key(pr(0), &key, MINUTE), Ok(None));
+            assert_eq!(
+                state.post_by_idempotency_key(pr(0), "key-0", MINUTE),
+                Ok(Some(post_id))
+            );
+        })
+    }
+
+    #[test]
+    fn test_realm_stats() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let realm = "STATS".to_string();
+            state.realms.insert(realm.clone(), Default::default());
+            for i in 0..2 {
+                create_user(state, pr(i));
+                assert_eq!(
+                    state.toggle_realm_membership(pr(i), realm.clone()),
+                    Ok(true)
+                );
             }
-            if user.is_bot() {
-                bots.push(user.id);
+            let now = 40 * DAY;
+            let mut parent = None;
+            for (i, timestamp) in [
+                (0, now - 35 * DAY),
+                (0, now - 10 * DAY),
+                (1, now - DAY),
+                (1, now),
+            ] {
+                let post_id = Post::create(
+                    state,
+                    "Test".into(),
+                    &[],
+                    pr(i),
+                    timestamp,
+                    parent,
+                    Some(realm.clone()),
+                    None,
+                )
+                .unwrap();
+                parent = Some(post_id);
             }
-            if user.invited_by.is_some() {
-                invited_users += 1;
+            Post::create(state, "Elsewhere".into(), &[], pr(0), now, None, None, None).unwrap();
+            assert_eq!(
+                state.realm_stats("NOPE").map(|stats| stats.posts),
+                Err("no realm found".into())
+            );
+
+            state.compute_realm_stats(now);
+            let stats = state.realm_stats(&realm).unwrap();
+            assert_eq!(stats.members, 2);
+            assert_eq!(stats.posts, 4);
+            assert_eq!(stats.posts_last_week, 2);
+            assert_eq!(stats.posts_last_month, 3);
+            assert_eq!(stats.active_authors, 2);
+            assert_eq!(stats.tips, 0);
+        })
+    }
+
+    #[test]
+    fn test_realm_tags() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let realm = "TAGS".to_string();
+            state.realms.insert(realm.clone(), Default::default());
+            for i in 0..4 {
+                let id = create_user(state, pr(i));
+                state.users.get_mut(&id).unwrap().realms.push(realm.clone());
             }
-            if user.active_within_weeks(now, 1) {
-                active_users += 1;
-                if user.karma_to_reward() > 0 {
-                    weekly_karma_leaders.push((user.id, user.karma_to_reward()));
-                }
+            let now = CONFIG.trending_tags_window_days * DAY + 1;
+            for (i, body, realm, timestamp) in [
+                (0, "#old", Some(realm.clone()), 0),
+                (1, "#Rust #ic", Some(realm.clone()), now),
+                (2, "#rust", Some(realm.clone()), now),
+                (3, "#elsewhere", None, now),
+            ] {
+                Post::create(state, body.into(), &[], pr(i), timestamp, None, realm, None).unwrap();
             }
-            cycles += user.cycles();
-        }
-        stalwarts.sort_unstable_by_key(|u1| std::cmp::Reverse(u1.karma()));
-        weekly_karma_leaders.sort_unstable_by_key(|k| k.1);
-        weekly_karma_leaders = weekly_karma_leaders.into_iter().rev().take(12).collect();
-        let posts = self.root_posts;
-        let emergency_votes = self.emergency_votes.values().sum::<Token>() as f32
-            / self.active_voting_power(time()).max(1) as f32
-            * 100.0;
-        Stats {
-            emergency_release: format!(
-                "Binary set: {}, votes: {}% (required: {}%)",
-                !self.emergency_binary.is_empty(),
-                emergency_votes as u32,
-                CONFIG.proposal_approval_threshold
-            ),
-            team_tokens: self.team_tokens.clone(),
-            emergency_votes: self.emergency_votes.keys().cloned().collect(),
-            meta: format!("Memory health: {}", self.memory.health("MB")),
-            weekly_karma_leaders,
-            bootcamp_users,
-            module_hash: self.module_hash.clone(),
-            canister_id: ic_cdk::id(),
-            last_upgrade: self.last_upgrade,
-            last_weekly_chores: self.last_weekly_chores,
-            last_daily_chores: self.last_weekly_chores,
-            last_hourly_chores: self.last_weekly_chores,
-            canister_cycle_balance: canister_balance(),
-            users: self.users.len(),
-            posts,
-            comments: Post::count(self) - posts,
-            cycles,
-            burned_cycles: self.burned_cycles,
-            burned_cycles_total: self.burned_cycles_total,
-            total_revenue_shared: self.total_revenue_shared,
-            total_rewards_shared: self.total_rewards_shared,
-            account: invoices::main_account().to_string(),
-            users_online,
-            stalwarts: stalwarts.into_iter().map(|u| u.id).collect(),
-            bots,
-            state_size: stable64_size() << 16,
-            invited_users,
-            active_users,
-            circulating_supply: self.balances.values().sum(),
-            buckets: self
-                .storage
-                .buckets
-                .iter()
-                .map(|(id, size)| (id.to_string(), *size))
-                .collect(),
-        }
+
+            assert_eq!(
+                state.realm_tags(realm.clone(), 10, now),
+                vec![("rust".to_string(), 2), ("ic".to_string(), 1)]
+            );
+            assert_eq!(
+                state.realm_tags(realm.clone(), 1, now),
+                vec![("rust".to_string(), 2)]
+            );
+            assert!(state.realm_tags("UNKNOWN".into(), 10, now).is_empty());
+        })
     }
 
-    pub fn vote_on_report(
-        &mut self,
-        principal: Principal,
-        domain: String,
-        id: u64,
-        vote: bool,
-    ) -> Result<(), String> {
-        let user = self
-            .principal_to_user(principal)
-            .ok_or("no user found")?
-            .clone();
-        if !user.stalwart {
-            return Err("only stalwarts can vote on reports".into());
-        }
-        let stalwarts = self.users.values().filter(|u| u.stalwart).count();
-        let (user_id, report, penalty, subject) = match domain.as_str() {
-            "post" => Post::mutate(
-                self,
-                &id,
-                |post| -> Result<(UserId, Report, Cycles, String), String> {
-                    post.vote_on_report(stalwarts, user.id, vote)?;
-                    let post_user = post.user;
-                    let post_report = post.report.clone().ok_or("no report")?;
-                    Ok((
-                        post_user,
-                        post_report,
-                        CONFIG.reporting_penalty_post,
-                        format!("post {}", id),
-                    ))
+    #[test]
+    fn test_pinned_posts() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let u0 = create_user(state, pr(0));
+            let u1 = create_user(state, pr(1));
+            let realm = "PINS".to_string();
+            state.realms.insert(
+                realm.clone(),
+                Realm {
+                    controllers: vec![u0],
+                    ..Default::default()
                 },
-            )?,
-            "misbehaviour" => {
-                if user.id == id {
-                    return Err("votes on own reports are not accepted".into());
-                }
-                let report = self
-                    .users
-                    .get_mut(&id)
-                    .and_then(|u| u.report.as_mut())
-                    .expect("no user found");
-                report.vote(stalwarts, user.id, vote)?;
-                (
-                    id,
-                    report.clone(),
-                    CONFIG.reporting_penalty_misbehaviour,
-                    format!("user {}", id),
-                )
+            );
+            for id in [u0, u1] {
+                state.users.get_mut(&id).unwrap().realms.push(realm.clone());
             }
-            _ => return Err("unknown report type".into()),
-        };
-        reports::finalize_report(self, &report, penalty, user_id, subject)
-    }
+            let create = |state: &mut State, p, realm: Option<String>, parent| {
+                Post::create(state, "Test".into(), &[], p, 0, parent, realm, None).unwrap()
+            };
+            let p0 = create(state, pr(0), Some(realm.clone()), None);
+            let p1 = create(state, pr(1), Some(realm.clone()), None);
+            let p2 = create(state, pr(0), None, None);
+            let comment = create(state, pr(1), None, Some(p2));
+
+            // profile pins
+            assert_eq!(
+                state.pin_post(pr(1), Some(p0), None),
+                Err("not authorized".into())
+            );
+            assert_eq!(
+                state.pin_post(pr(1), Some(comment), None),
+                Err("only root posts can be pinned".into())
+            );
+            assert_eq!(state.pin_post(pr(0), Some(p0), None), Ok(()));
+            let user = state.users.get(&u0).unwrap();
+            assert_eq!(
+                state
+                    .with_pinned(user.pinned, user.posts(state))
+                    .map(|post| post.id)
+                    .collect::<Vec<_>>(),
+                vec![p0, p2]
+            );
+            assert_eq!(state.pin_post(pr(0), None, None), Ok(()));
+            assert_eq!(state.users.get(&u0).unwrap().pinned, None);
+
+            // realm pins
+            assert_eq!(
+                state.pin_post(pr(1), Some(p0), Some(realm.clone())),
+                Err("not authorized".into())
+            );
+            assert_eq!(
+                state.pin_post(pr(0), Some(p2), Some(realm.clone())),
+                Err("post doesn't belong to the realm".into())
+            );
+            assert_eq!(state.pin_post(pr(0), Some(p0), Some(realm.clone())), Ok(()));
+            let realm_feed = |state: &State| {
+                state
+                    .feed(
+                        FeedMode::New,
+                        Some(realm.clone()),
+                        &[],
+                        false,
+                        0,
+                        CONFIG.feed_page_size,
+                        0,
+                    )
+                    .into_iter()
+                    .map(|post| post.id)
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(realm_feed(state), vec![p0, p1]);
+            assert_eq!(
+                state.feed(FeedMode::New, None, &[], false, 0, CONFIG.feed_page_size, 0)[0].id,
+                p2,
+                "the global feed is not affected"
+            );
 
-    pub fn vote_on_poll(
-        &mut self,
-        principal: Principal,
-        time: u64,
-        post_id: PostId,
-        vote: u16,
-    ) -> Result<(), String> {
-        let user = self
-            .principal_to_user(principal)
-            .ok_or_else(|| "no user found".to_string())?;
-        let (user_id, user_realms) = (user.id, user.realms.clone());
-        Post::mutate(self, &post_id, |post| {
-            post.watchers.insert(user_id);
-            post.vote_on_poll(user_id, user_realms.clone(), time, vote)
+            // deleted posts lose their pins
+            assert_eq!(state.pin_post(pr(0), Some(p0), None), Ok(()));
+            assert_eq!(state.delete_post(pr(0), p0, vec!["Test".into()], 0), Ok(()));
+            assert_eq!(realm_feed(state), vec![p1]);
+            state.delete_pending_posts(CONFIG.post_deletion_grace_period_minutes * MINUTE);
+            assert_eq!(state.realms.get(&realm).unwrap().pinned, None);
+            assert_eq!(state.users.get(&u0).unwrap().pinned, None);
         })
     }
 
-    pub fn report(
-        &mut self,
-        principal: Principal,
-        domain: String,
-        id: u64,
-        reason: String,
-    ) -> Result<(), String> {
-        if reason.len() > 1000 {
-            return Err("reason too long".into());
-        }
-        let cycles_required = if domain == "post" {
-            CONFIG.reporting_penalty_post
-        } else {
-            CONFIG.reporting_penalty_misbehaviour
-        } / 2;
-        let user = match self.principal_to_user(principal) {
-            Some(user) if user.cycles() >= cycles_required => user.clone(),
-            _ => {
-                return Err(format!(
-                    "You need at least {} cycles for this report",
-                    cycles_required
-                ))
+    #[test]
+    fn test_realm_reputation() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let ids = (0..3)
+                .map(|i| create_user(state, pr(i)))
+                .collect::<Vec<_>>();
+            let realm = "LOCAL".to_string();
+            state.realms.insert(
+                realm.clone(),
+                Realm {
+                    controllers: vec![ids[0]],
+                    ..Default::default()
+                },
+            );
+            for id in &ids {
+                state.users.get_mut(id).unwrap().realms.push(realm.clone());
             }
-        };
-        let report = Some(Report {
-            reporter: user.id,
-            reason,
-            ..Default::default()
-        });
+            let post = |state: &mut State, p, extension| {
+                Post::create(
+                    state,
+                    "Test".into(),
+                    &[],
+                    p,
+                    0,
+                    None,
+                    Some(realm.clone()),
+                    extension,
+                )
+                .unwrap()
+            };
+            post(state, pr(0), None);
+            post(state, pr(0), None);
+            let post_id = post(state, pr(1), None);
+            // posts outside of the realm don't count
+            Post::create(state, "Test".into(), &[], pr(2), 0, None, None, None).unwrap();
+            state.react(pr(2), post_id, 50, 0).unwrap();
+            assert_eq!(
+                state.realm_reputation(&realm),
+                vec![(ids[0], 2), (ids[1], 2)]
+                    .into_iter()
+                    .collect::<BTreeMap<_, _>>()
+            );
 
-        match domain.as_str() {
-            "post" => {
-                let post_user = Post::mutate(self, &id, |post| {
-                    if post.report.is_some() {
-                        return Err("this post is already reported".into());
-                    }
-                    post.report = report.clone();
-                    Ok(post.user)
-                })?;
-                let author_name = self
-                    .users
-                    .get(&post_user)
-                    .map(|user| user.name.clone())
-                    .unwrap_or_default();
-                self.notify_with_predicate(
-                    &|u| u.stalwart && u.id != user.id,
-                    format!("@{} reported this post by @{}", user.name, author_name),
-                    Predicate::ReportOpen(id),
-                );
-            }
-            "misbehaviour" => {
-                let misbehaving_user = self.users.get_mut(&id).ok_or("no user found")?;
-                if misbehaving_user.report.as_ref().map(|r| r.closed) == Some(true) {
-                    return Err("this user is already reported".into());
-                }
-                misbehaving_user.report = report;
-                let user_name = misbehaving_user.name.clone();
-                self.notify_with_predicate(
-                    &|u| u.stalwart && u.id != id,
-                    format!("@{} reported user @{}", user.name, user_name),
-                    Predicate::UserReportOpen(id),
-                );
-            }
-            _ => unimplemented!(),
-        }
+            assert_eq!(
+                state.set_realm_min_voting_reputation(pr(1), &realm, 3),
+                Err("not authorized".into())
+            );
+            assert_eq!(
+                state.set_realm_min_voting_reputation(pr(0), &realm, 3),
+                Ok(())
+            );
 
-        Ok(())
+            let poll_id = post(
+                state,
+                pr(0),
+                Some(Extension::Poll(Poll {
+                    options: vec!["A".into(), "B".into()],
+                    deadline: 1,
+                    ..Default::default()
+                })),
+            );
+            Post::mutate(state, &poll_id, |post| {
+                if let Some(Extension::Poll(poll)) = post.extension.as_mut() {
+                    poll.votes
+                        .insert(0, vec![ids[0], ids[2]].into_iter().collect());
+                    poll.votes.insert(1, vec![ids[1]].into_iter().collect());
+                }
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(Post::conclude_poll(state, &poll_id, HOUR), Ok(true));
+            let Some(Extension::Poll(poll)) = &Post::get(state, &poll_id).unwrap().extension
+            else {
+                panic!("should be a poll")
+            };
+            // the poll author has a reputation of 3, the other voters are below the threshold
+            assert_eq!(
+                poll.weighted_by_realm_reputation,
+                vec![(0, 1), (1, 0)].into_iter().collect::<BTreeMap<_, _>>()
+            );
+        })
     }
 
-    pub fn delete_post(
-        &mut self,
-        principal: Principal,
-        post_id: PostId,
-        versions: Vec<String>,
-    ) -> Result<(), String> {
-        let post = Post::get(self, &post_id).ok_or("no post found")?.clone();
-        if self.principal_to_user(principal).map(|user| user.id) != Some(post.user) {
-            return Err("not authorized".into());
-        }
-
-        let comments_tree_penalty =
-            post.tree_size as Cycles * CONFIG.post_deletion_penalty_factor as Cycles;
-        let karma = reaction_karma();
-        let reaction_costs = post
-            .reactions
-            .iter()
-            .filter_map(|(r_id, users)| {
-                let cost = karma.get(r_id).copied().unwrap_or_default();
-                (cost > 0).then_some((users, cost as Cycles))
-            })
-            .collect::<Vec<_>>();
+    #[test]
+    fn test_realm_treasury() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let ids = (0..3)
+                .map(|i| create_user(state, pr(i)))
+                .collect::<Vec<_>>();
+            let realm = "LOCAL".to_string();
+            state.realms.insert(
+                realm.clone(),
+                Realm {
+                    controllers: vec![ids[0]],
+                    ..Default::default()
+                },
+            );
+            for id in &ids[..2] {
+                state.users.get_mut(id).unwrap().realms.push(realm.clone());
+            }
 
-        let costs: Cycles = CONFIG.post_cost
-            + reaction_costs.iter().map(|(_, cost)| *cost).sum::<u64>()
-            + comments_tree_penalty;
-        if costs > self.users.get(&post.user).ok_or("no user found")?.cycles() {
-            return Err(format!(
-                "not enough cycles (this post requires {} cycles to be deleted)",
-                costs
-            ));
-        }
+            assert_eq!(
+                state.set_realm_post_cost(pr(1), &realm, 5),
+                Err("not authorized".into())
+            );
+            assert_eq!(
+                state.set_realm_post_cost(pr(0), &realm, CONFIG.max_realm_post_cost + 1),
+                Err(format!(
+                    "realm post cost can't be higher than {} cycles",
+                    CONFIG.max_realm_post_cost
+                ))
+            );
+            assert_eq!(state.set_realm_post_cost(pr(0), &realm, 5), Ok(()));
 
-        let mut karma_penalty = post.children.len() as Karma * CONFIG.response_reward as Karma;
+            let cycles = |state: &State, id| state.users.get(&id).unwrap().cycles();
+            let cycles_before = cycles(state, ids[1]);
+            Post::create(
+                state,
+                "Test".into(),
+                &[],
+                pr(1),
+                0,
+                None,
+                Some(realm.clone()),
+                None,
+            )
+            .unwrap();
+            assert_eq!(cycles(state, ids[1]), cycles_before - CONFIG.post_cost - 5);
+            assert_eq!(state.realms.get(&realm).unwrap().treasury, 5);
 
-        // refund rewards
-        for (users, amount) in reaction_costs {
-            for user_id in users {
-                self.cycle_transfer(
-                    post.user,
-                    *user_id,
-                    amount,
+            // the realm post cost can't be paid
+            let spent = cycles(state, ids[1]) - CONFIG.post_cost;
+            state
+                .users
+                .get_mut(&ids[1])
+                .unwrap()
+                .change_cycles(spent, CyclesDelta::Minus, "")
+                .unwrap();
+            assert_eq!(
+                Post::create(
+                    state,
+                    "Test".into(),
+                    &[],
+                    pr(1),
                     0,
-                    Destination::Cycles,
-                    format!("rewards refund after deletion of post {}", post.id),
                     None,
-                )?;
-                karma_penalty += amount as Karma;
-            }
-        }
+                    Some(realm.clone()),
+                    None
+                ),
+                Err("not enough cycles".into())
+            );
+            assert_eq!(cycles(state, ids[1]), CONFIG.post_cost);
 
-        // penalize for comments tree destruction
-        self.charge(
-            post.user,
-            CONFIG.post_cost + comments_tree_penalty,
-            format!("deletion of post {}", post.id),
-        )?;
+            assert_eq!(
+                state.grant_from_realm_treasury(pr(1), &realm, ids[1], 5),
+                Err("not authorized".into())
+            );
+            assert_eq!(
+                state.grant_from_realm_treasury(pr(0), &realm, ids[1], 6),
+                Err("invalid grant amount".into())
+            );
+            assert_eq!(
+                state.grant_from_realm_treasury(pr(0), &realm, ids[2], 5),
+                Err("grants can only be made to realm members".into())
+            );
+            assert_eq!(
+                state.grant_from_realm_treasury(pr(0), &realm, ids[1], 5),
+                Ok(())
+            );
+            assert_eq!(cycles(state, ids[1]), CONFIG.post_cost + 5);
+            assert_eq!(state.realms.get(&realm).unwrap().treasury, 0);
+        })
+    }
+
+    #[test]
+    fn test_realm_max_members() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let ids = (0..3)
+                .map(|i| create_user(state, pr(i)))
+                .collect::<Vec<_>>();
+            let realm = "CLUB".to_string();
+            state.realms.insert(
+                realm.clone(),
+                Realm {
+                    controllers: vec![ids[0]],
+                    ..Default::default()
+                },
+            );
+            assert_eq!(
+                state.set_realm_max_members(pr(1), &realm, Some(1)),
+                Err("not authorized".into())
+            );
+            assert_eq!(
+                state.set_realm_max_members(pr(0), &realm, Some(0)),
+                Err("the member limit must be positive".into())
+            );
+            assert_eq!(
+                state.toggle_realm_membership(pr(0), realm.clone()),
+                Ok(true)
+            );
+            assert_eq!(
+                state.toggle_realm_membership(pr(1), realm.clone()),
+                Ok(true)
+            );
+
+            // existing members stay after the limit is lowered
+            assert_eq!(state.set_realm_max_members(pr(0), &realm, Some(1)), Ok(()));
+            assert_eq!(
+                state.toggle_realm_membership(pr(2), realm.clone()),
+                Err("realm CLUB has reached its member limit".into())
+            );
+            assert_eq!(state.realms.get(&realm).unwrap().num_members, 2);
+
+            // members can leave a full realm, but nobody joins until it's below the limit
+            assert_eq!(
+                state.toggle_realm_membership(pr(1), realm.clone()),
+                Ok(false)
+            );
+            assert_eq!(
+                state.toggle_realm_membership(pr(2), realm.clone()),
+                Err("realm CLUB has reached its member limit".into())
+            );
+            assert_eq!(state.set_realm_max_members(pr(0), &realm, None), Ok(()));
+            assert_eq!(
+                state.toggle_realm_membership(pr(2), realm.clone()),
+                Ok(true)
+            );
+        })
+    }
+
+    #[actix_rt::test]
+    async fn test_realms() {
+        let (p1, realm_name) = STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let p0 = pr(0);
+            let p1 = pr(1);
+            let _u0 = create_user_with_params(state, p0, "user1", true, 1000);
+            let _u1 = create_user_with_params(state, p1, "user2", true, 1000);
+
+            let user1 = state.users.get_mut(&_u1).unwrap();
+            assert_eq!(user1.cycles(), 1000);
+            user1.change_cycles(500, CyclesDelta::Minus, "").unwrap();
+            assert_eq!(user1.cycles(), 500);
 
-        // subtract all rewards from karma
-        self.users
-            .get_mut(&post.user)
-            .expect("no user found")
-            .change_karma(-karma_penalty, format!("deletion of post {}", post.id));
+            let name = "TAGGRDAO".to_string();
+            let description = "Test description".to_string();
+            let controllers = vec![_u0];
 
-        self.hot.retain(|id| id != &post_id);
+            // simple creation and description change edge cases
+            assert_eq!(
+                state.create_realm(
+                    pr(2),
+                    name.clone(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    description.clone(),
+                    controllers.clone()
+                ),
+                Err("no user found".to_string())
+            );
 
-        match &post.extension {
-            Some(Extension::Proposal(proposal_id)) => {
-                if let Some(proposal) = self.proposals.iter_mut().find(|p| &p.id == proposal_id) {
-                    proposal.status = Status::Cancelled
-                }
-            }
-            Some(Extension::Poll(_)) => {
-                self.pending_polls.remove(&post_id);
-            }
-            _ => {}
-        };
+            assert_eq!(
+                state.create_realm(
+                    p1,
+                    name.clone(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    description.clone(),
+                    controllers.clone()
+                ),
+                Err("the realm creator must be a controller".to_string())
+            );
 
-        Post::mutate(self, &post_id, |post| {
-            post.delete(versions.clone());
-            Ok(())
-        })
-    }
+            assert_eq!(
+                state.create_realm(
+                    p1,
+                    name.clone(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    description.clone(),
+                    vec![_u1]
+                ),
+                Err(
+                    "couldn't charge 1000 cycles for realm creation: not enough cycles".to_string()
+                )
+            );
 
-    pub fn react(
-        &mut self,
-        principal: Principal,
-        post_id: PostId,
-        reaction: u16,
-        time: u64,
-    ) -> Result<(), String> {
-        let delta: i64 = match CONFIG.reactions.iter().find(|(id, _)| id == &reaction) {
-            Some((_, delta)) => *delta,
-            _ => return Err("unknown reaction".into()),
-        };
-        let user = self
-            .principal_to_user(principal)
-            .ok_or("no user for principal found")?
-            .clone();
-        let post = Post::get(self, &post_id).ok_or("post not found")?.clone();
-        if post.is_deleted() {
-            return Err("post deleted".into());
-        }
-        if post.user == user.id {
-            return Err("reactions to own posts are forbidden".into());
-        }
-        if post
-            .reactions
-            .values()
-            .flatten()
-            .any(|user_id| user_id == &user.id)
-        {
-            return Err("multiple reactions are forbidden".into());
-        }
+            assert_eq!(
+                state.create_realm(
+                    p0,
+                    "THIS_NAME_IS_TOO_LONG".to_string(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    description.clone(),
+                    controllers.clone()
+                ),
+                Err("realm name too long".to_string())
+            );
 
-        let log = format!("reaction to post {}", post_id);
-        // If the user is untrusted, they can only upvote, but this does not affect author's karma.
-        if !user.trusted() {
-            if delta < 0 {
-                return Err("bootcamp users can't downvote".into());
-            }
-            self.charge(user.id, delta.unsigned_abs() + CONFIG.reaction_fee, log)
-                .expect("coudln't charge user");
-        }
-        // If the user is trusted, they initiate a cycle transfer for upvotes, but burn their own cycles on
-        // down votes + cycles and karma of the author
-        else if delta < 0 {
-            self.users
-                .get_mut(&post.user)
-                .expect("user not found")
-                .change_karma(delta, log.clone());
-            self.charge(
-                user.id,
-                delta.unsigned_abs().min(user.cycles()),
-                log.clone(),
-            )?;
-            self.charge(
-                post.user,
-                delta
-                    .unsigned_abs()
-                    .min(self.users.get(&post.user).expect("no user found").cycles()),
-                log,
-            )
-            .expect("couldn't charge user");
-        } else {
-            self.cycle_transfer(
-                user.id,
-                post.user,
-                delta as Cycles,
-                CONFIG.reaction_fee,
-                Destination::Karma,
-                log,
-                None,
-            )?;
-            post.make_hot(&mut self.hot, self.users.len(), user.id);
-        }
+            assert_eq!(
+                state.create_realm(
+                    p0,
+                    name.clone(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    description.clone(),
+                    vec![]
+                ),
+                Err("no controllers specified".to_string())
+            );
 
-        self.principal_to_user_mut(principal)
-            .expect("no user for principal found")
-            .last_activity = time;
-        let user_id = user.id;
-        Post::mutate(self, &post_id, |post| {
-            post.reactions.entry(reaction).or_default().insert(user_id);
-            Ok(())
-        })
-    }
+            assert_eq!(
+                state.create_realm(
+                    p0,
+                    "TEST NAME".to_string(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    description.clone(),
+                    controllers.clone()
+                ),
+                Err("realm name should be an alpha-numeric string".to_string(),)
+            );
 
-    pub fn toggle_following_user(&mut self, principal: Principal, followee_id: UserId) -> bool {
-        let (added, (id, name)) = {
-            let user = match self.principal_to_user_mut(principal) {
-                Some(user) => user,
-                _ => return false,
-            };
-            (
-                if user.followees.contains(&followee_id) {
-                    user.followees.remove(&followee_id);
-                    false
-                } else {
-                    user.followees.insert(followee_id);
-                    true
-                },
-                (user.id, user.name.clone()),
-            )
-        };
-        let followee = self.users.get_mut(&followee_id).expect("User not found");
-        if added {
-            followee.followers.insert(id);
-            followee.notify(format!("@{} followed you", name));
-        } else {
-            followee.followers.remove(&id);
-        }
-        added
-    }
-}
+            assert_eq!(
+                state.create_realm(
+                    p0,
+                    name.clone(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    description.clone(),
+                    controllers.clone()
+                ),
+                Ok(())
+            );
+            assert_eq!(state.controlled_realms(_u0), vec![name.clone()]);
+            assert!(state.controlled_realms(_u1).is_empty());
 
-// Checks if any feed represents the superset for the given tag set.
-// The `strict` option requires the sets to be equal.
-fn covered_by_feeds(
-    feeds: &[BTreeSet<String>],
-    tags: &BTreeSet<String>,
-    strict: bool,
-) -> Option<usize> {
-    for (i, feed) in feeds.iter().enumerate() {
-        if strict && tags.len() != feed.len() {
-            continue;
-        }
-        if feed.iter().all(|tag| tags.contains(tag)) {
-            return Some(i);
-        }
-    }
-    None
-}
+            let user0 = state.users.get_mut(&_u0).unwrap();
+            user0.change_cycles(1000, CyclesDelta::Plus, "").unwrap();
 
-pub fn id() -> Principal {
-    #[cfg(test)]
-    return Principal::anonymous();
-    #[cfg(not(test))]
-    ic_cdk::id()
-}
+            assert_eq!(
+                state.create_realm(
+                    p0,
+                    name.clone(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    description.clone(),
+                    controllers.clone()
+                ),
+                Err("realm name taken".to_string())
+            );
 
-pub fn time() -> u64 {
-    #[cfg(test)]
-    return CONFIG.trusted_user_min_age_weeks * WEEK + 1;
-    #[cfg(not(test))]
-    api::time()
-}
+            assert_eq!(
+                state.realms.get(&name).unwrap().description,
+                "Test description".to_string()
+            );
 
-#[cfg(test)]
-pub(crate) mod tests {
-    use super::*;
-    use crate::STATE;
-    use post::Post;
+            let new_description = "New test description".to_string();
 
-    pub fn pr(n: u8) -> Principal {
-        let v = vec![0, n];
-        Principal::from_slice(&v)
-    }
+            assert_eq!(
+                state.edit_realm(
+                    p0,
+                    name.clone(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    new_description.clone(),
+                    vec![]
+                ),
+                Err("no controllers specified".to_string())
+            );
 
-    pub fn create_user(state: &mut State, p: Principal) -> UserId {
-        create_user_with_params(state, p, &p.to_string().replace('-', ""), true, 1000)
-    }
+            assert_eq!(
+                state.edit_realm(
+                    pr(2),
+                    name.clone(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    new_description.clone(),
+                    controllers.clone()
+                ),
+                Err("no user found".to_string())
+            );
 
-    pub fn create_user_with_cycles(state: &mut State, p: Principal, cycles: Cycles) -> UserId {
-        create_user_with_params(state, p, &p.to_string().replace('-', ""), true, cycles)
-    }
+            assert_eq!(
+                state.edit_realm(
+                    p0,
+                    "WRONGNAME".to_string(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    new_description.clone(),
+                    controllers.clone()
+                ),
+                Err("no realm found".to_string())
+            );
 
-    pub fn create_untrusted_user(state: &mut State, p: Principal) -> UserId {
-        create_user_with_params(state, p, &p.to_string().replace('-', ""), false, 1000)
-    }
+            assert_eq!(
+                state.edit_realm(
+                    p1,
+                    name.clone(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    new_description.clone(),
+                    controllers.clone()
+                ),
+                Err("not authorized".to_string())
+            );
 
-    fn create_user_with_params(
-        state: &mut State,
-        p: Principal,
-        name: &str,
-        trusted: bool,
-        cycles: Cycles,
-    ) -> UserId {
-        let id = state.new_user(p, 0, name.to_string());
-        let u = state.users.get_mut(&id).unwrap();
-        u.change_cycles(cycles, CyclesDelta::Plus, "").unwrap();
-        if trusted {
-            u.change_karma(CONFIG.trusted_user_min_karma, "");
-            u.apply_rewards();
-        }
-        id
-    }
+            assert_eq!(
+                state.edit_realm(
+                    p0,
+                    name.clone(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    new_description.clone(),
+                    controllers.clone()
+                ),
+                Ok(())
+            );
 
-    #[test]
-    fn test_poll_conclusion() {
-        STATE.with(|cell| {
-            cell.replace(Default::default());
-            let state = &mut *cell.borrow_mut();
+            assert_eq!(
+                state.realms.get(&name).unwrap().description,
+                new_description
+            );
 
-            // create users each having trusted_user_min_karma + i*10, e.g.
-            // user 1: 35, user 2: 45, user 3: 55, etc...
-            let mut eligigble = HashMap::default();
-            for i in 1..11 {
-                let p = pr(i);
-                let id = create_user(state, p);
-                let user = state.users.get_mut(&id).unwrap();
-                // we create the same amount of new and hard karma so that we have both karma and
-                // balances after minting
-                user.change_karma(i as Karma * 10, "test");
-                user.apply_rewards();
-                user.change_karma(i as Karma * 10, "test");
-                assert_eq!(
-                    user.karma(),
-                    i as Karma * 10 + CONFIG.trusted_user_min_karma
-                );
-                assert!(user.trusted());
-                eligigble.insert(id, user.karma_to_reward());
-            }
+            // wrong user and wrong realm joining
+            assert_eq!(
+                state.toggle_realm_membership(pr(2), name.clone()),
+                Err("no user found".into())
+            );
+            assert_eq!(
+                state.toggle_realm_membership(p1, "WRONGNAME".to_string()),
+                Err("no realm found".into())
+            );
 
-            // mint tokens
-            state.mint(eligigble);
-            assert_eq!(state.ledger.len(), 10);
+            assert_eq!(state.toggle_realm_membership(p1, name.clone()), Ok(true));
+            assert!(state.users.get(&_u1).unwrap().realms.contains(&name));
+            assert_eq!(state.realms.get(&name).unwrap().num_members, 1);
 
+            // creating a post in a realm
             let post_id = Post::create(
                 state,
-                "Test".to_string(),
+                "Realm post".to_string(),
                 &[],
-                pr(1),
+                p1,
                 0,
                 None,
+                Some(name.clone()),
                 None,
-                Some(Extension::Poll(Poll {
-                    options: vec!["A".into(), "B".into(), "C".into()],
-                    deadline: 72,
-                    ..Default::default()
-                })),
             )
             .unwrap();
+            assert_eq!(state.realms.get(&name).unwrap().num_posts, 1);
 
-            let now = Post::mutate(state, &post_id, |post| {
-                let mut votes = BTreeMap::new();
-                votes.insert(0, vec![1, 2, 3].into_iter().collect());
-                votes.insert(1, vec![4, 5, 6].into_iter().collect());
-                votes.insert(2, vec![7, 8, 9].into_iter().collect());
-                if let Some(Extension::Poll(poll)) = post.extension.as_mut() {
-                    poll.votes = votes;
-                }
-                Ok(post.timestamp())
-            })
-            .unwrap();
-            assert_eq!(state.pending_polls.len(), 1);
-            state.conclude_polls(now + 24 * HOUR);
-            assert_eq!(state.pending_polls.len(), 1);
-            state.conclude_polls(now + 3 * 24 * HOUR);
-            assert_eq!(state.pending_polls.len(), 0);
-            if let Some(Extension::Poll(poll)) =
-                Post::get(state, &post_id).unwrap().extension.as_ref()
-            {
-                // Here we can see that by karma the difference is way smaller becasue values are
-                // normalized by the square root.
-                assert_eq!(*poll.weighted_by_karma.get(&0).unwrap(), 21);
-                assert_eq!(*poll.weighted_by_karma.get(&1).unwrap(), 26);
-                assert_eq!(*poll.weighted_by_karma.get(&2).unwrap(), 31);
-                assert_eq!(*poll.weighted_by_tokens.get(&0).unwrap(), 9000);
-                assert_eq!(*poll.weighted_by_tokens.get(&1).unwrap(), 18000);
-                assert_eq!(*poll.weighted_by_tokens.get(&2).unwrap(), 27000);
-            } else {
-                panic!("should be a poll")
-            }
-        });
-    }
-
-    #[actix_rt::test]
-    async fn test_principal_change() {
-        let u_id = STATE.with(|cell| {
-            cell.replace(Default::default());
-            let state = &mut *cell.borrow_mut();
-
-            let mut eligigble = HashMap::default();
-            for i in 1..3 {
-                let p = pr(i);
-                let id = create_user(state, p);
-                let user = state.users.get_mut(&id).unwrap();
-                user.change_karma(i as Karma * 111, "test");
-                assert_eq!(user.karma(), CONFIG.trusted_user_min_karma);
-                assert!(user.trusted());
-                eligigble.insert(id, user.karma_to_reward());
-            }
+            assert_eq!(
+                Post::get(state, &post_id).unwrap().realm,
+                Some(name.clone())
+            );
+            assert!(realm_posts(state, &name).contains(&post_id));
 
-            // mint tokens
-            state.mint(eligigble);
-            assert_eq!(state.ledger.len(), 2);
-            assert_eq!(*state.balances.get(&account(pr(1))).unwrap(), 11100);
+            // Posting without realm creates the post in the global realm
+            let post_id = Post::create(
+                state,
+                "Realm post".to_string(),
+                &[],
+                p1,
+                0,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
 
-            let u_id = state.principal_to_user(pr(1)).unwrap().id;
-            u_id
-        });
-        let new_principal_str: String =
-            "yh4uw-lqajx-4dxcu-rwe6s-kgfyk-6dicz-yisbt-pjg7v-to2u5-morox-hae".into();
-        assert!(State::change_principal(pr(1), new_principal_str.clone())
-            .await
-            .is_ok());
+            assert_eq!(Post::get(state, &post_id).unwrap().realm, None,);
 
-        mutate(|state| {
-            let principal = Principal::from_text(new_principal_str).unwrap();
-            assert_eq!(state.principal_to_user(principal).unwrap().id, u_id);
-            assert!(state.balances.get(&account(pr(1))).is_none());
-            assert_eq!(
-                *state.balances.get(&account(principal)).unwrap(),
-                11100 - CONFIG.transaction_fee
-            );
-            let user = state.users.get(&u_id).unwrap();
-            assert_eq!(user.principal, principal);
+            // comments not possible if user is not in the realm
             assert_eq!(
-                user.account,
-                AccountIdentifier::new(&id(), &principal_to_subaccount(&principal)).to_string()
+                Post::create(
+                    state,
+                    "comment".to_string(),
+                    &[],
+                    p0,
+                    0,
+                    Some(0),
+                    None,
+                    None
+                ),
+                Err("not a member of the realm TAGGRDAO".to_string())
             );
-        })
-    }
 
-    #[test]
-    fn test_realm_change() {
-        STATE.with(|cell| {
-            cell.replace(Default::default());
-            let state = &mut *cell.borrow_mut();
-            state.realms.insert("TEST".into(), Realm::default());
-            state.realms.insert("TEST2".into(), Realm::default());
+            assert_eq!(state.toggle_realm_membership(p0, name.clone()), Ok(true));
+            assert_eq!(state.realms.get(&name).unwrap().num_members, 2);
 
-            create_user(state, pr(0));
-            assert!(state.toggle_realm_membership(pr(0), "TEST".into()));
             assert_eq!(
-                state
-                    .users
-                    .values()
-                    .filter(|user| user.realms.contains(&"TEST".to_string()))
-                    .count(),
-                1
+                Post::create(
+                    state,
+                    "comment".to_string(),
+                    &[],
+                    p0,
+                    0,
+                    Some(0),
+                    None,
+                    None
+                ),
+                Ok(2)
             );
 
+            assert!(realm_posts(state, &name).contains(&2));
+
+            // Create post without a realm
+
             let post_id = Post::create(
                 state,
-                "Root".to_string(),
+                "No realm post".to_string(),
                 &[],
-                pr(0),
+                p1,
                 0,
                 None,
-                Some("TEST".into()),
+                None,
                 None,
             )
             .unwrap();
-
-            let comment_1_id = Post::create(
+            let comment_id = Post::create(
                 state,
-                "Comment 1".to_string(),
+                "comment".to_string(),
                 &[],
-                pr(0),
+                p0,
                 0,
                 Some(post_id),
-                Some("TEST".into()),
                 None,
-            )
-            .unwrap();
-
-            Post::create(
-                state,
-                "Comment 2".to_string(),
-                &[],
-                pr(0),
-                0,
-                Some(comment_1_id),
-                Some("TEST".into()),
                 None,
             )
             .unwrap();
 
-            assert_eq!(realm_posts(state, "TEST").len(), 3);
-            assert_eq!(realm_posts(state, "TEST2").len(), 0);
+            assert_eq!(Post::get(state, &comment_id).unwrap().realm, None);
 
-            crate::post::change_realm(state, post_id, Some("TEST2".into()));
+            // Creating post without entering the realm
+            let realm_name = "NEW_REALM".to_string();
+            assert_eq!(
+                Post::create(
+                    state,
+                    "test".to_string(),
+                    &[],
+                    p0,
+                    0,
+                    None,
+                    Some(realm_name.clone()),
+                    None
+                ),
+                Err(format!("not a member of the realm {}", realm_name))
+            );
 
-            assert_eq!(realm_posts(state, "TEST").len(), 0);
-            assert_eq!(realm_posts(state, "TEST2").len(), 3);
+            // create a new realm
+            let user0 = state.users.get_mut(&_u0).unwrap();
+            user0.change_cycles(1000, CyclesDelta::Plus, "").unwrap();
+            assert_eq!(
+                state.create_realm(
+                    p0,
+                    realm_name.clone(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                    description,
+                    controllers
+                ),
+                Ok(())
+            );
+
+            // we still can't post into it, because we didn't join
+            assert_eq!(
+                Post::create(
+                    state,
+                    "test".to_string(),
+                    &[],
+                    p0,
+                    0,
+                    None,
+                    Some(realm_name.clone()),
+                    None
+                ),
+                Err(format!("not a member of the realm {}", realm_name))
+            );
+
+            // join the realm and create the post without entering
+            assert_eq!(
+                state.toggle_realm_membership(p1, realm_name.clone()),
+                Ok(true)
+            );
+            assert!(state.users.get(&_u1).unwrap().realms.contains(&name));
+
+            assert_eq!(state.realms.get(&realm_name).unwrap().num_members, 1);
+            assert_eq!(state.realms.get(&realm_name).unwrap().num_posts, 0);
+
+            assert_eq!(
+                Post::create(
+                    state,
+                    "test".to_string(),
+                    &[],
+                    p1,
+                    0,
+                    None,
+                    Some(realm_name.clone()),
+                    None
+                ),
+                Ok(5)
+            );
+            assert_eq!(state.realms.get(&realm_name).unwrap().num_posts, 1);
+
+            assert!(state
+                .users
+                .get(&_u1)
+                .unwrap()
+                .realms
+                .contains(&"TAGGRDAO".to_string()));
+            (p1, realm_name)
         });
-    }
 
-    fn realm_posts(state: &State, name: &str) -> Vec<PostId> {
-        state
-            .last_posts(None, true)
-            .filter(|post| post.realm.as_ref() == Some(&name.to_string()))
-            .map(|post| post.id)
-            .collect::<Vec<_>>()
+        // Move the post to non-joined realm
+        assert_eq!(
+            Post::edit(
+                5,
+                "changed".to_string(),
+                vec![],
+                "".to_string(),
+                Some("TAGGRDAO_X".to_string()),
+                p1,
+                time(),
+            )
+            .await,
+            Err("you're not in the realm".into()),
+        );
+
+        let cycles_before = mutate(|state| {
+            assert_eq!(Post::get(state, &5).unwrap().realm, Some(realm_name));
+            assert_eq!(state.realms.get("TAGGRDAO").unwrap().num_posts, 2);
+            state.realms.get_mut("TAGGRDAO").unwrap().post_cost = 5;
+            state.principal_to_user(p1).unwrap().cycles()
+        });
+        assert_eq!(
+            Post::edit(
+                5,
+                "changed".to_string(),
+                vec![],
+                "".to_string(),
+                Some("TAGGRDAO".to_string()),
+                p1,
+                time(),
+            )
+            .await,
+            Ok(())
+        );
+
+        read(|state| {
+            assert_eq!(state.realms.get("NEW_REALM").unwrap().num_posts, 0);
+            assert_eq!(state.realms.get("TAGGRDAO").unwrap().num_posts, 3);
+            assert_eq!(
+                Post::get(state, &5).unwrap().realm,
+                Some("TAGGRDAO".to_string())
+            );
+            // moving the post into the realm is charged with the realm post cost
+            assert_eq!(
+                state.principal_to_user(p1).unwrap().cycles(),
+                cycles_before - CONFIG.post_cost - 5
+            );
+            assert_eq!(state.realms.get("TAGGRDAO").unwrap().treasury, 5);
+        });
     }
 
     #[test]
-    fn test_post_deletion() {
+    fn test_feed_modes() {
         STATE.with(|cell| {
             cell.replace(Default::default());
             let state = &mut *cell.borrow_mut();
-
-            let id = create_user(state, pr(0));
-            let user = state.users.get_mut(&id).unwrap();
-            assert_eq!(user.karma_to_reward(), 0);
-            let upvoter_id = create_user(state, pr(1));
-            let user = state.users.get_mut(&upvoter_id).unwrap();
-            let upvoter_cycles = user.cycles();
-            user.change_karma(1000, "test");
-            assert!(user.trusted());
-            let uid = create_user(state, pr(2));
-            create_user(state, pr(3));
-            state
-                .users
-                .get_mut(&uid)
-                .unwrap()
-                .change_karma(1000, "test");
-
-            let post_id =
-                Post::create(state, "Test".to_string(), &[], pr(0), 0, None, None, None).unwrap();
-
-            // Create 2 comments
-            let mut comment_id = 0;
-            for i in 1..=2 {
-                comment_id = Post::create(
+            for i in 0..4 {
+                create_user(state, pr(i));
+            }
+            for i in 0..3 {
+                Post::create(
                     state,
-                    "Comment".to_string(),
+                    format!("Post {}", i),
                     &[],
-                    pr(i),
-                    0,
-                    Some(post_id),
+                    pr(0),
+                    i * DAY,
+                    None,
                     None,
                     None,
                 )
                 .unwrap();
             }
+            // post 1 gets two upvotes, post 2 gets one
+            state.react(pr(1), 1, 100, 2 * DAY).unwrap();
+            state.react(pr(2), 1, 100, 2 * DAY).unwrap();
+            state.react(pr(3), 2, 100, 2 * DAY).unwrap();
 
-            let leaf = Post::create(
+            let ids = |mode| {
+                state
+                    .feed(
+                        mode,
+                        None,
+                        &[],
+                        false,
+                        0,
+                        CONFIG.feed_page_size,
+                        2 * DAY + HOUR,
+                    )
+                    .into_iter()
+                    .map(|post| post.id)
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(ids(FeedMode::New), vec![2, 1, 0]);
+            assert_eq!(ids(FeedMode::Top(TopWindow::Day)), vec![2]);
+            assert_eq!(ids(FeedMode::Top(TopWindow::Week)), vec![1, 2, 0]);
+        });
+    }
+
+    #[test]
+    fn test_languages() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            create_user(state, pr(1));
+            let mut ids = Vec::new();
+            for language in [Some("de"), None, Some("en")] {
+                let id =
+                    Post::create(state, "Test".into(), &[], pr(1), 0, None, None, None).unwrap();
+                Post::mutate(state, &id, |post| {
+                    post.language = language.map(String::from);
+                    Ok(())
+                })
+                .unwrap();
+                ids.push(id);
+            }
+            // comments inherit the language of the parent
+            let comment_id = Post::create(
                 state,
-                "Leaf".to_string(),
+                "Reply".into(),
                 &[],
-                pr(0),
+                pr(1),
                 0,
-                Some(comment_id),
+                Some(ids[0]),
                 None,
                 None,
             )
             .unwrap();
+            assert_eq!(
+                Post::get(state, &comment_id).unwrap().language,
+                Some("de".into())
+            );
 
-            assert_eq!(Post::get(state, &post_id).unwrap().tree_size, 3);
-            assert_eq!(Post::get(state, &comment_id).unwrap().tree_size, 1);
-            assert_eq!(Post::get(state, &leaf).unwrap().tree_size, 0);
+            let feed = |state: &State, languages: &[String]| {
+                state
+                    .feed(
+                        FeedMode::New,
+                        None,
+                        languages,
+                        false,
+                        0,
+                        CONFIG.feed_page_size,
+                        0,
+                    )
+                    .into_iter()
+                    .map(|post| post.id)
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(feed(state, &[]), vec![ids[2], ids[1], ids[0]]);
+            // posts without a language are always included
+            assert_eq!(feed(state, &["en".into()]), vec![ids[2], ids[1]]);
+            assert_eq!(
+                feed(state, &["de".into(), "fr".into()]),
+                vec![ids[1], ids[0]]
+            );
+
+            assert_eq!(
+                state.set_languages(pr(1), vec!["eng".into()]),
+                Err("invalid language code eng".into())
+            );
+            assert_eq!(
+                state.set_languages(pr(1), vec!["en".into(); CONFIG.max_user_languages + 1]),
+                Err(format!(
+                    "not more than {} languages are allowed",
+                    CONFIG.max_user_languages
+                ))
+            );
+            assert_eq!(
+                state.set_languages(pr(1), vec!["fr".into(), "en".into(), "fr".into()]),
+                Ok(())
+            );
+            assert_eq!(
+                state.principal_to_user(pr(1)).unwrap().languages,
+                vec!["fr".to_string(), "en".to_string()]
+            );
+        });
+    }
+
+    #[test]
+    fn test_content_warnings() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let author_id = create_user(state, pr(0));
+            let stalwart_id = create_user(state, pr(1));
+            state.users.get_mut(&stalwart_id).unwrap().stalwart = true;
+            let viewer_id = create_user(state, pr(2));
+            let ids = (0..3)
+                .map(|i| {
+                    Post::create(
+                        state,
+                        format!("Post {}", i),
+                        &[],
+                        pr(0),
+                        0,
+                        None,
+                        None,
+                        None,
+                    )
+                    .unwrap()
+                })
+                .collect::<Vec<_>>();
+            Post::mutate(state, &ids[1], |post| {
+                post.content_warning = Some(ContentWarning::Spoiler);
+                Ok(())
+            })
+            .unwrap();
+
+            let feed = |state: &State, hide_flagged| {
+                state
+                    .feed(FeedMode::New, None, &[], hide_flagged, 0, 10, 0)
+                    .into_iter()
+                    .map(|post| post.id)
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(feed(state, false), vec![ids[2], ids[1], ids[0]]);
+            assert_eq!(feed(state, true), vec![ids[2], ids[0]]);
 
-            // React from both users
-            assert!(state.react(pr(1), post_id, 100, 0).is_ok());
-            assert!(state.react(pr(2), post_id, 50, 0).is_ok());
+            assert!(state.toggle_following_user(pr(2), author_id));
+            assert_eq!(
+                state.set_content_warnings(pr(2), ContentWarnings::Hide),
+                Ok(())
+            );
+            let viewer = state.users.get(&viewer_id).unwrap();
+            assert_eq!(
+                viewer
+                    .personal_feed(state, 0, 10, true)
+                    .map(|post| post.id)
+                    .collect::<Vec<_>>(),
+                vec![ids[2], ids[0]]
+            );
 
+            // stalwarts can cover reported posts
             assert_eq!(
-                state.users.get(&id).unwrap().karma_to_reward(),
-                10 + 5 + 2 * CONFIG.response_reward
+                state.add_content_warning(pr(2), ids[2], ContentWarning::Nsfw),
+                Err("only stalwarts can add content warnings".into())
+            );
+            assert_eq!(
+                state.add_content_warning(pr(1), ids[2], ContentWarning::Nsfw),
+                Err("only reported posts can get content warnings".into())
+            );
+            assert_eq!(
+                state.report(pr(2), "post".into(), ids[2], "nsfw".into()),
+                Ok(())
+            );
+            assert_eq!(
+                state.add_content_warning(pr(1), ids[2], ContentWarning::Nsfw),
+                Ok(())
+            );
+            assert_eq!(
+                Post::get(state, &ids[2]).unwrap().content_warning,
+                Some(ContentWarning::Nsfw)
+            );
+            assert_eq!(
+                state.add_content_warning(pr(1), ids[1], ContentWarning::Nsfw),
+                Err("only reported posts can get content warnings".into())
+            );
+            assert_eq!(
+                state.add_content_warning(pr(1), ids[2], ContentWarning::Spoiler),
+                Err("post already has a content warning".into())
             );
+            assert_eq!(feed(state, true), vec![ids[0]]);
+        })
+    }
 
+    #[test]
+    fn test_impressions() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let author_id = create_user(state, pr(0));
+            let viewer_id = create_user(state, pr(1));
+            create_user(state, pr(2));
+            let post_id =
+                Post::create(state, "Hello".into(), &[], pr(0), 0, None, None, None).unwrap();
+            let impressions =
+                |state: &State| state.impressions(Post::get(state, &post_id).unwrap());
+
+            // nothing is counted until the author opts in
+            assert_eq!(state.record_impressions(pr(1), vec![post_id], 0), Ok(()));
+            assert_eq!(impressions(state), None);
+            assert_eq!(state.set_impressions_enabled(pr(0), true), Ok(()));
+
+            // own views and anonymous callers don't count
+            assert_eq!(state.record_impressions(pr(0), vec![post_id], 0), Ok(()));
+            assert_eq!(impressions(state), None);
             assert_eq!(
-                state.users.get_mut(&upvoter_id).unwrap().cycles(),
-                // reward + fee + post creation
-                upvoter_cycles - 10 - 1 - 2
+                state.record_impressions(pr(5), vec![post_id], 0),
+                Err("no user found".into())
             );
 
-            let versions = vec!["a".into(), "b".into()];
+            // repeated views within the window are deduplicated
+            assert_eq!(state.record_impressions(pr(1), vec![post_id], 0), Ok(()));
+            assert_eq!(state.record_impressions(pr(1), vec![post_id], HOUR), Ok(()));
+            assert_eq!(state.record_impressions(pr(2), vec![post_id], HOUR), Ok(()));
+            assert_eq!(impressions(state), Some(2));
+
+            let window = CONFIG.impression_window_hours * HOUR;
             assert_eq!(
-                state.delete_post(pr(1), post_id, versions.clone()),
-                Err("not authorized".into())
+                state.record_impressions(pr(1), vec![post_id, 777], window),
+                Ok(())
+            );
+            assert_eq!(impressions(state), Some(3));
+            assert_eq!(
+                state.recent_impressions.get(&(viewer_id, post_id)),
+                Some(&window)
             );
 
-            state
-                .charge(id, state.users.get(&id).unwrap().cycles(), "")
-                .unwrap();
+            // counts are only buffered until the chores flush them into the post
+            assert_eq!(Post::get(state, &post_id).unwrap().impressions, None);
+            state.flush_impressions();
+            assert!(state.pending_impressions.is_empty());
+            assert_eq!(Post::get(state, &post_id).unwrap().impressions, Some(3));
+            assert_eq!(impressions(state), Some(3));
+
+            // indexers never see the counter
+            assert!(state
+                .posts_since(None, 10)
+                .posts
+                .iter()
+                .all(|(_, post)| post.impressions.is_none()));
+
             assert_eq!(
-                state.delete_post(pr(0), post_id, versions.clone()),
-                Err("not enough cycles (this post requires 47 cycles to be deleted)".into())
+                state.record_impressions(
+                    pr(1),
+                    vec![post_id; CONFIG.max_feed_page_size + 1],
+                    window
+                ),
+                Err("too many posts".into())
             );
 
-            state
-                .users
-                .get_mut(&id)
-                .unwrap()
-                .change_cycles(1000, CyclesDelta::Plus, "")
-                .unwrap();
+            // only the author and realm controllers can see the counter
+            let post = Post::get(state, &post_id).unwrap();
+            assert!(state.counts_impressions(viewer_id, post));
+            assert!(!state.counts_impressions(author_id, post));
+            let mut archived = post.clone();
+            archived.archived = true;
+            assert!(!state.counts_impressions(viewer_id, &archived));
+            assert!(post.impressions_visible_to(author_id, None));
+            assert!(!post.impressions_visible_to(viewer_id, None));
+            let realm = Realm {
+                controllers: vec![viewer_id],
+                ..Default::default()
+            };
+            assert!(post.impressions_visible_to(viewer_id, Some(&realm)));
+        });
+    }
 
-            assert_eq!(&Post::get(state, &0).unwrap().body, "Test");
-            assert_eq!(state.delete_post(pr(0), post_id, versions.clone()), Ok(()));
-            assert_eq!(&Post::get(state, &0).unwrap().body, "");
-            assert_eq!(Post::get(state, &0).unwrap().hashes.len(), versions.len());
+    #[test]
+    fn test_anonymous_caller() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let anon = Principal::anonymous();
+            create_user(state, pr(0));
+            let post_id =
+                Post::create(state, "Hello".into(), &[], pr(0), 0, None, None, None).unwrap();
+            state.realms.insert("TEST".into(), Realm::default());
 
+            // public feeds are served without viewer context
+            let feed = state.feed(FeedMode::New, None, &[], false, 0, 10, 0);
+            assert_eq!(feed.len(), 1);
+            assert!(feed[0].viewer.is_none());
+            assert_eq!(state.hot_posts(None, &[], false, 0, 10).len(), 0);
+            assert_eq!(state.last_posts(None, false).count(), 1);
+
+            // personal queries return empty results or clear errors
+            assert!(state.invites(anon, time()).is_empty());
+            assert!(state.reacted_posts(anon, None, 0).is_empty());
+            assert!(state.stalwart_status(anon, time()).is_none());
+            assert!(state.reward_preview(anon).is_none());
             assert_eq!(
-                state.users.get(&upvoter_id).unwrap().cycles(),
-                // reward received back
-                upvoter_cycles - 10 - 1 - 2 + 10
+                state.export_posts(anon, 0).map(|export| export.posts.len()),
+                Err("no user found".into())
             );
-            assert_eq!(state.users.get(&id).unwrap().karma_to_reward(), 0);
 
+            // updates are rejected without trapping
             assert_eq!(
-                state.react(pr(1), post_id, 1, 0),
-                Err("post deleted".into())
+                state.react(anon, post_id, 100, 0),
+                Err("no user for principal found".into())
+            );
+            assert!(!state.toggle_following_user(anon, 0));
+            assert!(!state.toggle_following_realm(anon, "TEST".into()));
+            assert_eq!(
+                state.toggle_realm_membership(anon, "TEST".into()),
+                Err("no user found".into())
+            );
+            assert_eq!(
+                state.record_impressions(anon, vec![post_id], 0),
+                Err("no user found".into())
             );
+            assert_eq!(
+                state.set_content_warnings(anon, ContentWarnings::Hide),
+                Err("no user found".into())
+            );
+            let proposals = state.proposals.len();
+            proposals::cancel_proposal(state, anon, 0);
+            assert_eq!(state.proposals.len(), proposals);
+        });
+    }
+
+    #[test]
+    fn test_posts_in_range() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            create_user(state, pr(0));
+            for i in 0..6 {
+                Post::create(
+                    state,
+                    format!("Post {}", i),
+                    &[],
+                    pr(0),
+                    i * HOUR,
+                    None,
+                    None,
+                    None,
+                )
+                .unwrap();
+            }
+            let ids = |range: RangeInclusive<u64>| {
+                state
+                    .posts_in_range(range, 0)
+                    .into_iter()
+                    .map(|post| post.id)
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(ids(2 * HOUR..=4 * HOUR), vec![2, 3, 4]);
+            assert_eq!(ids(2 * HOUR + 1..=4 * HOUR - 1), vec![3]);
+            assert_eq!(ids(0..=u64::MAX), vec![0, 1, 2, 3, 4, 5]);
+            assert!(ids(6 * HOUR..=u64::MAX).is_empty());
         });
     }
 
     #[actix_rt::test]
-    async fn test_realms() {
-        let (p1, realm_name) = STATE.with(|cell| {
+    async fn test_search_after_edit() {
+        let p = pr(0);
+        let id = STATE.with(|cell| {
             cell.replace(Default::default());
             let state = &mut *cell.borrow_mut();
-            let p0 = pr(0);
-            let p1 = pr(1);
-            let _u0 = create_user_with_params(state, p0, "user1", true, 1000);
-            let _u1 = create_user_with_params(state, p1, "user2", true, 1000);
+            create_user(state, p);
+            Post::create(
+                state,
+                "Initial draft of the canister docs".to_string(),
+                &[],
+                p,
+                0,
+                None,
+                None,
+                None,
+            )
+            .unwrap()
+        });
+        let post_ids = |term: &str| {
+            read(|state| {
+                state
+                    .search(term.to_string(), 0, None, None, 0..=u64::MAX)
+                    .into_iter()
+                    .filter(|result| result.result == "post")
+                    .map(|result| result.id)
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(post_ids("draft"), vec![id]);
+        assert_eq!(post_ids("canisters"), vec![id]);
 
-            let user1 = state.users.get_mut(&_u1).unwrap();
-            assert_eq!(user1.cycles(), 1000);
-            user1.change_cycles(500, CyclesDelta::Minus, "").unwrap();
-            assert_eq!(user1.cycles(), 500);
+        assert_eq!(
+            Post::edit(
+                id,
+                "Final roadmap of the canister docs".to_string(),
+                vec![],
+                "".to_string(),
+                None,
+                p,
+                time(),
+            )
+            .await,
+            Ok(())
+        );
 
-            let name = "TAGGRDAO".to_string();
-            let description = "Test description".to_string();
-            let controllers = vec![_u0];
+        assert!(post_ids("draft").is_empty());
+        assert_eq!(post_ids("roadmap"), vec![id]);
+        assert_eq!(post_ids("canister docs"), vec![id]);
 
-            // simple creation and description change edge cases
-            assert_eq!(
-                state.create_realm(
-                    pr(2),
-                    name.clone(),
-                    Default::default(),
-                    Default::default(),
-                    Default::default(),
-                    description.clone(),
-                    controllers.clone()
-                ),
-                Err("no user found".to_string())
+        // scoped searches
+        let (author_id, id2) = STATE.with(|cell| {
+            let state = &mut *cell.borrow_mut();
+            let author_id = create_user(state, pr(1));
+            let id2 = Post::create(
+                state,
+                "The #roadmap of realms".to_string(),
+                &[],
+                pr(1),
+                0,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+            (author_id, id2)
+        });
+        assert_eq!(post_ids("roadmap"), vec![id2, id]);
+        read(|state| {
+            let results = state.search("roadmap".into(), 0, None, Some(author_id), 0..=u64::MAX);
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].id, id2);
+            assert_eq!(results[0].matched, "tag");
+            let results = state.search("canister".into(), 0, None, Some(author_id), 0..=u64::MAX);
+            assert!(results.is_empty());
+            let results = state.search(
+                "roadmap".into(),
+                0,
+                Some("NO_REALM".into()),
+                None,
+                0..=u64::MAX,
             );
+            assert!(results.is_empty());
+        });
+    }
 
-            assert_eq!(
-                state.create_realm(
-                    p1,
-                    name.clone(),
-                    Default::default(),
-                    Default::default(),
-                    Default::default(),
-                    description.clone(),
-                    controllers.clone()
-                ),
-                Err(
-                    "couldn't charge 1000 cycles for realm creation: not enough cycles".to_string()
-                )
-            );
+    #[test]
+    fn test_covered_by_feed() {
+        let m = |v: Vec<&str>| v.into_iter().map(|v| v.to_string()).collect();
+        let tests = vec![
+            (
+                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
+                m(vec!["tag1"]),
+                true,
+                None,
+            ),
+            (
+                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
+                m(vec!["tag1", "tag2"]),
+                false,
+                Some(0),
+            ),
+            (
+                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
+                m(vec!["tag1", "tag2"]),
+                true,
+                Some(0),
+            ),
+            (
+                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
+                m(vec!["tag1", "tag2", "tag3"]),
+                true,
+                None,
+            ),
+            (
+                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
+                m(vec!["tag1", "tag2", "tag3"]),
+                false,
+                Some(0),
+            ),
+            (
+                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
+                m(vec!["tagX", "tag2", "tag3"]),
+                false,
+                Some(1),
+            ),
+        ];
 
-            assert_eq!(
-                state.create_realm(
-                    p0,
-                    "THIS_NAME_IS_TOO_LONG".to_string(),
-                    Default::default(),
-                    Default::default(),
-                    Default::default(),
-                    description.clone(),
-                    controllers.clone()
-                ),
-                Err("realm name too long".to_string())
-            );
+        for (i, t) in tests.iter().enumerate() {
+            let (feeds, tags, strict, result) = t;
+            if covered_by_feeds(feeds, tags, *strict) != *result {
+                panic!("Test {} failed", i)
+            }
+        }
+    }
+
+    #[test]
+    fn test_user_by_handle() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+
+            let u1 = create_user_with_params(state, pr(0), "user1", true, 1000);
+            let u2 = create_user_with_params(state, pr(1), "user2", true, 1000);
+            let u3 = create_user_with_params(state, pr(2), "user3", true, 1000);
+
+            assert_eq!(state.user("user1").unwrap().id, u1);
+            assert_eq!(state.user("0").unwrap().id, u1);
+            assert_eq!(state.user("user2").unwrap().id, u2);
+            assert_eq!(state.user("1").unwrap().id, u2);
+            assert_eq!(state.user("user3").unwrap().id, u3);
+            assert_eq!(state.user("2").unwrap().id, u3);
+            assert!(state.user("user22").is_none());
+        });
+    }
 
+    #[test]
+    fn test_feed_notifications() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            for i in 0..3 {
+                create_user(state, pr(i));
+            }
+            let feed = vec!["Rust".to_string()];
+            for i in 1..3 {
+                let user = state.principal_to_user_mut(pr(i)).unwrap();
+                assert_eq!(
+                    user.toggle_feed_notifications(feed.clone()),
+                    Err("feed is not followed".into())
+                );
+                assert!(user.toggle_following_feed(feed.clone()));
+            }
             assert_eq!(
-                state.create_realm(
-                    p0,
-                    name.clone(),
-                    Default::default(),
-                    Default::default(),
-                    Default::default(),
-                    description.clone(),
-                    vec![]
-                ),
-                Err("no controllers specified".to_string())
+                state.toggle_feed_notifications(pr(1), feed.clone()),
+                Ok(true)
             );
+            // the subscribers index is rebuilt on upgrades
+            state.feed_subscribers.clear();
+            state.index_feed_subscribers();
 
+            let feed_notifications = |state: &State, i| {
+                state
+                    .principal_to_user(pr(i))
+                    .unwrap()
+                    .notifications(Some(NotificationType::Feed))
+                    .len()
+            };
+            let post_id =
+                Post::create(state, "#Rust #IC".into(), &[], pr(0), 0, None, None, None).unwrap();
+            Post::create(state, "#ic".into(), &[], pr(0), 0, None, None, None).unwrap();
+            // comments don't trigger feed notifications
+            Post::create(
+                state,
+                "#rust".into(),
+                &[],
+                pr(0),
+                0,
+                Some(post_id),
+                None,
+                None,
+            )
+            .unwrap();
+            assert_eq!(feed_notifications(state, 1), 1);
+            assert_eq!(feed_notifications(state, 2), 0);
+
+            // notifications beyond the hourly limit are dropped
+            let user = state.principal_to_user_mut(pr(1)).unwrap();
+            let tags = std::iter::once("rust".to_string()).collect();
+            for _ in 0..CONFIG.max_feed_notifications_per_hour {
+                user.notify_about_feed_match(&tags, "test", 0, 0);
+            }
             assert_eq!(
-                state.create_realm(
-                    p0,
-                    "TEST NAME".to_string(),
-                    Default::default(),
-                    Default::default(),
-                    Default::default(),
-                    description.clone(),
-                    controllers.clone()
-                ),
-                Err("realm name should be an alpha-numeric string".to_string(),)
+                feed_notifications(state, 1),
+                CONFIG.max_feed_notifications_per_hour as usize
             );
-
+            let user = state.principal_to_user_mut(pr(1)).unwrap();
+            user.notify_about_feed_match(&tags, "test", 0, HOUR);
             assert_eq!(
-                state.create_realm(
-                    p0,
-                    name.clone(),
-                    Default::default(),
-                    Default::default(),
-                    Default::default(),
-                    description.clone(),
-                    controllers.clone()
-                ),
-                Ok(())
+                feed_notifications(state, 1),
+                CONFIG.max_feed_notifications_per_hour as usize + 1
             );
 
-            let user0 = state.users.get_mut(&_u0).unwrap();
-            user0.change_cycles(1000, CyclesDelta::Plus, "").unwrap();
+            // unfollowing the feed disables its notifications
+            let user = state.principal_to_user_mut(pr(1)).unwrap();
+            assert!(!user.toggle_following_feed(feed));
+            assert!(user.notified_feeds.is_empty());
+        })
+    }
 
-            assert_eq!(
-                state.create_realm(
-                    p0,
-                    name.clone(),
-                    Default::default(),
-                    Default::default(),
-                    Default::default(),
-                    description.clone(),
-                    controllers.clone()
-                ),
-                Err("realm name taken".to_string())
-            );
+    #[test]
+    fn test_personal_feed() {
+        STATE.with(|cell| cell.replace(Default::default()));
 
-            assert_eq!(
-                state.realms.get(&name).unwrap().description,
-                "Test description".to_string()
-            );
+        mutate(|state| {
+            // create a post author and one post for its principal
+            let p = pr(0);
+            let post_author_id = create_user(state, p);
+            let post_id = Post::create(
+                state,
+                "This is a #post with #tags".to_string(),
+                &[],
+                p,
+                0,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
 
-            let new_description = "New test description".to_string();
+            // create a user and make sure his feed is empty
+            let pr1 = pr(1);
+            let user_id = create_user(state, pr1);
+            assert!(state
+                .user(&user_id.to_string())
+                .unwrap()
+                .personal_feed(state, 0, CONFIG.feed_page_size, true)
+                .next()
+                .is_none());
 
-            assert_eq!(
-                state.edit_realm(
-                    p0,
-                    name.clone(),
-                    Default::default(),
-                    Default::default(),
-                    Default::default(),
-                    new_description.clone(),
-                    vec![]
-                ),
-                Err("no controllers specified".to_string())
-            );
+            // now we follow post_author_id
+            let _user = state.users.get_mut(&user_id).unwrap();
+            assert!(state.toggle_following_user(pr1, post_author_id));
 
-            assert_eq!(
-                state.edit_realm(
-                    pr(2),
-                    name.clone(),
-                    Default::default(),
-                    Default::default(),
-                    Default::default(),
-                    new_description.clone(),
-                    controllers.clone()
-                ),
-                Err("no user found".to_string())
-            );
+            // make sure the feed contains exactly one post from post_author_id
+            let feed = state
+                .users
+                .get(&user_id)
+                .unwrap()
+                .personal_feed(state, 0, CONFIG.feed_page_size, true)
+                .map(|post| post.id)
+                .collect::<Vec<_>>();
+            assert_eq!(feed.len(), 1);
+            assert!(feed.contains(&post_id));
 
-            assert_eq!(
-                state.edit_realm(
-                    p0,
-                    "WRONGNAME".to_string(),
-                    Default::default(),
-                    Default::default(),
-                    Default::default(),
-                    new_description.clone(),
-                    controllers.clone()
-                ),
-                Err("no realm found".to_string())
-            );
+            // now we follow a feed #post+#tags
+            let user = state.users.get_mut(&user_id).unwrap();
+            assert!(user.toggle_following_feed(
+                vec!["post".to_owned(), "tags".to_owned()]
+                    .into_iter()
+                    .collect()
+            ));
 
-            assert_eq!(
-                state.edit_realm(
-                    p1,
-                    name.clone(),
-                    Default::default(),
-                    Default::default(),
-                    Default::default(),
-                    new_description.clone(),
-                    controllers.clone()
-                ),
-                Err("not authorized".to_string())
-            );
+            // make sure the feed still contains the same post
+            let feed = state
+                .users
+                .get(&user_id)
+                .unwrap()
+                .personal_feed(state, 0, CONFIG.feed_page_size, true)
+                .map(|post| post.id)
+                .collect::<Vec<_>>();
+            assert_eq!(feed.len(), 1);
+            assert!(feed.contains(&post_id));
+
+            // now a different post with the same tags appears
+            let p = pr(2);
+            let _post_author_id = create_user(state, p);
+            let post_id2 = Post::create(
+                state,
+                "This is a different #post, but with the same #tags".to_string(),
+                &[],
+                p,
+                0,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+
+            // make sure the feed contains both posts
+            let feed = state
+                .users
+                .get(&user_id)
+                .unwrap()
+                .personal_feed(state, 0, CONFIG.feed_page_size, true)
+                .map(|post| post.id)
+                .collect::<Vec<_>>();
+            assert_eq!(feed.len(), 2);
+            assert!(feed.contains(&post_id));
+            assert!(feed.contains(&post_id2));
+
+            // yet another post appears
+            let p = pr(3);
+            let _post_author_id = create_user(state, p);
+            let post_id3 = Post::create(
+                state,
+                "Different #post, different #feed".to_string(),
+                &[],
+                p,
+                0,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+
+            // make sure the feed contains the same old posts
+            let feed = state
+                .users
+                .get(&user_id)
+                .unwrap()
+                .personal_feed(state, 0, CONFIG.feed_page_size, true)
+                .map(|post| post.id)
+                .collect::<Vec<_>>();
+            assert_eq!(feed.len(), 2);
+            assert!(feed.contains(&post_id));
+            assert!(feed.contains(&post_id2));
+
+            // now we follow a feed "post"
+            let user = state.users.get_mut(&user_id).unwrap();
+            let tags: Vec<_> = vec!["post".to_string()].into_iter().collect();
+            assert!(user.toggle_following_feed(tags.clone()));
+            // make sure the feed contains the new post
+            let feed = state
+                .users
+                .get(&user_id)
+                .unwrap()
+                .personal_feed(state, 0, CONFIG.feed_page_size, true)
+                .map(|post| post.id)
+                .collect::<Vec<_>>();
+            assert_eq!(feed.len(), 3);
+            assert!(feed.contains(&post_id));
+            assert!(feed.contains(&post_id2));
+            assert!(feed.contains(&post_id3));
+
+            // Make sure we can unsubscribe and the feed gets back to 2 posts
+            let user = state.users.get_mut(&user_id).unwrap();
+            assert!(!user.toggle_following_feed(tags));
+            let feed = state
+                .users
+                .get(&user_id)
+                .unwrap()
+                .personal_feed(state, 0, CONFIG.feed_page_size, true)
+                .map(|post| post.id)
+                .collect::<Vec<_>>();
+            assert_eq!(feed.len(), 2);
+            assert!(feed.contains(&post_id));
+            assert!(feed.contains(&post_id2));
+        });
+    }
 
+    #[test]
+    fn test_following_realms() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let author_id = create_user_with_cycles(state, pr(0), 2000);
+            let reader_id = create_user(state, pr(1));
+            let name = "CRAFT".to_string();
             assert_eq!(
-                state.edit_realm(
-                    p0,
+                state.create_realm(
+                    pr(0),
                     name.clone(),
                     Default::default(),
                     Default::default(),
                     Default::default(),
-                    new_description.clone(),
-                    controllers.clone()
+                    "Test".into(),
+                    vec![author_id]
                 ),
                 Ok(())
             );
-
-            assert_eq!(
-                state.realms.get(&name).unwrap().description,
-                new_description
-            );
-
-            // wrong user and wrong realm joining
-            assert!(!state.toggle_realm_membership(pr(2), name.clone()));
-            assert!(!state.toggle_realm_membership(p1, "WRONGNAME".to_string()));
-
-            assert!(state.toggle_realm_membership(p1, name.clone()));
-            assert!(state.users.get(&_u1).unwrap().realms.contains(&name));
-            assert_eq!(state.realms.get(&name).unwrap().num_members, 1);
-
-            // creating a post in a realm
-            let post_id = Post::create(
+            assert_eq!(state.toggle_realm_membership(pr(0), name.clone()), Ok(true));
+            let realm_post_id = Post::create(
                 state,
-                "Realm post".to_string(),
+                "Realm post".into(),
                 &[],
-                p1,
+                pr(0),
                 0,
                 None,
                 Some(name.clone()),
                 None,
             )
             .unwrap();
-            assert_eq!(state.realms.get(&name).unwrap().num_posts, 1);
+            Post::create(state, "Other post".into(), &[], pr(0), 0, None, None, None).unwrap();
+            let feed = |state: &State| {
+                state
+                    .users
+                    .get(&reader_id)
+                    .unwrap()
+                    .personal_feed(state, 0, CONFIG.feed_page_size, true)
+                    .map(|post| post.id)
+                    .collect::<Vec<_>>()
+            };
+            assert!(feed(state).is_empty());
+
+            assert!(!state.toggle_following_realm(pr(1), "UNKNOWN".into()));
+            assert!(state.toggle_following_realm(pr(1), name.clone()));
+            assert!(!state.users.get(&reader_id).unwrap().realms.contains(&name));
+            assert_eq!(feed(state), vec![realm_post_id]);
+
+            // muted threads of followed realms are excluded
+            let reader = state.users.get_mut(&reader_id).unwrap();
+            assert!(reader.toggle_muting_thread(realm_post_id));
+            assert!(feed(state).is_empty());
+            let reader = state.users.get_mut(&reader_id).unwrap();
+            assert!(!reader.toggle_muting_thread(realm_post_id));
+            assert_eq!(feed(state), vec![realm_post_id]);
+
+            assert!(!state.toggle_following_realm(pr(1), name));
+            assert!(feed(state).is_empty());
+        })
+    }
+
+    #[test]
+    fn test_clean_up() {
+        STATE.with(|cell| cell.replace(Default::default()));
+        mutate(|state| {
+            let inactive_id1 = create_user_with_cycles(state, pr(1), 500);
+            let inactive_id2 = create_user_with_cycles(state, pr(2), 100);
+            let inactive_id3 = create_user_with_cycles(state, pr(3), 200);
+            let active_id = create_user_with_cycles(state, pr(3), 300);
+
+            let user = state.users.get_mut(&inactive_id1).unwrap();
+            assert_eq!(user.karma(), CONFIG.trusted_user_min_karma);
+            let user = state.users.get_mut(&inactive_id3).unwrap();
+            assert_eq!(user.karma(), CONFIG.trusted_user_min_karma);
+            let user = state.users.get_mut(&active_id).unwrap();
+            assert_eq!(user.karma(), CONFIG.trusted_user_min_karma);
+
+            let now = WEEK * 27;
+            state.users.get_mut(&active_id).unwrap().last_activity = now;
+
+            state.clean_up(now);
+
+            let penalty = CONFIG.inactivity_penalty;
 
+            // penalized
+            let user = state.users.get_mut(&inactive_id1).unwrap();
+            assert_eq!(user.cycles(), 500 - penalty);
+            assert_eq!(user.karma(), 0);
+            // not penalized due to low balance, but karma penalized
+            let user = state.users.get_mut(&inactive_id2).unwrap();
+            assert_eq!(user.cycles(), 100);
+            assert_eq!(user.karma(), 0);
+            // penalized to the minimum balance
             assert_eq!(
-                Post::get(state, &post_id).unwrap().realm,
-                Some(name.clone())
+                state.users.get_mut(&inactive_id3).unwrap().cycles(),
+                penalty * 4
             );
-            assert!(realm_posts(state, &name).contains(&post_id));
+            // Active user not penalized
+            let user = state.users.get_mut(&active_id).unwrap();
+            assert_eq!(user.cycles(), 300);
+            assert_eq!(user.karma(), CONFIG.trusted_user_min_karma)
+        })
+    }
 
-            // Posting without realm creates the post in the global realm
-            let post_id = Post::create(
-                state,
-                "Realm post".to_string(),
-                &[],
-                p1,
-                0,
-                None,
-                None,
-                None,
-            )
-            .unwrap();
+    #[test]
+    fn test_cycles_accounting() {
+        STATE.with(|cell| cell.replace(Default::default()));
+        mutate(|state| {
+            let p0 = pr(0);
+            let post_author_id = create_user(state, p0);
+            let post_id =
+                Post::create(state, "test".to_string(), &[], p0, 0, None, None, None).unwrap();
+            let p = pr(1);
+            let p2 = pr(2);
+            let p3 = pr(3);
 
-            assert_eq!(Post::get(state, &post_id).unwrap().realm, None,);
+            let lurker_id = create_user(state, p);
+            create_user(state, p2);
+            create_user(state, p3);
+            let farmer_id = create_untrusted_user(state, pr(111));
+            let c = CONFIG;
+            assert_eq!(state.burned_cycles as Cycles, c.post_cost);
+            state
+                .users
+                .get_mut(&lurker_id)
+                .unwrap()
+                .change_karma(10, "");
+            state.users.get_mut(&lurker_id).unwrap().apply_rewards();
+            // make author to a new user
+            state
+                .users
+                .get_mut(&post_author_id)
+                .unwrap()
+                .change_karma(-CONFIG.trusted_user_min_karma, "");
+            let author = state.users.get(&post_author_id).unwrap();
+            let farmer = state.users.get(&farmer_id).unwrap();
+            let lurker = state.users.get(&lurker_id).unwrap();
+            assert!(!author.trusted());
+            assert!(!farmer.trusted());
+            assert!(lurker.trusted());
+            assert_eq!(author.cycles(), c.native_cycles_per_xdr - c.post_cost);
+            assert_eq!(lurker.cycles(), c.native_cycles_per_xdr);
 
-            // comments not possible if user is not in the realm
+            assert_eq!(author.karma(), 0);
+
+            // react on the new post
+            assert!(state.react(pr(111), post_id, 1, 0).is_err());
+            // this is a noop for author
+            assert!(state.react(pr(111), post_id, 100, 0).is_ok());
+            let burned_cycles_by_reaction_from_untrusted = 11;
             assert_eq!(
-                Post::create(
-                    state,
-                    "comment".to_string(),
-                    &[],
-                    p0,
-                    0,
-                    Some(0),
-                    None,
-                    None
-                ),
-                Err("not a member of the realm TAGGRDAO".to_string())
+                state.users.get(&post_author_id).unwrap().cycles(),
+                c.native_cycles_per_xdr - c.post_cost
             );
+            assert!(state.react(p, post_id, 50, 0).is_ok());
+            assert!(state.react(p2, post_id, 100, 0).is_ok());
+            let reaction_costs_1 = 6;
+            let burned_cycles_by_reactions = 1 + 1;
+            let mut rewards_from_reactions = 5 + 10;
 
-            assert!(state.toggle_realm_membership(p0, name.clone()));
-            assert_eq!(state.realms.get(&name).unwrap().num_members, 2);
+            // try to self upvote (should be a no-op)
+            assert!(state.react(p0, post_id, 100, 0).is_err());
 
+            let author = state.users.get(&post_author_id).unwrap();
+            assert_eq!(author.cycles(), c.native_cycles_per_xdr - c.post_cost);
+            assert_eq!(author.karma_to_reward(), rewards_from_reactions);
             assert_eq!(
-                Post::create(
-                    state,
-                    "comment".to_string(),
-                    &[],
-                    p0,
-                    0,
-                    Some(0),
-                    None,
-                    None
-                ),
-                Ok(2)
+                state.burned_cycles as Cycles,
+                c.post_cost + burned_cycles_by_reactions + burned_cycles_by_reaction_from_untrusted
             );
 
-            assert!(realm_posts(state, &name).contains(&2));
+            let lurker = state.users.get(&lurker_id).unwrap();
+            assert_eq!(lurker.cycles(), c.native_cycles_per_xdr - reaction_costs_1);
 
-            // Create post without a realm
+            // downvote
+            assert!(state.react(p3, post_id, 1, 0).is_ok());
+            let reaction_penalty = 3;
+            rewards_from_reactions -= 3;
+            let author = state.users.get(&post_author_id).unwrap();
+            let lurker_3 = state.principal_to_user(p3).unwrap();
+            assert_eq!(
+                author.cycles(),
+                c.native_cycles_per_xdr - c.post_cost - reaction_penalty
+            );
+            assert_eq!(author.karma_to_reward(), rewards_from_reactions);
+            assert_eq!(lurker_3.cycles(), c.native_cycles_per_xdr - 3);
+            assert_eq!(
+                state.burned_cycles,
+                (c.post_cost
+                    + burned_cycles_by_reactions
+                    + burned_cycles_by_reaction_from_untrusted
+                    + 2 * 3) as i64
+            );
 
-            let post_id = Post::create(
-                state,
-                "No realm post".to_string(),
-                &[],
-                p1,
-                0,
-                None,
-                None,
-                None,
-            )
-            .unwrap();
-            let comment_id = Post::create(
-                state,
-                "comment".to_string(),
-                &[],
-                p0,
-                0,
-                Some(post_id),
-                None,
-                None,
-            )
-            .unwrap();
+            Post::create(state, "test".to_string(), &[], p0, 0, Some(0), None, None).unwrap();
+
+            let c = CONFIG;
+            assert_eq!(
+                state.burned_cycles,
+                (2 * c.post_cost
+                    + burned_cycles_by_reactions
+                    + burned_cycles_by_reaction_from_untrusted
+                    + 2 * 3) as i64
+            );
+            let author = state.users.get(&post_author_id).unwrap();
+            assert_eq!(
+                author.cycles(),
+                c.native_cycles_per_xdr - c.post_cost - c.post_cost - reaction_penalty
+            );
+
+            let author = state.users.get_mut(&post_author_id).unwrap();
+            author
+                .change_cycles(author.cycles(), CyclesDelta::Minus, "")
+                .unwrap();
+
+            assert!(Post::create(state, "test".to_string(), &[], p0, 0, None, None, None).is_err());
+
+            create_user(state, pr(10));
+            let lurker = state.principal_to_user_mut(pr(10)).unwrap();
+            lurker
+                .change_cycles(lurker.cycles(), CyclesDelta::Minus, "")
+                .unwrap();
+            assert_eq!(
+                state.react(pr(10), post_id, 10, 0),
+                Err("not enough cycles".into())
+            );
+        })
+    }
 
-            assert_eq!(Post::get(state, &comment_id).unwrap().realm, None);
+    #[test]
+    fn test_reaction_toggling() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let author_id = create_user(state, pr(0));
+            let reactor_id = create_user(state, pr(1));
+            create_untrusted_user(state, pr(2));
+            let post_id =
+                Post::create(state, "Test".into(), &[], pr(0), 0, None, None, None).unwrap();
+            let author_cycles = state.users.get(&author_id).unwrap().cycles();
+            let total_karma = |state: &State| {
+                let author = state.users.get(&author_id).unwrap();
+                author.karma() + author.karma_to_reward() as Karma
+            };
+            let author_karma = total_karma(state);
+            let reactor_cycles = |state: &State| state.users.get(&reactor_id).unwrap().cycles();
+            let burned_cycles = state.burned_cycles;
+            let reactions = |state: &State| {
+                Post::get(state, &post_id)
+                    .unwrap()
+                    .reactions
+                    .iter()
+                    .map(|(id, users)| (*id, users.iter().copied().collect::<Vec<_>>()))
+                    .collect::<Vec<_>>()
+            };
 
-            // Creating post without entering the realm
-            let realm_name = "NEW_REALM".to_string();
+            // add
+            assert_eq!(state.react(pr(1), post_id, 100, 0), Ok(()));
+            assert_eq!(reactor_cycles(state), 1000 - 10 - CONFIG.reaction_fee);
+            assert_eq!(total_karma(state), author_karma + 10);
+            assert_eq!(reactions(state), vec![(100, vec![reactor_id])]);
+
+            // switch to another upvote
+            assert_eq!(state.react(pr(1), post_id, 50, 0), Ok(()));
+            assert_eq!(reactor_cycles(state), 1000 - 5 - 2 * CONFIG.reaction_fee);
+            assert_eq!(total_karma(state), author_karma + 5);
+            assert_eq!(reactions(state), vec![(50, vec![reactor_id])]);
+
+            // switch to a downvote
+            assert_eq!(state.react(pr(1), post_id, 1, 0), Ok(()));
+            assert_eq!(reactor_cycles(state), 1000 - 3 - 2 * CONFIG.reaction_fee);
+            assert_eq!(total_karma(state), author_karma - 3);
             assert_eq!(
-                Post::create(
-                    state,
-                    "test".to_string(),
-                    &[],
-                    p0,
-                    0,
-                    None,
-                    Some(realm_name.clone()),
-                    None
-                ),
-                Err(format!("not a member of the realm {}", realm_name))
+                state.users.get(&author_id).unwrap().cycles(),
+                author_cycles - 3
+            );
+            assert_eq!(reactions(state), vec![(1, vec![reactor_id])]);
+
+            // remove; only the fees are not refunded
+            assert_eq!(state.react(pr(1), post_id, 1, 0), Ok(()));
+            assert_eq!(reactor_cycles(state), 1000 - 2 * CONFIG.reaction_fee);
+            assert_eq!(total_karma(state), author_karma);
+            assert_eq!(state.users.get(&author_id).unwrap().cycles(), author_cycles);
+            assert!(reactions(state).is_empty());
+            assert!(Post::get(state, &post_id)
+                .unwrap()
+                .reaction_charges
+                .is_empty());
+            assert_eq!(
+                state.burned_cycles,
+                burned_cycles + 2 * CONFIG.reaction_fee as i64
             );
 
-            // create a new realm
-            let user0 = state.users.get_mut(&_u0).unwrap();
-            user0.change_cycles(1000, CyclesDelta::Plus, "").unwrap();
+            // reactions of untrusted users are refunded as well
+            assert_eq!(state.react(pr(2), post_id, 50, 0), Ok(()));
+            assert_eq!(state.react(pr(2), post_id, 50, 0), Ok(()));
+            assert_eq!(state.principal_to_user(pr(2)).unwrap().cycles(), 999);
+            assert_eq!(total_karma(state), author_karma);
             assert_eq!(
-                state.create_realm(
-                    p0,
-                    realm_name.clone(),
-                    Default::default(),
-                    Default::default(),
-                    Default::default(),
-                    description,
-                    controllers
-                ),
-                Ok(())
+                state.burned_cycles,
+                burned_cycles + 3 * CONFIG.reaction_fee as i64
             );
+        })
+    }
 
-            // we still can't post into it, because we didn't join
+    #[test]
+    fn test_reaction_refunds_end_with_minting() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let author_id = create_user(state, pr(0));
+            let reactor_id = create_user(state, pr(1));
+            let post_id =
+                Post::create(state, "Test".into(), &[], pr(0), 0, None, None, None).unwrap();
+            let total_karma = |state: &State| {
+                let author = state.users.get(&author_id).unwrap();
+                author.karma() + author.karma_to_reward() as Karma
+            };
+            let reactor_cycles = |state: &State| state.users.get(&reactor_id).unwrap().cycles();
+
+            assert_eq!(state.react(pr(1), post_id, 100, 0), Ok(()));
+            let karma = total_karma(state);
+            let cycles = reactor_cycles(state);
+
+            // the karma was collected for minting, so the removal refunds nothing
+            state.collect_new_karma();
+            assert_eq!(state.react(pr(1), post_id, 100, 0), Ok(()));
+            assert_eq!(total_karma(state), karma);
+            assert_eq!(reactor_cycles(state), cycles);
+            assert!(Post::get(state, &post_id).unwrap().reactions.is_empty());
+
+            // a charge that can't be paid keeps the previous reaction
+            assert_eq!(state.react(pr(1), post_id, 50, 0), Ok(()));
+            let cycles = reactor_cycles(state);
+            state
+                .users
+                .get_mut(&reactor_id)
+                .unwrap()
+                .change_cycles(cycles - 5, CyclesDelta::Minus, "")
+                .unwrap();
             assert_eq!(
-                Post::create(
-                    state,
-                    "test".to_string(),
-                    &[],
-                    p0,
-                    0,
-                    None,
-                    Some(realm_name.clone()),
-                    None
-                ),
-                Err(format!("not a member of the realm {}", realm_name))
+                state.react(pr(1), post_id, 100, 0),
+                Err("not enough cycles".into())
+            );
+            assert_eq!(
+                Post::get(state, &post_id)
+                    .unwrap()
+                    .reactions
+                    .keys()
+                    .copied()
+                    .collect::<Vec<_>>(),
+                vec![50]
             );
+        })
+    }
 
-            // join the realm and create the post without entering
-            assert!(state.toggle_realm_membership(p1, realm_name.clone()));
-            assert!(state.users.get(&_u1).unwrap().realms.contains(&name));
+    #[test]
+    fn test_reacted_posts() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            create_user(state, pr(0));
+            create_user(state, pr(1));
+            let ids = (0..3)
+                .map(|i| {
+                    Post::create(
+                        state,
+                        format!("Post {}", i),
+                        &[],
+                        pr(0),
+                        0,
+                        None,
+                        None,
+                        None,
+                    )
+                    .unwrap()
+                })
+                .collect::<Vec<_>>();
+            let reacted = |state: &State, reaction| {
+                state
+                    .reacted_posts(pr(1), reaction, 0)
+                    .into_iter()
+                    .map(|post| post.id)
+                    .collect::<Vec<_>>()
+            };
 
-            assert_eq!(state.realms.get(&realm_name).unwrap().num_members, 1);
-            assert_eq!(state.realms.get(&realm_name).unwrap().num_posts, 0);
+            assert_eq!(state.react(pr(1), ids[0], 100, 0), Ok(()));
+            assert_eq!(state.react(pr(1), ids[1], 50, 0), Ok(()));
+            assert_eq!(state.react(pr(1), ids[2], 100, 0), Ok(()));
+            assert_eq!(reacted(state, None), vec![ids[2], ids[1], ids[0]]);
+            assert_eq!(reacted(state, Some(100)), vec![ids[2], ids[0]]);
+            assert!(state.reacted_posts(pr(0), None, 0).is_empty());
+
+            // switching the reaction moves the post to the front
+            assert_eq!(state.react(pr(1), ids[0], 50, 0), Ok(()));
+            assert_eq!(reacted(state, Some(50)), vec![ids[0], ids[1]]);
+
+            // removed reactions and deleted posts are excluded
+            assert_eq!(state.react(pr(1), ids[1], 50, 0), Ok(()));
+            assert_eq!(state.delete_post(pr(0), ids[2], vec![], 0), Ok(()));
+            assert_eq!(reacted(state, None), vec![ids[0]]);
+        })
+    }
+
+    #[test]
+    fn test_asymmetric_reaction_costs() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let author_id = create_user(state, pr(0));
+            let reactor_id = create_user(state, pr(1));
+            let post_id =
+                Post::create(state, "Test".into(), &[], pr(0), 0, None, None, None).unwrap();
+            let reaction = |id, karma: Karma, cost| Reaction {
+                id,
+                emoji: "🚀".into(),
+                karma,
+                positive: karma > 0,
+                cost,
+            };
+            state.reaction_catalog = vec![reaction(1, -2, 8), reaction(2, 5, 7), reaction(3, 4, 0)];
+            assert_eq!(state.reactions().get(&3).unwrap().cost, 4);
 
+            let author_cycles = state.users.get(&author_id).unwrap().cycles();
+            let total_karma = |state: &State| {
+                let author = state.users.get(&author_id).unwrap();
+                author.karma() + author.karma_to_reward() as Karma
+            };
+            let author_karma = total_karma(state);
+            let reactor_cycles = |state: &State| state.users.get(&reactor_id).unwrap().cycles();
+            let burned_cycles = state.burned_cycles;
+
+            // the reactor pays more than the author gains, the rest is burned
+            assert_eq!(state.react(pr(1), post_id, 2, 0), Ok(()));
+            assert_eq!(reactor_cycles(state), 1000 - 7 - CONFIG.reaction_fee);
+            assert_eq!(total_karma(state), author_karma + 5);
             assert_eq!(
-                Post::create(
-                    state,
-                    "test".to_string(),
-                    &[],
-                    p1,
-                    0,
-                    None,
-                    Some(realm_name.clone()),
-                    None
-                ),
-                Ok(5)
+                state.burned_cycles,
+                burned_cycles + 2 + CONFIG.reaction_fee as i64
             );
-            assert_eq!(state.realms.get(&realm_name).unwrap().num_posts, 1);
-
-            assert!(state
-                .users
-                .get(&_u1)
-                .unwrap()
-                .realms
-                .contains(&"TAGGRDAO".to_string()));
-            (p1, realm_name)
-        });
 
-        // Move the post to non-joined realm
-        assert_eq!(
-            Post::edit(
-                5,
-                "changed".to_string(),
-                vec![],
-                "".to_string(),
-                Some("TAGGRDAO_X".to_string()),
-                p1,
-                time(),
-            )
-            .await,
-            Err("you're not in the realm".into()),
-        );
-
-        read(|state| {
-            assert_eq!(Post::get(state, &5).unwrap().realm, Some(realm_name));
-            assert_eq!(state.realms.get("TAGGRDAO").unwrap().num_posts, 2);
-        });
-        assert_eq!(
-            Post::edit(
-                5,
-                "changed".to_string(),
-                vec![],
-                "".to_string(),
-                Some("TAGGRDAO".to_string()),
-                p1,
-                time(),
-            )
-            .await,
-            Ok(())
-        );
+            // a downvote costs the reactor more than the karma it removes
+            assert_eq!(state.react(pr(1), post_id, 1, 0), Ok(()));
+            assert_eq!(reactor_cycles(state), 1000 - 8 - CONFIG.reaction_fee);
+            assert_eq!(total_karma(state), author_karma - 2);
+            assert_eq!(
+                state.users.get(&author_id).unwrap().cycles(),
+                author_cycles - 2
+            );
 
-        read(|state| {
-            assert_eq!(state.realms.get("NEW_REALM").unwrap().num_posts, 0);
-            assert_eq!(state.realms.get("TAGGRDAO").unwrap().num_posts, 3);
+            // removal refunds both sides
+            assert_eq!(state.react(pr(1), post_id, 1, 0), Ok(()));
+            assert_eq!(reactor_cycles(state), 1000 - CONFIG.reaction_fee);
+            assert_eq!(total_karma(state), author_karma);
+            assert_eq!(state.users.get(&author_id).unwrap().cycles(), author_cycles);
             assert_eq!(
-                Post::get(state, &5).unwrap().realm,
-                Some("TAGGRDAO".to_string())
+                state.burned_cycles,
+                burned_cycles + CONFIG.reaction_fee as i64
             );
-        });
+        })
     }
 
     #[test]
-    fn test_covered_by_feed() {
-        let m = |v: Vec<&str>| v.into_iter().map(|v| v.to_string()).collect();
-        let tests = vec![
-            (
-                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
-                m(vec!["tag1"]),
-                true,
-                None,
-            ),
-            (
-                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
-                m(vec!["tag1", "tag2"]),
-                false,
-                Some(0),
-            ),
-            (
-                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
-                m(vec!["tag1", "tag2"]),
-                true,
-                Some(0),
-            ),
-            (
-                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
-                m(vec!["tag1", "tag2", "tag3"]),
-                true,
-                None,
-            ),
-            (
-                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
-                m(vec!["tag1", "tag2", "tag3"]),
-                false,
-                Some(0),
-            ),
-            (
-                vec![m(vec!["tag1", "tag2"]), m(vec!["tag2", "tag3"])],
-                m(vec!["tagX", "tag2", "tag3"]),
-                false,
-                Some(1),
-            ),
-        ];
+    fn test_following() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
 
-        for (i, t) in tests.iter().enumerate() {
-            let (feeds, tags, strict, result) = t;
-            if covered_by_feeds(feeds, tags, *strict) != *result {
-                panic!("Test {} failed", i)
-            }
-        }
+            let p = pr(0);
+            let id = create_user(state, p);
+
+            let u1 = create_user(state, pr(1));
+            let u2 = create_user(state, pr(2));
+            let u3 = create_user(state, pr(3));
+
+            assert!(state.toggle_following_user(p, 1));
+            assert!(state.toggle_following_user(p, 2));
+            assert!(state.toggle_following_user(p, 3));
+
+            let f1 = &state.user(&u1.to_string()).unwrap().followers;
+            assert_eq!(f1.len(), 1);
+            assert!(f1.contains(&id));
+            let f2 = &state.user(&u2.to_string()).unwrap().followers;
+            assert_eq!(f2.len(), 1);
+            assert!(f2.contains(&id));
+            let f3 = &state.user(&u3.to_string()).unwrap().followers;
+            assert_eq!(f3.len(), 1);
+            assert!(f3.contains(&id));
+
+            assert!(!state.toggle_following_user(p, 1));
+            assert!(!state.toggle_following_user(p, 2));
+            assert!(!state.toggle_following_user(p, 3));
+
+            let f1 = &state.user(&u1.to_string()).unwrap().followers;
+            assert!(!f1.contains(&id));
+            let f2 = &state.user(&u2.to_string()).unwrap().followers;
+            assert!(!f2.contains(&id));
+            let f3 = &state.user(&u3.to_string()).unwrap().followers;
+            assert!(!f3.contains(&id));
+
+            let tags: Vec<_> = vec!["tag1".to_string(), "tag2".to_string()]
+                .into_iter()
+                .collect();
+            let tags2: Vec<_> = vec!["tag1".to_owned()].into_iter().collect();
+            let user = state.users.get_mut(&id).unwrap();
+            assert!(user.toggle_following_feed(tags.clone()));
+            assert!(user.toggle_following_feed(tags2.clone()));
+            assert!(!user.toggle_following_feed(tags));
+            assert!(!user.toggle_following_feed(tags2));
+        })
     }
 
     #[test]
-    fn test_user_by_handle() {
+    fn test_stalwarts() {
         STATE.with(|cell| {
             cell.replace(Default::default());
             let state = &mut *cell.borrow_mut();
 
-            let u1 = create_user_with_params(state, pr(0), "user1", true, 1000);
-            let u2 = create_user_with_params(state, pr(1), "user2", true, 1000);
-            let u3 = create_user_with_params(state, pr(2), "user3", true, 1000);
+            let now = CONFIG.min_stalwart_account_age_weeks as u64 * WEEK;
 
-            assert_eq!(state.user("user1").unwrap().id, u1);
-            assert_eq!(state.user("0").unwrap().id, u1);
-            assert_eq!(state.user("user2").unwrap().id, u2);
-            assert_eq!(state.user("1").unwrap().id, u2);
-            assert_eq!(state.user("user3").unwrap().id, u3);
-            assert_eq!(state.user("2").unwrap().id, u3);
-            assert!(state.user("user22").is_none());
-        });
+            for i in 0..200 {
+                let id = create_user(state, pr(i as u8));
+                let user = state.users.get_mut(&id).unwrap();
+                user.change_karma(i as i64, "");
+                user.apply_rewards();
+                // every second user was active
+                if i % 2 == 0 {
+                    user.last_activity = now;
+                    user.active_weeks = CONFIG.min_stalwart_activity_weeks as u32;
+                    user.timestamp = 0;
+                    user.change_karma(CONFIG.proposal_rejection_penalty as Karma, "");
+                    user.apply_rewards();
+                }
+            }
+
+            state.recompute_stalwarts(now + WEEK * 2);
+
+            // make sure we have right number of stalwarts
+            assert_eq!(
+                state.users.values().filter(|u| u.stalwart).count(),
+                CONFIG.stalwart_percentage * 2
+            );
+        })
     }
 
     #[test]
-    fn test_personal_feed() {
-        STATE.with(|cell| cell.replace(Default::default()));
-
-        mutate(|state| {
-            // create a post author and one post for its principal
-            let p = pr(0);
-            let post_author_id = create_user(state, p);
-            let post_id = Post::create(
-                state,
-                "This is a #post with #tags".to_string(),
-                &[],
-                p,
-                0,
-                None,
-                None,
-                None,
-            )
-            .unwrap();
+    fn test_stalwart_transitions() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let now = CONFIG.min_stalwart_account_age_weeks as u64 * WEEK;
 
-            // create a user and make sure his feed is empty
-            let pr1 = pr(1);
-            let user_id = create_user(state, pr1);
-            assert!(state
-                .user(&user_id.to_string())
-                .unwrap()
-                .personal_feed(state, 0, true)
-                .next()
-                .is_none());
+            // with 34 users, there is exactly one seat
+            for i in 0..34 {
+                create_user(state, pr(i));
+            }
+            for (id, karma) in vec![(0, CONFIG.min_stalwart_karma), (1, CONFIG.min_stalwart_karma + 1)] {
+                let user = state.users.get_mut(&id).unwrap();
+                user.timestamp = 0;
+                user.active_weeks = CONFIG.min_stalwart_activity_weeks as u32;
+                user.change_karma(karma, "");
+                user.apply_rewards();
+            }
+            let stalwarts = |state: &State| {
+                state
+                    .users
+                    .values()
+                    .filter(|user| user.stalwart)
+                    .map(|user| user.id)
+                    .collect::<Vec<_>>()
+            };
+            let notified = |state: &State, id: UserId, text: &str| {
+                state.users.get(&id).unwrap().notifications(None).iter().any(
+                    |(_, _, notification)| {
+                        matches!(notification, Notification::Generic(message) if message.contains(text))
+                    },
+                )
+            };
 
-            // now we follow post_author_id
-            let _user = state.users.get_mut(&user_id).unwrap();
-            assert!(state.toggle_following_user(pr1, post_author_id));
+            // the eligible user with the highest karma takes the seat
+            state.recompute_stalwarts(now);
+            assert_eq!(stalwarts(state), vec![1]);
+            assert!(notified(state, 1, "stalwart now"));
 
-            // make sure the feed contains exactly one post from post_author_id
-            let feed = state
-                .users
-                .get(&user_id)
-                .unwrap()
-                .personal_feed(state, 0, true)
-                .map(|post| post.id)
-                .collect::<Vec<_>>();
-            assert_eq!(feed.len(), 1);
-            assert!(feed.contains(&post_id));
+            // an inactive stalwart is demoted and the seat goes to the next eligible user
+            state.users.get_mut(&1).unwrap().active_weeks = 0;
+            state.recompute_stalwarts(now);
+            assert_eq!(stalwarts(state), vec![0]);
+            assert!(notified(state, 1, "not a Taggr stalwart anymore (inactivity)"));
+            assert!(notified(state, 0, "stalwart now"));
 
-            // now we follow a feed #post+#tags
-            let user = state.users.get_mut(&user_id).unwrap();
-            assert!(user.toggle_following_feed(
-                vec!["post".to_owned(), "tags".to_owned()]
-                    .into_iter()
-                    .collect()
-            ));
+            // a reported stalwart is demoted and nobody else qualifies
+            state.users.get_mut(&0).unwrap().report = Some(Default::default());
+            state.recompute_stalwarts(now);
+            assert!(stalwarts(state).is_empty());
+            assert!(notified(state, 0, "anymore (report)"));
+            let entry = format!("@{} (report)", state.users.get(&0).unwrap().name);
+            assert!(state
+                .logger
+                .events
+                .iter()
+                .any(|event| event.message.contains(&entry)));
 
-            // make sure the feed still contains the same post
-            let feed = state
-                .users
-                .get(&user_id)
-                .unwrap()
-                .personal_feed(state, 0, true)
-                .map(|post| post.id)
-                .collect::<Vec<_>>();
-            assert_eq!(feed.len(), 1);
-            assert!(feed.contains(&post_id));
+            // a stalwart falling below the karma threshold is demoted
+            state.users.get_mut(&0).unwrap().report = None;
+            state.users.get_mut(&1).unwrap().active_weeks = CONFIG.min_stalwart_activity_weeks as u32;
+            state.recompute_stalwarts(now);
+            assert_eq!(stalwarts(state), vec![1]);
+            let user = state.users.get_mut(&1).unwrap();
+            let delta = CONFIG.min_stalwart_karma - 1 - user.karma();
+            user.change_karma(delta, "");
+            assert_eq!(user.karma(), CONFIG.min_stalwart_karma - 1);
+            state.recompute_stalwarts(now);
+            assert_eq!(stalwarts(state), vec![0]);
+            assert!(notified(state, 1, "anymore (karma)"));
+        })
+    }
 
-            // now a different post with the same tags appears
-            let p = pr(2);
-            let _post_author_id = create_user(state, p);
-            let post_id2 = Post::create(
-                state,
-                "This is a different #post, but with the same #tags".to_string(),
-                &[],
-                p,
-                0,
-                None,
-                None,
-                None,
-            )
-            .unwrap();
+    #[actix_rt::test]
+    async fn test_invites() {
+        let principal = pr(1);
+        let (id, code, prev_balance) = STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let id = create_user(state, principal);
 
-            // make sure the feed contains both posts
-            let feed = state
-                .users
-                .get(&user_id)
-                .unwrap()
-                .personal_feed(state, 0, true)
-                .map(|post| post.id)
-                .collect::<Vec<_>>();
-            assert_eq!(feed.len(), 2);
-            assert!(feed.contains(&post_id));
-            assert!(feed.contains(&post_id2));
+            // use too many cycles
+            assert_eq!(
+                state.create_invite(principal, 1111, None, None, None, vec![], time()),
+                Err("not enough cycles".to_string())
+            );
 
-            // yet another post appears
-            let p = pr(3);
-            let _post_author_id = create_user(state, p);
-            let post_id3 = Post::create(
-                state,
-                "Different #post, different #feed".to_string(),
-                &[],
-                p,
-                0,
-                None,
-                None,
-                None,
-            )
-            .unwrap();
+            // use enough cycles and make sure they were deducted
+            let prev_balance = state.users.get(&id).unwrap().cycles();
+            assert_eq!(
+                state.create_invite(principal, 111, None, None, None, vec![], time()),
+                Ok(())
+            );
+            let new_balance = state.users.get(&id).unwrap().cycles();
+            // no charging yet
+            assert_eq!(new_balance, prev_balance);
+            let invite = state.invites(principal, time());
+            assert_eq!(invite.len(), 1);
+            let (code, cycles, _, _) = invite.get(0).unwrap().clone();
+            assert_eq!(cycles, 111);
+            (id, code, prev_balance)
+        });
 
-            // make sure the feed contains the same old posts
-            let feed = state
-                .users
-                .get(&user_id)
-                .unwrap()
-                .personal_feed(state, 0, true)
-                .map(|post| post.id)
-                .collect::<Vec<_>>();
-            assert_eq!(feed.len(), 2);
-            assert!(feed.contains(&post_id));
-            assert!(feed.contains(&post_id2));
+        // use the invite
+        assert!(State::create_user(pr(2), "name".to_string(), Some(code))
+            .await
+            .is_ok());
 
-            // now we follow a feed "post"
-            let user = state.users.get_mut(&user_id).unwrap();
-            let tags: Vec<_> = vec!["post".to_string()].into_iter().collect();
-            assert!(user.toggle_following_feed(tags.clone()));
-            // make sure the feed contains the new post
-            let feed = state
-                .users
-                .get(&user_id)
-                .unwrap()
-                .personal_feed(state, 0, true)
-                .map(|post| post.id)
-                .collect::<Vec<_>>();
-            assert_eq!(feed.len(), 3);
-            assert!(feed.contains(&post_id));
-            assert!(feed.contains(&post_id2));
-            assert!(feed.contains(&post_id3));
+        let new_balance = mutate(|state| state.users.get(&id).unwrap().cycles());
+        assert_eq!(new_balance, prev_balance - 111);
 
-            // Make sure we can unsubscribe and the feed gets back to 2 posts
-            let user = state.users.get_mut(&user_id).unwrap();
-            assert!(!user.toggle_following_feed(tags));
-            let feed = state
-                .users
-                .get(&user_id)
-                .unwrap()
-                .personal_feed(state, 0, true)
-                .map(|post| post.id)
-                .collect::<Vec<_>>();
-            assert_eq!(feed.len(), 2);
-            assert!(feed.contains(&post_id));
-            assert!(feed.contains(&post_id2));
+        // Subsidized invite
+        let (id, code, prev_balance) = mutate(|state| {
+            let user = state.users.get_mut(&id).unwrap();
+            user.invites_budget = 300;
+            let prev_balance = user.cycles();
+            assert_eq!(
+                state.create_invite(principal, 222, None, None, None, vec![], time()),
+                Ok(())
+            );
+            let invite = state.invites(principal, time());
+            let (code, cycles, _, _) = invite.get(0).unwrap().clone();
+            assert_eq!(cycles, 222);
+            (id, code, prev_balance)
         });
-    }
 
-    #[test]
-    fn test_clean_up() {
-        STATE.with(|cell| cell.replace(Default::default()));
-        mutate(|state| {
-            let inactive_id1 = create_user_with_cycles(state, pr(1), 500);
-            let inactive_id2 = create_user_with_cycles(state, pr(2), 100);
-            let inactive_id3 = create_user_with_cycles(state, pr(3), 200);
-            let active_id = create_user_with_cycles(state, pr(3), 300);
+        let prev_revenue = read(|state| state.burned_cycles);
+
+        assert!(State::create_user(pr(3), "name2".to_string(), Some(code))
+            .await
+            .is_ok());
+
+        let user = read(|state| state.users.get(&id).unwrap().clone());
+        // Make sure didn't pay with own cycles
+        assert_eq!(user.cycles(), prev_balance);
+        // Make sure Taggr payed for the invite
+        assert_eq!(user.invites_budget, 300 - 222);
+        assert_eq!(read(|state| state.burned_cycles), prev_revenue - 222);
+    }
 
-            let user = state.users.get_mut(&inactive_id1).unwrap();
-            assert_eq!(user.karma(), CONFIG.trusted_user_min_karma);
-            let user = state.users.get_mut(&inactive_id3).unwrap();
-            assert_eq!(user.karma(), CONFIG.trusted_user_min_karma);
-            let user = state.users.get_mut(&active_id).unwrap();
-            assert_eq!(user.karma(), CONFIG.trusted_user_min_karma);
+    #[test]
+    fn test_sanitize_tip_message() {
+        assert_eq!(
+            sanitize_tip_message("  great\n\n `write-up` [here](x) \u{7}"),
+            Ok("great write-up here(x)".into())
+        );
+        assert_eq!(sanitize_tip_message("``"), Ok("".into()));
+        assert!(sanitize_tip_message(&"a".repeat(CONFIG.max_tip_message_length + 1)).is_err());
+    }
 
-            let now = WEEK * 27;
-            state.users.get_mut(&active_id).unwrap().last_activity = now;
+    #[test]
+    fn test_split_tip() {
+        assert_eq!(
+            split_tip("0.001"),
+            Err(format!(
+                "tips must be at least {} ICP",
+                e8s_to_icp(CONFIG.min_tip_e8s)
+            ))
+        );
+        assert!(split_tip("1.2.3").is_err());
+        assert_eq!(
+            split_tip("2.5"),
+            Ok((250_000_000, tip_fee(250_000_000, CONFIG.tip_fee_percentage)))
+        );
+        assert_eq!(
+            split_tip(&e8s_to_icp(CONFIG.min_tip_e8s)).map(|(e8s, _)| e8s),
+            Ok(CONFIG.min_tip_e8s)
+        );
 
-            state.clean_up(now);
+        // small fees are raised to the ledger fee
+        assert_eq!(tip_fee(100_000_000, 0), 0);
+        assert_eq!(tip_fee(100_000_000, 1), 1_000_000);
+        assert_eq!(tip_fee(500_000, 1), invoices::fee());
+    }
 
-            let penalty = CONFIG.inactivity_penalty;
+    #[test]
+    fn test_validate_tip_split() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let tipper_id = create_user(state, pr(0));
+            let alice = create_user(state, pr(1));
+            let bob = create_user(state, pr(2));
+            let post_id =
+                Post::create(state, "Joint work".into(), &[], pr(1), 0, None, None, None).unwrap();
+            let shares = |list: &[(UserId, &str)]| {
+                list.iter()
+                    .map(|(id, amount)| (*id, amount.to_string()))
+                    .collect::<Vec<_>>()
+            };
+            let fee = |e8s: u64| tip_fee(e8s, CONFIG.tip_fee_percentage);
 
-            // penalized
-            let user = state.users.get_mut(&inactive_id1).unwrap();
-            assert_eq!(user.cycles(), 500 - penalty);
-            assert_eq!(user.karma(), 0);
-            // not penalized due to low balance, but karma penalized
-            let user = state.users.get_mut(&inactive_id2).unwrap();
-            assert_eq!(user.cycles(), 100);
-            assert_eq!(user.karma(), 0);
-            // penalized to the minimum balance
             assert_eq!(
-                state.users.get_mut(&inactive_id3).unwrap().cycles(),
-                penalty * 4
+                state.validate_tip_split(
+                    pr(0),
+                    post_id,
+                    "1.5",
+                    &shares(&[(alice, "1"), (bob, "0.5")])
+                ),
+                Ok(vec![
+                    (alice, 100_000_000, fee(100_000_000)),
+                    (bob, 50_000_000, fee(50_000_000))
+                ])
+            );
+            assert_eq!(
+                state.validate_tip_split(
+                    pr(0),
+                    post_id,
+                    "2",
+                    &shares(&[(alice, "1"), (bob, "0.5")])
+                ),
+                Err("the shares don't add up to the tipped amount".into())
+            );
+            assert_eq!(
+                state.validate_tip_split(
+                    pr(0),
+                    post_id,
+                    "2",
+                    &shares(&[(alice, "1"), (tipper_id, "1")])
+                ),
+                Err("you can't tip yourself".into())
+            );
+            assert_eq!(
+                state.validate_tip_split(
+                    pr(0),
+                    post_id,
+                    "2",
+                    &shares(&[(alice, "1"), (alice, "1")])
+                ),
+                Err(format!("user {} is listed more than once", alice))
+            );
+            assert_eq!(
+                state.validate_tip_split(pr(0), post_id, "2", &shares(&[(alice, "1"), (77, "1")])),
+                Err("no user with id 77 found".into())
+            );
+            assert_eq!(
+                state.validate_tip_split(
+                    pr(0),
+                    post_id,
+                    "1",
+                    &shares(&[(alice, "0.999"), (bob, "0.001")])
+                ),
+                Err(format!(
+                    "tips must be at least {} ICP",
+                    e8s_to_icp(CONFIG.min_tip_e8s)
+                ))
+            );
+            assert_eq!(
+                state.validate_tip_split(pr(0), post_id, "1", &[]),
+                Err("no recipients specified".into())
+            );
+            assert_eq!(
+                state.validate_tip_split(pr(0), 777, "1", &shares(&[(alice, "1")])),
+                Err("post not found".into())
+            );
+            assert_eq!(
+                state.validate_tip_split(pr(5), post_id, "1", &shares(&[(alice, "1")])),
+                Err("no user found".into())
             );
-            // Active user not penalized
-            let user = state.users.get_mut(&active_id).unwrap();
-            assert_eq!(user.cycles(), 300);
-            assert_eq!(user.karma(), CONFIG.trusted_user_min_karma)
         })
     }
 
-    #[test]
-    fn test_cycles_accounting() {
-        STATE.with(|cell| cell.replace(Default::default()));
-        mutate(|state| {
-            let p0 = pr(0);
-            let post_author_id = create_user(state, p0);
-            let post_id =
-                Post::create(state, "test".to_string(), &[], p0, 0, None, None, None).unwrap();
-            let p = pr(1);
-            let p2 = pr(2);
-            let p3 = pr(3);
-
-            let lurker_id = create_user(state, p);
-            create_user(state, p2);
-            create_user(state, p3);
-            let farmer_id = create_untrusted_user(state, pr(111));
-            let c = CONFIG;
-            assert_eq!(state.burned_cycles as Cycles, c.post_cost);
-            state
-                .users
-                .get_mut(&lurker_id)
-                .unwrap()
-                .change_karma(10, "");
-            state.users.get_mut(&lurker_id).unwrap().apply_rewards();
-            // make author to a new user
-            state
-                .users
-                .get_mut(&post_author_id)
-                .unwrap()
-                .change_karma(-CONFIG.trusted_user_min_karma, "");
-            let author = state.users.get(&post_author_id).unwrap();
-            let farmer = state.users.get(&farmer_id).unwrap();
-            let lurker = state.users.get(&lurker_id).unwrap();
-            assert!(!author.trusted());
-            assert!(!farmer.trusted());
-            assert!(lurker.trusted());
-            assert_eq!(author.cycles(), c.native_cycles_per_xdr - c.post_cost);
-            assert_eq!(lurker.cycles(), c.native_cycles_per_xdr);
+    #[actix_rt::test]
+    async fn test_invite_limits() {
+        let principal = pr(1);
+        let code = STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            create_user(state, principal);
+            assert_eq!(
+                state.create_invite(principal, 50, Some(0), None, None, vec![], time()),
+                Err("invites can be used between 1 and 100 times".into())
+            );
+            assert_eq!(
+                state.create_invite(principal, 50, Some(2), Some(time()), None, vec![], time()),
+                Err("expiry must be in the future".into())
+            );
+            assert_eq!(
+                state.create_invite(
+                    principal,
+                    50,
+                    Some(2),
+                    Some(time() + DAY),
+                    None,
+                    vec![],
+                    time()
+                ),
+                Ok(())
+            );
+            let invites = state.invites(principal, time());
+            let (code, _, uses_left, expires_in) = invites.get(0).unwrap().clone();
+            assert_eq!(uses_left, 2);
+            assert_eq!(expires_in, Some(DAY));
+            code
+        });
 
-            assert_eq!(author.karma(), 0);
+        for i in 2..4 {
+            assert_eq!(
+                State::create_user(pr(i), format!("name{}", i), Some(code.clone())).await,
+                Ok(())
+            );
+        }
+        assert_eq!(
+            State::create_user(pr(4), "name4".into(), Some(code.clone())).await,
+            Err("invite has no uses left".into())
+        );
 
-            // react on the new post
-            assert!(state.react(pr(111), post_id, 1, 0).is_err());
-            // this is a noop for author
-            assert!(state.react(pr(111), post_id, 100, 0).is_ok());
-            let burned_cycles_by_reaction_from_untrusted = 11;
+        let code = mutate(|state| {
+            assert_eq!(state.invites(principal, time())[0].2, 0);
             assert_eq!(
-                state.users.get(&post_author_id).unwrap().cycles(),
-                c.native_cycles_per_xdr - c.post_cost
+                state.create_invite(
+                    principal,
+                    50,
+                    Some(2),
+                    Some(time() + DAY),
+                    None,
+                    vec![],
+                    time() + 1
+                ),
+                Ok(())
             );
-            assert!(state.react(p, post_id, 50, 0).is_ok());
-            assert!(state.react(p, post_id, 100, 0).is_err());
-            assert!(state.react(p2, post_id, 100, 0).is_ok());
-            let reaction_costs_1 = 6;
-            let burned_cycles_by_reactions = 1 + 1;
-            let mut rewards_from_reactions = 5 + 10;
+            let code = state
+                .invites
+                .iter()
+                .find(|(_, invite)| invite.uses == 0)
+                .map(|(code, _)| code.clone())
+                .unwrap();
+            state.invites.get_mut(&code).unwrap().expires_at = Some(time() - 1);
+            code
+        });
+        assert_eq!(
+            State::create_user(pr(4), "name4".into(), Some(code)).await,
+            Err("invite expired".into())
+        );
 
-            // try to self upvote (should be a no-op)
-            assert!(state.react(p0, post_id, 100, 0).is_err());
+        mutate(|state| {
+            state.clean_up_invites(time());
+            assert!(state.invites.is_empty());
+            // the inviter is notified about the expired invite
+            let user = state.principal_to_user(principal).unwrap();
+            assert!(user
+                .notifications(None)
+                .iter()
+                .any(|(_, _, notification)| matches!(
+                    notification,
+                    Notification::Generic(message) if message.contains("expired")
+                )));
+        })
+    }
 
-            let author = state.users.get(&post_author_id).unwrap();
-            assert_eq!(author.cycles(), c.native_cycles_per_xdr - c.post_cost);
-            assert_eq!(author.karma_to_reward(), rewards_from_reactions);
+    #[test]
+    fn test_invite_quota() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let principal = pr(1);
+            let id = create_user(state, principal);
+            assert_eq!(state.invite_quota(id, time()), Some(100));
             assert_eq!(
-                state.burned_cycles as Cycles,
-                c.post_cost + burned_cycles_by_reactions + burned_cycles_by_reaction_from_untrusted
+                state.create_invite(principal, 49, None, None, None, vec![], time()),
+                Err("smallest invite must contain 50 cycles".into())
+            );
+            assert_eq!(
+                state.create_invite(principal, 50, Some(60), None, None, vec![], time()),
+                Ok(())
+            );
+            assert_eq!(
+                state.create_invite(
+                    principal,
+                    50,
+                    Some(40),
+                    Some(time() + DAY),
+                    None,
+                    vec![],
+                    time() + 1
+                ),
+                Ok(())
+            );
+            assert_eq!(state.invite_quota(id, time()), Some(0));
+            assert_eq!(
+                state.create_invite(principal, 50, None, None, None, vec![], time() + 2),
+                Err("you can create invites for 0 more users only".into())
             );
 
-            let lurker = state.users.get(&lurker_id).unwrap();
-            assert_eq!(lurker.cycles(), c.native_cycles_per_xdr - reaction_costs_1);
+            // expired invites don't count against the quota
+            assert_eq!(state.invite_quota(id, time() + 2 * DAY), Some(40));
+            assert_eq!(
+                state.create_invite(
+                    principal,
+                    50,
+                    Some(40),
+                    None,
+                    None,
+                    vec![],
+                    time() + 2 * DAY
+                ),
+                Ok(())
+            );
+        })
+    }
 
-            // downvote
-            assert!(state.react(p3, post_id, 1, 0).is_ok());
-            let reaction_penalty = 3;
-            rewards_from_reactions -= 3;
-            let author = state.users.get(&post_author_id).unwrap();
-            let lurker_3 = state.principal_to_user(p3).unwrap();
+    #[actix_rt::test]
+    async fn test_invite_onboarding() {
+        let principal = pr(1);
+        let (inviter_id, code) = STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            let inviter_id = create_user(state, principal);
+            state.realms.insert("TEST".into(), Realm::default());
             assert_eq!(
-                author.cycles(),
-                c.native_cycles_per_xdr - c.post_cost - reaction_penalty
+                state.create_invite(
+                    principal,
+                    50,
+                    None,
+                    None,
+                    Some("NOPE".into()),
+                    vec![],
+                    time()
+                ),
+                Err("realm NOPE not found".into())
             );
-            assert_eq!(author.karma_to_reward(), rewards_from_reactions);
-            assert_eq!(lurker_3.cycles(), c.native_cycles_per_xdr - 3);
             assert_eq!(
-                state.burned_cycles,
-                (c.post_cost
-                    + burned_cycles_by_reactions
-                    + burned_cycles_by_reaction_from_untrusted
-                    + 2 * 3) as i64
+                state.create_invite(principal, 50, None, None, None, vec![inviter_id, 7], time()),
+                Err("user 7 not found".into())
+            );
+            assert_eq!(
+                state.create_invite(
+                    principal,
+                    50,
+                    None,
+                    None,
+                    Some("TEST".into()),
+                    // duplicates don't cancel each other out
+                    vec![inviter_id, inviter_id],
+                    time()
+                ),
+                Ok(())
+            );
+            assert_eq!(
+                state.invites.values().next().unwrap().follow,
+                vec![inviter_id]
             );
+            (inviter_id, state.invites.keys().next().unwrap().clone())
+        });
+
+        assert_eq!(
+            State::create_user(pr(2), "name".into(), Some(code)).await,
+            Ok(())
+        );
+
+        read(|state| {
+            let user = state.principal_to_user(pr(2)).unwrap();
+            assert_eq!(user.realms, vec!["TEST".to_string()]);
+            assert!(user.followees.contains(&inviter_id));
+            assert!(state
+                .users
+                .get(&inviter_id)
+                .unwrap()
+                .followers
+                .contains(&user.id));
+            assert_eq!(state.realms.get("TEST").unwrap().num_members, 1);
+        })
+    }
+
+    #[test]
+    fn test_logs() {
+        let mut state = State::default();
+        for i in 0..5 {
+            state.logger.info(format!("info {}", i));
+        }
+        state.logger.error("error 0");
+        state.logger.info("info 5");
+
+        let messages = |events: Vec<&Event>| {
+            events
+                .into_iter()
+                .map(|event| event.message.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            messages(state.logs(None, 0, 3)),
+            vec!["info 5", "error 0", "info 4"]
+        );
+        assert_eq!(
+            messages(state.logs(Some("info"), 1, 2)),
+            vec!["info 3", "info 2"]
+        );
+        assert_eq!(messages(state.logs(Some("ERROR"), 0, 10)), vec!["error 0"]);
+        assert!(state.logs(Some("ERROR"), 1, 10).is_empty());
+    }
+
+    #[test]
+    fn test_devices() {
+        let mut state = State::default();
+        let id = create_user(&mut state, pr(1));
+        create_user(&mut state, pr(2));
+        let device = pr(3).to_string();
+
+        assert_eq!(
+            state.add_device(pr(1), pr(2).to_string(), "phone".into(), 0),
+            Err("principal already controls a user".into())
+        );
+        assert_eq!(
+            state.add_device(pr(1), device.clone(), " ".into(), 0),
+            Err("invalid device label".into())
+        );
+        assert_eq!(
+            state.remove_device(pr(1), pr(1).to_string()),
+            Err("the last device can't be removed".into())
+        );
+        assert_eq!(
+            state.add_device(pr(1), Principal::anonymous().to_string(), "x".into(), 0),
+            Err("anonymous principal can't control a user".into())
+        );
+        assert_eq!(
+            state.add_device(pr(1), device.clone(), "phone".into(), 7),
+            Ok(())
+        );
 
-            Post::create(state, "test".to_string(), &[], p0, 0, Some(0), None, None).unwrap();
+        // the device doesn't control the account before it confirms it
+        assert!(state.principal_to_user(pr(3)).is_none());
+        assert_eq!(
+            state.confirm_device(pr(4), id),
+            Err("no pending device found".into())
+        );
+        assert_eq!(
+            state.confirm_device(pr(2), id),
+            Err("principal already controls a user".into())
+        );
+        assert_eq!(state.confirm_device(pr(3), id), Ok(()));
+        assert!(state.users.get(&id).unwrap().pending_devices.is_empty());
 
-            let c = CONFIG;
-            assert_eq!(
-                state.burned_cycles,
-                (2 * c.post_cost
-                    + burned_cycles_by_reactions
-                    + burned_cycles_by_reaction_from_untrusted
-                    + 2 * 3) as i64
-            );
-            let author = state.users.get(&post_author_id).unwrap();
-            assert_eq!(
-                author.cycles(),
-                c.native_cycles_per_xdr - c.post_cost - c.post_cost - reaction_penalty
-            );
+        // both principals control the account
+        assert_eq!(state.principal_to_user(pr(3)).map(|user| user.id), Some(id));
+        assert_eq!(state.principal_to_user(pr(1)).map(|user| user.id), Some(id));
+        let devices = state.users.get(&id).unwrap().devices();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].principal, pr(1));
+        assert_eq!((devices[1].label.as_str(), devices[1].added), ("phone", 7));
 
-            let author = state.users.get_mut(&post_author_id).unwrap();
-            author
-                .change_cycles(author.cycles(), CyclesDelta::Minus, "")
-                .unwrap();
+        assert_eq!(
+            state.remove_device(pr(3), pr(1).to_string()),
+            Err("the primary principal can only be replaced with a new one".into())
+        );
+        assert_eq!(state.remove_device(pr(1), device), Ok(()));
+        assert!(state.principal_to_user(pr(3)).is_none());
+        assert_eq!(state.users.get(&id).unwrap().devices().len(), 1);
 
-            assert!(Post::create(state, "test".to_string(), &[], p0, 0, None, None, None).is_err());
+        // pending devices can be withdrawn
+        assert_eq!(
+            state.add_device(pr(1), device.clone(), "tablet".into(), 0),
+            Ok(())
+        );
+        assert_eq!(state.remove_device(pr(1), device), Ok(()));
+        assert_eq!(
+            state.confirm_device(pr(3), id),
+            Err("no pending device found".into())
+        );
+    }
 
-            assert_eq!(
-                state.react(p, post_id, 10, 0),
-                Err("multiple reactions are forbidden".into())
-            );
-            create_user(state, pr(10));
-            let lurker = state.principal_to_user_mut(pr(10)).unwrap();
-            lurker
-                .change_cycles(lurker.cycles(), CyclesDelta::Minus, "")
-                .unwrap();
-            assert_eq!(
-                state.react(pr(10), post_id, 10, 0),
-                Err("not enough cycles".into())
-            );
-        })
+    #[test]
+    fn test_chores_dry_run() {
+        let mut state = State::default();
+        let id = create_user(&mut state, pr(1));
+        let active_id = create_user(&mut state, pr(2));
+        state.users.get_mut(&id).unwrap().change_karma(500, "test");
+        let now = time() + CONFIG.inactivity_duration_weeks * WEEK;
+        state.users.get_mut(&active_id).unwrap().last_activity = now;
+
+        let plan = state.plan_chores(now);
+        assert!(plan.weekly && plan.daily && plan.hourly);
+        assert_eq!(
+            plan.inactivity_penalties.karma.get(&id),
+            Some(
+                &CONFIG
+                    .trusted_user_min_karma
+                    .min(CONFIG.inactivity_penalty as Karma)
+            )
+        );
+        assert!(!plan.inactivity_penalties.karma.contains_key(&active_id));
+        let planned = *plan.tokens_to_mint.get(&id).unwrap();
+        assert_eq!(plan.tokens_to_mint.len(), 1);
+
+        // the dry run doesn't change the state
+        assert_eq!(state.users.get(&id).unwrap().karma_to_reward(), 500);
+        assert!(state.balances.is_empty());
+
+        // the live path mints exactly the planned amounts
+        let karma = state.collect_new_karma();
+        state.mint(karma);
+        assert_eq!(state.balances.get(&account(pr(1))), Some(&planned));
+
+        state.last_weekly_chores = now;
+        state.last_daily_chores = now;
+        state.last_hourly_chores = now;
+        let plan = state.plan_chores(now + HOUR + 1);
+        assert!(!plan.weekly && !plan.daily && plan.hourly);
+        assert!(plan.tokens_to_mint.is_empty());
     }
 
     #[test]
-    fn test_following() {
+    fn test_minting_ratio_history() {
+        let mut state = State::default();
+        state
+            .balances
+            .insert(account(pr(1)), CONFIG.total_supply / 2);
+        for i in 0..CONFIG.max_minting_ratio_samples as u64 + 5 {
+            state.sample_minting_ratio(i * DAY);
+        }
+        assert_eq!(
+            state.minting_ratio_history.len(),
+            CONFIG.max_minting_ratio_samples
+        );
+        assert_eq!(
+            state.minting_ratio_history.front(),
+            Some(&(5 * DAY, 32, CONFIG.total_supply / 2))
+        );
+    }
+
+    #[test]
+    fn test_tree_sort() {
         STATE.with(|cell| {
             cell.replace(Default::default());
             let state = &mut *cell.borrow_mut();
+            for i in 1..=3 {
+                create_user(state, pr(i));
+            }
+            let mut post = |body: &str, parent| {
+                Post::create(
+                    state,
+                    body.to_string(),
+                    &[],
+                    pr(1),
+                    time(),
+                    parent,
+                    None,
+                    None,
+                )
+                .unwrap()
+            };
+            let root = post("root", None);
+            let c1 = post("c1", Some(root));
+            let g = post("g", Some(c1));
+            let c2 = post("c2", Some(root));
+            let c3 = post("c3", Some(root));
+            assert_eq!(state.react(pr(3), c1, 10, time()), Ok(()));
+            assert_eq!(state.react(pr(2), c2, 100, time()), Ok(()));
+            assert_eq!(state.react(pr(2), c3, 1, time()), Ok(()));
 
-            let p = pr(0);
-            let id = create_user(state, p);
-
-            let u1 = create_user(state, pr(1));
-            let u2 = create_user(state, pr(2));
-            let u3 = create_user(state, pr(3));
-
-            assert!(state.toggle_following_user(p, 1));
-            assert!(state.toggle_following_user(p, 2));
-            assert!(state.toggle_following_user(p, 3));
-
-            let f1 = &state.user(&u1.to_string()).unwrap().followers;
-            assert_eq!(f1.len(), 1);
-            assert!(f1.contains(&id));
-            let f2 = &state.user(&u2.to_string()).unwrap().followers;
-            assert_eq!(f2.len(), 1);
-            assert!(f2.contains(&id));
-            let f3 = &state.user(&u3.to_string()).unwrap().followers;
-            assert_eq!(f3.len(), 1);
-            assert!(f3.contains(&id));
-
-            assert!(!state.toggle_following_user(p, 1));
-            assert!(!state.toggle_following_user(p, 2));
-            assert!(!state.toggle_following_user(p, 3));
-
-            let f1 = &state.user(&u1.to_string()).unwrap().followers;
-            assert!(!f1.contains(&id));
-            let f2 = &state.user(&u2.to_string()).unwrap().followers;
-            assert!(!f2.contains(&id));
-            let f3 = &state.user(&u3.to_string()).unwrap().followers;
-            assert!(!f3.contains(&id));
+            assert_eq!(
+                state.tree(root, TreeSort::Chronological),
+                vec![c1, g, c2, c3]
+            );
+            assert_eq!(state.tree(root, TreeSort::Top), vec![c2, c1, g, c3]);
+            assert_eq!(state.tree(c1, TreeSort::Top), vec![g]);
 
-            let tags: Vec<_> = vec!["tag1".to_string(), "tag2".to_string()]
-                .into_iter()
-                .collect();
-            let tags2: Vec<_> = vec!["tag1".to_owned()].into_iter().collect();
-            let user = state.users.get_mut(&id).unwrap();
-            assert!(user.toggle_following_feed(tags.clone()));
-            assert!(user.toggle_following_feed(tags2.clone()));
-            assert!(!user.toggle_following_feed(tags));
-            assert!(!user.toggle_following_feed(tags2));
+            // reactions on deleted comments are ignored
+            Post::mutate(state, &c2, |post| {
+                post.delete(vec![], DeletedBy::Author);
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(state.tree(root, TreeSort::Top), vec![c1, g, c2, c3]);
         })
     }
 
     #[test]
-    fn test_stalwarts() {
+    fn test_deleted_parent_tombstone() {
         STATE.with(|cell| {
             cell.replace(Default::default());
             let state = &mut *cell.borrow_mut();
+            for i in 0..2 {
+                create_user(state, pr(i));
+            }
+            let now = time();
+            let root =
+                Post::create(state, "Root".into(), &[], pr(0), now, None, None, None).unwrap();
+            let middle = Post::create(
+                state,
+                "Middle".into(),
+                &[],
+                pr(1),
+                now,
+                Some(root),
+                None,
+                None,
+            )
+            .unwrap();
+            let leaf = Post::create(
+                state,
+                "Leaf".into(),
+                &[],
+                pr(0),
+                now,
+                Some(middle),
+                None,
+                None,
+            )
+            .unwrap();
 
-            let now = CONFIG.min_stalwart_account_age_weeks as u64 * WEEK;
+            assert_eq!(state.delete_post(pr(1), middle, vec![], now), Ok(()));
+            state.delete_pending_posts(now + CONFIG.post_deletion_grace_period_minutes * MINUTE);
 
-            for i in 0..200 {
-                let id = create_user(state, pr(i as u8));
-                let user = state.users.get_mut(&id).unwrap();
-                user.change_karma(i as i64, "");
-                user.apply_rewards();
-                // every second user was active
-                if i % 2 == 0 {
-                    user.last_activity = now;
-                    user.active_weeks = CONFIG.min_stalwart_activity_weeks as u32;
-                    user.timestamp = 0;
-                    user.change_karma(CONFIG.proposal_rejection_penalty as Karma, "");
-                    user.apply_rewards();
-                }
-            }
+            let tombstone = Post::get(state, &middle).unwrap();
+            assert!(tombstone.is_deleted());
+            assert!(tombstone.body.is_empty());
+            assert_eq!(tombstone.deleted_by, Some(DeletedBy::Author));
+            assert_eq!(tombstone.children, vec![leaf]);
 
-            state.recompute_stalwarts(now + WEEK * 2);
+            // the reply stays reachable below the tombstone
+            assert_eq!(
+                state.tree(root, TreeSort::Chronological),
+                vec![middle, leaf]
+            );
+            assert_eq!(
+                state.thread(leaf).collect::<Vec<_>>(),
+                vec![root, middle, leaf]
+            );
+            assert_eq!(Post::get(state, &leaf).unwrap().body, "Leaf");
 
-            // make sure we have right number of stalwarts
+            // tombstones are not surfaced in feeds
             assert_eq!(
-                state.users.values().filter(|u| u.stalwart).count(),
-                CONFIG.stalwart_percentage * 2
+                state
+                    .last_posts(None, true)
+                    .map(|post| post.id)
+                    .collect::<Vec<_>>(),
+                vec![leaf, root]
             );
         })
     }
 
-    #[actix_rt::test]
-    async fn test_invites() {
-        let principal = pr(1);
-        let (id, code, prev_balance) = STATE.with(|cell| {
+    #[test]
+    fn test_follow_list() {
+        let mut state = State::default();
+        let id = create_user(&mut state, pr(0));
+        for i in 1..=CONFIG.feed_page_size as u8 + 5 {
+            create_user(&mut state, pr(i));
+            assert!(state.toggle_following_user(pr(i), id));
+        }
+        let name = state.users.get(&id).unwrap().name.clone();
+
+        let (total, page) = state.follow_list(&name, true, 0).unwrap();
+        assert_eq!(total, CONFIG.feed_page_size + 5);
+        assert_eq!(page.len(), CONFIG.feed_page_size);
+        let (_, page) = state.follow_list(&id.to_string(), true, 1).unwrap();
+        assert_eq!(page.len(), 5);
+        assert_eq!(page[0].2, CONFIG.trusted_user_min_karma);
+        assert_eq!(state.follow_list(&name, false, 0), Ok((0, vec![])));
+        assert_eq!(
+            state.follow_list("nobody", true, 0),
+            Err("no user found".into())
+        );
+    }
+
+    #[test]
+    fn test_account_deletion() {
+        STATE.with(|cell| {
             cell.replace(Default::default());
             let state = &mut *cell.borrow_mut();
-            let id = create_user(state, principal);
-
-            // use too many cycles
+            let id = create_user(state, pr(1));
+            let friend_id = create_user(state, pr(2));
+            state.realms.insert("TEST".into(), Realm::default());
             assert_eq!(
-                state.create_invite(principal, 1111),
-                Err("not enough cycles".to_string())
+                state.toggle_realm_membership(pr(1), "TEST".into()),
+                Ok(true)
+            );
+            assert!(state.toggle_following_user(pr(1), friend_id));
+            assert!(state.toggle_following_user(pr(2), id));
+            let post_id = Post::create(
+                state,
+                "Personal data".to_string(),
+                &[],
+                pr(1),
+                time(),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+            let pending_id = Post::create(
+                state,
+                "Edited".to_string(),
+                &[],
+                pr(1),
+                time(),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+            assert_eq!(
+                state.delete_post(
+                    pr(1),
+                    pending_id,
+                    vec!["Original".into(), "Edited".into()],
+                    time()
+                ),
+                Ok(())
             );
+            let name = state.users.get(&id).unwrap().name.clone();
 
-            // use enough cycles and make sure they were deducted
-            let prev_balance = state.users.get(&id).unwrap().cycles();
-            assert_eq!(state.create_invite(principal, 111), Ok(()));
-            let new_balance = state.users.get(&id).unwrap().cycles();
-            // no charging yet
-            assert_eq!(new_balance, prev_balance);
-            let invite = state.invites(principal);
-            assert_eq!(invite.len(), 1);
-            let (code, cycles) = invite.get(0).unwrap().clone();
-            assert_eq!(cycles, 111);
-            (id, code, prev_balance)
-        });
+            assert_eq!(
+                state.delete_account(pr(1), "wrong".into(), time()),
+                Err("please confirm the deletion with your handle".into())
+            );
+            state.balances.insert(account(pr(1)), 1);
+            assert_eq!(
+                state.delete_account(pr(1), name.clone(), time()),
+                Err("please transfer your tokens before deleting the account".into())
+            );
+            state.balances.remove(&account(pr(1)));
 
-        // use the invite
-        assert!(State::create_user(pr(2), "name".to_string(), Some(code))
-            .await
-            .is_ok());
+            // tokens held by other devices block the deletion too
+            assert_eq!(
+                state.add_device(pr(1), pr(3).to_string(), "phone".into(), 0),
+                Ok(())
+            );
+            assert_eq!(state.confirm_device(pr(3), id), Ok(()));
+            state.balances.insert(account(pr(3)), 1);
+            assert_eq!(
+                state.delete_account(pr(1), name.clone(), time()),
+                Err("please transfer your tokens before deleting the account".into())
+            );
+            state.balances.remove(&account(pr(3)));
+            let cycles = state.users.get(&id).unwrap().cycles();
+            let burned_cycles = state.burned_cycles;
+            assert_eq!(state.delete_account(pr(1), name.clone(), time()), Ok(()));
+            assert_eq!(state.burned_cycles, burned_cycles + cycles as i64);
+            assert!(state.principal_to_user(pr(3)).is_none());
+
+            assert!(state.principal_to_user(pr(1)).is_none());
+            let post = Post::get(state, &post_id).unwrap();
+            assert!(post.is_deleted());
+            assert!(post.body.is_empty());
+            // the versions stored with the pending deletion are kept
+            let post = Post::get(state, &pending_id).unwrap();
+            assert!(post.is_deleted() && post.deletion_scheduled.is_none());
+            assert_eq!(post.hashes.len(), 2);
+            assert!(state.pending_deletions.is_empty());
+            assert_eq!(state.realms.get("TEST").unwrap().num_members, 0);
+            let friend = state.users.get(&friend_id).unwrap();
+            assert!(!friend.followers.contains(&id) && !friend.followees.contains(&id));
+            let user = state.users.get(&id).unwrap();
+            assert_eq!(user.karma(), 0);
+            assert!(user.realms.is_empty());
 
-        let new_balance = mutate(|state| state.users.get(&id).unwrap().cycles());
-        assert_eq!(new_balance, prev_balance - 111);
+            // the handle is released after the grace period
+            state.free_deleted_handles(time());
+            assert_eq!(state.users.get(&id).unwrap().name, name);
+            state.free_deleted_handles(
+                time() + (CONFIG.account_deletion_grace_period_days + 1) * DAY,
+            );
+            assert_eq!(
+                state.users.get(&id).unwrap().name,
+                format!("deleted_{}", id)
+            );
+        })
+    }
 
-        // Subsidized invite
-        let (id, code, prev_balance) = mutate(|state| {
-            let user = state.users.get_mut(&id).unwrap();
-            user.invites_budget = 300;
-            let prev_balance = user.cycles();
-            assert_eq!(state.create_invite(principal, 222), Ok(()));
-            let invite = state.invites(principal);
-            let (code, cycles) = invite.get(0).unwrap().clone();
-            assert_eq!(cycles, 222);
-            (id, code, prev_balance)
-        });
+    #[test]
+    fn test_cycle_balance_alerts() {
+        let mut state = State::default();
+        let id = create_user(&mut state, pr(1));
+        state.users.get_mut(&id).unwrap().stalwart = true;
+        let alerts = |state: &State| {
+            state
+                .users
+                .get(&id)
+                .unwrap()
+                .notifications(None)
+                .iter()
+                .filter(|(_, _, notification)| {
+                    matches!(notification, Notification::Generic(message) if message.contains("top it up"))
+                })
+                .count()
+        };
 
-        let prev_revenue = read(|state| state.burned_cycles);
+        let threshold = CONFIG.low_cycle_balance_threshold;
+        state.check_cycle_balance(threshold + 10 * HOUR, 0);
+        assert_eq!(alerts(&state), 0);
+        // the balance burns one cycle per nanosecond
+        state.check_cycle_balance(threshold - HOUR, 11 * HOUR);
+        assert_eq!(alerts(&state), 1);
+        assert!(state
+            .logger
+            .events
+            .iter()
+            .any(|event| event.message.contains(&format!(
+                "run out of cycles in about {} hours",
+                (threshold - HOUR) / HOUR
+            ))));
+        // no repeated alerts until the balance recovers
+        state.check_cycle_balance(threshold - 2 * HOUR, 12 * HOUR);
+        assert_eq!(alerts(&state), 1);
+        state.check_cycle_balance(threshold, 13 * HOUR);
+        state.check_cycle_balance(threshold - 1, 14 * HOUR);
+        assert_eq!(alerts(&state), 2);
+    }
 
-        assert!(State::create_user(pr(3), "name2".to_string(), Some(code))
-            .await
-            .is_ok());
+    #[test]
+    fn test_health() {
+        let mut state = State::default();
+        create_user(&mut state, pr(1));
+        let balance = CONFIG.low_cycle_balance_threshold;
+        let now = 10 * HOUR;
+        state.last_hourly_chores = now - HOUR;
+        let health = state.health(balance, CONFIG.max_heap_size / 2, now);
+        assert_eq!(health.users, 1);
+        assert!(health.degraded.is_empty());
+
+        state.storage.buckets.insert(pr(2), CONFIG.max_bucket_size);
+        let health = state.health(balance - 1, CONFIG.max_heap_size, now + 2 * HOUR);
+        assert_eq!(health.degraded, vec!["cycles", "heap", "storage", "chores"]);
+    }
 
-        let user = read(|state| state.users.get(&id).unwrap().clone());
-        // Make sure didn't pay with own cycles
-        assert_eq!(user.cycles(), prev_balance);
-        // Make sure Taggr payed for the invite
-        assert_eq!(user.invites_budget, 300 - 222);
-        assert_eq!(read(|state| state.burned_cycles), prev_revenue - 222);
+    #[test]
+    fn test_time_to_empty() {
+        let samples = |s: &[(u64, u64)]| s.iter().copied().collect::<VecDeque<_>>();
+        assert_eq!(time_to_empty(&samples(&[])), None);
+        assert_eq!(time_to_empty(&samples(&[(0, 100)])), None);
+        assert_eq!(time_to_empty(&samples(&[(0, 100), (10, 90)])), Some(90));
+        // top-ups don't count as negative burn
+        assert_eq!(
+            time_to_empty(&samples(&[(0, 100), (10, 90), (20, 190), (30, 180)])),
+            Some(180 * 30 / 20)
+        );
     }
 }
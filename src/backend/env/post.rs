@@ -1,7 +1,10 @@
 use std::cmp::{Ordering, PartialOrd};
 
 use super::*;
-use super::{storage::Storage, user::UserId};
+use super::{
+    storage::{blob_hash, Storage},
+    user::UserId,
+};
 use crate::mutate;
 use crate::reports::Report;
 use serde::{Deserialize, Serialize};
@@ -37,6 +40,24 @@ pub struct Poll {
     pub weighted_by_karma: BTreeMap<u16, Karma>,
     #[serde(default)]
     pub weighted_by_tokens: BTreeMap<u16, Token>,
+    // only set for polls in realms
+    #[serde(default)]
+    pub weighted_by_realm_reputation: BTreeMap<u16, u64>,
+}
+
+/// The party that deleted the post. Deleted posts stay in their threads as tombstones, so that
+/// their comments remain reachable.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DeletedBy {
+    Author,
+    Moderators,
+}
+
+/// Warning covering the post in the clients until the viewer chooses to see it.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, candid::CandidType)]
+pub enum ContentWarning {
+    Nsfw,
+    Spoiler,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -46,6 +67,186 @@ pub enum Extension {
     Repost(PostId),
 }
 
+/// Colors of the post's realm, so that clients can render realm posts of mixed feeds without
+/// fetching every realm.
+#[derive(Clone, Serialize)]
+pub struct RealmStyle {
+    pub label_color: String,
+    pub theme: String,
+}
+
+/// Engagement metrics computed for the clients, so that they don't need to aggregate the raw
+/// reactions of a post themselves.
+#[derive(Clone, Default, Serialize)]
+pub struct Engagement {
+    /// Sum of the karma of all reactions.
+    pub reactions_karma: i64,
+    /// Number of all comments in the post tree.
+    pub comments: u32,
+    pub reposts: u32,
+    /// Number of unique users who reacted.
+    pub reactors: usize,
+    /// The first reactors up to the sample size.
+    pub reactor_sample: Vec<UserId>,
+}
+
+/// Relationship of the caller to the post, only computed for authenticated callers.
+#[derive(Clone, Default, Serialize)]
+pub struct ViewerContext {
+    pub following_author: bool,
+    pub reacted: bool,
+    pub bookmarked: bool,
+    /// True if the impressions of the post by the caller are counted.
+    pub counts_impressions: bool,
+}
+
+/// A post in the export format, containing only the author's own data. Deleted posts are
+/// exported as tombstones without a body and files.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ExportedPost {
+    pub id: PostId,
+    pub parent: Option<PostId>,
+    pub realm: Option<String>,
+    pub timestamp: u64,
+    pub body: String,
+    /// Keys of the attached blobs.
+    pub files: Vec<String>,
+    pub deleted: bool,
+}
+
+/// A page of the post export; `next_cursor` is not set on the final page.
+#[derive(Serialize)]
+pub struct PostExport {
+    pub posts: Vec<ExportedPost>,
+    pub next_cursor: Option<PostId>,
+}
+
+/// The change of a post seen by indexers.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PostEvent {
+    Created,
+    Edited,
+    Deleted,
+}
+
+/// The log of post changes for external indexers. Every change gets the next sequence number and
+/// only the latest change of each post is kept, so the log never outgrows the number of posts.
+#[derive(Default, Serialize, Deserialize)]
+pub struct PostChanges {
+    changes: BTreeMap<u64, (PostId, PostEvent)>,
+    latest: BTreeMap<PostId, u64>,
+    next_seq: u64,
+}
+
+impl PostChanges {
+    pub fn record(&mut self, post_id: PostId, event: PostEvent) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if let Some(previous) = self.latest.insert(post_id, seq) {
+            self.changes.remove(&previous);
+        }
+        self.changes.insert(seq, (post_id, event));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.next_seq == 0
+    }
+
+    /// Returns up to `limit` changes with sequence numbers greater than the cursor.
+    pub fn since(
+        &self,
+        cursor: Option<u64>,
+        limit: usize,
+    ) -> impl Iterator<Item = (u64, PostId, PostEvent)> + '_ {
+        let start = cursor.map(|seq| seq.saturating_add(1)).unwrap_or_default();
+        self.changes
+            .range(start..)
+            .take(limit)
+            .map(|(seq, (post_id, event))| (*seq, *post_id, *event))
+    }
+
+    pub fn last_seq(&self) -> Option<u64> {
+        self.next_seq.checked_sub(1)
+    }
+}
+
+/// A page of the indexer sync. `cursor` is the sequence number of the last returned change and
+/// should be passed to the next request. The sync is complete once the cursor reaches
+/// `high_water_mark`, the sequence number of the latest change.
+#[derive(Serialize)]
+pub struct PostsSince {
+    pub posts: Vec<(PostEvent, Post)>,
+    pub cursor: Option<u64>,
+    pub high_water_mark: Option<u64>,
+}
+
+/// A version of the post body.
+#[derive(Serialize)]
+pub enum Version {
+    /// A previous version with its timestamp, given as a patch transforming the next version into
+    /// this one.
+    Patch(u64, String),
+    /// The current body of the post with its timestamp.
+    Current(u64, String),
+    /// A version removed by the post deletion, given as the SHA256 hash of its body.
+    Redacted(String),
+}
+
+/// Cycles and karma moved by a reaction; needed to revert it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(from = "StoredReactionCharge")]
+pub struct ReactionCharge {
+    /// Cycles paid by the reactor without the fee.
+    pub reactor_cycles: Cycles,
+    /// Cycles burned from the author.
+    pub author_cycles: Cycles,
+    /// Karma change of the author.
+    pub author_karma: Karma,
+    /// Minting epoch of the reaction; once the karma of the epoch was collected for minting,
+    /// the reaction can't be refunded anymore.
+    pub epoch: Option<u64>,
+}
+
+// Charges recorded before the minting epochs were tracked are stored as tuples and are never
+// refunded.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredReactionCharge {
+    Legacy(Cycles, Cycles, Karma),
+    Current {
+        reactor_cycles: Cycles,
+        author_cycles: Cycles,
+        author_karma: Karma,
+        epoch: Option<u64>,
+    },
+}
+
+impl From<StoredReactionCharge> for ReactionCharge {
+    fn from(charge: StoredReactionCharge) -> Self {
+        match charge {
+            StoredReactionCharge::Legacy(reactor_cycles, author_cycles, author_karma) => {
+                ReactionCharge {
+                    reactor_cycles,
+                    author_cycles,
+                    author_karma,
+                    epoch: None,
+                }
+            }
+            StoredReactionCharge::Current {
+                reactor_cycles,
+                author_cycles,
+                author_karma,
+                epoch,
+            } => ReactionCharge {
+                reactor_cycles,
+                author_cycles,
+                author_karma,
+                epoch,
+            },
+        }
+    }
+}
+
 #[derive(Clone, Default, Serialize, Deserialize)]
 pub struct Post {
     pub id: PostId,
@@ -63,9 +264,48 @@ pub struct Post {
     pub tree_update: u64,
     pub report: Option<Report>,
     pub tips: Vec<(UserId, u64)>,
+    // index of the tip -> message attached to it
+    #[serde(default)]
+    pub tip_messages: BTreeMap<usize, String>,
+    // index of the tip -> recipients and their shares, if the tip was split
+    #[serde(default)]
+    pub tip_shares: BTreeMap<usize, Vec<(UserId, u64)>>,
     pub extension: Option<Extension>,
     pub realm: Option<String>,
     pub hashes: Vec<String>,
+    // timestamp of the deletion request; until the deletion becomes permanent the post is
+    // hidden from feeds and can be restored by the author
+    #[serde(default)]
+    pub deletion_scheduled: Option<u64>,
+    // number of times the post was reposted
+    #[serde(default)]
+    pub reposts: u32,
+    // charges of the reactions by reactor; needed to revert reactions
+    #[serde(default)]
+    pub reaction_charges: BTreeMap<UserId, ReactionCharge>,
+    // true while some blobs referenced by the body couldn't be stored yet
+    #[serde(default)]
+    pub media_pending: bool,
+    // ISO 639-1 code of the post language; comments inherit the language of the parent
+    #[serde(default)]
+    pub language: Option<String>,
+    // only set on deleted posts
+    #[serde(default)]
+    pub deleted_by: Option<DeletedBy>,
+    // set by the author on creation or by stalwarts resolving a report
+    #[serde(default)]
+    pub content_warning: Option<ContentWarning>,
+
+    // number of deduplicated views by other users; only counted if the author opted in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impressions: Option<u32>,
+    // only attached to posts returned to the clients and never persisted
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub engagement: Option<Engagement>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub viewer: Option<ViewerContext>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub realm_style: Option<RealmStyle>,
 
     #[serde(skip)]
     pub archived: bool,
@@ -111,7 +351,20 @@ impl Post {
             patches: Default::default(),
             files: Default::default(),
             tips: Default::default(),
+            tip_messages: Default::default(),
+            tip_shares: Default::default(),
             hashes: Default::default(),
+            deletion_scheduled: None,
+            reposts: 0,
+            reaction_charges: Default::default(),
+            media_pending: false,
+            language: None,
+            deleted_by: None,
+            content_warning: None,
+            impressions: None,
+            engagement: None,
+            viewer: None,
+            realm_style: None,
             tree_size: 0,
             tree_update: timestamp,
             report: None,
@@ -166,13 +419,37 @@ impl Post {
         Ok(())
     }
 
+    /// Checks the body and the blobs against the configured limits. The body length is counted
+    /// in characters, so that non-latin text is not penalized.
     pub fn valid(&self, blobs: &[(String, Blob)]) -> Result<(), String> {
-        if self.body.is_empty() || self.body.chars().count() > CONFIG.max_post_length {
-            return Err("invalid post content".into());
+        if self.body.is_empty() {
+            return Err("post is empty".into());
+        }
+        if self.body.chars().count() > CONFIG.max_post_length {
+            return Err(format!(
+                "post is longer than {} characters",
+                CONFIG.max_post_length
+            ));
+        }
+        if blobs.len() > CONFIG.max_blobs_per_post {
+            return Err(format!(
+                "not more than {} blobs per post allowed",
+                CONFIG.max_blobs_per_post
+            ));
         }
-        if !blobs.iter().all(|(key, blob)| {
-            key.len() <= 8 && blob.len() > 0 && blob.len() <= CONFIG.max_blob_size_bytes
-        }) {
+        if let Some((key, _)) = blobs
+            .iter()
+            .find(|(_, blob)| blob.len() > CONFIG.max_blob_size_bytes)
+        {
+            return Err(format!(
+                "blob {} is larger than {} bytes",
+                key, CONFIG.max_blob_size_bytes
+            ));
+        }
+        if !blobs
+            .iter()
+            .all(|(key, blob)| key.len() <= 8 && !blob.is_empty())
+        {
             return Err("invalid blobs".into());
         }
         Ok(())
@@ -185,25 +462,43 @@ impl Post {
         })
         .unwrap_or_default();
 
-        for (id, blob) in blobs
+        let mut blobs = blobs
             .into_iter()
-            .filter(|(id, _)| !existing_blobs.contains(id))
-        {
-            match Storage::write_to_bucket(blob.as_slice()).await {
+            .filter(|(id, _)| !existing_blobs.contains(id));
+        while let Some((id, blob)) = blobs.next() {
+            // identical blobs are stored only once
+            let hash = blob_hash(blob.as_slice());
+            let location = match read(|state| state.storage.lookup(&hash)) {
+                Some(location) => Ok(location),
+                None => Storage::write_to_bucket(blob.as_slice()).await,
+            };
+            match location {
                 Ok((bucket_id, offset)) => mutate(|state| {
-                    Post::mutate(state, &post_id, |post| {
+                    let (bucket_id, offset) =
+                        state.storage.acquire(hash, bucket_id, offset, blob.len());
+                    let result = Post::mutate(state, &post_id, |post| {
                         post.files
                             .insert(format!("{}@{}", id, bucket_id), (offset, blob.len()));
                         Ok(())
-                    })
+                    });
+                    if result.is_err() {
+                        state.storage.release(bucket_id, offset);
+                    }
+                    result
                 }),
                 Err(err) => {
                     let msg = format!("Couldn't write a blob to bucket: {:?}", err);
-                    mutate(|state| state.logger.error(&msg));
+                    // the remaining blobs are retried in the chores
+                    let remaining = std::iter::once((id, blob)).chain(blobs).collect();
+                    mutate(|state| {
+                        state.logger.error(&msg);
+                        defer_blobs(state, post_id, remaining, time());
+                    });
                     Err(err)
                 }
             }?
         }
+        mutate(|state| complete_blobs(state, post_id));
         Ok(())
     }
 
@@ -211,26 +506,78 @@ impl Post {
         &mut self,
         stalwarts: usize,
         stalwart: UserId,
+        karma: Karma,
         confirmed: bool,
     ) -> Result<(), String> {
         if self.user == stalwart {
             return Err("no voting on own posts".into());
         }
         let report = self.report.as_mut().ok_or("no report found".to_string())?;
-        report.vote(stalwarts, stalwart, confirmed)?;
-        let approved = report.closed && report.confirmed_by.len() > report.rejected_by.len();
-        if approved {
-            self.delete(vec![self.body.clone()]);
+        report.vote(stalwarts, stalwart, karma, confirmed)?;
+        let approved = report.closed && report.confirmed();
+        // posts confirmed on appeal were already deleted by the original decision
+        if approved && !self.is_deleted() {
+            self.delete(vec![self.body.clone()], DeletedBy::Moderators);
         }
         Ok(())
     }
 
+    pub fn export(&self) -> ExportedPost {
+        ExportedPost {
+            id: self.id,
+            parent: self.parent,
+            realm: self.realm.clone(),
+            timestamp: self.timestamp(),
+            body: self.body.clone(),
+            files: self.files.keys().cloned().collect(),
+            deleted: self.is_deleted(),
+        }
+    }
+
+    /// Returns the number of reactions, direct replies and tips of the post.
+    pub fn engagement_score(&self) -> usize {
+        self.reactions
+            .values()
+            .map(|users| users.len())
+            .sum::<usize>()
+            + self.children.len()
+            + self.tips.len()
+    }
+
     pub fn is_deleted(&self) -> bool {
         !self.hashes.is_empty()
     }
 
-    pub fn delete(&mut self, versions: Vec<String>) {
-        self.files.clear();
+    /// Returns true if the post has a content warning and the viewer hides such posts.
+    pub fn hidden_by_warning(&self, hide_flagged: bool) -> bool {
+        hide_flagged && self.content_warning.is_some()
+    }
+
+    /// Returns true if the post is in one of the languages or has no language. An empty list
+    /// accepts all languages.
+    pub fn in_languages(&self, languages: &[String]) -> bool {
+        languages.is_empty()
+            || self
+                .language
+                .as_ref()
+                .map(|language| languages.contains(language))
+                .unwrap_or(true)
+    }
+
+    /// Returns true if the post is deleted or its deletion is pending.
+    pub fn hidden(&self) -> bool {
+        self.is_deleted() || self.deletion_scheduled.is_some()
+    }
+
+    /// Deletes the post content and returns the removed files, so that their blobs can be
+    /// released.
+    pub fn delete(
+        &mut self,
+        versions: Vec<String>,
+        deleted_by: DeletedBy,
+    ) -> BTreeMap<String, (u64, usize)> {
+        let files = std::mem::take(&mut self.files);
+        self.deleted_by = Some(deleted_by);
         self.body.clear();
         self.patches.clear();
         self.tags.clear();
@@ -243,6 +590,23 @@ impl Post {
                 format!("{:x}", hasher.finalize())
             })
             .collect();
+        files
+    }
+
+    /// Returns all versions of the post from the oldest to the current one.
+    pub fn history(&self) -> Vec<Version> {
+        if self.is_deleted() {
+            return self.hashes.iter().cloned().map(Version::Redacted).collect();
+        }
+        self.patches
+            .iter()
+            .cloned()
+            .map(|(timestamp, patch)| Version::Patch(timestamp, patch))
+            .chain(std::iter::once(Version::Current(
+                self.timestamp,
+                self.body.clone(),
+            )))
+            .collect()
     }
 
     pub fn costs(&self, blobs: usize) -> Cycles {
@@ -256,30 +620,107 @@ impl Post {
             }
     }
 
-    pub fn make_hot(&self, hot_list: &mut VecDeque<PostId>, total_users: usize, user_id: UserId) {
+    /// Returns the number of positive reactions on the post.
+    pub fn positive_reactions(&self, catalog: &BTreeMap<u16, Reaction>) -> usize {
+        self.reactions
+            .iter()
+            .filter_map(|(id, users)| {
+                catalog
+                    .get(id)
+                    .filter(|reaction| reaction.positive)
+                    .map(|_| users.len())
+            })
+            .sum()
+    }
+
+    /// Returns the sum of karma of all reactions on the post, including the negative ones.
+    pub fn reaction_karma(&self, catalog: &BTreeMap<u16, Reaction>) -> i64 {
+        self.reactions
+            .iter()
+            .filter_map(|(id, users)| {
+                catalog
+                    .get(id)
+                    .map(|reaction| reaction.karma * users.len() as i64)
+            })
+            .sum()
+    }
+
+    /// Returns the post with the engagement metrics attached.
+    pub fn with_reaction_summary(mut self, catalog: &BTreeMap<u16, Reaction>) -> Self {
+        let reactors = self
+            .reactions
+            .values()
+            .flatten()
+            .copied()
+            .collect::<BTreeSet<_>>();
+        self.engagement = Some(Engagement {
+            reactions_karma: self.reaction_karma(catalog),
+            comments: self.tree_size,
+            reposts: self.reposts,
+            reactors: reactors.len(),
+            reactor_sample: reactors
+                .into_iter()
+                .take(CONFIG.reactor_sample_size)
+                .collect(),
+        });
+        self
+    }
+
+    /// Returns the post with the colors of its realm attached.
+    pub fn with_realm_style(mut self, realm: &Realm) -> Self {
+        self.realm_style = Some(RealmStyle {
+            label_color: realm.label_color.clone(),
+            theme: realm.theme.clone(),
+        });
+        self
+    }
+
+    /// Returns true if the user can see the impressions of the post: only the author and the
+    /// controllers of the post's realm can.
+    pub fn impressions_visible_to(&self, user_id: UserId, realm: Option<&Realm>) -> bool {
+        self.user == user_id || realm.map_or(false, |realm| realm.controllers.contains(&user_id))
+    }
+
+    /// Returns the post with the relationship of the given user to it attached.
+    pub fn with_viewer_context(mut self, viewer: &User, counts_impressions: bool) -> Self {
+        self.viewer = Some(ViewerContext {
+            following_author: viewer.followees.contains(&self.user),
+            reacted: self
+                .reactions
+                .values()
+                .any(|users| users.contains(&viewer.id)),
+            bookmarked: viewer.bookmarks.contains(&self.id),
+            counts_impressions,
+        });
+        self
+    }
+
+    pub fn make_hot(
+        &self,
+        hot_list: &mut VecDeque<PostId>,
+        total_users: usize,
+        user_id: UserId,
+        catalog: &BTreeMap<u16, Reaction>,
+    ) {
         // if it's a comment or reaction is from the users itself, exit
         if self.parent.is_some() || self.user == user_id {
             return;
         };
-        let engagements = self
-            .reactions
-            .iter()
-            .filter_map(|(id, users)| {
-                (*id >= CONFIG.min_positive_reaction_id).then_some(users.len())
-            })
-            .sum::<usize>() as u32
-            + self.tree_size;
+        let engagements = self.positive_reactions(catalog) as u32 + self.tree_size;
 
         if engagements as f32 / (total_users as f32) < CONFIG.hot_post_engagement_percentage {
             return;
         }
         // negative reactions balance
-        let karma = reaction_karma();
         if self
             .reactions
             .iter()
             .map(|(r_id, users)| {
-                karma.get(r_id).copied().unwrap_or_default() * users.len() as Karma
+                catalog
+                    .get(r_id)
+                    .map(|reaction| reaction.karma)
+                    .unwrap_or_default()
+                    * users.len() as Karma
             })
             .sum::<i64>()
             < 0
@@ -296,8 +737,18 @@ impl Post {
 
     /// Checks if the poll has ended. If not, returns `Ok(false)`. If the poll ended,
     /// returns `Ok(true)` and assings the result weighted by the square root of karma and by the token
-    /// voting power.
+    /// voting power. Polls in realms are additionally weighted by the square root of the realm
+    /// reputation of voters reaching the realm's threshold.
     pub fn conclude_poll(state: &mut State, post_id: &PostId, now: u64) -> Result<bool, String> {
+        let realm_reputation = Post::get(state, post_id)
+            .and_then(|post| post.realm.as_ref())
+            .and_then(|name| {
+                state.realms.get(name).map(|realm| {
+                    let mut reputation = state.realm_reputation(name);
+                    reputation.retain(|_, value| *value >= realm.min_voting_reputation);
+                    reputation
+                })
+            });
         let (balances, users) = Post::get(state, post_id)
             .and_then(|post| {
                 if let Some(Extension::Poll(poll)) = post.extension.as_ref() {
@@ -363,6 +814,22 @@ impl Post {
                     })
                     .collect();
 
+                if let Some(reputation) = realm_reputation {
+                    poll.weighted_by_realm_reputation = poll
+                        .votes
+                        .iter()
+                        .map(|(k, ids)| {
+                            (
+                                *k,
+                                ids.iter()
+                                    .filter_map(|id| reputation.get(id))
+                                    .map(|value| (*value as f32).sqrt() as u64)
+                                    .sum(),
+                            )
+                        })
+                        .collect();
+                }
+
                 return Ok(true);
             }
             Err("no poll extension".into())
@@ -388,10 +855,14 @@ impl Post {
             if post.user != user.id {
                 return Err("unauthorized".to_string());
             }
+            if post.hidden() {
+                return Err("post deleted".into());
+            }
             if let Some(false) = picked_realm.as_ref().map(|name| user.realms.contains(name)) {
                 return Err("you're not in the realm".into());
             }
             let user_id = user.id;
+            let old_mentions = mentioned_users(state, &post.body);
             post.tags = tags(CONFIG.max_tag_length, &body);
             post.body = body;
             post.valid(&blobs)?;
@@ -405,7 +876,34 @@ impl Post {
                 .filter(|(id, _)| !old_blob_ids.contains(id.as_str()))
                 .count();
             let costs = post.costs(new_blobs);
+            // moving the post into a realm is levied like posting into it
+            let realm_post_cost = picked_realm
+                .as_ref()
+                .filter(|name| post.realm.as_ref() != Some(name))
+                .and_then(|name| state.realms.get(name))
+                .map(|realm| realm.post_cost)
+                .unwrap_or_default();
+            if realm_post_cost > 0 && user.cycles() < costs + realm_post_cost {
+                return Err("not enough cycles".into());
+            }
             state.charge(user_id, costs, format!("editing of post {}", id))?;
+            if realm_post_cost > 0 {
+                state
+                    .users
+                    .get_mut(&user_id)
+                    .expect("no user found")
+                    .change_cycles(
+                        realm_post_cost,
+                        CyclesDelta::Minus,
+                        format!("realm post cost of post {}", id),
+                    )?;
+                if let Some(realm) = picked_realm
+                    .as_ref()
+                    .and_then(|name| state.realms.get_mut(name))
+                {
+                    realm.treasury += realm_post_cost;
+                }
+            }
             post.patches.push((post.timestamp, patch));
             post.timestamp = timestamp;
 
@@ -413,8 +911,17 @@ impl Post {
 
             // After we validated the new edited copy of the post, charged the user, we should remove the
             // old post, and insert the edited one.
+            // only users mentioned for the first time get notified
+            let new_mentions = mentioned_users(state, &post.body)
+                .into_iter()
+                .filter(|id| *id != user_id && !old_mentions.contains(id))
+                .collect();
+
             Post::take(state, &id);
+            state.search_index.add(post.id, &post.body);
             Post::save(state, post);
+            state.log_post_change(id, PostEvent::Edited);
+            notify_mentioned_users(state, &user.name, id, new_mentions);
 
             if current_realm != picked_realm {
                 change_realm(state, id, picked_realm)
@@ -454,15 +961,23 @@ impl Post {
             }
         };
 
+        if user.banned(timestamp) {
+            return Err("banned users can't post".into());
+        }
+
         if user.is_bot() && parent.is_some() {
             return Err("Bots can't create comments currently".into());
         }
 
-        let limit = if parent.is_none() {
+        let mut limit = if parent.is_none() {
             CONFIG.max_posts_per_hour
         } else {
             CONFIG.max_comments_per_hour
         } as usize;
+        let cooldown_end = state.new_user_cooldown_end(user, timestamp);
+        if cooldown_end.is_some() {
+            limit = limit.min(CONFIG.new_user_max_posts_per_hour as usize);
+        }
 
         if user
             .posts(state)
@@ -474,13 +989,19 @@ impl Post {
             >= limit
         {
             return Err(format!(
-                "not more than {} {} per hour are allowed",
+                "not more than {} {} per hour are allowed{}",
                 limit,
                 if parent.is_none() {
                     "posts"
                 } else {
                     "comments"
-                }
+                },
+                cooldown_end
+                    .map(|end| format!(
+                        " for new users, the restriction lifts in {} hours",
+                        (end - timestamp + HOUR - 1) / HOUR
+                    ))
+                    .unwrap_or_default()
             ));
         }
         let realm = match parent.and_then(|id| Post::get(state, &id)) {
@@ -505,13 +1026,48 @@ impl Post {
             extension,
             realm.clone(),
         );
+        post.language = parent
+            .and_then(|id| Post::get(state, &id))
+            .and_then(|parent| parent.language.clone());
         let costs = post.costs(blobs.len());
+        let realm_post_cost = realm
+            .as_ref()
+            .and_then(|name| state.realms.get(name))
+            .map(|realm| realm.post_cost)
+            .unwrap_or_default();
+        if realm_post_cost > 0 && user.cycles() < costs + realm_post_cost {
+            return Err("not enough cycles".into());
+        }
         post.valid(blobs)?;
         let trusted_user = user.trusted();
+        let auto_follow = !user.auto_follow_disabled;
+        let rate_limit = rate_limits::limit(user, rate_limits::Action::Post);
+        state
+            .rate_limits
+            .check(user_id, rate_limits::Action::Post, rate_limit, timestamp)?;
         let future_id = state.next_post_id;
         state.charge(user_id, costs, format!("new post {}", future_id))?;
+        if realm_post_cost > 0 {
+            state
+                .users
+                .get_mut(&user_id)
+                .expect("no user found")
+                .change_cycles(
+                    realm_post_cost,
+                    CyclesDelta::Minus,
+                    format!("realm post cost of new post {}", future_id),
+                )?;
+        }
+        state
+            .rate_limits
+            .record(user_id, rate_limits::Action::Post, timestamp);
         let user = state.users.get_mut(&user_id).expect("no user found");
         user.num_posts += 1;
+        if parent.is_none() {
+            user.engagement.posts += 1;
+        } else {
+            user.engagement.comments += 1;
+        }
         // reorder realms
         if let Some(name) = &realm {
             user.realms.retain(|id| id != name);
@@ -522,11 +1078,14 @@ impl Post {
         post.id = id;
         if let Some(realm) = realm.and_then(|name| state.realms.get_mut(&name)) {
             realm.num_posts += 1;
+            realm.treasury += realm_post_cost;
         }
         if let Some(parent_id) = post.parent {
             let result = Post::mutate(state, &parent_id, |parent_post| {
                 parent_post.children.push(id);
-                parent_post.watchers.insert(user_id);
+                if auto_follow {
+                    parent_post.watchers.insert(user_id);
+                }
                 if parent_post.user != user_id && trusted_user {
                     return Ok(Some((parent_post.user, parent_post.id)));
                 }
@@ -544,6 +1103,14 @@ impl Post {
         if matches!(&post.extension, &Some(Extension::Poll(_))) {
             state.pending_polls.insert(post.id);
         }
+        if let Some(Extension::Repost(reposted_id)) = &post.extension {
+            if Post::get(state, reposted_id).is_some() {
+                Post::mutate(state, reposted_id, |reposted| {
+                    reposted.reposts += 1;
+                    Ok(())
+                })?;
+            }
+        }
 
         notify_about(state, &post);
 
@@ -551,9 +1118,12 @@ impl Post {
             state.root_posts += 1
         }
 
+        state.search_index.add(post.id, &post.body);
         Post::save(state, post);
+        state.log_post_change(id, PostEvent::Created);
 
         let users_len = state.users.len();
+        let catalog = state.reactions();
         let mut hot_posts = std::mem::take(&mut state.hot);
         state
             .thread(id)
@@ -562,7 +1132,7 @@ impl Post {
                 Post::mutate(state, &id, |post| {
                     post.tree_size += 1;
                     post.tree_update = timestamp;
-                    post.make_hot(&mut hot_posts, users_len, user_id);
+                    post.make_hot(&mut hot_posts, users_len, user_id, &catalog);
                     Ok(())
                 })
             })
@@ -621,25 +1191,138 @@ impl Post {
     }
 }
 
-// Moves a configured number of posts from hot to cold memory.
-pub fn archive_cold_posts(state: &mut State, max_posts_in_heap: usize) -> Result<(), String> {
+// Keeps the blobs which couldn't be stored for retries until the deadline and marks the post as
+// having pending media.
+fn defer_blobs(state: &mut State, post_id: PostId, blobs: Vec<(String, Blob)>, now: u64) {
+    let deadline = now + CONFIG.blob_upload_deadline_hours * HOUR;
+    state
+        .pending_blobs
+        .entry(post_id)
+        .or_insert((deadline, vec![]))
+        .1 = blobs;
+    if Post::get(state, &post_id).is_some() {
+        let _ = Post::mutate(state, &post_id, |post| {
+            post.media_pending = true;
+            Ok(())
+        });
+    }
+}
+
+fn complete_blobs(state: &mut State, post_id: PostId) {
+    if state.pending_blobs.remove(&post_id).is_some() && Post::get(state, &post_id).is_some() {
+        let _ = Post::mutate(state, &post_id, |post| {
+            post.media_pending = false;
+            Ok(())
+        });
+    }
+}
+
+/// Retries storing the blobs of all posts with pending media. Posts whose blobs couldn't be
+/// stored until the deadline lose the references to the missing blobs.
+pub async fn retry_pending_blobs(now: u64) {
+    let pending = mutate(|state| {
+        roll_back_pending_blobs(state, now);
+        state
+            .pending_blobs
+            .iter()
+            .map(|(post_id, (_, blobs))| (*post_id, blobs.clone()))
+            .collect::<Vec<_>>()
+    });
+    for (post_id, blobs) in pending {
+        // failures are logged and deferred again
+        let _ = Post::save_blobs(post_id, blobs).await;
+    }
+}
+
+fn roll_back_pending_blobs(state: &mut State, now: u64) {
+    let expired = state
+        .pending_blobs
+        .iter()
+        .filter(|(_, (deadline, _))| *deadline <= now)
+        .map(|(post_id, _)| *post_id)
+        .collect::<Vec<_>>();
+    for post_id in expired {
+        let missing = state
+            .pending_blobs
+            .remove(&post_id)
+            .map(|(_, blobs)| blobs.into_iter().map(|(id, _)| id).collect::<BTreeSet<_>>())
+            .unwrap_or_default();
+        let post = match Post::get(state, &post_id) {
+            Some(post) => post,
+            None => continue,
+        };
+        let author = post.user;
+        let body = strip_blob_references(&post.body, &missing);
+        if !post.is_deleted() {
+            state.search_index.add(post_id, &body);
+        }
+        let _ = Post::mutate(state, &post_id, |post| {
+            post.body = body;
+            post.media_pending = false;
+            Ok(())
+        });
+        state.logger.error(format!(
+            "Removed {} blobs from post {} after failing to store them.",
+            missing.len(),
+            post_id
+        ));
+        if let Some(user) = state.users.get_mut(&author) {
+            user.notify_about_post(
+                NotificationType::System,
+                format!(
+                    "Some pictures of your post couldn't be stored and were removed from it: {}",
+                    missing.into_iter().collect::<Vec<_>>().join(", ")
+                ),
+                post_id,
+            );
+        }
+    }
+}
+
+// Removes all markdown images pointing to the given blob ids from the text.
+fn strip_blob_references(body: &str, ids: &BTreeSet<String>) -> String {
+    let mut body = body.to_string();
+    for id in ids {
+        let target = format!("](/blob/{})", id);
+        while let Some(end) = body.find(&target) {
+            let start = body[..end].rfind("![").unwrap_or(end);
+            body.replace_range(start..end + target.len(), "");
+        }
+    }
+    body.trim().to_string()
+}
+
+// Moves posts from hot to cold memory: all posts exceeding `max_posts_in_heap` and posts older
+// than the configured age having less engagement than the configured floor.
+pub fn archive_cold_posts(
+    state: &mut State,
+    max_posts_in_heap: usize,
+    now: u64,
+) -> Result<(), String> {
+    let cold_threshold = now.saturating_sub(CONFIG.cold_post_age_days * DAY);
     let mut posts: Vec<&Post> = state.posts.values().collect();
-    let posts_to_archive = posts
-        .len()
-        .saturating_sub(max_posts_in_heap)
+    // sort from newest to oldest
+    posts.sort_unstable_by_key(|p| std::cmp::Reverse(p.timestamp()));
+    let ids = posts
+        .into_iter()
+        .enumerate()
+        .filter(|(i, post)| {
+            *i >= max_posts_in_heap
+                || (post.timestamp() < cold_threshold
+                    && post.engagement_score() < CONFIG.cold_post_engagement_floor
+                    && !state.pending_polls.contains(&post.id))
+        })
+        .map(|(_, post)| post.id)
         // We cap the number at `max_posts_in_heap` because we know for sure this archiving will
         // never run out of instructions.
-        .min(max_posts_in_heap);
+        .take(max_posts_in_heap)
+        .collect::<Vec<_>>();
+    let posts_to_archive = ids.len();
     if posts_to_archive == 0 {
         return Ok(());
     }
 
-    // sort from newest to oldest
-    posts.sort_unstable_by_key(|p| std::cmp::Reverse(p.timestamp()));
-    let ids = posts.into_iter().map(|post| post.id).collect::<Vec<_>>();
-
     ids.into_iter()
-        .skip(max_posts_in_heap)
         .try_for_each(|post_id| {
             let post = state
                 .posts
@@ -676,6 +1359,7 @@ pub fn change_realm(state: &mut State, root_post_id: PostId, new_realm: Option<S
             Ok(())
         })
         .expect("couldn't mutate post");
+        state.log_post_change(post_id, PostEvent::Edited);
     }
 }
 
@@ -689,39 +1373,38 @@ fn notify_about(state: &mut State, post: &Post) {
     let mut notified: HashSet<_> = HashSet::new();
     // Don't notify the author
     notified.insert(post.user);
+    let root_id = post
+        .parent
+        .and_then(|parent_id| state.thread(parent_id).next());
+    let muted = |user: &User| root_id.map_or(false, |id| user.muted_threads.contains(&id));
     if let Some(parent) = post
         .parent
         .and_then(|parent_id| Post::get(state, &parent_id))
     {
-        let parent_author = parent.user;
+        let (parent_id, parent_author) = (parent.id, parent.user);
         if parent_author != post.user {
-            if let Some(user) = state.users.get_mut(&parent_author) {
-                user.notify_about_post(
+            if let Some(user) = state
+                .users
+                .get_mut(&parent_author)
+                .filter(|user| !muted(user))
+            {
+                user.notify_about_post_event(
+                    NotificationType::Reply,
                     format!("@{} replied to your post", post_user_name,),
                     post.id,
+                    parent_id,
+                    |n| format!("`{}` new replies to your post", n),
                 );
                 notified.insert(user.id);
             }
         }
     }
 
-    user_handles(CONFIG.max_tag_length, &post.body)
+    let mentioned = mentioned_users(state, &post.body)
         .into_iter()
-        .filter_map(|handle| state.user(&handle).map(|user| user.id))
-        .filter(|id| !notified.contains(id))
-        .collect::<Vec<_>>()
-        .into_iter()
-        .for_each(|mentioned_user_id| {
-            let user = state
-                .users
-                .get_mut(&mentioned_user_id)
-                .expect("no user found");
-            user.notify_about_post(
-                format!("@{} mentioned you in a post", post_user_name),
-                post.id,
-            );
-            notified.insert(user.id);
-        });
+        .filter(|id| notified.insert(*id))
+        .collect();
+    notify_mentioned_users(state, &post_user_name, post.id, mentioned);
 
     if let Some(parent_id) = post.parent {
         state
@@ -739,7 +1422,7 @@ fn notify_about(state: &mut State, post: &Post) {
                 if notified.contains(&user_id) {
                     return;
                 }
-                if let Some(user) = state.users.get_mut(&user_id) {
+                if let Some(user) = state.users.get_mut(&user_id).filter(|user| !muted(user)) {
                     user.notify_about_watched_post(
                         post_id,
                         post.id,
@@ -749,6 +1432,43 @@ fn notify_about(state: &mut State, post: &Post) {
                 notified.insert(user_id);
             });
     }
+
+    if post.parent.is_none() && !post.tags.is_empty() {
+        let tags: BTreeSet<_> = post.tags.iter().map(|tag| tag.to_lowercase()).collect();
+        let subscribers = tags
+            .iter()
+            .filter_map(|tag| state.feed_subscribers.get(tag))
+            .flatten()
+            .filter(|id| !notified.contains(id))
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        for user_id in subscribers {
+            let Some(user) = state.users.get_mut(&user_id).filter(|user| {
+                post.realm
+                    .as_ref()
+                    .map(|realm| user.realms.contains(realm))
+                    .unwrap_or(true)
+            }) else {
+                continue;
+            };
+            if let Some(feed) = user
+                .notified_feeds
+                .iter()
+                .find(|feed| feed.is_subset(&tags))
+                .cloned()
+            {
+                user.notify_about_feed_match(&feed, &post_user_name, post.id, post.timestamp());
+            }
+        }
+    }
+}
+
+/// Accepts ISO 639-1 language codes, e.g. `en`.
+pub fn validate_language(code: &str) -> Result<(), String> {
+    if code.len() != 2 || !code.chars().all(|c| c.is_ascii_lowercase()) {
+        return Err(format!("invalid language code {}", code));
+    }
+    Ok(())
 }
 
 // Extracts hashtags from a string.
@@ -756,6 +1476,41 @@ fn tags(max_tag_length: usize, input: &str) -> BTreeSet<String> {
     tokens(max_tag_length, input, &['#', '$'])
 }
 
+// Returns the ids of the existing users mentioned in the body. Only the first mentions are
+// considered to prevent notification spam.
+fn mentioned_users(state: &State, body: &str) -> BTreeSet<UserId> {
+    let mut ids = BTreeSet::new();
+    for user in user_handles(CONFIG.max_tag_length, body)
+        .into_iter()
+        .filter_map(|handle| state.user(&handle))
+    {
+        if ids.len() >= CONFIG.max_mentions_per_post {
+            break;
+        }
+        if user.deleted_at.is_none() {
+            ids.insert(user.id);
+        }
+    }
+    ids
+}
+
+fn notify_mentioned_users(
+    state: &mut State,
+    author_name: &str,
+    post_id: PostId,
+    user_ids: BTreeSet<UserId>,
+) {
+    for user_id in user_ids {
+        if let Some(user) = state.users.get_mut(&user_id) {
+            user.notify_about_post(
+                NotificationType::Mention,
+                format!("@{} mentioned you in a post", author_name),
+                post_id,
+            );
+        }
+    }
+}
+
 // Extracts user names from a string.
 fn user_handles(max_tag_length: usize, input: &str) -> BTreeSet<String> {
     tokens(max_tag_length, input, &['@'])
@@ -801,9 +1556,270 @@ mod tests {
     use super::*;
     use crate::{
         env::tests::{create_user, pr},
-        STATE,
+        read, STATE,
     };
 
+    #[test]
+    fn test_post_validation() {
+        let post = |body: String| Post::new(0, Default::default(), body, 0, None, None, None);
+        let blob = |size| ByteBuf::from(vec![0; size]);
+
+        // multi-byte characters count as one
+        assert_eq!(post("é".repeat(CONFIG.max_post_length)).valid(&[]), Ok(()));
+        assert_eq!(
+            post("é".repeat(CONFIG.max_post_length + 1)).valid(&[]),
+            Err(format!(
+                "post is longer than {} characters",
+                CONFIG.max_post_length
+            ))
+        );
+        assert_eq!(post(" ".into()).valid(&[]), Err("post is empty".into()));
+
+        let body = "Test".to_string();
+        let blobs = (0..CONFIG.max_blobs_per_post)
+            .map(|i| (i.to_string(), blob(1)))
+            .collect::<Vec<_>>();
+        assert_eq!(post(body.clone()).valid(&blobs), Ok(()));
+        let mut too_many = blobs.clone();
+        too_many.push(("x".into(), blob(1)));
+        assert_eq!(
+            post(body.clone()).valid(&too_many),
+            Err(format!(
+                "not more than {} blobs per post allowed",
+                CONFIG.max_blobs_per_post
+            ))
+        );
+
+        let max_size = CONFIG.max_blob_size_bytes;
+        assert_eq!(
+            post(body.clone()).valid(&[("a".into(), blob(max_size))]),
+            Ok(())
+        );
+        assert_eq!(
+            post(body.clone()).valid(&[("a".into(), blob(max_size + 1))]),
+            Err(format!("blob a is larger than {} bytes", max_size))
+        );
+        assert_eq!(
+            post(body).valid(&[("a".into(), blob(0))]),
+            Err("invalid blobs".into())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_mentions() {
+        STATE.with(|cell| cell.replace(Default::default()));
+        let post_id = mutate(|state| {
+            for (i, name) in ["alice", "bob", "carol"].iter().enumerate() {
+                let id = create_user(state, pr(i as u8));
+                state.users.get_mut(&id).unwrap().name = name.to_string();
+            }
+            Post::create(
+                state,
+                "Hi @bob, @Bob, @alice and @nobody!".into(),
+                &[],
+                pr(0),
+                0,
+                None,
+                None,
+                None,
+            )
+            .unwrap()
+        });
+        let inbox = |id| read(|state| state.users.get(&id).unwrap().inbox.len());
+        // self-mentions and repeated mentions are ignored
+        assert_eq!((inbox(0), inbox(1), inbox(2)), (0, 1, 0));
+
+        assert_eq!(
+            Post::edit(
+                post_id,
+                "Hi @bob and @carol!".into(),
+                vec![],
+                "".into(),
+                None,
+                pr(0),
+                time(),
+            )
+            .await,
+            Ok(())
+        );
+        // only the newly mentioned user is notified
+        assert_eq!((inbox(0), inbox(1), inbox(2)), (0, 1, 1));
+
+        // the number of mentions is capped
+        let body = mutate(|state| {
+            for i in 3..20 {
+                let id = create_user(state, pr(i));
+                state.users.get_mut(&id).unwrap().name = format!("user{}", i);
+            }
+            (3..20).map(|i| format!("@user{} ", i)).collect::<String>()
+        });
+        assert_eq!(
+            read(|state| mentioned_users(state, &body).len()),
+            CONFIG.max_mentions_per_post
+        );
+    }
+
+    #[test]
+    fn test_thread_muting() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            for i in 0..3 {
+                create_user(state, pr(i));
+            }
+            let root_id =
+                Post::create(state, "Root".into(), &[], pr(0), 0, None, None, None).unwrap();
+            let reply = |state: &mut State, i: u8, parent: PostId| {
+                Post::create(
+                    state,
+                    "Reply".into(),
+                    &[],
+                    pr(i),
+                    0,
+                    Some(parent),
+                    None,
+                    None,
+                )
+                .unwrap()
+            };
+            let inbox_empty =
+                |state: &State, id: UserId| state.users.get(&id).unwrap().inbox.is_empty();
+
+            state.users.get_mut(&2).unwrap().auto_follow_disabled = true;
+            let comment_id = reply(state, 1, root_id);
+            reply(state, 2, root_id);
+            assert_eq!(
+                Post::get(state, &root_id).unwrap().watchers,
+                vec![0, 1].into_iter().collect()
+            );
+            assert!(!inbox_empty(state, 0));
+            assert!(!inbox_empty(state, 1));
+
+            // muting suppresses the notifications about the whole thread, even for the author
+            assert!(state
+                .users
+                .get_mut(&0)
+                .unwrap()
+                .toggle_muting_thread(root_id));
+            for user in state.users.values_mut() {
+                user.inbox.clear();
+            }
+            reply(state, 1, root_id);
+            reply(state, 2, comment_id);
+            assert!(inbox_empty(state, 0));
+            assert!(!inbox_empty(state, 1));
+
+            assert!(!state
+                .users
+                .get_mut(&0)
+                .unwrap()
+                .toggle_muting_thread(root_id));
+            reply(state, 2, root_id);
+            assert!(!inbox_empty(state, 0));
+        })
+    }
+
+    #[test]
+    fn test_engagement() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            for i in 0..3 {
+                create_user(state, pr(i));
+            }
+            let post_id =
+                Post::create(state, "Test".into(), &[], pr(0), 0, None, None, None).unwrap();
+            assert_eq!(state.react(pr(1), post_id, 100, 0), Ok(()));
+            assert_eq!(state.react(pr(2), post_id, 50, 0), Ok(()));
+            Post::create(
+                state,
+                "Comment".into(),
+                &[],
+                pr(1),
+                0,
+                Some(post_id),
+                None,
+                None,
+            )
+            .unwrap();
+            Post::create(
+                state,
+                "Repost".into(),
+                &[],
+                pr(2),
+                0,
+                None,
+                None,
+                Some(Extension::Repost(post_id)),
+            )
+            .unwrap();
+
+            let post = Post::get(state, &post_id).unwrap().clone();
+            // the metrics are never stored with the post
+            assert!(serde_json::to_value(&post)
+                .unwrap()
+                .get("engagement")
+                .is_none());
+            let engagement = post
+                .with_reaction_summary(&state.reactions())
+                .engagement
+                .unwrap();
+            assert_eq!(engagement.reactions_karma, 15);
+            assert_eq!(engagement.comments, 1);
+            assert_eq!(engagement.reposts, 1);
+            assert_eq!(engagement.reactors, 2);
+            assert_eq!(engagement.reactor_sample, vec![1, 2]);
+        })
+    }
+
+    #[test]
+    fn test_viewer_context() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            for i in 0..3 {
+                create_user(state, pr(i));
+            }
+            let post_id =
+                Post::create(state, "Test".into(), &[], pr(0), 0, None, None, None).unwrap();
+            assert_eq!(state.react(pr(1), post_id, 100, 0), Ok(()));
+            let user = state.principal_to_user_mut(pr(1)).unwrap();
+            user.followees.insert(0);
+            user.toggle_bookmark(post_id);
+
+            let post = Post::get(state, &post_id).unwrap().clone();
+            assert!(serde_json::to_value(&post).unwrap().get("viewer").is_none());
+            let context = |principal| {
+                post.clone()
+                    .with_viewer_context(state.principal_to_user(principal).unwrap(), false)
+                    .viewer
+                    .unwrap()
+            };
+            let viewer = context(pr(1));
+            assert!(viewer.following_author && viewer.reacted && viewer.bookmarked);
+            let viewer = context(pr(2));
+            assert!(!viewer.following_author && !viewer.reacted && !viewer.bookmarked);
+        })
+    }
+
+    #[test]
+    fn test_realm_style() {
+        let post = Post::new(0, Default::default(), "Test".into(), 0, None, None, None);
+        assert!(serde_json::to_value(&post)
+            .unwrap()
+            .get("realm_style")
+            .is_none());
+
+        let realm = Realm {
+            label_color: "#ff0000".into(),
+            theme: "dark".into(),
+            ..Default::default()
+        };
+        let value = serde_json::to_value(&post.with_realm_style(&realm)).unwrap();
+        assert_eq!(value["realm_style"]["label_color"], "#ff0000");
+        assert_eq!(value["realm_style"]["theme"], "dark");
+    }
+
     #[test]
     fn test_post_archiving() {
         static mut MEM_END: u64 = 16;
@@ -866,7 +1882,7 @@ mod tests {
 
             assert_eq!(state.posts.len(), 10);
             // Trigger post archiving
-            archive_cold_posts(state, 5).unwrap();
+            archive_cold_posts(state, 5, 0).unwrap();
             assert_eq!(
                 state.memory.health("B"),
                 "boundary=819B, mem_size=819B, segments=0".to_string()
@@ -917,7 +1933,7 @@ mod tests {
             );
 
             // Archive posts again
-            archive_cold_posts(state, 5).unwrap();
+            archive_cold_posts(state, 5, 0).unwrap();
             assert_eq!(state.posts.len(), 5);
             assert_eq!(state.memory.posts.len(), 6);
             // Segments were reduced, becasue the new post 10 fits into a gap left from one of the
@@ -926,6 +1942,17 @@ mod tests {
                 state.memory.health("B"),
                 "boundary=1145B, mem_size=1145B, segments=1".to_string()
             );
+
+            // Old posts with little engagement get archived regardless of the heap limit
+            Post::mutate(state, &9, |post| {
+                post.reactions
+                    .insert(10, (0..CONFIG.cold_post_engagement_floor as u64).collect());
+                Ok(())
+            })
+            .unwrap();
+            archive_cold_posts(state, 5, CONFIG.cold_post_age_days * DAY + 10_000).unwrap();
+            assert_eq!(state.posts.keys().collect::<Vec<_>>(), vec![&9]);
+            assert_eq!(state.memory.posts.len(), 10);
         });
     }
 
@@ -974,6 +2001,32 @@ mod tests {
         assert_eq!(p.costs(1), 2 * CONFIG.tag_cost + CONFIG.blob_cost);
     }
 
+    #[test]
+    fn test_history() {
+        let mut post = Post {
+            body: "third".into(),
+            timestamp: 3,
+            patches: vec![(1, "patch1".into()), (2, "patch2".into())],
+            ..Default::default()
+        };
+        assert!(matches!(
+            post.history().as_slice(),
+            [
+                Version::Patch(1, p1),
+                Version::Patch(2, p2),
+                Version::Current(3, body)
+            ] if p1 == "patch1" && p2 == "patch2" && body == "third"
+        ));
+
+        post.delete(
+            vec!["first".into(), "second".into(), "third".into()],
+            DeletedBy::Author,
+        );
+        let history = post.history();
+        assert_eq!(history.len(), 3);
+        assert!(history.iter().all(|v| matches!(v, Version::Redacted(_))));
+    }
+
     #[test]
     fn test_validity() {
         let mut p = Post::default();
@@ -1038,4 +2091,53 @@ mod tests {
             .valid(vec![("abcdefgh".to_string(), Default::default())].as_slice())
             .is_err());
     }
+
+    #[test]
+    fn test_blob_store_failure() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            create_user(state, pr(0));
+            let blob = ByteBuf::from(vec![0; 8]);
+            let body = "Pics\n![1x1, 1kb](/blob/a)\n![1x1, 1kb](/blob/b)".to_string();
+            let blobs = vec![("a".to_string(), blob.clone()), ("b".to_string(), blob)];
+            let post_id =
+                Post::create(state, body.clone(), &blobs, pr(0), 0, None, None, None).unwrap();
+
+            // the first blob was stored, storing the second one failed
+            Post::mutate(state, &post_id, |post| {
+                post.files.insert("a@aaaaa-aa".into(), (0, 8));
+                Ok(())
+            })
+            .unwrap();
+            defer_blobs(state, post_id, blobs[1..].to_vec(), 0);
+            assert!(Post::get(state, &post_id).unwrap().media_pending);
+            assert_eq!(state.pending_blobs.get(&post_id).unwrap().1.len(), 1);
+
+            // a failed retry keeps the original deadline
+            defer_blobs(state, post_id, blobs[1..].to_vec(), HOUR);
+            let deadline = CONFIG.blob_upload_deadline_hours * HOUR;
+            assert_eq!(state.pending_blobs.get(&post_id).unwrap().0, deadline);
+
+            roll_back_pending_blobs(state, deadline - 1);
+            assert_eq!(Post::get(state, &post_id).unwrap().body, body);
+
+            let inbox = state.principal_to_user(pr(0)).unwrap().inbox.len();
+            roll_back_pending_blobs(state, deadline);
+            let post = Post::get(state, &post_id).unwrap();
+            assert_eq!(post.body, "Pics\n![1x1, 1kb](/blob/a)");
+            assert!(!post.media_pending);
+            assert!(state.pending_blobs.is_empty());
+            assert_eq!(
+                state.principal_to_user(pr(0)).unwrap().inbox.len(),
+                inbox + 1
+            );
+
+            // completed uploads clear the pending state
+            defer_blobs(state, post_id, blobs[1..].to_vec(), 0);
+            complete_blobs(state, post_id);
+            assert!(!Post::get(state, &post_id).unwrap().media_pending);
+            assert!(state.pending_blobs.is_empty());
+        })
+    }
 }
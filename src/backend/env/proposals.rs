@@ -1,13 +1,17 @@
-use super::config::CONFIG;
+use super::config::{Reaction, CONFIG};
+use super::invoices::{self, parse_account};
 use super::post::{Extension, Post, PostId};
 use super::token::account;
-use super::user::Predicate;
+use super::user::{CyclesDelta, Predicate};
+use super::{realm_name_char, Cycles, Karma, DAY, HOUR, MINUTE, WEEK};
 use super::{user::UserId, State};
-use super::{Karma, HOUR};
+use crate::mutate;
 use crate::token::Token;
 use candid::Principal;
+use ic_ledger_types::{Memo, Tokens};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, VecDeque};
 
 #[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub enum Status {
@@ -18,6 +22,15 @@ pub enum Status {
     Cancelled,
 }
 
+/// State of the cycles deposited by the proposer.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum DepositStatus {
+    Held,
+    Refunded,
+    // burned after a rejection without controversy
+    Slashed,
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Release {
     pub commit: String,
@@ -33,6 +46,64 @@ pub struct Reward {
     pub receiver: String,
     pub votes: Vec<(Token, ProposedReward)>,
     pub minted: Token,
+    // if set, the reward is granted instead of being minted at once
+    #[serde(default)]
+    pub vesting: Option<Vesting>,
+}
+
+impl Reward {
+    /// Returns the tokens to mint: the average of the proposed rewards weighted by the voting
+    /// power of their voters, computed in `f32` and truncated.
+    pub fn tokens_to_mint(&self) -> Token {
+        let total: Token = self.votes.iter().map(|(vp, _)| vp).sum();
+        self.votes.iter().fold(0.0, |acc, (vp, reward)| {
+            acc + *vp as f32 / total as f32 * *reward as f32
+        }) as Token
+    }
+}
+
+/// Nothing is released before the cliff, afterwards tokens unlock linearly from the grant's
+/// start until the end of the vesting period.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct Vesting {
+    pub cliff_weeks: u64,
+    pub weeks: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VestingGrant {
+    pub proposal_id: u32,
+    pub receiver: Principal,
+    // the receiving user, so that the grant survives principal changes; not set if the receiver
+    // is not a user
+    #[serde(default)]
+    pub receiver_id: Option<UserId>,
+    pub total: Token,
+    pub released: Token,
+    pub start: u64,
+    pub vesting: Vesting,
+}
+
+impl VestingGrant {
+    /// Returns the id of the receiving user. Grants created before the receiver id was recorded
+    /// are resolved via the receiver principal.
+    pub fn receiver_id(&self, state: &State) -> Option<UserId> {
+        self.receiver_id
+            .or_else(|| state.principal_to_user(self.receiver).map(|user| user.id))
+    }
+
+    fn vested(&self, now: u64) -> Token {
+        let elapsed = now.saturating_sub(self.start);
+        if elapsed < self.vesting.cliff_weeks * WEEK {
+            return 0;
+        }
+        let duration = self.vesting.weeks * WEEK;
+        (self.total as u128 * elapsed.min(duration) as u128 / duration as u128) as Token
+    }
+
+    pub fn unvested(&self) -> Token {
+        self.total - self.released
+    }
 }
 
 #[derive(Clone, Default, Serialize, Deserialize)]
@@ -42,6 +113,33 @@ pub enum Payload {
     Release(Release),
     Fund(String, Token),
     Reward(Reward),
+    Reactions(Vec<Reaction>),
+    // recipient account and the amount of e8s to be transferred from the treasury
+    TransferICP(String, u64),
+    // user and the ban duration in days
+    Ban(UserId, u64),
+    // duration of the new user cooldown in hours
+    NewUserCooldown(u64),
+    // cycles spent on the karma of every voter
+    VotingReward(Cycles),
+    // handles nobody can register
+    ReservedHandles(Vec<String>),
+    // realm names nobody can create
+    ReservedRealmNames(Vec<String>),
+    // cycles deposited by proposers
+    ProposalDeposit(Cycles),
+    // weeks of inactivity after which the voting power of holders starts to decay and after
+    // which it reaches zero
+    VotingPowerDecay(u64, u64),
+}
+
+/// A transition of a proposal from the open status to a final one.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StatusChange {
+    pub seq: u64,
+    pub proposal_id: u32,
+    pub status: Status,
+    pub timestamp: u64,
 }
 
 #[derive(Clone, Default, Serialize, Deserialize)]
@@ -54,27 +152,126 @@ pub struct Proposal {
     pub payload: Payload,
     pub bulletins: Vec<(UserId, bool, Token)>,
     voting_power: Token,
+    // true if the losing side collected at least the controversy threshold of the winning votes;
+    // set on conclusion
+    #[serde(default)]
+    pub controversial: bool,
+    // shares of the total voting power in percent; set on conclusion
+    #[serde(default)]
+    pub approval_percentage: u64,
+    #[serde(default)]
+    pub rejection_percentage: u64,
+    // voter -> cycles spent on the voter's karma
+    #[serde(default)]
+    pub voting_rewards: BTreeMap<UserId, Cycles>,
+    // sum of the voting rewards; only set in query responses
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub voting_rewards_total: Option<Cycles>,
+    // cycles charged to the proposer for the proposal post
+    #[serde(default)]
+    pub creation_cost: Cycles,
+    // true if the proposal can still be cancelled with a refund; only set in query responses
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub refundable: Option<bool>,
+    // cycles held from the proposer until the conclusion
+    #[serde(default)]
+    pub deposit: Cycles,
+    // not set on proposals created before deposits were introduced
+    #[serde(default)]
+    pub deposit_status: Option<DepositStatus>,
 }
 
 impl Proposal {
+    /// Returns the sums of approving and rejecting votes.
+    fn votes(&self) -> (Token, Token) {
+        self.bulletins
+            .iter()
+            .fold((0, 0), |(approvals, rejects), (_, approved, balance)| {
+                if *approved {
+                    (approvals + balance, rejects)
+                } else {
+                    (approvals, rejects + balance)
+                }
+            })
+    }
+
+    fn record_outcome(&mut self, approvals: Token, rejects: Token) {
+        let (winning, losing) = if self.status == Status::Rejected {
+            (rejects, approvals)
+        } else {
+            (approvals, rejects)
+        };
+        self.controversial =
+            losing * 100 >= CONFIG.proposal_controversy_threashold as u64 * winning;
+        let percentage = |votes: Token| {
+            if self.voting_power == 0 {
+                0
+            } else {
+                (votes as u128 * 100 / self.voting_power as u128) as u64
+            }
+        };
+        self.approval_percentage = percentage(approvals);
+        self.rejection_percentage = percentage(rejects);
+    }
+
+    /// Returns true if cancelling the open proposal refunds its creation costs, which is only
+    /// the case shortly after the creation and before anyone voted.
+    pub fn refundable(&self, now: u64) -> bool {
+        self.status == Status::Open
+            && self.creation_cost > 0
+            && self.bulletins.is_empty()
+            && now < self.timestamp + CONFIG.proposal_refund_window_minutes * MINUTE
+    }
+
+    pub fn voting_rewards_spent(&self) -> Cycles {
+        self.voting_rewards.values().sum()
+    }
+
+    /// Fills in the voting rewards of proposals voted on before they were recorded, when the
+    /// reward was always the configured default.
+    pub fn backfill_voting_rewards(&mut self) {
+        if self.voting_rewards.is_empty() {
+            self.voting_rewards = self
+                .bulletins
+                .iter()
+                .map(|(user_id, _, _)| (*user_id, CONFIG.voting_reward))
+                .collect();
+        }
+    }
+
+    /// Fills in the outcome of proposals concluded before it was recorded.
+    pub fn backfill_outcome(&mut self) {
+        if matches!(self.status, Status::Rejected | Status::Executed) {
+            let (approvals, rejects) = self.votes();
+            self.record_outcome(approvals, rejects);
+        }
+    }
+
     fn vote(
         &mut self,
         state: &State,
         principal: Principal,
         approve: bool,
         data: &str,
+        time: u64,
     ) -> Result<(), String> {
         let user = state.principal_to_user(principal).ok_or("no user found")?;
         if !user.trusted() {
             return Err("only trusted users can vote".into());
         }
+        if user.banned(time) {
+            return Err("banned users can't vote".into());
+        }
         if self.bulletins.iter().any(|(voter, _, _)| *voter == user.id) {
             return Err("double vote".into());
         }
+        // tokens are held by the primary principal, even if the vote comes from another device
         let balance = state
             .balances
-            .get(&account(principal))
+            .get(&account(user.principal))
             .ok_or_else(|| "only token holders can vote".to_string())?;
+        // votes are weighted like the total voting power they're tallied against
+        let voting_power = state.effective_voting_power(user, *balance, time);
 
         match &mut self.payload {
             Payload::Release(release) => {
@@ -108,21 +305,63 @@ impl Proposal {
                         max_funding_amount
                     ));
                 }
-                votes.push((*balance, tokens * base))
+                votes.push((voting_power, tokens * base))
             }
             _ => {}
         }
 
-        self.bulletins.push((user.id, approve, *balance));
+        self.bulletins.push((user.id, approve, voting_power));
         Ok(())
     }
 
-    fn execute(&mut self, state: &mut State, time: u64) -> Result<(), String> {
+    /// Concludes the proposal if the votes are decisive. With `with_delay`, the total voting
+    /// power shrinks with every day the proposal stays open, so that stalled proposals get
+    /// resolved eventually; otherwise the current voting power is used.
+    /// Returns the total voting power, the approving and rejecting votes and the status the
+    /// proposal would be concluded with, if the votes are decisive.
+    pub fn tally(
+        &self,
+        state: &State,
+        time: u64,
+        with_delay: bool,
+    ) -> (Token, Token, Token, Option<Status>) {
         let supply_of_users_total = state.active_voting_power(time);
         // decrease the total number according to the delay
-        let delay =
-            ((100 - (time.saturating_sub(self.timestamp) / (HOUR * 24))).max(1)) as f64 / 100.0;
+        let delay = if with_delay {
+            ((100 - (time.saturating_sub(self.timestamp) / (HOUR * 24))).max(1)) as f64 / 100.0
+        } else {
+            1.0
+        };
         let voting_power = (supply_of_users_total as f64 * delay) as u64;
+
+        let (approvals, rejects) = self.votes();
+
+        // banning a stalwart requires a larger majority
+        let threshold = match self.payload {
+            Payload::Ban(user_id, _)
+                if state
+                    .users
+                    .get(&user_id)
+                    .map(|user| user.stalwart)
+                    .unwrap_or_default() =>
+            {
+                CONFIG.stalwart_ban_approval_threshold
+            }
+            _ => CONFIG.proposal_approval_threshold,
+        };
+
+        let status = if rejects * 100 >= voting_power * (100 - threshold) as u64 {
+            Some(Status::Rejected)
+        } else if approvals * 100 >= voting_power * threshold as u64 {
+            Some(Status::Executed)
+        } else {
+            None
+        };
+        (voting_power, approvals, rejects, status)
+    }
+
+    fn execute(&mut self, state: &mut State, time: u64, with_delay: bool) -> Result<(), String> {
+        let (voting_power, approvals, rejects, status) = self.tally(state, time, with_delay);
         if self.voting_power > 0 && self.voting_power > voting_power {
             state.logger.info(format!(
                 "Decreasing the total voting power on latest proposal from `{}` to `{}`.",
@@ -131,21 +370,11 @@ impl Proposal {
         }
         self.voting_power = voting_power;
 
-        let (approvals, rejects): (Token, Token) =
-            self.bulletins
-                .iter()
-                .fold((0, 0), |(approvals, rejects), (_, approved, balance)| {
-                    if *approved {
-                        (approvals + balance, rejects)
-                    } else {
-                        (approvals, rejects + balance)
-                    }
-                });
-
-        if rejects * 100 >= voting_power * (100 - CONFIG.proposal_approval_threshold) as u64 {
+        if status == Some(Status::Rejected) {
             self.status = Status::Rejected;
+            self.record_outcome(approvals, rejects);
             // if proposal was rejected without a controversion, penalize the proposer
-            if approvals * 100 < CONFIG.proposal_controversy_threashold as u64 * rejects {
+            if !self.controversial {
                 let proposer = state
                     .users
                     .get_mut(&self.proposer)
@@ -166,30 +395,134 @@ impl Proposal {
             return Ok(());
         }
 
-        if approvals * 100 >= voting_power * CONFIG.proposal_approval_threshold as u64 {
+        if status == Some(Status::Executed) {
             match &mut self.payload {
-                Payload::Fund(receiver, tokens) => mint_tokens(state, receiver, *tokens)?,
+                Payload::Fund(receiver, tokens) => mint_tokens(state, receiver, *tokens, time)?,
                 Payload::Reward(reward) => {
-                    let total: Token = reward.votes.iter().map(|(vp, _)| vp).sum();
-                    let tokens_to_mint: Token =
-                        reward.votes.iter().fold(0.0, |acc, (vp, reward)| {
-                            acc + *vp as f32 / total as f32 * *reward as f32
-                        }) as Token;
-                    mint_tokens(state, &reward.receiver, tokens_to_mint)?;
+                    let tokens_to_mint = reward.tokens_to_mint();
+                    match reward.vesting {
+                        Some(vesting) => {
+                            let receiver = Principal::from_text(&reward.receiver)
+                                .map_err(|e| e.to_string())?;
+                            let receiver_id = state.principal_to_user(receiver).map(|user| user.id);
+                            state.vesting_grants.push(VestingGrant {
+                                proposal_id: self.id,
+                                receiver,
+                                receiver_id,
+                                total: tokens_to_mint,
+                                released: 0,
+                                start: time,
+                                vesting,
+                            });
+                            state.logger.info(format!(
+                                "`{}` ${} tokens were granted to `{}` with a vesting period of {} weeks.",
+                                tokens_to_mint / 10_u64.pow(CONFIG.token_decimals as u32),
+                                CONFIG.token_symbol,
+                                receiver,
+                                vesting.weeks
+                            ));
+                        }
+                        None => mint_tokens(state, &reward.receiver, tokens_to_mint, time)?,
+                    }
                     reward.votes.clear();
                     reward.minted = tokens_to_mint;
                 }
+                Payload::Ban(user_id, days) => {
+                    let user = state.users.get_mut(user_id).ok_or("user not found")?;
+                    user.banned_until = Some(time + *days * DAY);
+                    user.stalwart = false;
+                    user.notify(format!(
+                        "You were banned for {} days via proposal {}.",
+                        days, self.id
+                    ));
+                    let name = user.name.clone();
+                    state.logger.info(format!(
+                        "@{} was banned for {} days via proposal execution.",
+                        name, days
+                    ));
+                }
+                // ledger calls are asynchronous, so the transfer is performed in the next chores
+                Payload::TransferICP(recipient, e8s) => {
+                    state
+                        .pending_icp_transfers
+                        .push((self.id, recipient.clone(), *e8s))
+                }
+                Payload::Reactions(reactions) => {
+                    state.reaction_catalog = reactions.clone();
+                    state.logger.info(format!(
+                        "A new catalog of {} reactions was adopted via proposal execution.",
+                        reactions.len()
+                    ));
+                }
+                Payload::NewUserCooldown(hours) => {
+                    state.new_user_cooldown_hours = Some(*hours);
+                    state.logger.info(format!(
+                        "The new user cooldown was set to {} hours via proposal execution.",
+                        hours
+                    ));
+                }
+                Payload::VotingReward(cycles) => {
+                    state.voting_reward = Some(*cycles);
+                    state.logger.info(format!(
+                        "The voting reward was set to {} cycles via proposal execution.",
+                        cycles
+                    ));
+                }
+                Payload::ProposalDeposit(cycles) => {
+                    state.proposal_deposit = Some(*cycles);
+                    state.logger.info(format!(
+                        "The proposal deposit was set to {} cycles via proposal execution.",
+                        cycles
+                    ));
+                }
+                Payload::VotingPowerDecay(start, horizon) => {
+                    state.voting_power_decay = Some((*start, *horizon));
+                    state.logger.info(format!(
+                        "The voting power decay was set to start after {} and to end after {} weeks of inactivity via proposal execution.",
+                        start, horizon
+                    ));
+                }
+                Payload::ReservedRealmNames(names) => {
+                    state.reserved_realm_names =
+                        Some(names.iter().map(|name| name.to_lowercase()).collect());
+                    state.logger.info(format!(
+                        "A new list of {} additional reserved realm names was adopted via proposal execution.",
+                        names.len()
+                    ));
+                }
+                Payload::ReservedHandles(handles) => {
+                    state.reserved_handles =
+                        Some(handles.iter().map(|handle| handle.to_lowercase()).collect());
+                    state.logger.info(format!(
+                        "A new list of {} additional reserved handles was adopted via proposal execution.",
+                        handles.len()
+                    ));
+                }
                 _ => {}
             }
             self.status = Status::Executed;
+            self.record_outcome(approvals, rejects);
         }
 
         Ok(())
     }
 }
 
-fn mint_tokens(state: &mut State, receiver: &str, mut tokens: Token) -> Result<(), String> {
+fn mint_tokens(
+    state: &mut State,
+    receiver: &str,
+    mut tokens: Token,
+    now: u64,
+) -> Result<(), String> {
     let receiver = Principal::from_text(receiver).map_err(|e| e.to_string())?;
+    let headroom = state.mint_headroom(now);
+    if tokens > headroom {
+        return Err(format!(
+            "the minting cap allows only {} more ${} tokens to be minted until the window ends",
+            headroom / 10_u64.pow(CONFIG.token_decimals as u32),
+            CONFIG.token_symbol
+        ));
+    }
     crate::token::mint(state, account(receiver), tokens);
     tokens /= 10_u64.pow(CONFIG.token_decimals as u32);
     state.logger.info(format!(
@@ -205,9 +538,143 @@ fn mint_tokens(state: &mut State, receiver: &str, mut tokens: Token) -> Result<(
     Ok(())
 }
 
+/// Mints the unlocked portions of all vesting grants. Grants of users who deleted their account
+/// or stayed inactive for too long are forfeited. Grants to principals which are not users are
+/// released to the principal until they're fully vested.
+pub fn release_vested_tokens(state: &mut State, now: u64) {
+    for mut grant in std::mem::take(&mut state.vesting_grants) {
+        grant.receiver_id = grant.receiver_id(state);
+        let receiver = match grant.receiver_id {
+            Some(user_id) => state
+                .users
+                .get(&user_id)
+                .filter(|user| {
+                    user.deleted_at.is_none()
+                        && user.active_within_weeks(now, CONFIG.vesting_forfeiture_inactivity_weeks)
+                })
+                // tokens are released to the current principal of the user
+                .map(|user| user.principal),
+            None => Some(grant.receiver),
+        };
+        let Some(receiver) = receiver else {
+            state.logger.info(format!(
+                "`{}` unvested ${} tokens granted by proposal {} to `{}` were forfeited.",
+                grant.unvested() / 10_u64.pow(CONFIG.token_decimals as u32),
+                CONFIG.token_symbol,
+                grant.proposal_id,
+                grant.receiver
+            ));
+            continue;
+        };
+        let amount = grant.vested(now) - grant.released;
+        if amount > 0 {
+            if let Err(err) = mint_tokens(state, &receiver.to_string(), amount, now) {
+                state
+                    .logger
+                    .error(format!("couldn't release vested tokens: {}", err));
+            } else {
+                grant.released += amount;
+            }
+        }
+        if grant.unvested() > 0 {
+            state.vesting_grants.push(grant);
+        }
+    }
+}
+
+/// Transfers ICP from the treasury for all executed transfer proposals. Failed transfers are
+/// retried in the next chores until the maximum number of attempts is reached; transfers to
+/// invalid accounts are dropped right away.
+pub async fn execute_icp_transfers() {
+    let transfers = mutate(|state| std::mem::take(&mut state.pending_icp_transfers));
+    for (proposal_id, recipient, e8s) in transfers {
+        let fee = invoices::fee();
+        let treasury_balance = invoices::main_account_balance().await.e8s();
+        let result = if e8s + fee > treasury_balance {
+            Err(format!(
+                "treasury balance of `{}` ICP is too low",
+                invoices::e8s_to_icp(treasury_balance)
+            ))
+        } else {
+            match parse_account(&recipient) {
+                // the fee is deducted from the transferred amount
+                Ok(account) => invoices::transfer(
+                    account,
+                    Tokens::from_e8s(e8s + fee),
+                    Memo(proposal_id as u64),
+                    None,
+                )
+                .await
+                .map(|_| ()),
+                Err(err) => {
+                    mutate(|state| {
+                        state.logger.error(format!(
+                            "ICP transfer of proposal {} to `{}` failed: {}",
+                            proposal_id, recipient, err
+                        ))
+                    });
+                    continue;
+                }
+            }
+        };
+        mutate(|state| match result {
+            Ok(_) => {
+                state.icp_transfer_attempts.remove(&proposal_id);
+                state.logger.info(format!(
+                    "`{}` ICP were transferred from the treasury to `{}` via proposal {}.",
+                    invoices::e8s_to_icp(e8s),
+                    recipient,
+                    proposal_id
+                ))
+            }
+            Err(err) => {
+                let attempts = state.icp_transfer_attempts.entry(proposal_id).or_default();
+                *attempts += 1;
+                if *attempts < CONFIG.max_icp_transfer_attempts {
+                    state
+                        .pending_icp_transfers
+                        .push((proposal_id, recipient, e8s));
+                    return;
+                }
+                state.icp_transfer_attempts.remove(&proposal_id);
+                state.logger.error(format!(
+                    "ICP transfer of proposal {} to `{}` failed after {} attempts: {}",
+                    proposal_id, recipient, CONFIG.max_icp_transfer_attempts, err
+                ))
+            }
+        });
+    }
+}
+
 impl Payload {
-    fn validate(&mut self, minting_ratio: u64) -> Result<(), String> {
+    fn validate(&mut self, state: &State) -> Result<(), String> {
+        let minting_ratio = state.minting_ratio();
         match self {
+            Payload::Ban(user_id, days) => {
+                if !state.users.contains_key(user_id) {
+                    return Err("user not found".into());
+                }
+                if *days == 0 || *days > CONFIG.max_ban_days {
+                    return Err(format!(
+                        "ban duration must be between 1 and {} days",
+                        CONFIG.max_ban_days
+                    ));
+                }
+            }
+            Payload::Reward(Reward {
+                vesting: Some(vesting),
+                ..
+            }) => {
+                if vesting.weeks == 0 || vesting.weeks > CONFIG.max_vesting_weeks {
+                    return Err(format!(
+                        "vesting period must be between 1 and {} weeks",
+                        CONFIG.max_vesting_weeks
+                    ));
+                }
+                if vesting.cliff_weeks > vesting.weeks {
+                    return Err("cliff can't be longer than the vesting period".into());
+                }
+            }
             Payload::Release(release) => {
                 if release.commit.is_empty() {
                     return Err("commit is not specified".to_string());
@@ -230,13 +697,176 @@ impl Payload {
                     ));
                 }
             }
+            Payload::Reactions(reactions) => validate_reactions(reactions)?,
+            Payload::NewUserCooldown(hours) => {
+                // users become trusted after this period anyway
+                let max_hours = CONFIG.trusted_user_min_age_weeks * 7 * 24;
+                if *hours > max_hours {
+                    return Err(format!(
+                        "new user cooldown can't be longer than {} hours",
+                        max_hours
+                    ));
+                }
+            }
+            Payload::VotingReward(cycles) => {
+                if *cycles > CONFIG.max_voting_reward {
+                    return Err(format!(
+                        "voting reward can't be higher than {} cycles",
+                        CONFIG.max_voting_reward
+                    ));
+                }
+            }
+            Payload::ProposalDeposit(cycles) => {
+                if *cycles > CONFIG.max_proposal_deposit {
+                    return Err(format!(
+                        "proposal deposit can't be higher than {} cycles",
+                        CONFIG.max_proposal_deposit
+                    ));
+                }
+            }
+            Payload::VotingPowerDecay(start, horizon) => {
+                if start > horizon {
+                    return Err("the decay can't start after its horizon".into());
+                }
+                if *horizon == 0 || *horizon > CONFIG.max_voting_power_decay_weeks {
+                    return Err(format!(
+                        "the decay horizon must be between 1 and {} weeks",
+                        CONFIG.max_voting_power_decay_weeks
+                    ));
+                }
+            }
+            Payload::ReservedHandles(handles) => {
+                if handles.len() > CONFIG.max_reserved_handles {
+                    return Err(format!(
+                        "not more than {} reserved handles are allowed",
+                        CONFIG.max_reserved_handles
+                    ));
+                }
+                if let Some(handle) = handles.iter().find(|handle| {
+                    handle.is_empty()
+                        || handle.len() > CONFIG.max_handle_length
+                        || !handle.chars().all(|c| c.is_ascii_alphanumeric())
+                }) {
+                    return Err(format!("invalid handle {}", handle));
+                }
+            }
+            Payload::ReservedRealmNames(names) => {
+                if names.len() > CONFIG.max_reserved_realm_names {
+                    return Err(format!(
+                        "not more than {} reserved realm names are allowed",
+                        CONFIG.max_reserved_realm_names
+                    ));
+                }
+                if let Some(name) = names.iter().find(|name| {
+                    name.is_empty()
+                        || name.len() > CONFIG.max_realm_name
+                        || !name.chars().all(realm_name_char)
+                }) {
+                    return Err(format!("invalid realm name {}", name));
+                }
+            }
+            Payload::TransferICP(recipient, e8s) => {
+                parse_account(recipient)?;
+                if *e8s == 0 {
+                    return Err("transfer amount is zero".into());
+                }
+            }
             _ => {}
         }
         Ok(())
     }
 }
 
+fn validate_reactions(reactions: &[Reaction]) -> Result<(), String> {
+    if reactions.is_empty() {
+        return Err("reaction catalog is empty".into());
+    }
+    if reactions.len() > CONFIG.max_reactions {
+        return Err(format!(
+            "reaction catalog can't have more than {} reactions",
+            CONFIG.max_reactions
+        ));
+    }
+    let mut ids = std::collections::BTreeSet::new();
+    for reaction in reactions {
+        if !ids.insert(reaction.id) {
+            return Err(format!("duplicate reaction id {}", reaction.id));
+        }
+        if reaction.emoji.trim().is_empty() || reaction.emoji.chars().count() > 8 {
+            return Err(format!("invalid emoji for reaction {}", reaction.id));
+        }
+        if reaction.karma.abs() > CONFIG.max_reaction_karma {
+            return Err(format!(
+                "karma of reaction {} exceeds the maximum of {}",
+                reaction.id, CONFIG.max_reaction_karma
+            ));
+        }
+        if reaction.cost() > CONFIG.max_reaction_karma as Cycles {
+            return Err(format!(
+                "cost of reaction {} exceeds the maximum of {}",
+                reaction.id, CONFIG.max_reaction_karma
+            ));
+        }
+        if reaction.positive != (reaction.karma > 0) {
+            return Err(format!(
+                "only reactions with a positive karma can be positive (reaction {})",
+                reaction.id
+            ));
+        }
+        if reaction.positive && reaction.cost() < reaction.karma as Cycles {
+            return Err(format!(
+                "cost of positive reaction {} can't be lower than its karma",
+                reaction.id
+            ));
+        }
+    }
+    Ok(())
+}
+
 pub fn propose(
+    state: &mut State,
+    caller: Principal,
+    description: String,
+    payload: Payload,
+    time: u64,
+) -> Result<u32, String> {
+    submit(state, caller, description, payload, time)
+}
+
+/// Creates a fresh open proposal with the same payload and description as the cancelled or
+/// rejected proposal. The same checks as for new proposals apply to the caller.
+pub fn resubmit_proposal(
+    state: &mut State,
+    caller: Principal,
+    proposal_id: u32,
+    time: u64,
+) -> Result<u32, String> {
+    let proposal = state
+        .proposals
+        .get(proposal_id as usize)
+        .ok_or("no proposal found")?;
+    if !matches!(proposal.status, Status::Cancelled | Status::Rejected) {
+        return Err("only cancelled or rejected proposals can be resubmitted".into());
+    }
+    let mut payload = proposal.payload.clone();
+    match &mut payload {
+        // release binaries are not persisted, so they are gone after an upgrade
+        Payload::Release(release) if release.binary.is_empty() => {
+            return Err("the release binary is not available anymore".into())
+        }
+        Payload::Reward(reward) => {
+            reward.votes.clear();
+            reward.minted = 0;
+        }
+        _ => {}
+    }
+    let description = Post::get(state, &proposal.post_id)
+        .map(|post| post.body.clone())
+        .ok_or("no post found")?;
+    submit(state, caller, description, payload, time)
+}
+
+fn submit(
     state: &mut State,
     caller: Principal,
     description: String,
@@ -247,28 +877,59 @@ pub fn propose(
     if !user.stalwart {
         return Err("only stalwarts can create proposals".to_string());
     }
+    state.check_new_user_cooldown(user, time, "create proposals")?;
     if description.is_empty() {
         return Err("description is empty".to_string());
     }
-    payload.validate(state.minting_ratio())?;
+    if user.banned(time) {
+        return Err("banned users can't create proposals".into());
+    }
+    payload.validate(state)?;
     let proposer = user.id;
     let proposer_name = user.name.clone();
-    // invalidate some previous proposals depending on their type
-    state
-        .proposals
-        .iter_mut()
-        .filter(|p| {
-            p.status == Status::Open
-                && matches!(p.payload, Payload::Release(_))
-                && matches!(payload, Payload::Release(_))
-        })
-        .for_each(|proposal| {
-            proposal.status = Status::Cancelled;
-        });
-
     let id = state.proposals.len() as u32;
 
-    let post_id = Post::create(
+    let deposit = state.proposal_deposit();
+    if deposit > 0 {
+        state
+            .users
+            .get_mut(&proposer)
+            .ok_or("user not found")?
+            .change_cycles(
+                deposit,
+                CyclesDelta::Minus,
+                format!("deposit for proposal {}", id),
+            )
+            .map_err(|_| format!("the proposal deposit of {} cycles is required", deposit))?;
+    }
+
+    // invalidate some previous proposals depending on their type
+    let mut proposals = std::mem::take(&mut state.proposals);
+    for proposal in proposals.iter_mut().filter(|p| {
+        p.status == Status::Open
+            && matches!(p.payload, Payload::Release(_))
+            && matches!(payload, Payload::Release(_))
+    }) {
+        proposal.status = Status::Cancelled;
+        log_status_change(
+            &mut state.proposal_events,
+            proposal.id,
+            Status::Cancelled,
+            time,
+        );
+        settle_deposit(state, proposal, false);
+    }
+    state.proposals = proposals;
+
+    let cycles = |state: &State| {
+        state
+            .users
+            .get(&proposer)
+            .map(|user| user.cycles())
+            .unwrap_or_default()
+    };
+    let cycles_before = cycles(state);
+    let post_id = match Post::create(
         state,
         description,
         Default::default(),
@@ -277,7 +938,20 @@ pub fn propose(
         None,
         None,
         Some(Extension::Proposal(id)),
-    )?;
+    ) {
+        Ok(post_id) => post_id,
+        Err(err) => {
+            if let Some(user) = state.users.get_mut(&proposer) {
+                user.change_cycles(
+                    deposit,
+                    CyclesDelta::Plus,
+                    format!("deposit refund for proposal {}", id),
+                )?;
+            }
+            return Err(err);
+        }
+    };
+    let creation_cost = cycles_before.saturating_sub(cycles(state));
 
     state.proposals.push(Proposal {
         post_id,
@@ -288,6 +962,10 @@ pub fn propose(
         bulletins: Vec::default(),
         voting_power: 0,
         id,
+        creation_cost,
+        deposit,
+        deposit_status: (deposit > 0).then_some(DepositStatus::Held),
+        ..Default::default()
     });
     state.notify_with_predicate(
         &|user| user.active_within_weeks(time, 1) && user.balance > 0,
@@ -317,37 +995,161 @@ pub fn vote_on_proposal(
         state.proposals = proposals;
         return Err("last proposal is not open".into());
     }
-    if let Err(err) = proposal.vote(state, caller, approved, data) {
+    if let Err(err) = proposal.vote(state, caller, approved, data, time) {
         state.proposals = proposals;
         return Err(err);
     }
     if let Some(user) = state.principal_to_user(caller) {
+        let user_id = user.id;
+        let reward = state.voting_reward();
         state.spend_to_user_karma(
-            user.id,
-            CONFIG.voting_reward,
+            user_id,
+            reward,
             format!("voting rewards for proposal {}", proposal_id),
         );
+        proposal.voting_rewards.insert(user_id, reward);
     }
+    // Decisive votes of the current voting power can't be reversed by more votes, so such
+    // proposals are executed right away. Reward proposals keep being evaluated with the delay,
+    // because later votes still change the minted amount.
+    let with_delay = matches!(proposal.payload, Payload::Reward(_));
     state.proposals = proposals;
-    execute_proposal(state, proposal_id, time)
+    execute_proposal(state, proposal_id, time, with_delay)
+}
+
+/// Appends the status change to the bounded log of proposal events. Sequence numbers start at 1.
+pub fn log_status_change(
+    events: &mut VecDeque<StatusChange>,
+    proposal_id: u32,
+    status: Status,
+    timestamp: u64,
+) {
+    let seq = events.back().map(|event| event.seq).unwrap_or_default() + 1;
+    events.push_back(StatusChange {
+        seq,
+        proposal_id,
+        status,
+        timestamp,
+    });
+    while events.len() > CONFIG.max_proposal_events {
+        events.pop_front();
+    }
+}
+
+/// Returns the proposal status changes with sequence numbers greater than `since`, so that
+/// integrators can follow proposals without polling all of them.
+pub fn status_changes(state: &State, since: u64) -> Vec<StatusChange> {
+    state
+        .proposal_events
+        .iter()
+        .skip_while(|event| event.seq <= since)
+        .take(CONFIG.feed_page_size)
+        .cloned()
+        .collect()
+}
+
+/// Returns the commit and the hash of the binary of a release proposal, so that voters can
+/// reproduce the build and verify the hash before voting. The binary itself is not returned.
+pub fn release_candidate(state: &State, proposal_id: u32) -> Result<Release, String> {
+    match state
+        .proposals
+        .get(proposal_id as usize)
+        .map(|proposal| &proposal.payload)
+    {
+        Some(Payload::Release(release)) => Ok(Release {
+            commit: release.commit.clone(),
+            hash: release.hash.clone(),
+            binary: Default::default(),
+        }),
+        Some(_) => Err("not a release proposal".into()),
+        None => Err("no proposal found".into()),
+    }
+}
+
+/// Returns the tokens a reward proposal would mint if it was adopted with the current votes, or
+/// the minted tokens once it was executed.
+pub fn preview_reward_outcome(state: &State, proposal_id: u32) -> Result<Token, String> {
+    let proposal = state
+        .proposals
+        .get(proposal_id as usize)
+        .ok_or("no proposal found")?;
+    match &proposal.payload {
+        Payload::Reward(reward) if proposal.status == Status::Executed => Ok(reward.minted),
+        Payload::Reward(reward) => Ok(reward.tokens_to_mint()),
+        _ => Err("not a reward proposal".into()),
+    }
 }
 
 pub fn cancel_proposal(state: &mut State, caller: Principal, proposal_id: u32) {
+    let Some((user_id, user_name)) = state
+        .principal_to_user(caller)
+        .map(|user| (user.id, user.name.clone()))
+    else {
+        return;
+    };
     let mut proposals = std::mem::take(&mut state.proposals);
-    let proposal = proposals
-        .get_mut(proposal_id as usize)
-        .expect("no proposals founds");
-    let user = state.principal_to_user(caller).expect("no user found");
-    if proposal.status == Status::Open && proposal.proposer == user.id {
+    let Some(proposal) = proposals.get_mut(proposal_id as usize) else {
+        state.proposals = proposals;
+        return;
+    };
+    let now = super::time();
+    if proposal.status == Status::Open && proposal.proposer == user_id {
+        if proposal.refundable(now) {
+            let cost = proposal.creation_cost;
+            if let Some(user) = state.users.get_mut(&proposal.proposer) {
+                let log = format!("refund for the cancelled proposal {}", proposal_id);
+                if user.change_cycles(cost, CyclesDelta::Plus, &log).is_ok() {
+                    state.burned_cycles -= cost as i64;
+                    state.logger.info(format!(
+                        "Refunded `{}` cycles to @{} for the cancelled proposal {}.",
+                        cost, user_name, proposal_id
+                    ));
+                }
+            }
+        }
         proposal.status = Status::Cancelled;
+        log_status_change(
+            &mut state.proposal_events,
+            proposal_id,
+            Status::Cancelled,
+            now,
+        );
+        settle_deposit(state, proposal, false);
     }
     state.proposals = proposals;
 }
 
+/// Refunds the deposit held for the concluded proposal or burns it if `slash` is true.
+pub(super) fn settle_deposit(state: &mut State, proposal: &mut Proposal, slash: bool) {
+    if proposal.deposit_status != Some(DepositStatus::Held) {
+        return;
+    }
+    if slash {
+        state.burned_cycles += proposal.deposit as i64;
+        proposal.deposit_status = Some(DepositStatus::Slashed);
+        state.logger.info(format!(
+            "The deposit of `{}` cycles for proposal {} was slashed.",
+            proposal.deposit, proposal.id
+        ));
+    } else if let Some(user) = state.users.get_mut(&proposal.proposer) {
+        if user
+            .change_cycles(
+                proposal.deposit,
+                CyclesDelta::Plus,
+                format!("deposit refund for proposal {}", proposal.id),
+            )
+            .is_ok()
+        {
+            proposal.deposit_status = Some(DepositStatus::Refunded);
+        }
+    }
+}
+
 pub(super) fn execute_proposal(
     state: &mut State,
     proposal_id: u32,
     time: u64,
+    with_delay: bool,
 ) -> Result<(), String> {
     let mut proposals = std::mem::take(&mut state.proposals);
     let proposal = proposals
@@ -358,17 +1160,26 @@ pub(super) fn execute_proposal(
         return Err("last proposal is not open".into());
     }
     let previous_state = proposal.status.clone();
-    let result = proposal.execute(state, time);
+    let result = proposal.execute(state, time, with_delay);
     if let Err(err) = &result {
         state
             .logger
             .error(format!("Proposal execution failed: {:?}", err));
     }
     if previous_state != proposal.status {
+        log_status_change(
+            &mut state.proposal_events,
+            proposal_id,
+            proposal.status.clone(),
+            time,
+        );
+        // the deposit is only lost if the proposal was rejected without any controversy
+        let slash = proposal.status == Status::Rejected && !proposal.controversial;
+        settle_deposit(state, proposal, slash);
         state.denotify_users(&|user| user.active_within_weeks(time, 1) && user.balance > 0);
         state.logger.info(format!(
             "Spent `{}` cycles on proposal voting rewards.",
-            proposal.bulletins.len() * CONFIG.voting_reward as usize
+            proposal.voting_rewards_spent()
         ));
     }
     state.proposals = proposals;
@@ -393,6 +1204,7 @@ mod tests {
         STATE.with(|cell| {
             cell.replace(Default::default());
             let state = &mut *cell.borrow_mut();
+            state.proposal_deposit = Some(0);
 
             // create voters, make each of them earn some karma
             for i in 1..=2 {
@@ -497,9 +1309,77 @@ mod tests {
                 state.proposals.get(upgrade_id2 as usize).unwrap().status,
                 Status::Open
             );
+
+            // both cancellations are logged as events
+            let events = |since| {
+                status_changes(state, since)
+                    .into_iter()
+                    .map(|event| (event.seq, event.proposal_id, event.status))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(
+                events(0),
+                vec![
+                    (1, id, Status::Cancelled),
+                    (2, upgrade_id, Status::Cancelled)
+                ]
+            );
+            assert_eq!(events(1), vec![(2, upgrade_id, Status::Cancelled)]);
+            assert!(events(2).is_empty());
         });
     }
 
+    #[test]
+    fn test_proposal_refund() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            state.proposal_deposit = Some(0);
+            let id = create_user(state, pr(1));
+            state.users.get_mut(&id).unwrap().stalwart = true;
+            let cycles = |state: &State| state.users.get(&id).unwrap().cycles();
+            let now = time();
+            let initial_cycles = cycles(state);
+
+            // cancelled right away
+            let prop_id = propose(state, pr(1), "test".into(), Payload::Noop, now).unwrap();
+            let proposal = state.proposals.get(prop_id as usize).unwrap();
+            assert_eq!(proposal.creation_cost, CONFIG.post_cost);
+            assert!(proposal.refundable(now));
+            assert_eq!(cycles(state), initial_cycles - CONFIG.post_cost);
+            cancel_proposal(state, pr(1), prop_id);
+            assert_eq!(cycles(state), initial_cycles);
+            assert!(!state
+                .proposals
+                .get(prop_id as usize)
+                .unwrap()
+                .refundable(now));
+
+            // cancelled after a vote
+            let prop_id = propose(state, pr(1), "test".into(), Payload::Noop, now).unwrap();
+            let proposal = state.proposals.get_mut(prop_id as usize).unwrap();
+            proposal.bulletins.push((0, true, 1));
+            assert!(!proposal.refundable(now));
+            cancel_proposal(state, pr(1), prop_id);
+            assert_eq!(cycles(state), initial_cycles - CONFIG.post_cost);
+
+            // cancelled after the refund window
+            let created = now - CONFIG.proposal_refund_window_minutes * MINUTE;
+            let prop_id = propose(state, pr(1), "test".into(), Payload::Noop, created).unwrap();
+            assert!(!state
+                .proposals
+                .get(prop_id as usize)
+                .unwrap()
+                .refundable(now));
+            cancel_proposal(state, pr(1), prop_id);
+            assert_eq!(cycles(state), initial_cycles - 2 * CONFIG.post_cost);
+            assert_eq!(
+                state.proposals.get(prop_id as usize).unwrap().status,
+                Status::Cancelled
+            );
+        })
+    }
+
     #[test]
     fn test_proposal_voting() {
         let data = &"".to_string();
@@ -507,6 +1387,7 @@ mod tests {
         STATE.with(|cell| {
             cell.replace(Default::default());
             let state = &mut *cell.borrow_mut();
+            state.proposal_deposit = Some(0);
 
             // create voters, make each of them earn some karma
             let mut eligigble = HashMap::default();
@@ -683,6 +1564,7 @@ mod tests {
         STATE.with(|cell| {
             cell.replace(Default::default());
             let state = &mut *cell.borrow_mut();
+            state.proposal_deposit = Some(0);
 
             // create voters, make each of them earn some karma
             let mut eligigble = HashMap::default();
@@ -712,13 +1594,16 @@ mod tests {
             );
 
             // after a day we only count 99% of voting power
-            assert_eq!(execute_proposal(state, prop_id, time() + HOUR * 24), Ok(()));
+            assert_eq!(
+                execute_proposal(state, prop_id, time() + HOUR * 24, true),
+                Ok(())
+            );
             assert_eq!(state.proposals.iter().last().unwrap().voting_power, 29700);
             assert_eq!(state.proposals.iter().last().unwrap().status, Status::Open);
 
             // after a day we only count 98% of voting power and it's enough to reject
             assert_eq!(
-                execute_proposal(state, prop_id, time() + 2 * HOUR * 24),
+                execute_proposal(state, prop_id, time() + 2 * HOUR * 24, true),
                 Ok(())
             );
             assert_eq!(state.proposals.iter().last().unwrap().voting_power, 29400);
@@ -734,6 +1619,7 @@ mod tests {
         STATE.with(|cell| {
             cell.replace(Default::default());
             let state = &mut *cell.borrow_mut();
+            state.proposal_deposit = Some(0);
 
             // create voters, make each of them earn some karma
             let mut eligigble = HashMap::new();
@@ -773,23 +1659,181 @@ mod tests {
                 state.principal_to_user(pr(1)).unwrap().karma(),
                 proposers_karma - CONFIG.proposal_rejection_penalty as i64
             );
+
+            let proposal = state.proposals.iter_mut().last().unwrap();
+            assert!(!proposal.controversial);
+            assert_eq!(proposal.approval_percentage, 0);
+            assert_eq!(proposal.rejection_percentage, 40);
+            // the outcome can be restored from the bulletins
+            proposal.rejection_percentage = 0;
+            proposal.backfill_outcome();
+            assert_eq!(proposal.rejection_percentage, 40);
         })
     }
 
     #[test]
-    fn test_funding_proposal() {
+    fn test_proposal_deposit() {
         STATE.with(|cell| {
             cell.replace(Default::default());
             let state = &mut *cell.borrow_mut();
 
-            // create voters, make each of them earn some karma
             let mut eligigble = HashMap::new();
-            for i in 1..=2 {
-                let p = pr(i);
-                let id = create_user(state, p);
+            for i in 1..=5 {
+                let id = create_user(state, pr(i));
                 let user = state.users.get_mut(&id).unwrap();
-                user.change_karma(100 * (1 << i), "test");
-                assert_eq!(user.karma(), CONFIG.trusted_user_min_karma);
+                user.change_karma(100, "test");
+                eligigble.insert(id, user.karma_to_reward());
+            }
+            state.principal_to_user_mut(pr(1)).unwrap().stalwart = true;
+            state.mint(eligigble);
+
+            assert_eq!(
+                Payload::ProposalDeposit(CONFIG.max_proposal_deposit + 1).validate(state),
+                Err(format!(
+                    "proposal deposit can't be higher than {} cycles",
+                    CONFIG.max_proposal_deposit
+                ))
+            );
+            assert_eq!(state.proposal_deposit(), 100);
+            let cycles = |state: &State| state.principal_to_user(pr(1)).unwrap().cycles();
+
+            // a cancelled proposal gets the deposit back
+            let cycles_before = cycles(state);
+            let prop_id = propose(state, pr(1), "test".into(), Payload::Noop, 0).unwrap();
+            let proposal = state.proposals.get(prop_id as usize).unwrap();
+            assert_eq!(proposal.deposit, 100);
+            assert_eq!(proposal.deposit_status, Some(DepositStatus::Held));
+            assert_eq!(cycles(state), cycles_before - 100 - CONFIG.post_cost);
+            cancel_proposal(state, pr(1), prop_id);
+            assert_eq!(
+                state
+                    .proposals
+                    .get(prop_id as usize)
+                    .unwrap()
+                    .deposit_status,
+                Some(DepositStatus::Refunded)
+            );
+            assert_eq!(cycles(state), cycles_before - CONFIG.post_cost);
+
+            // a rejection without controversy slashes the deposit
+            let prop_id = propose(state, pr(1), "test".into(), Payload::Noop, 0).unwrap();
+            let burned_cycles = state.burned_cycles;
+            for i in 2..4 {
+                assert_eq!(
+                    vote_on_proposal(state, time(), pr(i), prop_id, false, ""),
+                    Ok(())
+                );
+            }
+            let proposal = state.proposals.get(prop_id as usize).unwrap();
+            assert_eq!(proposal.status, Status::Rejected);
+            assert_eq!(proposal.deposit_status, Some(DepositStatus::Slashed));
+            assert!(state.burned_cycles >= burned_cycles + 100);
+
+            // the deposit is required
+            state.principal_to_user_mut(pr(1)).unwrap().stalwart = true;
+            let balance = cycles(state);
+            state
+                .principal_to_user_mut(pr(1))
+                .unwrap()
+                .change_cycles(balance - 99, crate::env::user::CyclesDelta::Minus, "")
+                .unwrap();
+            assert_eq!(
+                propose(state, pr(1), "test".into(), Payload::Noop, 0),
+                Err("the proposal deposit of 100 cycles is required".into())
+            );
+            assert_eq!(cycles(state), 99);
+
+            // a release proposal without the deposit doesn't cancel the open release
+            state.proposal_deposit = Some(0);
+            let release = || {
+                Payload::Release(Release {
+                    commit: "sdasd".into(),
+                    hash: "".into(),
+                    binary: vec![1],
+                })
+            };
+            let release_id = propose(state, pr(1), "test".into(), release(), 0).unwrap();
+            state.proposal_deposit = Some(100);
+            assert_eq!(
+                propose(state, pr(1), "test".into(), release(), 0),
+                Err("the proposal deposit of 100 cycles is required".into())
+            );
+            assert_eq!(
+                state.proposals.get(release_id as usize).unwrap().status,
+                Status::Open
+            );
+        })
+    }
+
+    #[test]
+    fn test_voting_power_decay() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            state.proposal_deposit = Some(0);
+            let id1 = create_user(state, pr(1));
+            let id2 = create_user(state, pr(2));
+            for (p, id) in vec![(pr(1), id1), (pr(2), id2)] {
+                state.balances.insert(account(p), 1000);
+                state.users.get_mut(&id).unwrap().last_activity = 0;
+            }
+
+            // by default, the voting power drops to zero at once
+            assert_eq!(state.active_voting_power(7 * WEEK), 2000);
+            assert_eq!(state.active_voting_power(8 * WEEK), 0);
+
+            assert_eq!(
+                Payload::VotingPowerDecay(5, 4).validate(state),
+                Err("the decay can't start after its horizon".into())
+            );
+            assert_eq!(
+                Payload::VotingPowerDecay(0, CONFIG.max_voting_power_decay_weeks + 1)
+                    .validate(state),
+                Err(format!(
+                    "the decay horizon must be between 1 and {} weeks",
+                    CONFIG.max_voting_power_decay_weeks
+                ))
+            );
+            assert_eq!(Payload::VotingPowerDecay(4, 12).validate(state), Ok(()));
+            state.voting_power_decay = Some((4, 12));
+
+            state.users.get_mut(&id2).unwrap().last_activity = 8 * WEEK;
+            let mut voting_powers = state.voting_powers(8 * WEEK);
+            voting_powers.sort();
+            assert_eq!(voting_powers, vec![(id1, 500), (id2, 1000)]);
+            assert_eq!(state.active_voting_power(8 * WEEK), 1500);
+            assert_eq!(state.voting_powers(12 * WEEK), vec![(id2, 1000)]);
+            assert_eq!(state.active_voting_power(14 * WEEK), 750);
+
+            // votes are weighted with the decayed voting power
+            state.users.get_mut(&id2).unwrap().stalwart = true;
+            let prop_id = propose(state, pr(2), "test".into(), Payload::Noop, 8 * WEEK).unwrap();
+            assert_eq!(
+                vote_on_proposal(state, 8 * WEEK, pr(1), prop_id, true, ""),
+                Ok(())
+            );
+            assert_eq!(
+                state.proposals.last().unwrap().bulletins,
+                vec![(id1, true, 500)]
+            );
+        })
+    }
+
+    #[test]
+    fn test_funding_proposal() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            state.proposal_deposit = Some(0);
+
+            // create voters, make each of them earn some karma
+            let mut eligigble = HashMap::new();
+            for i in 1..=2 {
+                let p = pr(i);
+                let id = create_user(state, p);
+                let user = state.users.get_mut(&id).unwrap();
+                user.change_karma(100 * (1 << i), "test");
+                assert_eq!(user.karma(), CONFIG.trusted_user_min_karma);
                 eligigble.insert(id, user.karma_to_reward());
             }
             state.principal_to_user_mut(pr(1)).unwrap().stalwart = true;
@@ -805,6 +1849,7 @@ mod tests {
                     receiver: pr(1).to_string(),
                     votes: Default::default(),
                     minted: 0,
+                    vesting: None,
                 }),
                 time(),
             )
@@ -822,6 +1867,7 @@ mod tests {
         STATE.with(|cell| {
             cell.replace(Default::default());
             let state = &mut *cell.borrow_mut();
+            state.proposal_deposit = Some(0);
 
             // create voters, make each of them earn some karma
             let mut eligigble = HashMap::new();
@@ -848,6 +1894,7 @@ mod tests {
                     receiver: pr(4).to_string(),
                     votes: Default::default(),
                     minted: 0,
+                    vesting: None,
                 }),
                 time(),
             )
@@ -870,6 +1917,7 @@ mod tests {
                 vote_on_proposal(state, time(), pr(2), prop_id, true, "200"),
                 Ok(())
             );
+            assert_eq!(preview_reward_outcome(state, prop_id), Ok(46666));
             // 800 tokens vote for reward of size 500
             assert_eq!(
                 vote_on_proposal(state, time(), pr(3), prop_id, true, "500"),
@@ -883,6 +1931,11 @@ mod tests {
             } else {
                 panic!("unexpected payload")
             };
+            assert_eq!(preview_reward_outcome(state, prop_id), Ok(48571));
+            assert_eq!(
+                preview_reward_outcome(state, prop_id + 1),
+                Err("no proposal found".into())
+            );
 
             assert_eq!(state.active_voting_power(time()), 140000);
 
@@ -895,6 +1948,7 @@ mod tests {
                     receiver: pr(111).to_string(),
                     votes: Default::default(),
                     minted: 0,
+                    vesting: None,
                 }),
                 time(),
             )
@@ -938,6 +1992,7 @@ mod tests {
                     receiver: pr(111).to_string(),
                     votes: Default::default(),
                     minted: 0,
+                    vesting: None,
                 }),
                 time(),
             )
@@ -981,6 +2036,7 @@ mod tests {
                     receiver: pr(1).to_string(),
                     votes: Default::default(),
                     minted: 0,
+                    vesting: None,
                 }),
                 time(),
             )
@@ -992,4 +2048,593 @@ mod tests {
             );
         })
     }
+
+    #[test]
+    fn test_vesting_reward() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            state.proposal_deposit = Some(0);
+
+            let mut eligigble = HashMap::new();
+            for i in 1..=2 {
+                let id = create_user(state, pr(i));
+                let user = state.users.get_mut(&id).unwrap();
+                user.change_karma(100, "test");
+                eligigble.insert(id, user.karma_to_reward());
+            }
+            state.principal_to_user_mut(pr(1)).unwrap().stalwart = true;
+            state.mint(eligigble);
+            create_user(state, pr(3));
+            state.principal_to_user_mut(pr(3)).unwrap().last_activity = time();
+
+            let reward = |vesting| {
+                Payload::Reward(Reward {
+                    receiver: pr(3).to_string(),
+                    votes: Default::default(),
+                    minted: 0,
+                    vesting: Some(vesting),
+                })
+            };
+            assert_eq!(
+                propose(
+                    state,
+                    pr(1),
+                    "test".into(),
+                    reward(Vesting {
+                        cliff_weeks: 5,
+                        weeks: 4
+                    }),
+                    time()
+                ),
+                Err("cliff can't be longer than the vesting period".into())
+            );
+
+            let prop_id = propose(
+                state,
+                pr(1),
+                "test".into(),
+                reward(Vesting {
+                    cliff_weeks: 2,
+                    weeks: 4,
+                }),
+                time(),
+            )
+            .expect("couldn't propose");
+            for i in 1..=2 {
+                assert_eq!(
+                    vote_on_proposal(state, time(), pr(i), prop_id, true, "100"),
+                    Ok(())
+                );
+            }
+            assert_eq!(state.proposals[prop_id as usize].status, Status::Executed);
+
+            let balance = |state: &State| {
+                state
+                    .balances
+                    .get(&account(pr(3)))
+                    .copied()
+                    .unwrap_or_default()
+            };
+            // nothing is minted before the cliff
+            assert_eq!(balance(state), 0);
+            assert_eq!(state.vesting_grants[0].unvested(), 10000);
+            release_vested_tokens(state, time() + WEEK);
+            assert_eq!(balance(state), 0);
+
+            // the tokens vested since the start get released at the cliff
+            release_vested_tokens(state, time() + 2 * WEEK);
+            assert_eq!(balance(state), 5000);
+            assert_eq!(state.vesting_grants[0].unvested(), 5000);
+
+            // the grant is removed once everything is released
+            release_vested_tokens(state, time() + 5 * WEEK);
+            assert_eq!(balance(state), 10000);
+            assert!(state.vesting_grants.is_empty());
+
+            let grant = |receiver, receiver_id| VestingGrant {
+                proposal_id: prop_id,
+                receiver,
+                receiver_id,
+                total: 10000,
+                released: 0,
+                start: time(),
+                vesting: Vesting {
+                    cliff_weeks: 0,
+                    weeks: 100,
+                },
+            };
+
+            // grants of inactive receivers are forfeited, grants to principals which are not
+            // users are kept
+            state.vesting_grants.push(grant(pr(3), None));
+            state.vesting_grants.push(grant(pr(9), None));
+            let later = time() + (CONFIG.vesting_forfeiture_inactivity_weeks + 1) * WEEK;
+            release_vested_tokens(state, later);
+            assert_eq!(balance(state), 10000);
+            assert_eq!(state.vesting_grants.len(), 1);
+            assert_eq!(state.vesting_grants[0].receiver, pr(9));
+            assert!(state.vesting_grants[0].released > 0);
+            state.vesting_grants.clear();
+
+            // grants follow the user to the new principal
+            let user_id = state.principal_to_user(pr(3)).unwrap().id;
+            state.vesting_grants.push(grant(pr(3), Some(user_id)));
+            let user = state.users.get_mut(&user_id).unwrap();
+            user.principal = pr(8);
+            user.last_activity = later;
+            state.principals.remove(&pr(3));
+            state.principals.insert(pr(8), user_id);
+            release_vested_tokens(state, later);
+            assert_eq!(balance(state), 10000);
+            assert!(
+                state
+                    .balances
+                    .get(&account(pr(8)))
+                    .copied()
+                    .unwrap_or_default()
+                    > 0
+            );
+        })
+    }
+
+    #[test]
+    fn test_icp_transfer_proposal() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            state.proposal_deposit = Some(0);
+
+            let mut eligigble = HashMap::new();
+            for i in 1..=2 {
+                let id = create_user(state, pr(i));
+                let user = state.users.get_mut(&id).unwrap();
+                user.change_karma(100, "test");
+                eligigble.insert(id, user.karma_to_reward());
+            }
+            state.principal_to_user_mut(pr(1)).unwrap().stalwart = true;
+            state.mint(eligigble);
+
+            assert!(propose(
+                state,
+                pr(1),
+                "test".into(),
+                Payload::TransferICP("deadbeef".into(), 100),
+                time()
+            )
+            .unwrap_err()
+            .contains("malformed account address"));
+
+            let recipient = ic_ledger_types::AccountIdentifier::new(
+                &pr(5),
+                &ic_ledger_types::DEFAULT_SUBACCOUNT,
+            )
+            .to_string();
+            assert_eq!(
+                propose(
+                    state,
+                    pr(1),
+                    "test".into(),
+                    Payload::TransferICP(recipient.clone(), 0),
+                    time()
+                ),
+                Err("transfer amount is zero".into())
+            );
+            let prop_id = propose(
+                state,
+                pr(1),
+                "test".into(),
+                Payload::TransferICP(recipient.clone(), 100_000_000),
+                time(),
+            )
+            .expect("couldn't propose");
+            for i in 1..=2 {
+                assert_eq!(
+                    vote_on_proposal(state, time(), pr(i), prop_id, true, ""),
+                    Ok(())
+                );
+            }
+            assert_eq!(state.proposals[prop_id as usize].status, Status::Executed);
+            assert_eq!(
+                state.pending_icp_transfers,
+                vec![(prop_id, recipient, 100_000_000)]
+            );
+        })
+    }
+
+    #[test]
+    fn test_ban_proposal() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            state.proposal_deposit = Some(0);
+
+            let mut eligigble = HashMap::new();
+            for i in 1..=3 {
+                let id = create_user(state, pr(i));
+                let user = state.users.get_mut(&id).unwrap();
+                user.change_karma(100, "test");
+                eligigble.insert(id, user.karma_to_reward());
+            }
+            state.principal_to_user_mut(pr(1)).unwrap().stalwart = true;
+            state.mint(eligigble);
+            let banned_id = state.principal_to_user(pr(3)).unwrap().id;
+
+            assert_eq!(
+                propose(state, pr(1), "test".into(), Payload::Ban(77, 7), time()),
+                Err("user not found".into())
+            );
+            assert_eq!(
+                propose(
+                    state,
+                    pr(1),
+                    "test".into(),
+                    Payload::Ban(banned_id, 0),
+                    time()
+                ),
+                Err(format!(
+                    "ban duration must be between 1 and {} days",
+                    CONFIG.max_ban_days
+                ))
+            );
+
+            // banning a stalwart requires a larger majority than 2 of 3 votes
+            state.principal_to_user_mut(pr(3)).unwrap().stalwart = true;
+            let prop_id = propose(
+                state,
+                pr(1),
+                "test".into(),
+                Payload::Ban(banned_id, 7),
+                time(),
+            )
+            .expect("couldn't propose");
+            for i in 1..=2 {
+                assert_eq!(
+                    vote_on_proposal(state, time(), pr(i), prop_id, true, ""),
+                    Ok(())
+                );
+            }
+            assert_eq!(state.proposals[prop_id as usize].status, Status::Open);
+            assert_eq!(
+                vote_on_proposal(state, time(), pr(3), prop_id, true, ""),
+                Ok(())
+            );
+            assert_eq!(state.proposals[prop_id as usize].status, Status::Executed);
+
+            let user = state.users.get(&banned_id).unwrap();
+            assert!(user.banned(time()) && !user.stalwart);
+            assert_eq!(
+                Post::create(state, "hi".into(), &[], pr(3), time(), None, None, None),
+                Err("banned users can't post".into())
+            );
+            let post_id =
+                Post::create(state, "hi".into(), &[], pr(1), time(), None, None, None).unwrap();
+            assert_eq!(
+                state.react(pr(3), post_id, 10, time()),
+                Err("banned users can't react".into())
+            );
+            let prop_id = propose(state, pr(1), "test".into(), Payload::Noop, time())
+                .expect("couldn't propose");
+            assert_eq!(
+                vote_on_proposal(state, time(), pr(3), prop_id, true, ""),
+                Err("banned users can't vote".into())
+            );
+
+            // the ban expires after the given number of days
+            assert!(!state
+                .users
+                .get(&banned_id)
+                .unwrap()
+                .banned(time() + 7 * DAY));
+        })
+    }
+
+    #[test]
+    fn test_proposal_resubmission() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            state.proposal_deposit = Some(0);
+
+            for i in 1..=3 {
+                create_user(state, pr(i));
+            }
+            state.principal_to_user_mut(pr(1)).unwrap().stalwart = true;
+            state.principal_to_user_mut(pr(2)).unwrap().stalwart = true;
+
+            let prop_id = propose(
+                state,
+                pr(1),
+                "Release!".into(),
+                Payload::Release(Release {
+                    commit: "sdasd".into(),
+                    hash: Default::default(),
+                    binary: vec![1],
+                }),
+                time(),
+            )
+            .expect("couldn't propose");
+            let candidate = release_candidate(state, prop_id).unwrap();
+            assert_eq!(candidate.commit, "sdasd");
+            assert_eq!(candidate.hash.len(), 64);
+            assert!(candidate.binary.is_empty());
+            assert_eq!(
+                release_candidate(state, prop_id + 1).map(|_| ()),
+                Err("no proposal found".into())
+            );
+            assert_eq!(
+                resubmit_proposal(state, pr(1), prop_id, time()),
+                Err("only cancelled or rejected proposals can be resubmitted".into())
+            );
+            cancel_proposal(state, pr(1), prop_id);
+
+            // resubmissions need the same permissions as new proposals, even for the proposer
+            state.principal_to_user_mut(pr(1)).unwrap().stalwart = false;
+            for i in vec![1, 3] {
+                assert_eq!(
+                    resubmit_proposal(state, pr(i), prop_id, time()),
+                    Err("only stalwarts can create proposals".into())
+                );
+            }
+            state.principal_to_user_mut(pr(1)).unwrap().stalwart = true;
+            let new_id = resubmit_proposal(state, pr(1), prop_id, time() + 1).unwrap();
+            let proposal = &state.proposals[new_id as usize];
+            assert_eq!(proposal.status, Status::Open);
+            assert_eq!(proposal.timestamp, time() + 1);
+            assert!(proposal.bulletins.is_empty());
+            assert!(
+                matches!(&proposal.payload, Payload::Release(release) if release.binary == vec![1])
+            );
+            assert_eq!(
+                Post::get(state, &proposal.post_id).unwrap().body,
+                "Release!"
+            );
+
+            // stalwarts can resubmit proposals of others, unless the binary is gone
+            cancel_proposal(state, pr(1), new_id);
+            if let Payload::Release(release) = &mut state.proposals[new_id as usize].payload {
+                release.binary.clear();
+            }
+            assert_eq!(
+                resubmit_proposal(state, pr(2), new_id, time()),
+                Err("the release binary is not available anymore".into())
+            );
+            assert!(resubmit_proposal(state, pr(2), prop_id, time()).is_ok());
+        })
+    }
+
+    #[test]
+    fn test_early_execution() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            state.proposal_deposit = Some(0);
+
+            let mut eligigble = HashMap::default();
+            for i in 1..=4 {
+                let id = create_user(state, pr(i));
+                let user = state.users.get_mut(&id).unwrap();
+                user.change_karma(100, "test");
+                eligigble.insert(id, user.karma_to_reward());
+            }
+            state.principal_to_user_mut(pr(1)).unwrap().stalwart = true;
+            state.mint(eligigble);
+
+            // a supermajority executes the proposal on the decisive vote
+            let prop_id = propose(state, pr(1), "test".into(), Payload::Noop, time())
+                .expect("couldn't propose");
+            for i in 1..=2 {
+                assert_eq!(
+                    vote_on_proposal(state, time(), pr(i), prop_id, true, ""),
+                    Ok(())
+                );
+                assert_eq!(state.proposals[prop_id as usize].status, Status::Open);
+            }
+            assert_eq!(
+                vote_on_proposal(state, time(), pr(3), prop_id, true, ""),
+                Ok(())
+            );
+            assert_eq!(state.proposals[prop_id as usize].status, Status::Executed);
+
+            // votes below the threshold of the current voting power don't execute the proposal
+            // even if the delayed voting power was already reached
+            let prop_id = propose(state, pr(1), "test".into(), Payload::Noop, time())
+                .expect("couldn't propose");
+            let later = time() + 40 * HOUR * 24;
+            for i in 1..=2 {
+                assert_eq!(
+                    vote_on_proposal(state, later, pr(i), prop_id, true, ""),
+                    Ok(())
+                );
+            }
+            assert_eq!(state.proposals[prop_id as usize].status, Status::Open);
+            // the stalled proposal is resolved by the delay-based evaluation in the chores
+            assert_eq!(execute_proposal(state, prop_id, later, true), Ok(()));
+            assert_eq!(state.proposals[prop_id as usize].status, Status::Executed);
+        })
+    }
+
+    #[test]
+    fn test_reactions_proposal() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            state.proposal_deposit = Some(0);
+
+            let mut eligigble = HashMap::new();
+            for i in 1..=2 {
+                let id = create_user(state, pr(i));
+                let user = state.users.get_mut(&id).unwrap();
+                user.change_karma(100 * (1 << i), "test");
+                eligigble.insert(id, user.karma_to_reward());
+            }
+            state.principal_to_user_mut(pr(1)).unwrap().stalwart = true;
+            state.mint(eligigble);
+
+            let reaction = |id, karma: Karma| Reaction {
+                id,
+                emoji: "🚀".into(),
+                karma,
+                positive: karma > 0,
+                cost: 0,
+            };
+
+            assert_eq!(
+                propose(
+                    state,
+                    pr(1),
+                    "test".into(),
+                    Payload::Reactions(vec![reaction(7, 1), reaction(7, 2)]),
+                    time(),
+                ),
+                Err("duplicate reaction id 7".into())
+            );
+            assert_eq!(
+                propose(
+                    state,
+                    pr(1),
+                    "test".into(),
+                    Payload::Reactions(vec![Reaction {
+                        positive: true,
+                        ..reaction(7, -1)
+                    }]),
+                    time(),
+                ),
+                Err("only reactions with a positive karma can be positive (reaction 7)".into())
+            );
+            assert_eq!(
+                propose(
+                    state,
+                    pr(1),
+                    "test".into(),
+                    Payload::Reactions(vec![Reaction {
+                        cost: 1,
+                        ..reaction(7, 2)
+                    }]),
+                    time(),
+                ),
+                Err("cost of positive reaction 7 can't be lower than its karma".into())
+            );
+
+            let prop_id = propose(
+                state,
+                pr(1),
+                "test".into(),
+                Payload::Reactions(vec![reaction(1, -3), reaction(7, 2)]),
+                time(),
+            )
+            .expect("couldn't propose");
+            let post_id = state.proposals[prop_id as usize].post_id;
+
+            // the default catalog is active until the proposal gets executed
+            assert!(state.reactions().contains_key(&10));
+            assert!(!state.reactions().contains_key(&7));
+
+            for i in 1..=2 {
+                assert_eq!(
+                    vote_on_proposal(state, time(), pr(i), prop_id, true, ""),
+                    Ok(())
+                );
+            }
+            assert_eq!(state.proposals[prop_id as usize].status, Status::Executed);
+            assert_eq!(state.reactions().keys().collect::<Vec<_>>(), vec![&1, &7]);
+
+            // retired reactions are rejected
+            assert_eq!(
+                state.react(pr(2), post_id, 10, time()),
+                Err("unknown reaction".into())
+            );
+            assert_eq!(state.react(pr(2), post_id, 7, time()), Ok(()));
+        })
+    }
+
+    #[test]
+    fn test_voting_reward_proposal() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+            state.proposal_deposit = Some(0);
+
+            let mut eligigble = HashMap::new();
+            for i in 1..=2 {
+                let id = create_user(state, pr(i));
+                let user = state.users.get_mut(&id).unwrap();
+                user.change_karma(100 * (1 << i), "test");
+                eligigble.insert(id, user.karma_to_reward());
+            }
+            state.principal_to_user_mut(pr(1)).unwrap().stalwart = true;
+            state.mint(eligigble);
+
+            assert_eq!(
+                propose(
+                    state,
+                    pr(1),
+                    "test".into(),
+                    Payload::VotingReward(CONFIG.max_voting_reward + 1),
+                    time(),
+                ),
+                Err(format!(
+                    "voting reward can't be higher than {} cycles",
+                    CONFIG.max_voting_reward
+                ))
+            );
+
+            let prop_id = propose(
+                state,
+                pr(1),
+                "test".into(),
+                Payload::VotingReward(20),
+                time(),
+            )
+            .expect("couldn't propose");
+            assert_eq!(state.voting_reward(), CONFIG.voting_reward);
+
+            // failed votes are not rewarded
+            assert!(vote_on_proposal(state, time(), pr(9), prop_id, true, "").is_err());
+            assert!(state.proposals[prop_id as usize].voting_rewards.is_empty());
+
+            for i in 1..=2 {
+                assert_eq!(
+                    vote_on_proposal(state, time(), pr(i), prop_id, true, ""),
+                    Ok(())
+                );
+            }
+            let proposal = &state.proposals[prop_id as usize];
+            assert_eq!(proposal.status, Status::Executed);
+            // both votes were cast before the new reward was adopted
+            assert_eq!(
+                proposal.voting_rewards.values().collect::<Vec<_>>(),
+                vec![&CONFIG.voting_reward, &CONFIG.voting_reward]
+            );
+            assert_eq!(proposal.voting_rewards_spent(), 2 * CONFIG.voting_reward);
+            assert_eq!(state.voting_reward(), 20);
+
+            // the next proposal rewards voters with the adopted amount
+            let prop_id = propose(
+                state,
+                pr(1),
+                "test".into(),
+                Payload::VotingReward(10),
+                time(),
+            )
+            .expect("couldn't propose");
+            let karma = state.principal_to_user(pr(1)).unwrap().karma_to_reward();
+            assert_eq!(
+                vote_on_proposal(state, time(), pr(1), prop_id, false, ""),
+                Ok(())
+            );
+            assert_eq!(
+                state.principal_to_user(pr(1)).unwrap().karma_to_reward(),
+                karma + 20
+            );
+            let proposal = &state.proposals[prop_id as usize];
+            assert_eq!(
+                proposal
+                    .voting_rewards
+                    .get(&state.principal_to_user(pr(1)).unwrap().id),
+                Some(&20)
+            );
+            assert_eq!(proposal.voting_rewards_spent(), 20);
+        })
+    }
 }
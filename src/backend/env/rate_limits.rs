@@ -0,0 +1,144 @@
+use super::user::UserId;
+use super::*;
+
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+pub enum Action {
+    /// Posts and comments.
+    Post,
+    Reaction,
+}
+
+/// Sliding-window rate limits of user actions. The windows are transient and get reset on
+/// upgrades.
+#[derive(Default)]
+pub struct RateLimits {
+    // (user id, action) -> timestamps of the actions within the last minute
+    windows: HashMap<(UserId, Action), VecDeque<u64>>,
+}
+
+/// Returns the number of actions the user can perform per minute; established users get higher
+/// limits.
+pub fn limit(user: &User, action: Action) -> usize {
+    let base = match action {
+        Action::Post => CONFIG.max_posts_per_minute,
+        Action::Reaction => CONFIG.max_reactions_per_minute,
+    };
+    let multiplier = if user.stalwart {
+        CONFIG.stalwart_rate_limit_multiplier
+    } else if user.trusted() {
+        CONFIG.trusted_rate_limit_multiplier
+    } else {
+        1
+    };
+    base * multiplier
+}
+
+impl RateLimits {
+    /// Fails if the user already performed `limit` actions within the last minute.
+    pub fn check(
+        &mut self,
+        user_id: UserId,
+        action: Action,
+        limit: usize,
+        now: u64,
+    ) -> Result<(), String> {
+        let window = match self.windows.get_mut(&(user_id, action)) {
+            Some(window) => window,
+            None => return Ok(()),
+        };
+        while window
+            .front()
+            .map(|timestamp| timestamp + MINUTE <= now)
+            .unwrap_or_default()
+        {
+            window.pop_front();
+        }
+        match window.front() {
+            Some(oldest) if window.len() >= limit => {
+                let wait = (oldest + MINUTE - now) as f64 / 1e9;
+                Err(format!(
+                    "rate limited, try again in {}s",
+                    wait.ceil() as u64
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn record(&mut self, user_id: UserId, action: Action, now: u64) {
+        self.windows
+            .entry((user_id, action))
+            .or_default()
+            .push_back(now);
+    }
+
+    /// Drops all windows without actions within the last minute.
+    pub fn clean_up(&mut self, now: u64) {
+        self.windows.retain(|_, window| {
+            window
+                .back()
+                .map(|timestamp| timestamp + MINUTE > now)
+                .unwrap_or_default()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = MINUTE / 60;
+
+    #[test]
+    fn test_window_roll_over() {
+        let mut limits = RateLimits::default();
+        let start = 1000 * MINUTE;
+        for i in 0..3 {
+            assert_eq!(limits.check(0, Action::Post, 3, start + i * SECOND), Ok(()));
+            limits.record(0, Action::Post, start + i * SECOND);
+        }
+        assert_eq!(
+            limits.check(0, Action::Post, 3, start + 10 * SECOND),
+            Err("rate limited, try again in 50s".into())
+        );
+        // other users and actions are not affected
+        assert_eq!(limits.check(1, Action::Post, 3, start), Ok(()));
+        assert_eq!(limits.check(0, Action::Reaction, 3, start), Ok(()));
+
+        // one nanosecond before the first action leaves the window
+        assert_eq!(
+            limits.check(0, Action::Post, 3, start + MINUTE - 1),
+            Err("rate limited, try again in 1s".into())
+        );
+        // the first action left the window
+        assert_eq!(limits.check(0, Action::Post, 3, start + MINUTE), Ok(()));
+        limits.record(0, Action::Post, start + MINUTE);
+        assert_eq!(
+            limits.check(0, Action::Post, 3, start + MINUTE),
+            Err("rate limited, try again in 1s".into())
+        );
+        assert_eq!(
+            limits.check(0, Action::Post, 3, start + MINUTE + SECOND),
+            Ok(())
+        );
+
+        limits.clean_up(start + 2 * MINUTE - 1);
+        assert_eq!(limits.windows.len(), 1);
+        limits.clean_up(start + 2 * MINUTE);
+        assert!(limits.windows.is_empty());
+    }
+
+    #[test]
+    fn test_limit_scaling() {
+        let mut user = User::new(crate::env::tests::pr(0), 0, 0, Default::default());
+        assert_eq!(
+            limit(&user, Action::Reaction),
+            CONFIG.max_reactions_per_minute
+        );
+        user.stalwart = true;
+        assert_eq!(
+            limit(&user, Action::Reaction),
+            CONFIG.max_reactions_per_minute * CONFIG.stalwart_rate_limit_multiplier
+        );
+    }
+}
@@ -9,13 +9,77 @@ pub struct Report {
     pub rejected_by: Vec<UserId>,
     pub closed: bool,
     pub reason: String,
+    // accumulated karma of the reporter and confirming stalwarts
+    #[serde(default)]
+    pub weight: Karma,
+    // content is hidden from feeds while an open report carries enough weight
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub closed_at: u64,
+    // cycles taken from the penalized user, refunded if the decision is overturned on appeal
+    #[serde(default)]
+    pub charged: Cycles,
+    // karma rewards paid from the charged cycles, reversed along with the charge
+    #[serde(default)]
+    pub rewards: Vec<(UserId, Cycles)>,
+    // the stalwart status and the active weeks the penalized user lost
+    #[serde(default)]
+    pub revoked_stalwart: bool,
+    #[serde(default)]
+    pub revoked_active_weeks: u32,
+    #[serde(default)]
+    pub appeal: Option<Appeal>,
+}
+
+/// Appeal of the penalized user reopening a confirmed report for a new vote.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Appeal {
+    pub text: String,
+    pub timestamp: u64,
+    // votes of the appealed decision
+    pub confirmed_by: Vec<UserId>,
+    pub rejected_by: Vec<UserId>,
 }
 
 impl Report {
+    pub fn confirmed(&self) -> bool {
+        self.confirmed_by.len() > self.rejected_by.len()
+    }
+
+    /// Reopens the report resolved against the user for a new vote of stalwarts.
+    pub fn appeal(&mut self, text: String, now: u64) -> Result<(), String> {
+        if !self.closed || !self.confirmed() {
+            return Err("only reports resolved against you can be appealed".into());
+        }
+        if self.appeal.is_some() {
+            return Err("this report was already appealed".into());
+        }
+        if self.closed_at + CONFIG.report_appeal_window_days * DAY < now {
+            return Err("the appeal window has expired".into());
+        }
+        self.appeal = Some(Appeal {
+            text,
+            timestamp: now,
+            confirmed_by: std::mem::take(&mut self.confirmed_by),
+            rejected_by: std::mem::take(&mut self.rejected_by),
+        });
+        self.closed = false;
+        Ok(())
+    }
+
+    /// Adds the karma of a reporter or a confirming stalwart to the report weight and hides the
+    /// reported content once the weight reaches `report_hiding_karma_threshold`.
+    pub fn add_weight(&mut self, karma: Karma) {
+        self.weight += karma.max(0);
+        self.hidden = !self.closed && self.weight >= CONFIG.report_hiding_karma_threshold;
+    }
+
     pub fn vote(
         &mut self,
         stalwarts: usize,
         stalwart: UserId,
+        karma: Karma,
         confirmed: bool,
     ) -> Result<(), String> {
         if stalwart == self.reporter
@@ -34,14 +98,19 @@ impl Report {
         let votes = self.confirmed_by.len().max(self.rejected_by.len()) as u16;
         if votes * 100 >= CONFIG.report_confirmation_percentage * stalwarts as u16 {
             self.closed = true;
+            self.closed_at = time();
         }
+        // resolved reports restore the visibility (confirmed ones delete the content anyway)
+        self.add_weight(if confirmed { karma } else { 0 });
         Ok(())
     }
 }
 
+/// Applies the penalties and rewards of a closed report and records everything a confirmed
+/// report took from the penalized user, so that it can be reversed on appeal.
 pub fn finalize_report(
     state: &mut State,
-    report: &Report,
+    report: &mut Report,
     penalty: Cycles,
     user_id: UserId,
     subject: String,
@@ -49,7 +118,10 @@ pub fn finalize_report(
     if !report.closed {
         return Ok(());
     }
-    let (sponsor_id, unit) = if report.confirmed_by.len() > report.rejected_by.len() {
+    if report.appeal.is_some() {
+        return finalize_appeal(state, report, penalty, user_id, subject);
+    }
+    let (sponsor_id, unit) = if report.confirmed() {
         // penalty for the user
         let user = state.users.get_mut(&user_id).ok_or("no user found")?;
         user.notify(format!(
@@ -60,6 +132,8 @@ pub fn finalize_report(
             -(penalty as Karma),
             format!("moderation penalty for {}", subject),
         );
+        report.revoked_stalwart = user.stalwart;
+        report.revoked_active_weeks = user.active_weeks;
         user.stalwart = false;
         user.active_weeks = 0;
         let unit = penalty.min(user.cycles()) / 2;
@@ -82,6 +156,7 @@ pub fn finalize_report(
                 None,
             )
             .map_err(|err| format!("couldn't reward reporter: {}", err))?;
+        report.rewards = vec![(report.reporter, unit)];
         (user_id, unit)
     } else {
         // penalty for reporter
@@ -122,6 +197,9 @@ pub fn finalize_report(
             )
             .map_err(|err| format!("couldn't reward stalwarts: {}", err))?;
         total_stalwart_rewards += stalwart_reward;
+        if sponsor_id == user_id {
+            report.rewards.push((moderator, stalwart_reward));
+        }
     }
     if unit > total_stalwart_rewards {
         state
@@ -133,6 +211,60 @@ pub fn finalize_report(
             .expect("couldn't charge user");
     }
     state.denotify_users(&|u| u.stalwart);
+    // the penalized user paid the reporter's reward and the same amount for stalwart rewards
+    // and the burned remainder
+    report.charged = if sponsor_id == user_id { 2 * unit } else { 0 };
+    Ok(())
+}
+
+// Reverses everything the appealed decision took and distributed if stalwarts overturned it.
+fn finalize_appeal(
+    state: &mut State,
+    report: &mut Report,
+    penalty: Cycles,
+    user_id: UserId,
+    subject: String,
+) -> Result<(), String> {
+    state.denotify_users(&|u| u.stalwart);
+    let user = state.users.get_mut(&user_id).ok_or("no user found")?;
+    if report.confirmed() {
+        user.notify(format!(
+            "Your appeal of the moderation decision on {} was rejected by stalwarts",
+            subject
+        ));
+        state.logger.info(format!(
+            "Moderation decision on {} was upheld on appeal.",
+            subject
+        ));
+        return Ok(());
+    }
+    let log = format!("moderation penalty reversal for {}", subject);
+    user.change_karma(penalty as Karma, &log);
+    user.change_cycles(report.charged, CyclesDelta::Plus, &log)?;
+    user.stalwart |= report.revoked_stalwart;
+    user.active_weeks += report.revoked_active_weeks;
+    user.notify(format!(
+        "Your appeal was accepted by stalwarts and the moderation penalty for {} was reversed",
+        subject
+    ));
+    let user_name = user.name.clone();
+    // the reporter and the stalwarts lose their moderation rewards
+    for (id, reward) in std::mem::take(&mut report.rewards) {
+        if let Some(user) = state.users.get_mut(&id) {
+            user.change_karma(
+                -(reward as Karma),
+                format!("moderation rewards reversal for {}", subject),
+            );
+        }
+    }
+    state.spend(report.charged, &log);
+    state.logger.info(format!(
+        "Moderation decision on {} was overturned on appeal: @{} got back `{}` karma and `{}` cycles.",
+        subject, user_name, penalty, report.charged
+    ));
+    report.charged = 0;
+    report.revoked_stalwart = false;
+    report.revoked_active_weeks = 0;
     Ok(())
 }
 
@@ -379,4 +511,115 @@ mod tests {
             );
         })
     }
+
+    #[test]
+    fn test_report_hiding() {
+        let mut report = Report::default();
+        report.add_weight(CONFIG.report_hiding_karma_threshold / 2);
+        assert!(!report.hidden);
+
+        // rejections don't add any weight
+        report.vote(100, 1, 10_000, false).unwrap();
+        assert!(!report.hidden);
+
+        report
+            .vote(100, 2, CONFIG.report_hiding_karma_threshold / 2, true)
+            .unwrap();
+        assert!(report.hidden);
+
+        // the content is visible again once the report is resolved
+        report.vote(2, 3, 0, false).unwrap();
+        assert!(report.closed);
+        assert!(!report.hidden);
+    }
+
+    #[test]
+    fn test_report_appeal() {
+        STATE.with(|cell| {
+            cell.replace(Default::default());
+            let state = &mut *cell.borrow_mut();
+
+            let author = create_user(state, pr(0));
+            let user = state.users.get_mut(&author).unwrap();
+            user.stalwart = true;
+            user.active_weeks = 5;
+            for i in 1..4 {
+                let id = create_user(state, pr(i));
+                state.users.get_mut(&id).unwrap().stalwart = true;
+            }
+            let post_id =
+                Post::create(state, "post".to_string(), &[], pr(0), 0, None, None, None).unwrap();
+            let _ = Post::create(state, "post".to_string(), &[], pr(1), 0, None, None, None);
+            state
+                .report(pr(1), "post".into(), post_id, "spam".into())
+                .unwrap();
+            assert_eq!(
+                state.appeal_report(pr(0), "post".into(), post_id, "not spam".into(), time()),
+                Err("only reports resolved against you can be appealed".into())
+            );
+            let rewards = |state: &State| {
+                [pr(1), pr(2)].map(|p| state.principal_to_user(p).unwrap().karma_to_reward())
+            };
+            let rewards_before = rewards(state);
+
+            state
+                .vote_on_report(pr(2), "post".into(), post_id, true)
+                .unwrap();
+            let report = Post::get(state, &post_id).unwrap().report.clone().unwrap();
+            assert!(report.closed);
+            let charged = report.charged;
+            assert!(charged > 0);
+            assert_eq!(report.rewards.len(), 2);
+            assert!(rewards(state)
+                .iter()
+                .zip(rewards_before.iter())
+                .all(|(after, before)| after > before));
+            let user = state.users.get(&author).unwrap();
+            assert!(!user.stalwart);
+            assert_eq!(user.active_weeks, 0);
+            let (karma, cycles) = (user.karma(), user.cycles());
+
+            assert_eq!(
+                state.appeal_report(pr(1), "post".into(), post_id, "not spam".into(), time()),
+                Err("only the penalized user can appeal".into())
+            );
+            assert_eq!(
+                state.appeal_report(
+                    pr(0),
+                    "post".into(),
+                    post_id,
+                    "not spam".into(),
+                    time() + (CONFIG.report_appeal_window_days + 1) * DAY
+                ),
+                Err("the appeal window has expired".into())
+            );
+            assert_eq!(
+                state.appeal_report(pr(0), "post".into(), post_id, "not spam".into(), time()),
+                Ok(())
+            );
+            assert_eq!(
+                state.appeal_report(pr(0), "post".into(), post_id, "not spam".into(), time()),
+                Err("this report was already appealed".into())
+            );
+            let report = Post::get(state, &post_id).unwrap().report.clone().unwrap();
+            assert!(!report.closed);
+            assert!(report.confirmed_by.is_empty());
+
+            // the decision gets overturned
+            state
+                .vote_on_report(pr(3), "post".into(), post_id, false)
+                .unwrap();
+            let report = Post::get(state, &post_id).unwrap().report.clone().unwrap();
+            assert!(report.closed);
+            assert_eq!(report.charged, 0);
+            assert!(report.rewards.is_empty());
+            let user = state.users.get(&author).unwrap();
+            assert_eq!(user.cycles(), cycles + charged);
+            assert!(user.karma() + user.karma_to_reward() as Karma > karma);
+            assert!(user.stalwart);
+            assert_eq!(user.active_weeks, 5);
+            // the reporter and the stalwart lost their rewards
+            assert_eq!(rewards(state), rewards_before);
+        })
+    }
 }
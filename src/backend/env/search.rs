@@ -0,0 +1,152 @@
+use super::post::PostId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+const STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "from", "has", "have", "i",
+    "if", "in", "is", "it", "its", "me", "my", "no", "not", "of", "on", "or", "so", "that", "the",
+    "this", "to", "was", "we", "were", "will", "with", "you",
+];
+
+const SUFFIXES: &[&str] = &["ings", "ing", "edly", "ed", "ly", "es", "s"];
+
+/// Inverted index of post bodies.
+#[derive(Default, Serialize, Deserialize)]
+pub struct SearchIndex {
+    // token -> post id -> number of the token occurrences in the post
+    postings: HashMap<String, BTreeMap<PostId, u32>>,
+    // post id -> indexed tokens, needed to drop stale postings on edits and deletions
+    documents: HashMap<PostId, Vec<String>>,
+}
+
+impl SearchIndex {
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    /// Indexes the post body, replacing the previously indexed version of the post.
+    pub fn add(&mut self, post_id: PostId, body: &str) {
+        self.remove(post_id);
+        let mut frequencies: HashMap<String, u32> = Default::default();
+        for token in tokenize(body) {
+            *frequencies.entry(token).or_default() += 1;
+        }
+        for (token, frequency) in &frequencies {
+            self.postings
+                .entry(token.clone())
+                .or_default()
+                .insert(post_id, *frequency);
+        }
+        self.documents
+            .insert(post_id, frequencies.into_keys().collect());
+    }
+
+    pub fn remove(&mut self, post_id: PostId) {
+        for token in self.documents.remove(&post_id).unwrap_or_default() {
+            if let Some(posts) = self.postings.get_mut(&token) {
+                posts.remove(&post_id);
+                if posts.is_empty() {
+                    self.postings.remove(&token);
+                }
+            }
+        }
+    }
+
+    /// Returns ids of posts containing all tokens of the term, ranked by the TF-IDF score of the
+    /// tokens weighted with the recency of the post.
+    pub fn search(&self, term: &str) -> Vec<PostId> {
+        let tokens = tokenize(term);
+        let postings = match tokens
+            .iter()
+            .map(|token| self.postings.get(token))
+            .collect::<Option<Vec<_>>>()
+        {
+            Some(postings) if !postings.is_empty() => postings,
+            _ => return Default::default(),
+        };
+        let documents = self.documents.len() as f64;
+        let last_id = self.documents.keys().max().copied().unwrap_or_default() as f64;
+        let (shortest, others) = {
+            let mut postings = postings.clone();
+            postings.sort_unstable_by_key(|posts| posts.len());
+            let shortest = postings.remove(0);
+            (shortest, postings)
+        };
+        let mut results = shortest
+            .keys()
+            .filter(|post_id| others.iter().all(|posts| posts.contains_key(post_id)))
+            .map(|post_id| {
+                let tf_idf: f64 = postings
+                    .iter()
+                    .map(|posts| {
+                        let tf = posts.get(post_id).copied().unwrap_or_default() as f64;
+                        let idf = (documents / posts.len() as f64).ln() + 1.0;
+                        tf.sqrt() * idf
+                    })
+                    .sum();
+                let recency = (*post_id as f64 + 1.0) / (last_id + 1.0);
+                (*post_id, tf_idf * (1.0 + recency))
+            })
+            .collect::<Vec<_>>();
+        results.sort_unstable_by(|(id_a, a), (id_b, b)| b.total_cmp(a).then(id_b.cmp(id_a)));
+        results.into_iter().map(|(post_id, _)| post_id).collect()
+    }
+}
+
+/// Splits the text into lower-cased and stemmed words excluding the stop-words.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() > 1)
+        .map(|word| word.to_lowercase())
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+        .map(|word| stem(&word))
+        .collect()
+}
+
+// A very basic suffix stripping stemmer.
+fn stem(word: &str) -> String {
+    SUFFIXES
+        .iter()
+        .find_map(|suffix| {
+            word.strip_suffix(suffix)
+                .filter(|stem| stem.chars().count() >= 3)
+        })
+        .unwrap_or(word)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("The #Roadmap: editing posts, and a faster index!"),
+            vec!["roadmap", "edit", "post", "faster", "index"]
+        );
+        assert!(tokenize("a to the").is_empty());
+    }
+
+    #[test]
+    fn test_index() {
+        let mut index = SearchIndex::default();
+        index.add(0, "Rust canisters are fun");
+        index.add(1, "Rust, rust and more rust");
+        index.add(2, "Motoko canisters");
+
+        assert_eq!(index.search("rust"), vec![1, 0]);
+        assert_eq!(index.search("canister"), vec![2, 0]);
+        assert_eq!(index.search("rust canisters"), vec![0]);
+        assert!(index.search("haskell").is_empty());
+        assert!(index.search("the").is_empty());
+
+        index.add(1, "Haskell only");
+        assert_eq!(index.search("rust"), vec![0]);
+        assert_eq!(index.search("haskell"), vec![1]);
+
+        index.remove(0);
+        assert!(index.search("rust").is_empty());
+        assert!(!index.postings.contains_key("rust"));
+    }
+}
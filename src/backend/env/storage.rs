@@ -4,19 +4,102 @@ use crate::{
 };
 use candid::Principal;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::BTreeMap;
 
 use super::config::CONFIG;
 
+/// A blob written to a bucket once and referenced by all posts with identical content.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredBlob {
+    pub bucket: Principal,
+    pub offset: u64,
+    pub len: usize,
+    pub refs: u32,
+}
+
 #[derive(Default, Serialize, Deserialize)]
 pub struct Storage {
     pub buckets: BTreeMap<Principal, u64>,
+    // content hash -> stored blob
+    #[serde(default)]
+    blobs: BTreeMap<String, StoredBlob>,
+    // (bucket, offset) -> content hash, needed to release blobs referenced by post files
+    #[serde(default)]
+    locations: BTreeMap<(Principal, u64), String>,
+}
+
+/// Returns the hex encoded SHA256 hash of the blob content.
+pub fn blob_hash(blob: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(blob);
+    format!("{:x}", hasher.finalize())
 }
 
 const BUCKET_WASM_GZ: &[u8] =
     include_bytes!("../../../target/wasm32-unknown-unknown/release/bucket.wasm.gz");
 
 impl Storage {
+    /// Returns the location of an already stored blob with the given content hash.
+    pub fn lookup(&self, hash: &str) -> Option<(Principal, u64)> {
+        self.blobs.get(hash).map(|blob| (blob.bucket, blob.offset))
+    }
+
+    /// Adds a reference to the blob with the given content hash and returns its location. If
+    /// the blob is not known yet, the given location is registered for it. If it is known
+    /// already (e.g. after two concurrent uploads of the same content), the existing location
+    /// is returned.
+    pub fn acquire(
+        &mut self,
+        hash: String,
+        bucket: Principal,
+        offset: u64,
+        len: usize,
+    ) -> (Principal, u64) {
+        let blob = self
+            .blobs
+            .entry(hash.clone())
+            .or_insert_with(|| StoredBlob {
+                bucket,
+                offset,
+                len,
+                refs: 0,
+            });
+        blob.refs += 1;
+        let location = (blob.bucket, blob.offset);
+        self.locations.insert(location, hash);
+        location
+    }
+
+    /// Drops a reference to the blob at the given location; the blob is freed once it is not
+    /// referenced anymore. Blobs stored before the deduplication are ignored.
+    pub fn release(&mut self, bucket: Principal, offset: u64) {
+        let Some(hash) = self.locations.get(&(bucket, offset)).cloned() else {
+            return;
+        };
+        let Some(blob) = self.blobs.get_mut(&hash) else {
+            return;
+        };
+        blob.refs = blob.refs.saturating_sub(1);
+        if blob.refs == 0 {
+            self.blobs.remove(&hash);
+            self.locations.remove(&(bucket, offset));
+        }
+    }
+
+    /// Releases all blobs referenced by the files of a post.
+    pub fn release_files(&mut self, files: &BTreeMap<String, (u64, usize)>) {
+        for (key, (offset, _)) in files {
+            if let Some(bucket) = key
+                .split('@')
+                .nth(1)
+                .and_then(|id| Principal::from_text(id).ok())
+            {
+                self.release(bucket, *offset)
+            }
+        }
+    }
+
     pub async fn allocate_space() -> Result<Principal, String> {
         if let Some(id) = read(|state| {
             state
@@ -72,3 +155,34 @@ pub async fn upgrade_buckets() {
             .info("Successfully upgraded all storage buckets.")
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blob_refs() {
+        let mut storage = Storage::default();
+        let bucket = Principal::anonymous();
+        let hash = blob_hash(b"pic");
+        assert_eq!(storage.lookup(&hash), None);
+
+        assert_eq!(storage.acquire(hash.clone(), bucket, 0, 3), (bucket, 0));
+        assert_eq!(storage.lookup(&hash), Some((bucket, 0)));
+        // a concurrent upload of the same content reuses the first location
+        assert_eq!(storage.acquire(hash.clone(), bucket, 3, 3), (bucket, 0));
+        assert_eq!(storage.blobs.get(&hash).unwrap().refs, 2);
+
+        let files = vec![(format!("a@{}", bucket), (0, 3))]
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+        storage.release_files(&files);
+        assert_eq!(storage.lookup(&hash), Some((bucket, 0)));
+        storage.release_files(&files);
+        assert_eq!(storage.lookup(&hash), None);
+        assert!(storage.locations.is_empty());
+
+        // unknown locations are ignored
+        storage.release(bucket, 0);
+    }
+}
@@ -252,6 +252,7 @@ pub fn account(owner: Principal) -> Account {
 
 pub fn mint(state: &mut State, account: Account, tokens: Token) {
     let now = time();
+    state.record_minted_tokens(tokens, now);
     let _result = transfer(
         now,
         state,
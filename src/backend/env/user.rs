@@ -10,6 +10,40 @@ pub enum CyclesDelta {
     Minus,
 }
 
+/// Relationship of the caller to the user, only computed for authenticated callers.
+#[derive(Clone, Serialize)]
+pub struct UserViewerContext {
+    /// The caller follows the user.
+    pub following: bool,
+    /// The user follows the caller.
+    pub followed_by: bool,
+}
+
+/// Rules for new handles, exposed to clients for the validation before the submission.
+#[derive(Serialize)]
+pub struct HandlePolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub allowed_chars: String,
+    pub reserved: Vec<String>,
+}
+
+/// Maps the handle to a canonical form, in which visually similar handles are equal, e.g.
+/// `I0rd` and `lord`.
+pub fn handle_skeleton(name: &str) -> String {
+    name.to_lowercase()
+        .replace("rn", "m")
+        .replace("vv", "w")
+        .chars()
+        .map(|c| match c {
+            '0' => 'o',
+            '1' | 'i' => 'l',
+            '5' => 's',
+            _ => c,
+        })
+        .collect()
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub enum Predicate {
     ReportOpen(PostId),
@@ -23,6 +57,61 @@ pub enum Notification {
     Generic(String),
     Conditional(String, Predicate),
     WatchedPostEntries(Vec<u64>),
+    // coalesced notifications about multiple events on the same post: message, post, number of events
+    Digest(String, PostId, u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum NotificationType {
+    Mention,
+    Reply,
+    Reaction,
+    Tip,
+    Proposal,
+    RealmInvite,
+    System,
+    Feed,
+}
+
+impl NotificationType {
+    fn prefix(&self) -> &'static str {
+        match self {
+            NotificationType::Mention => "mention",
+            NotificationType::Reply => "reply",
+            NotificationType::Reaction => "reaction",
+            NotificationType::Tip => "tip",
+            NotificationType::Proposal => "proposal",
+            NotificationType::RealmInvite => "realm_invite",
+            NotificationType::System => "system",
+            NotificationType::Feed => "feed",
+        }
+    }
+
+    /// Returns the type of the notification with the given inbox id. The type is encoded in
+    /// the id prefix; legacy generic and conditional notifications are system notifications.
+    pub fn of(id: &str) -> Self {
+        [
+            NotificationType::Mention,
+            NotificationType::Reply,
+            NotificationType::Reaction,
+            NotificationType::Tip,
+            NotificationType::Proposal,
+            NotificationType::RealmInvite,
+            NotificationType::Feed,
+        ]
+        .iter()
+        .copied()
+        .find(|kind| {
+            id.strip_prefix(kind.prefix())
+                .map(|rest| rest.starts_with('_'))
+                .unwrap_or_default()
+        })
+        .unwrap_or(if id.starts_with("watched_") {
+            NotificationType::Reply
+        } else {
+            NotificationType::System
+        })
+    }
 }
 
 // This struct will hold user's new post until it's saved.
@@ -34,6 +123,71 @@ pub struct Draft {
     pub blobs: Vec<(String, Blob)>,
 }
 
+/// How the posts with content warnings are shown to the user in feeds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum ContentWarnings {
+    #[default]
+    Cover,
+    Reveal,
+    Hide,
+}
+
+/// Periods during which the user receives only system notifications; all others are held back
+/// and delivered afterwards.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DoNotDisturb {
+    /// Daily window as start and end minutes since midnight UTC; wraps around midnight if the
+    /// start is after the end.
+    pub window: Option<(u16, u16)>,
+    pub snooze_until: Option<u64>,
+}
+
+impl DoNotDisturb {
+    pub fn validate(&self) -> Result<(), String> {
+        match self.window {
+            Some((start, end)) if start >= 24 * 60 || end >= 24 * 60 => {
+                Err("window bounds must be minutes of the day".into())
+            }
+            Some((start, end)) if start == end => Err("window is empty".into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn active(&self, now: u64) -> bool {
+        if self
+            .snooze_until
+            .map(|until| now < until)
+            .unwrap_or_default()
+        {
+            return true;
+        }
+        let minute = (now % DAY / MINUTE) as u16;
+        match self.window {
+            Some((start, end)) if start < end => start <= minute && minute < end,
+            Some((start, end)) => start <= minute || minute < end,
+            None => false,
+        }
+    }
+}
+
+/// A principal controlling the account, labeled by the user.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Device {
+    pub principal: Principal,
+    pub label: String,
+    pub added: u64,
+}
+
+/// Lifetime engagement totals of the user, maintained incrementally.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Engagement {
+    pub posts: u64,
+    pub comments: u64,
+    pub reactions_received: u64,
+    // token symbol -> tipped amount in the smallest units of the token
+    pub tips_received: BTreeMap<String, u64>,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: UserId,
@@ -68,6 +222,87 @@ pub struct User {
     pub invites_budget: Cycles,
     #[serde(skip)]
     pub draft: Option<Draft>,
+    #[serde(default)]
+    pub digest_mode: bool,
+    #[serde(default)]
+    pub webhook: Option<String>,
+    // notifications waiting for the delivery to the webhook
+    #[serde(default)]
+    pub outbox: Vec<(String, NotificationType, Notification)>,
+    #[serde(default)]
+    pub deleted_at: Option<u64>,
+    // post pinned to the top of the profile
+    #[serde(default)]
+    pub pinned: Option<PostId>,
+    // tokens granted via reward proposals that are not released yet; only attached to profiles
+    // returned to the clients
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub unvested_tokens: Option<Token>,
+    #[serde(default)]
+    pub banned_until: Option<u64>,
+    // all principals controlling the account including the primary one; empty for accounts
+    // that never added a device
+    #[serde(default)]
+    pub devices: Vec<Device>,
+    // devices proposed by the user that are not confirmed by their principals yet
+    #[serde(default)]
+    pub pending_devices: Vec<Device>,
+    // the most recent karma changes with their reasons, newest first; unlike the accounting, it
+    // is not cleared on weekly rewards
+    #[serde(default)]
+    pub karma_history: VecDeque<(u64, Karma, String)>,
+    // the most recent cycle spendings with their reasons, newest first
+    #[serde(default)]
+    pub cycles_history: VecDeque<(u64, Cycles, String)>,
+    // only attached to profiles returned to the clients and never persisted
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub viewer: Option<UserViewerContext>,
+    // replies don't subscribe the user to the notifications of the thread
+    #[serde(default)]
+    pub auto_follow_disabled: bool,
+    // root posts of threads the user gets no notifications about
+    #[serde(default)]
+    pub muted_threads: BTreeSet<PostId>,
+    // realms controlled by the user; only attached to profiles returned to the clients
+    #[serde(skip_deserializing, skip_serializing_if = "Vec::is_empty")]
+    pub controlled_realms: Vec<String>,
+    // number of realms the user can still create; only attached to the own profile
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub realm_quota: Option<usize>,
+    #[serde(default)]
+    pub do_not_disturb: DoNotDisturb,
+    // notifications held back during do-not-disturb periods
+    #[serde(default)]
+    pub held_notifications: Vec<(String, NotificationType, Notification)>,
+    // followed feeds the user gets notified about when new posts match them
+    #[serde(default)]
+    pub notified_feeds: Vec<BTreeSet<String>>,
+    // languages of the posts in the feeds of the user; posts without a language are always
+    // included and an empty list accepts all languages
+    #[serde(default)]
+    pub languages: Vec<String>,
+    // start of the current hourly window and the number of feed notifications within it
+    #[serde(default)]
+    feed_notifications: (u64, u32),
+    // idempotency keys of recently created posts: (key, post id, timestamp)
+    #[serde(default)]
+    recent_post_keys: VecDeque<(String, PostId, u64)>,
+    #[serde(default)]
+    pub engagement: Engagement,
+    // previous handles of the user with the timestamps of the renames
+    #[serde(default)]
+    pub previous_names: Vec<(String, u64)>,
+    // posts the user reacted to with the reaction ids, newest first
+    #[serde(default)]
+    pub reacted_posts: VecDeque<(PostId, u16)>,
+    // realms whose posts appear in the personal feed without a membership
+    #[serde(default)]
+    pub followed_realms: BTreeSet<String>,
+    #[serde(default)]
+    pub content_warnings: ContentWarnings,
+    // if set, impressions of the user's posts by other users are counted
+    #[serde(default)]
+    pub impressions_enabled: bool,
 }
 
 impl User {
@@ -106,10 +341,45 @@ impl User {
             karma_from_last_posts: Default::default(),
             treasury_e8s: 0,
             invites_budget: 0,
+            digest_mode: false,
+            webhook: None,
+            outbox: Default::default(),
+            deleted_at: None,
+            pinned: None,
+            unvested_tokens: None,
+            banned_until: None,
+            devices: Default::default(),
+            pending_devices: Default::default(),
+            karma_history: Default::default(),
+            cycles_history: Default::default(),
+            viewer: None,
+            auto_follow_disabled: false,
+            muted_threads: Default::default(),
+            controlled_realms: Default::default(),
+            realm_quota: None,
+            do_not_disturb: Default::default(),
+            held_notifications: Default::default(),
+            notified_feeds: Default::default(),
+            languages: Default::default(),
+            feed_notifications: Default::default(),
+            recent_post_keys: Default::default(),
+            engagement: Default::default(),
+            previous_names: Default::default(),
+            reacted_posts: Default::default(),
+            followed_realms: Default::default(),
+            content_warnings: Default::default(),
+            impressions_enabled: false,
             draft: None,
         }
     }
 
+    /// Erases all data of the user except for the id, the handle and the creation timestamp. The
+    /// cycles need to be charged before, otherwise they're lost without any accounting.
+    pub fn redact(&mut self, now: u64) {
+        *self = User::new(self.principal, self.id, self.timestamp, self.name.clone());
+        self.deleted_at = Some(now);
+    }
+
     pub fn posts<'a>(&'a self, state: &'a State) -> Box<dyn Iterator<Item = &'a Post> + 'a> {
         let id = self.id;
         Box::new(
@@ -119,12 +389,28 @@ impl User {
         )
     }
 
+    /// Returns an iterator of the user's journal entries: root posts not deleted and not starting
+    /// with tagging another user.
+    pub fn journal<'a>(&'a self, state: &'a State) -> Box<dyn Iterator<Item = &'a Post> + 'a> {
+        Box::new(self.posts(state).filter(|post| {
+            !post.is_deleted() && post.parent.is_none() && !post.body.starts_with('@')
+        }))
+    }
+
     pub fn update(&mut self, about: String, principals: Vec<String>, settings: String) {
         self.about = about;
         self.settings = settings;
         self.controllers = principals;
     }
 
+    pub fn toggle_muting_thread(&mut self, root_id: PostId) -> bool {
+        if self.muted_threads.remove(&root_id) {
+            return false;
+        }
+        self.muted_threads.insert(root_id);
+        true
+    }
+
     pub fn toggle_bookmark(&mut self, post_id: PostId) -> bool {
         if self.bookmarks.contains(&post_id) {
             self.bookmarks.retain(|id| id != &post_id);
@@ -134,10 +420,48 @@ impl User {
         true
     }
 
+    /// Records a reaction to the post, replacing a previous one and dropping the oldest entries
+    /// beyond the limit.
+    pub fn record_reaction(&mut self, post_id: PostId, reaction: u16) {
+        self.forget_reaction(post_id);
+        self.reacted_posts.push_front((post_id, reaction));
+        self.reacted_posts.truncate(CONFIG.max_reacted_posts);
+    }
+
+    pub fn forget_reaction(&mut self, post_id: PostId) {
+        self.reacted_posts.retain(|(id, _)| id != &post_id);
+    }
+
+    /// Returns true if the handle was given up by the user recently and can't be taken by other
+    /// users yet.
+    pub fn holds_previous_name(&self, handle: &str, now: u64) -> bool {
+        self.previous_names.iter().any(|(name, timestamp)| {
+            name.eq_ignore_ascii_case(handle) && timestamp + CONFIG.old_handle_hold_days * DAY > now
+        })
+    }
+
     pub fn active_within_weeks(&self, now: u64, n: u64) -> bool {
         self.last_activity + n * WEEK > now
     }
 
+    /// Returns all devices of the user, the primary principal is always the first one.
+    pub fn devices(&self) -> Vec<Device> {
+        if self.devices.is_empty() {
+            return vec![Device {
+                principal: self.principal,
+                label: "primary".into(),
+                added: self.timestamp,
+            }];
+        }
+        self.devices.clone()
+    }
+
+    pub fn banned(&self, now: u64) -> bool {
+        self.banned_until
+            .map(|timestamp| timestamp > now)
+            .unwrap_or_default()
+    }
+
     pub fn trusted(&self) -> bool {
         self.karma >= CONFIG.trusted_user_min_karma
             && time().saturating_sub(self.timestamp) >= CONFIG.trusted_user_min_age_weeks * WEEK
@@ -157,63 +481,212 @@ impl User {
         }
     }
 
+    /// Returns all inbox entries of the given type or all entries if no type is specified.
+    pub fn notifications(
+        &self,
+        kind: Option<NotificationType>,
+    ) -> Vec<(String, NotificationType, Notification)> {
+        self.inbox
+            .iter()
+            .map(|(id, notification)| (id.clone(), NotificationType::of(id), notification.clone()))
+            .filter(|(_, notification_type, _)| {
+                kind.map(|kind| kind == *notification_type).unwrap_or(true)
+            })
+            .collect()
+    }
+
+    pub fn clear_notifications_of_type(&mut self, kind: NotificationType) {
+        self.inbox.retain(|id, _| NotificationType::of(id) != kind);
+    }
+
     pub fn toggle_following_feed(&mut self, tags: Vec<String>) -> bool {
         let tags = tags.into_iter().map(|tag| tag.to_lowercase()).collect();
         if let Some(i) = covered_by_feeds(&self.feeds, &tags, true) {
-            self.feeds.remove(i);
+            let feed = self.feeds.remove(i);
+            self.notified_feeds.retain(|notified| notified != &feed);
             return false;
         }
         self.feeds.push(tags.into_iter().collect());
         true
     }
 
+    pub fn toggle_following_realm(&mut self, name: String) -> bool {
+        if self.followed_realms.remove(&name) {
+            return false;
+        }
+        self.followed_realms.insert(name);
+        true
+    }
+
+    /// Enables or disables notifications about new posts matching the followed feed. Returns
+    /// true if the notifications were enabled.
+    pub fn toggle_feed_notifications(&mut self, tags: Vec<String>) -> Result<bool, String> {
+        let tags: BTreeSet<_> = tags.into_iter().map(|tag| tag.to_lowercase()).collect();
+        if !self.feeds.contains(&tags) {
+            return Err("feed is not followed".into());
+        }
+        if let Some(i) = self.notified_feeds.iter().position(|feed| feed == &tags) {
+            self.notified_feeds.remove(i);
+            return Ok(false);
+        }
+        self.notified_feeds.push(tags);
+        Ok(true)
+    }
+
+    /// Returns the id of the post created with the given idempotency key within the
+    /// deduplication window.
+    pub fn post_by_idempotency_key(&self, key: &str, now: u64) -> Option<PostId> {
+        self.recent_post_keys
+            .iter()
+            .find(|(k, _, timestamp)| {
+                k == key && timestamp + CONFIG.idempotency_window_minutes * MINUTE > now
+            })
+            .map(|(_, post_id, _)| *post_id)
+    }
+
+    pub fn remember_idempotency_key(&mut self, key: String, post_id: PostId, now: u64) {
+        self.recent_post_keys.retain(|(_, _, timestamp)| {
+            timestamp + CONFIG.idempotency_window_minutes * MINUTE > now
+        });
+        self.recent_post_keys.push_back((key, post_id, now));
+        while self.recent_post_keys.len() > CONFIG.max_idempotency_keys {
+            self.recent_post_keys.pop_front();
+        }
+    }
+
     pub fn personal_feed<'a>(
         &'a self,
         state: &'a State,
         page: usize,
+        page_size: usize,
         with_comments: bool,
     ) -> Box<dyn Iterator<Item = &'a Post> + 'a> {
-        let posts_by_tags = Box::new(state.last_posts(None, with_comments).filter(move |post| {
-            let lc_tags: BTreeSet<_> = post.tags.iter().map(|t| t.to_lowercase()).collect();
-            covered_by_feeds(&self.feeds, &lc_tags, false).is_some()
-        }));
+        // the feed only contains posts from the heap, archived posts are never visited
+        let posts_by_tags = Box::new(
+            state
+                .last_posts(None, with_comments)
+                .take_while(|post| !post.archived)
+                .filter(move |post| {
+                    let lc_tags: BTreeSet<_> = post.tags.iter().map(|t| t.to_lowercase()).collect();
+                    covered_by_feeds(&self.feeds, &lc_tags, false).is_some()
+                }),
+        );
 
         let mut iterators: Vec<Box<dyn Iterator<Item = &'a Post> + 'a>> = self
             .followees
             .iter()
             .filter_map(move |id| state.users.get(id))
-            .map(|user| user.posts(state))
+            .map(|user| -> Box<dyn Iterator<Item = &'a Post> + 'a> {
+                Box::new(user.posts(state).take_while(|post| !post.archived))
+            })
             .collect();
 
         iterators.push(posts_by_tags);
 
+        if !self.followed_realms.is_empty() {
+            iterators.push(Box::new(
+                state
+                    .last_posts(None, with_comments)
+                    .take_while(|post| !post.archived)
+                    .filter(move |post| {
+                        post.realm
+                            .as_ref()
+                            .map(|name| self.followed_realms.contains(name))
+                            .unwrap_or_default()
+                            && state
+                                .thread(post.id)
+                                .next()
+                                .map(|root_id| !self.muted_threads.contains(&root_id))
+                                .unwrap_or(true)
+                    }),
+            ));
+        }
+
         Box::new(
             IteratorMerger {
                 iterators: iterators.into_iter().map(|i| i.peekable()).collect(),
             }
             .filter(move |post| with_comments || post.parent.is_none())
-            // if the post if from a realm, it's only included if user if part of it
+            // if the post if from a realm, it's only included if user if part of it or follows it
             .filter(move |post| {
                 post.realm
                     .as_ref()
-                    .map(|id| self.realms.contains(id))
+                    .map(|id| self.realms.contains(id) || self.followed_realms.contains(id))
                     .unwrap_or(true)
             })
-            .skip(page * CONFIG.feed_page_size)
-            .take(CONFIG.feed_page_size),
+            .filter(move |post| post.in_languages(&self.languages))
+            .filter(move |post| {
+                !post.hidden_by_warning(self.content_warnings == ContentWarnings::Hide)
+            })
+            .skip(page * page_size)
+            .take(page_size),
         )
     }
 
-    pub fn notify_with_params<T: AsRef<str>>(&mut self, message: T, predicate: Option<Predicate>) {
+    fn add_notification(&mut self, kind: NotificationType, notification: Notification) {
         self.messages += 1;
-        let id = self.messages;
+        let id = format!("{}_{}", kind.prefix(), self.messages);
+        self.insert_notification(id, kind, notification);
+    }
+
+    fn insert_notification(
+        &mut self,
+        id: String,
+        kind: NotificationType,
+        notification: Notification,
+    ) {
+        if kind != NotificationType::System && self.do_not_disturb.active(time()) {
+            // only the latest notifications are held back, older ones are dropped
+            if self.held_notifications.len() >= CONFIG.max_held_notifications {
+                self.held_notifications.remove(0);
+            }
+            self.held_notifications.push((id, kind, notification));
+            return;
+        }
+        self.deliver_notification(id, kind, notification);
+    }
+
+    // Puts the notification into the inbox and into the outbox, if the user has a webhook.
+    fn deliver_notification(
+        &mut self,
+        id: String,
+        kind: NotificationType,
+        notification: Notification,
+    ) {
+        if self.webhook.is_some() {
+            self.outbox.push((id.clone(), kind, notification.clone()));
+        }
+        self.inbox.insert(id, notification);
+    }
+
+    /// Delivers the notifications held back during a do-not-disturb period, once the period is
+    /// over, and sends one notification summarizing them.
+    pub fn release_held_notifications(&mut self, now: u64) {
+        if self.held_notifications.is_empty() || self.do_not_disturb.active(now) {
+            return;
+        }
+        let held = std::mem::take(&mut self.held_notifications);
+        let count = held.len();
+        for (id, kind, notification) in held {
+            self.deliver_notification(id, kind, notification);
+        }
+        self.notify(format!(
+            "You received {} notifications while in the do-not-disturb mode.",
+            count
+        ));
+    }
+
+    pub fn notify_with_params<T: AsRef<str>>(&mut self, message: T, predicate: Option<Predicate>) {
         match predicate {
-            None => self.inbox.insert(
-                format!("generic_{id}"),
+            None => self.add_notification(
+                NotificationType::System,
                 Notification::Generic(message.as_ref().into()),
             ),
-            Some(p) => self.inbox.insert(
-                format!("conditional_{id}"),
+            Some(p) => self.add_notification(
+                match p {
+                    Predicate::Proposal(_) => NotificationType::Proposal,
+                    _ => NotificationType::System,
+                },
                 Notification::Conditional(message.as_ref().into(), p),
             ),
         };
@@ -223,15 +696,94 @@ impl User {
         self.notify_with_params(message, None)
     }
 
-    pub fn notify_about_post<T: AsRef<str>>(&mut self, message: T, post_id: PostId) {
-        self.messages += 1;
-        let id = self.messages;
-        self.inbox.insert(
-            format!("generic_{id}"),
+    pub fn notify_with_type<T: AsRef<str>>(&mut self, kind: NotificationType, message: T) {
+        self.add_notification(kind, Notification::Generic(message.as_ref().into()));
+    }
+
+    pub fn notify_about_post<T: AsRef<str>>(
+        &mut self,
+        kind: NotificationType,
+        message: T,
+        post_id: PostId,
+    ) {
+        self.add_notification(
+            kind,
             Notification::NewPost(message.as_ref().into(), post_id),
         );
     }
 
+    /// Notifies about an event (e.g. a reaction or a reply) on the user's post `target`. In the
+    /// digest mode, events of the same type on the same post are coalesced into one notification
+    /// with the message produced by `digest` from the number of events.
+    pub fn notify_about_post_event<T: AsRef<str>>(
+        &mut self,
+        kind: NotificationType,
+        message: T,
+        post_id: PostId,
+        target: PostId,
+        digest: fn(u64) -> String,
+    ) {
+        if !self.digest_mode {
+            return self.notify_about_post(kind, message, post_id);
+        }
+        let id = format!("{}_digest_{}", kind.prefix(), target);
+        self.add_to_digest(id, kind, message, target, digest);
+    }
+
+    fn add_to_digest<T: AsRef<str>>(
+        &mut self,
+        id: String,
+        kind: NotificationType,
+        message: T,
+        post_id: PostId,
+        digest: fn(u64) -> String,
+    ) {
+        let notification = match self.inbox.get(&id) {
+            Some(Notification::Digest(_, _, count)) => {
+                Notification::Digest(digest(count + 1), post_id, count + 1)
+            }
+            _ => Notification::Digest(message.as_ref().into(), post_id, 1),
+        };
+        self.insert_notification(id, kind, notification);
+    }
+
+    /// Notifies about a new post matching the followed feed. Notifications beyond the hourly
+    /// limit are dropped; in the digest mode, matches of the same feed are coalesced.
+    pub fn notify_about_feed_match(
+        &mut self,
+        feed: &BTreeSet<String>,
+        author_name: &str,
+        post_id: PostId,
+        now: u64,
+    ) {
+        let (window_start, count) = &mut self.feed_notifications;
+        if *window_start + HOUR <= now {
+            *window_start = now;
+            *count = 0;
+        }
+        if *count >= CONFIG.max_feed_notifications_per_hour {
+            return;
+        }
+        *count += 1;
+        let tags = feed
+            .iter()
+            .map(|tag| format!("#{}", tag))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let message = format!("@{} posted in your feed {}", author_name, tags);
+        if !self.digest_mode {
+            return self.notify_about_post(NotificationType::Feed, message, post_id);
+        }
+        let id = format!(
+            "{}_digest_{}",
+            NotificationType::Feed.prefix(),
+            feed.iter().cloned().collect::<Vec<_>>().join("+")
+        );
+        self.add_to_digest(id, NotificationType::Feed, message, post_id, |n| {
+            format!("`{}` new posts in your followed feeds", n)
+        });
+    }
+
     pub fn notify_about_watched_post(&mut self, post_id: PostId, comment: PostId, parent: PostId) {
         let id = format!("watched_{post_id}");
         if let Notification::WatchedPostEntries(entries) = self
@@ -255,13 +807,18 @@ impl User {
         log: T,
     ) -> Result<(), String> {
         if delta == CyclesDelta::Minus && amount <= self.cycles || delta == CyclesDelta::Plus {
+            let now = time();
             if delta == CyclesDelta::Plus {
                 self.cycles += amount;
             } else {
                 self.cycles -= amount;
+                self.cycles_history
+                    .push_front((now, amount, log.to_string()));
+                self.cycles_history
+                    .truncate(CONFIG.max_cycles_history_entries);
             }
             self.accounting.push_front((
-                time(),
+                now,
                 "CYC".to_string(),
                 if delta == CyclesDelta::Plus {
                     amount as i64
@@ -297,8 +854,42 @@ impl User {
         if self.karma < 0 {
             self.rewarded_karma = 0;
         }
+        let now = time();
         self.accounting
-            .push_front((time(), "KRM".to_string(), amount, log.to_string()));
+            .push_front((now, "KRM".to_string(), amount, log.to_string()));
+        self.karma_history
+            .push_front((now, amount, log.to_string()));
+        self.karma_history
+            .truncate(CONFIG.max_karma_history_entries);
+    }
+
+    /// Seeds the karma history from the karma entries of the accounting, if the history is empty.
+    pub fn backfill_karma_history(&mut self) {
+        if !self.karma_history.is_empty() {
+            return;
+        }
+        self.karma_history = self
+            .accounting
+            .iter()
+            .filter(|(_, kind, _, _)| kind == "KRM")
+            .take(CONFIG.max_karma_history_entries)
+            .map(|(timestamp, _, delta, log)| (*timestamp, *delta, log.clone()))
+            .collect();
+    }
+
+    /// Seeds the cycles history from the cycle spendings of the accounting, if the history is
+    /// empty.
+    pub fn backfill_cycles_history(&mut self) {
+        if !self.cycles_history.is_empty() {
+            return;
+        }
+        self.cycles_history = self
+            .accounting
+            .iter()
+            .filter(|(_, kind, delta, _)| kind == "CYC" && *delta < 0)
+            .take(CONFIG.max_cycles_history_entries)
+            .map(|(timestamp, _, delta, log)| (*timestamp, delta.unsigned_abs(), log.clone()))
+            .collect();
     }
 
     pub fn karma_to_reward(&self) -> u64 {
@@ -386,6 +977,131 @@ mod tests {
     use super::*;
     use crate::env::tests::pr;
 
+    #[test]
+    fn test_notification_types() {
+        let mut user = User::new(pr(0), 66, 0, Default::default());
+        user.notify("welcome");
+        user.notify_with_type(NotificationType::Tip, "you got a tip");
+        user.notify_about_post(NotificationType::Mention, "you were mentioned", 5);
+        user.notify_with_params("new proposal", Some(Predicate::Proposal(7)));
+        user.notify_about_watched_post(3, 9, 3);
+        user.inbox
+            .insert("generic_100".into(), Notification::Generic("legacy".into()));
+
+        assert_eq!(user.notifications(None).len(), 6);
+        assert_eq!(user.notifications(Some(NotificationType::System)).len(), 2);
+        assert_eq!(user.notifications(Some(NotificationType::Mention)).len(), 1);
+        assert_eq!(user.notifications(Some(NotificationType::Reply)).len(), 1);
+        assert_eq!(user.notifications(Some(NotificationType::Tip)).len(), 1);
+        assert_eq!(
+            user.notifications(Some(NotificationType::Proposal)).len(),
+            1
+        );
+        assert!(user
+            .notifications(Some(NotificationType::Reaction))
+            .is_empty());
+
+        user.clear_notifications_of_type(NotificationType::Mention);
+        assert_eq!(user.inbox.len(), 5);
+        assert!(user
+            .notifications(Some(NotificationType::Mention))
+            .is_empty());
+
+        let id = user.notifications(Some(NotificationType::Tip))[0].0.clone();
+        user.clear_notifications(vec![id]);
+        assert_eq!(user.inbox.len(), 4);
+    }
+
+    #[test]
+    fn test_digest_notifications() {
+        let mut user = User::new(pr(0), 66, 0, Default::default());
+        let digest = |n: u64| format!("{} people reacted to your post", n);
+
+        // without the digest mode, every event is a separate notification
+        user.notify_about_post_event(NotificationType::Reaction, "@a reacted", 1, 1, digest);
+        user.notify_about_post_event(NotificationType::Reaction, "@b reacted", 1, 1, digest);
+        assert_eq!(user.inbox.len(), 2);
+        user.clear_notifications(vec![]);
+
+        user.digest_mode = true;
+        user.notify_about_post_event(NotificationType::Reaction, "@a reacted", 1, 1, digest);
+        let id = "reaction_digest_1".to_string();
+        assert!(matches!(
+            user.inbox.get(&id),
+            Some(Notification::Digest(message, 1, 1)) if message == "@a reacted"
+        ));
+        for name in ["b", "c"] {
+            user.notify_about_post_event(NotificationType::Reaction, name, 1, 1, digest);
+        }
+        user.notify_about_post_event(NotificationType::Reaction, "@d reacted", 2, 2, digest);
+        user.notify_about_post_event(NotificationType::Reply, "@d replied", 1, 1, digest);
+        assert_eq!(user.inbox.len(), 3);
+        assert!(matches!(
+            user.inbox.get(&id),
+            Some(Notification::Digest(message, 1, 3)) if message == "3 people reacted to your post"
+        ));
+        assert_eq!(
+            user.notifications(Some(NotificationType::Reaction)).len(),
+            2
+        );
+
+        // after clearing, a new digest starts
+        user.clear_notifications(vec![id.clone()]);
+        assert_eq!(user.inbox.len(), 2);
+        user.notify_about_post_event(NotificationType::Reaction, "@e reacted", 1, 1, digest);
+        assert!(matches!(
+            user.inbox.get(&id),
+            Some(Notification::Digest(_, 1, 1))
+        ));
+    }
+
+    #[test]
+    fn test_do_not_disturb() {
+        let window = |start, end| DoNotDisturb {
+            window: Some((start, end)),
+            snooze_until: None,
+        };
+        assert!(window(22 * 60, 7 * 60).active(23 * HOUR));
+        assert!(window(22 * 60, 7 * 60).active(DAY + 6 * HOUR));
+        assert!(!window(22 * 60, 7 * 60).active(DAY + 7 * HOUR));
+        assert!(window(9 * 60, 17 * 60).active(9 * HOUR));
+        assert!(!window(9 * 60, 17 * 60).active(17 * HOUR));
+        assert!(window(60, 60).validate().is_err());
+        assert!(window(0, 24 * 60).validate().is_err());
+
+        let mut user = User::new(pr(0), 66, 0, Default::default());
+        user.webhook = Some("https://example.com".into());
+        user.do_not_disturb.snooze_until = Some(time() + HOUR);
+        user.notify_with_type(NotificationType::Tip, "you got a tip");
+        user.notify_about_post(NotificationType::Mention, "you were mentioned", 5);
+        // system notifications bypass the do-not-disturb mode
+        user.notify("you were banned");
+        assert_eq!(user.inbox.len(), 1);
+        assert_eq!(user.outbox.len(), 1);
+        assert_eq!(user.held_notifications.len(), 2);
+
+        user.release_held_notifications(time());
+        assert_eq!(user.held_notifications.len(), 2);
+        user.release_held_notifications(time() + HOUR);
+        assert!(user.held_notifications.is_empty());
+        assert_eq!(user.notifications(Some(NotificationType::Tip)).len(), 1);
+        assert_eq!(user.notifications(Some(NotificationType::Mention)).len(), 1);
+        assert_eq!(user.notifications(Some(NotificationType::System)).len(), 2);
+        // released notifications are delivered to the webhook as well
+        assert_eq!(user.outbox.len(), 4);
+
+        // only the latest notifications are held back
+        user.do_not_disturb.snooze_until = Some(time() + 2 * HOUR);
+        for i in 0..=CONFIG.max_held_notifications {
+            user.notify_with_type(NotificationType::Tip, format!("tip {}", i));
+        }
+        assert_eq!(user.held_notifications.len(), CONFIG.max_held_notifications);
+        assert!(matches!(
+            &user.held_notifications[0].2,
+            Notification::Generic(message) if message == "tip 1"
+        ));
+    }
+
     #[test]
     fn test_automatic_top_up() {
         let mut user = User::new(pr(0), 66, 0, Default::default());
@@ -505,4 +1221,66 @@ mod tests {
         assert_eq!(u.karma(), 50);
         assert_eq!(u.karma_to_reward(), 0);
     }
+
+    #[test]
+    fn test_karma_history() {
+        let mut u = User::new(pr(1), 66, 0, Default::default());
+        u.change_karma(5, "reaction");
+        u.change_karma(-3, "penalty");
+        assert_eq!(
+            u.karma_history
+                .iter()
+                .map(|(_, delta, log)| (*delta, log.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(-3, "penalty"), (5, "reaction")]
+        );
+
+        // the history survives the weekly clean up of the accounting and is bounded
+        u.accounting.clear();
+        for _ in 0..CONFIG.max_karma_history_entries {
+            u.change_karma(1, "reward");
+        }
+        assert_eq!(u.karma_history.len(), CONFIG.max_karma_history_entries);
+        assert!(u.karma_history.iter().all(|(_, _, log)| log == "reward"));
+
+        // histories of accounts created before the history existed are seeded from the accounting
+        u.karma_history.clear();
+        u.accounting.clear();
+        u.change_cycles(10, CyclesDelta::Plus, "top up").unwrap();
+        u.change_karma(7, "post");
+        u.karma_history.clear();
+        u.backfill_karma_history();
+        assert_eq!(u.karma_history.len(), 1);
+        assert_eq!(u.karma_history[0].1, 7);
+    }
+
+    #[test]
+    fn test_cycles_history() {
+        let mut u = User::new(pr(1), 66, 0, Default::default());
+        u.change_cycles(500, CyclesDelta::Plus, "top up").unwrap();
+        u.change_cycles(2, CyclesDelta::Minus, "new post 1")
+            .unwrap();
+        u.change_cycles(1, CyclesDelta::Minus, "reaction").unwrap();
+        assert!(u.change_cycles(1000, CyclesDelta::Minus, "realm").is_err());
+        // only the spendings are recorded
+        assert_eq!(
+            u.cycles_history
+                .iter()
+                .map(|(_, amount, log)| (*amount, log.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(1, "reaction"), (2, "new post 1")]
+        );
+
+        // histories of accounts created before the history existed are seeded from the accounting
+        u.cycles_history.clear();
+        u.backfill_cycles_history();
+        assert_eq!(u.cycles_history.len(), 2);
+        assert_eq!(u.cycles_history[0].2, "reaction");
+
+        for _ in 0..CONFIG.max_cycles_history_entries {
+            u.change_cycles(1, CyclesDelta::Minus, "fee").unwrap();
+        }
+        assert_eq!(u.cycles_history.len(), CONFIG.max_cycles_history_entries);
+        assert!(u.cycles_history.iter().all(|(_, _, log)| log == "fee"));
+    }
 }
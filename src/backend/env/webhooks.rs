@@ -0,0 +1,229 @@
+use super::user::{Notification, NotificationType, UserId};
+use super::*;
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext, TransformFunc,
+};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Delivery {
+    id: String,
+    user_id: UserId,
+    url: String,
+    payload: String,
+    attempts: u32,
+    next_attempt: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Webhooks {
+    queue: VecDeque<Delivery>,
+    // user id -> (start of the current rate limiting window, number of deliveries within it)
+    rate_limits: HashMap<UserId, (u64, u32)>,
+}
+
+pub fn validate_url(url: &str) -> Result<(), String> {
+    let host = url
+        .strip_prefix("https://")
+        .ok_or("only https webhooks are supported")?
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    if url.len() > CONFIG.max_webhook_url_length {
+        return Err("webhook url is too long".into());
+    }
+    if host.is_empty()
+        || !host.contains('.')
+        || url.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err("invalid webhook url".into());
+    }
+    Ok(())
+}
+
+impl Webhooks {
+    /// Moves the pending notifications of all users with a webhook into the delivery queue,
+    /// dropping the ones exceeding the per-user rate limit.
+    fn enqueue(&mut self, users: &mut BTreeMap<UserId, User>, now: u64) {
+        for user in users.values_mut().filter(|user| !user.outbox.is_empty()) {
+            let outbox = std::mem::take(&mut user.outbox);
+            let url = match &user.webhook {
+                Some(url) => url.clone(),
+                None => continue,
+            };
+            let (window_start, count) = self.rate_limits.entry(user.id).or_insert((now, 0));
+            if *window_start + HOUR < now {
+                *window_start = now;
+                *count = 0;
+            }
+            for (id, kind, notification) in outbox {
+                if *count >= CONFIG.max_webhook_deliveries_per_hour {
+                    break;
+                }
+                *count += 1;
+                self.queue.push_back(Delivery {
+                    payload: payload(&id, kind, &notification),
+                    id,
+                    user_id: user.id,
+                    url: url.clone(),
+                    attempts: 0,
+                    next_attempt: now,
+                });
+            }
+        }
+        self.rate_limits
+            .retain(|_, (window_start, _)| *window_start + HOUR >= now);
+    }
+
+    fn take_due(&mut self, now: u64) -> Vec<Delivery> {
+        let (due, pending): (VecDeque<_>, VecDeque<_>) = std::mem::take(&mut self.queue)
+            .into_iter()
+            .partition(|delivery| delivery.next_attempt <= now);
+        self.queue = pending;
+        let mut due = due.into_iter().collect::<Vec<_>>();
+        // put back everything exceeding the per-run limit
+        for delivery in due.split_off(due.len().min(CONFIG.max_webhook_deliveries_per_run)) {
+            self.queue.push_back(delivery);
+        }
+        due
+    }
+
+    // Reschedules the failed delivery with an exponential backoff or drops it after too many
+    // attempts.
+    fn retry(&mut self, logger: &mut Logger, mut delivery: Delivery, err: String, now: u64) {
+        delivery.attempts += 1;
+        if delivery.attempts >= CONFIG.max_webhook_attempts {
+            logger.error(format!(
+                "Dropped webhook delivery of notification {} to user {} after {} attempts: {}",
+                delivery.id, delivery.user_id, delivery.attempts, err
+            ));
+            return;
+        }
+        delivery.next_attempt = now + 15 * MINUTE * 2_u64.pow(delivery.attempts);
+        self.queue.push_back(delivery);
+    }
+}
+
+fn payload(id: &str, kind: NotificationType, notification: &Notification) -> String {
+    serde_json::json!({
+        "id": id,
+        "type": kind,
+        "notification": notification,
+    })
+    .to_string()
+}
+
+/// Delivers all due webhook notifications. Failed deliveries are retried in later chores.
+pub async fn deliver(now: u64) {
+    let due = mutate(|state| {
+        state.webhooks.enqueue(&mut state.users, now);
+        state.webhooks.take_due(now)
+    });
+    for delivery in due {
+        if let Err(err) = send(&delivery).await {
+            mutate(|state| state.webhooks.retry(&mut state.logger, delivery, err, now));
+        }
+    }
+}
+
+async fn send(delivery: &Delivery) -> Result<(), String> {
+    let request = CanisterHttpRequestArgument {
+        url: delivery.url.clone(),
+        method: HttpMethod::POST,
+        headers: vec![
+            HttpHeader {
+                name: "Content-Type".into(),
+                value: "application/json".into(),
+            },
+            // the request is sent by every replica of the subnet, so receivers should use this
+            // key to deduplicate deliveries
+            HttpHeader {
+                name: "Idempotency-Key".into(),
+                value: format!("{}-{}", delivery.user_id, delivery.id),
+            },
+        ],
+        body: Some(delivery.payload.as_bytes().to_vec()),
+        max_response_bytes: Some(CONFIG.max_webhook_response_bytes),
+        transform: Some(TransformContext {
+            function: TransformFunc(candid::Func {
+                principal: api::id(),
+                method: "webhook_transform".into(),
+            }),
+            context: Default::default(),
+        }),
+    };
+    let (response,) = http_request(request)
+        .await
+        .map_err(|(code, msg)| format!("{:?}: {}", code, msg))?;
+    if response.status < candid::Nat::from(200) || response.status >= candid::Nat::from(300) {
+        return Err(format!("webhook responded with status {}", response.status));
+    }
+    Ok(())
+}
+
+// Strips everything but the status from the webhook response, so that the replicas can reach
+// consensus on it.
+#[ic_cdk_macros::query]
+fn webhook_transform(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: Default::default(),
+        body: Default::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_url() {
+        assert!(validate_url("https://example.com/hook?token=abc").is_ok());
+        assert!(validate_url("http://example.com/hook").is_err());
+        assert!(validate_url("https:///hook").is_err());
+        assert!(validate_url("https://localhost/hook").is_err());
+        assert!(validate_url("https://example.com/ hook").is_err());
+        assert!(validate_url(&format!("https://example.com/{}", "a".repeat(300))).is_err());
+    }
+
+    #[test]
+    fn test_enqueue_and_retry() {
+        let mut users = BTreeMap::new();
+        let mut user = User::new(crate::env::tests::pr(0), 0, 0, Default::default());
+        user.webhook = Some("https://example.com/hook".into());
+        for i in 0..CONFIG.max_webhook_deliveries_per_hour + 5 {
+            user.notify(format!("message {}", i));
+        }
+        users.insert(user.id, user);
+
+        let mut webhooks = Webhooks::default();
+        webhooks.enqueue(&mut users, 0);
+        assert!(users.get(&0).unwrap().outbox.is_empty());
+        assert_eq!(
+            webhooks.queue.len(),
+            CONFIG.max_webhook_deliveries_per_hour as usize
+        );
+
+        let mut logger = Logger::default();
+        for delivery in webhooks.take_due(0) {
+            webhooks.retry(&mut logger, delivery, "timeout".into(), 0);
+        }
+        assert_eq!(
+            webhooks.queue.len(),
+            CONFIG.max_webhook_deliveries_per_hour as usize
+        );
+        // retried deliveries are not due immediately
+        assert!(webhooks.take_due(0).is_empty());
+        assert!(logger.events.is_empty());
+
+        // deliveries are dropped after too many attempts
+        let mut delivery = webhooks.queue.pop_front().unwrap();
+        delivery.attempts = CONFIG.max_webhook_attempts - 1;
+        webhooks.retry(&mut logger, delivery, "timeout".into(), 0);
+        assert_eq!(
+            webhooks.queue.len(),
+            CONFIG.max_webhook_deliveries_per_hour as usize - 1
+        );
+        assert_eq!(logger.events.len(), 1);
+    }
+}
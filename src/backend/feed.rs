@@ -0,0 +1,179 @@
+use crate::post::Post;
+
+/// Renders the given posts as an RSS 2.0 channel.
+pub fn rss<'a>(
+    host: &str,
+    path: &str,
+    title: &str,
+    desc: &str,
+    posts: impl Iterator<Item = &'a Post>,
+) -> Vec<u8> {
+    let items = posts
+        .map(|post| {
+            let link = format!("https://{}/#/post/{}", host, post.id);
+            format!(
+                r#"<item><title>{}</title><link>{}</link><guid isPermaLink="true">{}</guid><pubDate>{}</pubDate><description>{}</description></item>"#,
+                escape(&item_title(&post.body)),
+                link,
+                link,
+                rfc822(post.timestamp()),
+                escape(&post.body),
+            )
+        })
+        .collect::<String>();
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><rss version="2.0"><channel><title>{}</title><link>https://{}/#/{}</link><description>{}</description>{}</channel></rss>"#,
+        escape(title),
+        host,
+        path,
+        escape(desc),
+        items
+    )
+    .into_bytes()
+}
+
+/// Renders the `(path, last modification timestamp)` pairs as a sitemap URL set. The paths are
+/// linked directly, because crawlers ignore URL fragments.
+pub fn sitemap<'a>(host: &str, urls: impl Iterator<Item = &'a (String, Option<u64>)>) -> Vec<u8> {
+    let urls = urls
+        .map(|(path, timestamp)| {
+            format!(
+                "<url><loc>https://{}/{}</loc>{}</url>",
+                host,
+                escape(path),
+                timestamp
+                    .map(|timestamp| format!("<lastmod>{}</lastmod>", w3c_date(timestamp)))
+                    .unwrap_or_default()
+            )
+        })
+        .collect::<String>();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">{}</urlset>"#,
+        urls
+    )
+    .into_bytes()
+}
+
+/// Renders a sitemap index referencing `pages` sitemaps served under `/sitemap/<page>.xml`.
+pub fn sitemap_index(host: &str, pages: usize) -> Vec<u8> {
+    let sitemaps = (0..pages)
+        .map(|page| {
+            format!(
+                "<sitemap><loc>https://{}/sitemap/{}.xml</loc></sitemap>",
+                host, page
+            )
+        })
+        .collect::<String>();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">{}</sitemapindex>"#,
+        sitemaps
+    )
+    .into_bytes()
+}
+
+// Uses the first non-empty line of the post without the markdown heading markers as the title.
+fn item_title(body: &str) -> String {
+    let line = body
+        .lines()
+        .map(|line| line.trim_start_matches('#').trim())
+        .find(|line| !line.is_empty())
+        .unwrap_or_default();
+    match line.char_indices().nth(80) {
+        None => line.to_string(),
+        Some((idx, _)) => format!("{}...", &line[..idx]),
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Formats a timestamp in nanoseconds as an RFC 822 date, e.g. `Tue, 01 Aug 2023 12:00:00 GMT`.
+fn rfc822(timestamp: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = timestamp / 1_000_000_000;
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Formats a timestamp in nanoseconds as a W3C date, e.g. `2023-08-01`.
+fn w3c_date(timestamp: u64) -> String {
+    let (year, month, day) = civil_from_days((timestamp / 1_000_000_000 / 86400) as i64);
+    format!("{}-{:02}-{:02}", year, month, day)
+}
+
+// Converts days since the Unix epoch into a (year, month, day) triple of the proleptic Gregorian
+// calendar (see http://howardhinnant.github.io/date_algorithms.html#civil_from_days).
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc822() {
+        assert_eq!(rfc822(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(
+            rfc822(1_690_891_200_000_000_000),
+            "Tue, 01 Aug 2023 12:00:00 GMT"
+        );
+        assert_eq!(
+            rfc822(1_709_208_000_000_000_000),
+            "Thu, 29 Feb 2024 12:00:00 GMT"
+        );
+    }
+
+    #[test]
+    fn test_w3c_date() {
+        assert_eq!(w3c_date(0), "1970-01-01");
+        assert_eq!(w3c_date(1_709_208_000_000_000_000), "2024-02-29");
+    }
+
+    #[test]
+    fn test_sitemap() {
+        let urls = vec![
+            ("post/7".to_string(), Some(1_709_208_000_000_000_000)),
+            ("journal/a&b".to_string(), None),
+        ];
+        assert_eq!(
+            String::from_utf8(sitemap("taggr.link", urls.iter())).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8"?><urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9"><url><loc>https://taggr.link/post/7</loc><lastmod>2024-02-29</lastmod></url><url><loc>https://taggr.link/journal/a&amp;b</loc></url></urlset>"#
+        );
+    }
+
+    #[test]
+    fn test_item_title() {
+        assert_eq!(item_title("\n# Hello <world>\nbody"), "Hello <world>");
+        assert_eq!(item_title(""), "");
+    }
+}
@@ -1,14 +1,21 @@
 use super::assets;
 use crate::assets::{index_html_headers, INDEX_HTML};
-use crate::post::Post;
+use crate::feed;
+use crate::post::{Extension, Post};
+use crate::proposals::Payload;
 use crate::read;
+use crate::State;
 use crate::{config::CONFIG, metadata::set_metadata};
 use candid::CandidType;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
 
 pub type Headers = Vec<(String, String)>;
 
+// Maximal number of URLs in one sitemap, as defined by the sitemaps protocol.
+const SITEMAP_MAX_URLS: usize = 50_000;
+
 #[derive(CandidType, Deserialize)]
 pub struct HttpRequest {
     url: String,
@@ -68,10 +75,14 @@ fn route(path: &str) -> Option<(Headers, ByteBuf)> {
         let mut parts = path.split('/').skip(1);
         match (parts.next(), parts.next()) {
             (Some("post"), Some(id)) | (Some("thread"), Some(id)) => {
-                if let Some(post) =
-                    Post::get(state, &id.parse::<u64>().expect("couldn't parse post id"))
-                {
-                    return index(
+                // Unknown or deleted posts fall back to the generic index page.
+                let post = id
+                    .parse::<u64>()
+                    .ok()
+                    .and_then(|id| Post::get(state, &id))
+                    .filter(|post| !post.is_deleted());
+                match post {
+                    Some(post) => index(
                         domain,
                         &format!(
                             "{}/{}",
@@ -92,9 +103,10 @@ fn route(path: &str) -> Option<(Headers, ByteBuf)> {
                         ),
                         &filter(&post.body),
                         "article",
-                    );
+                        post_image(post).as_deref(),
+                    ),
+                    None => assets::asset("/"),
                 }
-                None
             }
             (Some("journal"), Some(handle)) => {
                 let user = state.user(handle)?;
@@ -104,6 +116,7 @@ fn route(path: &str) -> Option<(Headers, ByteBuf)> {
                     &format!("@{}'s journal", user.name),
                     &filter(&user.about),
                     "website",
+                    None,
                 )
             }
             (Some("user"), Some(handle)) => {
@@ -114,6 +127,7 @@ fn route(path: &str) -> Option<(Headers, ByteBuf)> {
                     &format!("User @{}", user.name),
                     &filter(&user.about),
                     "profile",
+                    None,
                 )
             }
             (Some("realm"), Some(arg)) => {
@@ -125,15 +139,72 @@ fn route(path: &str) -> Option<(Headers, ByteBuf)> {
                     &format!("Realm {}", id),
                     &filter(&realm.description),
                     "website",
+                    None,
                 )
             }
+            (Some("feed"), Some(kind @ "user")) | (Some("feed"), Some(kind @ "realm"))
+                if path.ends_with(".xml") =>
+            {
+                let arg = parts.next()?.strip_suffix(".xml")?;
+                let posts = if kind == "user" {
+                    let user = state.user(arg)?;
+                    (
+                        format!("journal/{}", user.name),
+                        format!("@{}'s journal", user.name),
+                        user.about.clone(),
+                        user.journal(state),
+                    )
+                } else {
+                    let id = arg.to_uppercase();
+                    let realm = state.realms.get(&id)?;
+                    (
+                        format!("realm/{}", id),
+                        format!("Realm {}", id),
+                        realm.description.clone(),
+                        state.last_posts(Some(id), false),
+                    )
+                };
+                let (path, title, desc, posts) = posts;
+                Some((
+                    xml_headers("application/rss+xml"),
+                    ByteBuf::from(feed::rss(
+                        domain,
+                        &path,
+                        &title,
+                        &desc,
+                        posts.take(CONFIG.feed_page_size),
+                    )),
+                ))
+            }
             (Some("feed"), Some(filter)) => index(
                 domain,
                 &format!("feed/{}", filter),
                 filter,
                 &format!("Latest posts on {}", filter),
                 "website",
+                None,
             ),
+            (Some("sitemap.xml"), None) => {
+                let urls = sitemap_urls(state);
+                let body = if urls.len() > SITEMAP_MAX_URLS {
+                    feed::sitemap_index(
+                        domain,
+                        (urls.len() + SITEMAP_MAX_URLS - 1) / SITEMAP_MAX_URLS,
+                    )
+                } else {
+                    feed::sitemap(domain, urls.iter())
+                };
+                Some((xml_headers("application/xml"), ByteBuf::from(body)))
+            }
+            (Some("sitemap"), Some(page)) if page.ends_with(".xml") => {
+                let page = page.strip_suffix(".xml")?.parse::<usize>().ok()?;
+                let urls = sitemap_urls(state);
+                let chunk = urls.chunks(SITEMAP_MAX_URLS).nth(page)?;
+                Some((
+                    xml_headers("application/xml"),
+                    ByteBuf::from(feed::sitemap(domain, chunk.iter())),
+                ))
+            }
             _ => assets::asset("/"),
         }
     })
@@ -145,9 +216,88 @@ fn index(
     title: &str,
     desc: &str,
     page_type: &str,
+    image: Option<&str>,
 ) -> Option<(Headers, ByteBuf)> {
     Some((
         index_html_headers(),
-        ByteBuf::from(set_metadata(INDEX_HTML, host, path, title, desc, page_type)),
+        ByteBuf::from(set_metadata(
+            INDEX_HTML, host, path, title, desc, page_type, image,
+        )),
+    ))
+}
+
+// Returns the URL of the first picture of the post served by its storage bucket.
+fn post_image(post: &Post) -> Option<String> {
+    let (key, (offset, len)) = post.files.iter().next()?;
+    let bucket_id = key.split('@').nth(1)?;
+    Some(format!(
+        "https://{}.raw.icp0.io/image/{}/{}",
+        bucket_id, offset, len
     ))
 }
+
+fn xml_headers(content_type: &str) -> Headers {
+    vec![
+        (
+            "Content-Type".to_string(),
+            format!("{}; charset=UTF-8", content_type),
+        ),
+        // feed readers and crawlers poll frequently, so let them and the boundary nodes cache
+        // the response
+        (
+            "Cache-Control".to_string(),
+            "public, max-age=900".to_string(),
+        ),
+    ]
+}
+
+// Returns paths of realms, user journals and recent root posts with the timestamps of their
+// last posts. Only the latest root posts fitting into one sitemap are visited, so realms and
+// journals without recent posts are listed without a timestamp. Deleted posts and posts of
+// reward or release proposals are skipped.
+fn sitemap_urls(state: &State) -> Vec<(String, Option<u64>)> {
+    let mut realms = state
+        .realms
+        .keys()
+        .map(|id| (id.clone(), None))
+        .collect::<BTreeMap<_, Option<u64>>>();
+    let mut journals = state
+        .users
+        .values()
+        .filter(|user| user.num_posts > 0)
+        .map(|user| (user.id, None))
+        .collect::<BTreeMap<_, Option<u64>>>();
+    let mut posts = Vec::new();
+    for post in state
+        .last_posts(None, false)
+        .take_while(|post| !post.archived)
+        .take(SITEMAP_MAX_URLS)
+    {
+        if let Some(Extension::Proposal(id)) = &post.extension {
+            if matches!(
+                state.proposals.get(*id as usize).map(|p| &p.payload),
+                Some(Payload::Reward(_)) | Some(Payload::Release(_))
+            ) {
+                continue;
+            }
+        }
+        let timestamp = post.timestamp();
+        // posts are iterated from the newest, so the first one seen is the latest
+        if let Some(last) = post.realm.as_ref().and_then(|id| realms.get_mut(id)) {
+            last.get_or_insert(timestamp);
+        }
+        if let Some(last) = journals.get_mut(&post.user) {
+            last.get_or_insert(timestamp);
+        }
+        posts.push((format!("post/{}", post.id), Some(timestamp)));
+    }
+    realms
+        .into_iter()
+        .map(|(id, timestamp)| (format!("realm/{}", id), timestamp))
+        .chain(journals.into_iter().filter_map(|(user_id, timestamp)| {
+            let user = state.users.get(&user_id)?;
+            Some((format!("journal/{}", user.name), timestamp))
+        }))
+        .chain(posts)
+        .collect()
+}
@@ -5,13 +5,15 @@ use std::{
 
 use env::{
     canisters::get_full_neuron,
-    config::{reaction_karma, CONFIG},
+    config::{Reaction, CONFIG},
     memory,
-    post::{Extension, Post, PostId},
-    proposals::{Release, Reward},
+    post::{ContentWarning, Extension, Post, PostId},
+    proposals::{Release, Reward, Vesting},
     storage::Storage,
     token::account,
-    user::{Draft, User, UserId},
+    user::{
+        ContentWarnings, DoNotDisturb, Draft, NotificationType, User, UserId, UserViewerContext,
+    },
     State, *,
 };
 use ic_cdk::{
@@ -29,6 +31,7 @@ mod assets;
 #[cfg(feature = "dev")]
 mod dev_features;
 mod env;
+mod feed;
 mod http;
 mod metadata;
 
@@ -101,6 +104,17 @@ fn post_upgrade() {
 
 async fn post_upgrade_fixtures() {
     mutate(|state| {
+        // build the search index once
+        if state.search_index.is_empty() {
+            for (id, body) in state
+                .last_posts(None, true)
+                .map(|post| (post.id, post.body.clone()))
+                .collect::<Vec<_>>()
+            {
+                state.search_index.add(id, &body);
+            }
+        }
+
         state.balances.clear();
         let e8s_per_xdr = 33340000;
         for t in &mut state.ledger {
@@ -142,22 +156,44 @@ async fn get_neuron_info() -> Result<String, String> {
 
 #[export_name = "canister_update vote_on_poll"]
 fn vote_on_poll() {
-    let (post_id, vote): (PostId, u16) = parse(&arg_data_raw());
+    let Some((post_id, vote)) = parse_args::<(PostId, u16)>() else {
+        return;
+    };
     mutate(|state| reply(state.vote_on_poll(caller(), api::time(), post_id, vote)));
 }
 
 #[export_name = "canister_update report"]
 fn report() {
     mutate(|state| {
-        let (domain, id, reason): (String, u64, String) = parse(&arg_data_raw());
+        let Some((domain, id, reason)) = parse_args::<(String, u64, String)>() else {
+            return;
+        };
         reply(state.report(caller(), domain, id, reason))
     });
 }
 
+#[export_name = "canister_update appeal_report"]
+fn appeal_report() {
+    let Some((domain, id, appeal)) = parse_args::<(String, u64, String)>() else {
+        return;
+    };
+    mutate(|state| reply(state.appeal_report(caller(), domain, id, appeal, time())));
+}
+
+#[export_name = "canister_update add_content_warning"]
+fn add_content_warning() {
+    let Some((post_id, warning)) = parse_args::<(PostId, ContentWarning)>() else {
+        return;
+    };
+    mutate(|state| reply(state.add_content_warning(caller(), post_id, warning)));
+}
+
 #[export_name = "canister_update vote_on_report"]
 fn vote_on_report() {
     mutate(|state| {
-        let (domain, id, vote): (String, u64, bool) = parse(&arg_data_raw());
+        let Some((domain, id, vote)) = parse_args::<(String, u64, bool)>() else {
+            return;
+        };
         reply(state.vote_on_report(caller(), domain, id, vote))
     });
 }
@@ -165,23 +201,134 @@ fn vote_on_report() {
 #[export_name = "canister_update clear_notifications"]
 fn clear_notifications() {
     mutate(|state| {
-        let ids: Vec<String> = parse(&arg_data_raw());
+        let Some(ids) = parse_args::<Vec<String>>() else {
+            return;
+        };
         state.clear_notifications(caller(), ids);
         reply_raw(&[]);
     })
 }
 
+#[export_name = "canister_update clear_notifications_of_type"]
+fn clear_notifications_of_type() {
+    mutate(|state| {
+        let Some(kind) = parse_args::<NotificationType>() else {
+            return;
+        };
+        state.clear_notifications_of_type(caller(), kind);
+        reply_raw(&[]);
+    })
+}
+
+#[export_name = "canister_update set_digest_mode"]
+fn set_digest_mode() {
+    let Some(enabled) = parse_args::<bool>() else {
+        return;
+    };
+    mutate(|state| {
+        if let Some(user) = state.principal_to_user_mut(caller()) {
+            user.digest_mode = enabled;
+        }
+    });
+    reply_raw(&[]);
+}
+
+#[export_name = "canister_update set_auto_follow"]
+fn set_auto_follow() {
+    let Some(enabled) = parse_args::<bool>() else {
+        return;
+    };
+    mutate(|state| {
+        if let Some(user) = state.principal_to_user_mut(caller()) {
+            user.auto_follow_disabled = !enabled;
+        }
+    });
+    reply_raw(&[]);
+}
+
+#[export_name = "canister_update set_do_not_disturb"]
+fn set_do_not_disturb() {
+    let Some(do_not_disturb) = parse_args::<DoNotDisturb>() else {
+        return;
+    };
+    mutate(|state| reply(state.set_do_not_disturb(caller(), do_not_disturb, api::time())));
+}
+
+#[export_name = "canister_update set_content_warnings"]
+fn set_content_warnings() {
+    let Some(preference) = parse_args::<ContentWarnings>() else {
+        return;
+    };
+    mutate(|state| reply(state.set_content_warnings(caller(), preference)));
+}
+
+#[export_name = "canister_update set_impressions_enabled"]
+fn set_impressions_enabled() {
+    let Some(enabled) = parse_args::<bool>() else {
+        return;
+    };
+    mutate(|state| reply(state.set_impressions_enabled(caller(), enabled)));
+}
+
+#[export_name = "canister_update record_impressions"]
+fn record_impressions() {
+    let Some(ids) = parse_args::<Vec<PostId>>() else {
+        return;
+    };
+    mutate(|state| reply(state.record_impressions(caller(), ids, time())));
+}
+
+#[export_name = "canister_update set_languages"]
+fn set_languages() {
+    let Some(languages) = parse_args::<Vec<String>>() else {
+        return;
+    };
+    mutate(|state| reply(state.set_languages(caller(), languages)));
+}
+
+#[export_name = "canister_update change_username"]
+fn change_username() {
+    let Some(name) = parse_args::<String>() else {
+        return;
+    };
+    mutate(|state| reply(state.change_username(caller(), name, time())));
+}
+
+#[export_name = "canister_update set_webhook"]
+fn set_webhook() {
+    let Some(url) = parse_args::<Option<String>>() else {
+        return;
+    };
+    mutate(|state| reply(state.set_webhook(caller(), url)));
+}
+
 #[export_name = "canister_update tip"]
 fn tip() {
     spawn(async {
-        let (post_id, amount): (PostId, String) = parse(&arg_data_raw());
-        reply(State::tip(caller(), post_id, amount).await);
+        let Some((post_id, amount, message)) = parse_args::<(PostId, String, String)>() else {
+            return;
+        };
+        reply(State::tip(caller(), post_id, amount, optional(message)).await);
+    })
+}
+
+#[export_name = "canister_update tip_split"]
+fn tip_split() {
+    spawn(async {
+        let Some((post_id, amount, shares, message)) =
+            parse_args::<(PostId, String, Vec<(UserId, String)>, String)>()
+        else {
+            return;
+        };
+        reply(State::tip_split(caller(), post_id, amount, shares, optional(message)).await);
     })
 }
 
 #[export_name = "canister_update react"]
 fn react() {
-    let (post_id, reaction): (PostId, u16) = parse(&arg_data_raw());
+    let Some((post_id, reaction)) = parse_args::<(PostId, u16)>() else {
+        return;
+    };
     mutate(|state| reply(state.react(caller(), post_id, reaction, api::time())));
 }
 
@@ -198,15 +345,45 @@ fn update_last_activity() {
 #[export_name = "canister_update change_principal"]
 fn change_principal() {
     spawn(async {
-        let principal: String = parse(&arg_data_raw());
+        let Some(principal) = parse_args::<String>() else {
+            return;
+        };
         reply(State::change_principal(caller(), principal).await);
     });
 }
 
+#[export_name = "canister_update add_device"]
+fn add_device() {
+    let Some((device, label)) = parse_args::<(String, String)>() else {
+        return;
+    };
+    mutate(|state| reply(state.add_device(caller(), device, label, time())));
+}
+
+#[export_name = "canister_update confirm_device"]
+fn confirm_device() {
+    let Some(user_id) = parse_args::<UserId>() else {
+        return;
+    };
+    mutate(|state| reply(state.confirm_device(caller(), user_id)));
+}
+
+#[export_name = "canister_update remove_device"]
+fn remove_device() {
+    let Some(device) = parse_args::<String>() else {
+        return;
+    };
+    mutate(|state| reply(state.remove_device(caller(), device)));
+}
+
 #[export_name = "canister_update update_user"]
 fn update_user() {
     mutate(|state| {
-        let (about, principals, settings): (String, Vec<String>, String) = parse(&arg_data_raw());
+        let Some((about, principals, settings)) =
+            parse_args::<(String, Vec<String>, String)>()
+        else {
+            return;
+        };
         let mut response: Result<(), String> = Ok(());
         if !User::valid_info(&about, &settings) {
             response = Err("invalid user info".to_string());
@@ -236,7 +413,9 @@ fn update_user() {
 
 #[export_name = "canister_update create_user"]
 fn create_user() {
-    let (name, invite): (String, Option<String>) = parse(&arg_data_raw());
+    let Some((name, invite)) = parse_args::<(String, Option<String>)>() else {
+        return;
+    };
     spawn(async {
         reply(State::create_user(caller(), name, invite).await);
     });
@@ -244,10 +423,16 @@ fn create_user() {
 
 #[export_name = "canister_update transfer_cycles"]
 fn transfer_cycles() {
-    let (recipient, amount): (UserId, Cycles) = parse(&arg_data_raw());
+    let Some((recipient, amount)) = parse_args::<(UserId, Cycles)>() else {
+        return;
+    };
     reply(mutate(|state| {
-        let sender = state.principal_to_user(caller()).expect("no user found");
-        let recipient_name = &state.users.get(&recipient).expect("no user found").name;
+        let sender = state.principal_to_user(caller()).ok_or("no user found")?;
+        let recipient_name = &state
+            .users
+            .get(&recipient)
+            .ok_or("no recipient found")?
+            .name;
         state.cycle_transfer(
             sender.id,
             recipient,
@@ -269,7 +454,9 @@ fn transfer_cycles() {
 #[export_name = "canister_update transfer_icp"]
 fn transfer_icp() {
     spawn(async {
-        let (recipient, amount): (String, String) = parse(&arg_data_raw());
+        let Some((recipient, amount)) = parse_args::<(String, String)>() else {
+            return;
+        };
         reply(State::icp_transfer(caller(), recipient, &amount).await)
     });
 }
@@ -277,7 +464,9 @@ fn transfer_icp() {
 #[export_name = "canister_update transfer_tokens"]
 fn transfer_tokens() {
     mutate(|state| {
-        let (recipient, amount): (String, String) = parse(&arg_data_raw());
+        let Some((recipient, amount)) = parse_args::<(String, String)>() else {
+            return;
+        };
         reply(token::transfer_from_ui(state, recipient, amount))
     });
 }
@@ -285,15 +474,39 @@ fn transfer_tokens() {
 #[export_name = "canister_update mint_cycles"]
 fn mint_cycles() {
     spawn(async {
-        let kilo_cycles: u64 = parse(&arg_data_raw());
+        let Some(kilo_cycles) = parse_args::<u64>() else {
+            return;
+        };
         reply(State::mint_cycles(caller(), kilo_cycles).await)
     });
 }
 
+#[export_name = "canister_update delete_account"]
+fn delete_account() {
+    let Some(confirmation) = parse_args::<String>() else {
+        return;
+    };
+    mutate(|state| reply(state.delete_account(caller(), confirmation, time())));
+}
+
 #[export_name = "canister_update create_invite"]
 fn create_invite() {
-    let cycles: Cycles = parse(&arg_data_raw());
-    mutate(|state| reply(state.create_invite(caller(), cycles)));
+    let Some((cycles, max_uses, expires_at, realm, follow)) =
+        parse_args::<(Cycles, Option<u32>, Option<u64>, Option<String>, Vec<UserId>)>()
+    else {
+        return;
+    };
+    mutate(|state| {
+        reply(state.create_invite(
+            caller(),
+            cycles,
+            max_uses,
+            expires_at,
+            realm,
+            follow,
+            time(),
+        ))
+    });
 }
 
 #[update]
@@ -315,7 +528,11 @@ fn propose_release(description: String, commit: String, binary: ByteBuf) -> Resu
 
 #[export_name = "canister_update propose_reward"]
 fn propose_reward() {
-    let (description, receiver): (String, String) = parse(&arg_data_raw());
+    let Some((description, receiver, vesting)) =
+        parse_args::<(String, String, Option<Vesting>)>()
+    else {
+        return;
+    };
     mutate(|state| {
         reply(proposals::propose(
             state,
@@ -325,6 +542,7 @@ fn propose_reward() {
                 receiver,
                 votes: Default::default(),
                 minted: 0,
+                vesting,
             }),
             time(),
         ))
@@ -333,7 +551,9 @@ fn propose_reward() {
 
 #[export_name = "canister_update propose_funding"]
 fn propose_funding() {
-    let (description, receiver, tokens): (String, String, u64) = parse(&arg_data_raw());
+    let Some((description, receiver, tokens)) = parse_args::<(String, String, u64)>() else {
+        return;
+    };
     mutate(|state| {
         reply(proposals::propose(
             state,
@@ -345,9 +565,155 @@ fn propose_funding() {
     })
 }
 
+#[export_name = "canister_update propose_icp_transfer"]
+fn propose_icp_transfer() {
+    let Some((description, recipient, e8s)) = parse_args::<(String, String, u64)>() else {
+        return;
+    };
+    mutate(|state| {
+        reply(proposals::propose(
+            state,
+            caller(),
+            description,
+            proposals::Payload::TransferICP(recipient, e8s),
+            time(),
+        ))
+    })
+}
+
+#[export_name = "canister_update propose_ban"]
+fn propose_ban() {
+    let Some((description, user_id, days)) = parse_args::<(String, UserId, u64)>() else {
+        return;
+    };
+    mutate(|state| {
+        reply(proposals::propose(
+            state,
+            caller(),
+            description,
+            proposals::Payload::Ban(user_id, days),
+            time(),
+        ))
+    })
+}
+
+#[export_name = "canister_update propose_reactions"]
+fn propose_reactions() {
+    let Some((description, reactions)) = parse_args::<(String, Vec<Reaction>)>() else {
+        return;
+    };
+    mutate(|state| {
+        reply(proposals::propose(
+            state,
+            caller(),
+            description,
+            proposals::Payload::Reactions(reactions),
+            time(),
+        ))
+    })
+}
+
+#[export_name = "canister_update propose_new_user_cooldown"]
+fn propose_new_user_cooldown() {
+    let Some((description, hours)) = parse_args::<(String, u64)>() else {
+        return;
+    };
+    mutate(|state| {
+        reply(proposals::propose(
+            state,
+            caller(),
+            description,
+            proposals::Payload::NewUserCooldown(hours),
+            time(),
+        ))
+    })
+}
+
+#[export_name = "canister_update propose_voting_reward"]
+fn propose_voting_reward() {
+    let Some((description, cycles)) = parse_args::<(String, Cycles)>() else {
+        return;
+    };
+    mutate(|state| {
+        reply(proposals::propose(
+            state,
+            caller(),
+            description,
+            proposals::Payload::VotingReward(cycles),
+            time(),
+        ))
+    })
+}
+
+#[export_name = "canister_update propose_proposal_deposit"]
+fn propose_proposal_deposit() {
+    let Some((description, cycles)) = parse_args::<(String, Cycles)>() else {
+        return;
+    };
+    mutate(|state| {
+        reply(proposals::propose(
+            state,
+            caller(),
+            description,
+            proposals::Payload::ProposalDeposit(cycles),
+            time(),
+        ))
+    })
+}
+
+#[export_name = "canister_update propose_voting_power_decay"]
+fn propose_voting_power_decay() {
+    let Some((description, start, horizon)) = parse_args::<(String, u64, u64)>() else {
+        return;
+    };
+    mutate(|state| {
+        reply(proposals::propose(
+            state,
+            caller(),
+            description,
+            proposals::Payload::VotingPowerDecay(start, horizon),
+            time(),
+        ))
+    })
+}
+
+#[export_name = "canister_update propose_reserved_realm_names"]
+fn propose_reserved_realm_names() {
+    let Some((description, names)) = parse_args::<(String, Vec<String>)>() else {
+        return;
+    };
+    mutate(|state| {
+        reply(proposals::propose(
+            state,
+            caller(),
+            description,
+            proposals::Payload::ReservedRealmNames(names),
+            time(),
+        ))
+    })
+}
+
+#[export_name = "canister_update propose_reserved_handles"]
+fn propose_reserved_handles() {
+    let Some((description, handles)) = parse_args::<(String, Vec<String>)>() else {
+        return;
+    };
+    mutate(|state| {
+        reply(proposals::propose(
+            state,
+            caller(),
+            description,
+            proposals::Payload::ReservedHandles(handles),
+            time(),
+        ))
+    })
+}
+
 #[export_name = "canister_update vote_on_proposal"]
 fn vote_on_proposal() {
-    let (proposal_id, vote, data): (u32, bool, String) = parse(&arg_data_raw());
+    let Some((proposal_id, vote, data)) = parse_args::<(u32, bool, String)>() else {
+        return;
+    };
     mutate(|state| {
         reply(proposals::vote_on_proposal(
             state,
@@ -360,35 +726,74 @@ fn vote_on_proposal() {
     })
 }
 
+#[export_name = "canister_update resubmit_proposal"]
+fn resubmit_proposal() {
+    let Some(proposal_id) = parse_args::<u32>() else {
+        return;
+    };
+    mutate(|state| {
+        reply(proposals::resubmit_proposal(
+            state,
+            caller(),
+            proposal_id,
+            time(),
+        ))
+    })
+}
+
 #[export_name = "canister_update cancel_proposal"]
 fn cancel_proposal() {
-    let proposal_id: u32 = parse(&arg_data_raw());
+    let Some(proposal_id) = parse_args::<u32>() else {
+        return;
+    };
     mutate(|state| proposals::cancel_proposal(state, caller(), proposal_id));
     reply(());
 }
 
 #[update]
 /// This method adds a post atomically (from the user's point of view).
+#[allow(clippy::too_many_arguments)]
 async fn add_post(
     body: String,
     blobs: Vec<(String, Blob)>,
     parent: Option<PostId>,
     realm: Option<String>,
     extension: Option<Blob>,
+    idempotency_key: Option<String>,
+    language: Option<String>,
+    content_warning: Option<ContentWarning>,
 ) -> Result<PostId, String> {
-    let post_id = mutate(|state| {
-        let extension: Option<Extension> = extension.map(|bytes| parse(&bytes));
-        Post::create(
-            state,
-            body,
-            &blobs,
-            caller(),
-            api::time(),
-            parent,
-            realm,
-            extension,
-        )
+    let (post_id, created) = mutate(|state| {
+        let now = api::time();
+        if let Some(key) = &idempotency_key {
+            if let Some(post_id) = state.post_by_idempotency_key(caller(), key, now)? {
+                return Ok((post_id, false));
+            }
+        }
+        let extension: Option<Extension> = extension
+            .map(|bytes| serde_json::from_slice(&bytes))
+            .transpose()
+            .map_err(|err| format!("couldn't parse the extension: {}", err))?;
+        if let Some(language) = &language {
+            post::validate_language(language)?;
+        }
+        let post_id = Post::create(state, body, &blobs, caller(), now, parent, realm, extension)?;
+        if language.is_some() || content_warning.is_some() {
+            Post::mutate(state, &post_id, |post| {
+                post.language = language;
+                post.content_warning = content_warning;
+                Ok(())
+            })?;
+        }
+        if let Some(key) = idempotency_key {
+            state.remember_idempotency_key(post_id, key, now);
+        }
+        Ok::<_, String>((post_id, true))
     })?;
+    // the blobs of a deduplicated post were stored by the original request
+    if !created {
+        return Ok(post_id);
+    }
     let call_name = format!("blobs_storing_for_{}", post_id);
     canisters::open_call(&call_name);
     let result = Post::save_blobs(post_id, blobs).await;
@@ -442,7 +847,7 @@ async fn commit_post() -> Result<PostId, String> {
             .principal_to_user_mut(caller())
             .map(|user| user.draft.take())
     }) {
-        add_post(body, blobs, None, realm, extension).await
+        add_post(body, blobs, None, realm, extension, None, None, None).await
     } else {
         Err("no post data found".into())
     }
@@ -462,15 +867,35 @@ async fn edit_post(
 #[export_name = "canister_update delete_post"]
 fn delete_post() {
     mutate(|state| {
-        let (post_id, versions): (PostId, Vec<String>) = parse(&arg_data_raw());
-        reply(state.delete_post(caller(), post_id, versions))
+        let Some((post_id, versions)) = parse_args::<(PostId, Vec<String>)>() else {
+            return;
+        };
+        reply(state.delete_post(caller(), post_id, versions, time()))
     });
 }
 
+#[export_name = "canister_update restore_post"]
+fn restore_post() {
+    let Some(post_id) = parse_args::<PostId>() else {
+        return;
+    };
+    mutate(|state| reply(state.restore_post(caller(), post_id)));
+}
+
+#[export_name = "canister_update pin_post"]
+fn pin_post() {
+    let Some((post_id, realm)) = parse_args::<(Option<PostId>, String)>() else {
+        return;
+    };
+    mutate(|state| reply(state.pin_post(caller(), post_id, optional(realm))));
+}
+
 #[export_name = "canister_update toggle_bookmark"]
 fn toggle_bookmark() {
     mutate(|state| {
-        let post_id: PostId = parse(&arg_data_raw());
+        let Some(post_id) = parse_args::<PostId>() else {
+            return;
+        };
         if let Some(user) = state.principal_to_user_mut(caller()) {
             reply(user.toggle_bookmark(post_id));
             return;
@@ -481,24 +906,55 @@ fn toggle_bookmark() {
 
 #[export_name = "canister_update toggle_following_post"]
 fn toggle_following_post() {
-    let post_id: PostId = parse(&arg_data_raw());
-    let user_id = read(|state| state.principal_to_user(caller()).expect("no user found").id);
+    let Some(post_id) = parse_args::<PostId>() else {
+        return;
+    };
+    let Some(user_id) = read(|state| state.principal_to_user(caller()).map(|user| user.id)) else {
+        return reply(false);
+    };
     reply(
         mutate(|state| Post::mutate(state, &post_id, |post| Ok(post.toggle_following(user_id))))
             .unwrap_or_default(),
     )
 }
 
+// Mutes or unmutes the thread the post belongs to.
+#[export_name = "canister_update toggle_muting_thread"]
+fn toggle_muting_thread() {
+    let Some(post_id) = parse_args::<PostId>() else {
+        return;
+    };
+    mutate(|state| {
+        let root_id = state.thread(post_id).next();
+        match (root_id, state.principal_to_user_mut(caller())) {
+            (Some(root_id), Some(user)) => reply(user.toggle_muting_thread(root_id)),
+            _ => reply(false),
+        }
+    })
+}
+
 #[export_name = "canister_update toggle_following_user"]
 fn toggle_following_user() {
-    let followee_id: UserId = parse(&arg_data_raw());
+    let Some(followee_id) = parse_args::<UserId>() else {
+        return;
+    };
     mutate(|state| reply(state.toggle_following_user(caller(), followee_id)))
 }
 
+#[export_name = "canister_update toggle_feed_notifications"]
+fn toggle_feed_notifications() {
+    let Some(tags) = parse_args::<Vec<String>>() else {
+        return;
+    };
+    mutate(|state| reply(state.toggle_feed_notifications(caller(), tags)))
+}
+
 #[export_name = "canister_update toggle_following_feed"]
 fn toggle_following_feed() {
     mutate(|state| {
-        let tags: Vec<String> = parse(&arg_data_raw());
+        let Some(tags) = parse_args::<Vec<String>>() else {
+            return;
+        };
         reply(
             state
                 .principal_to_user_mut(caller())
@@ -508,17 +964,48 @@ fn toggle_following_feed() {
     })
 }
 
+#[export_name = "canister_update set_realm_min_voting_reputation"]
+fn set_realm_min_voting_reputation() {
+    let Some((name, min_voting_reputation)) = parse_args::<(String, u64)>() else {
+        return;
+    };
+    mutate(|state| {
+        reply(state.set_realm_min_voting_reputation(caller(), &name, min_voting_reputation))
+    });
+}
+
+#[export_name = "canister_update set_realm_post_cost"]
+fn set_realm_post_cost() {
+    let Some((name, post_cost)) = parse_args::<(String, Cycles)>() else {
+        return;
+    };
+    mutate(|state| reply(state.set_realm_post_cost(caller(), &name, post_cost)));
+}
+
+#[export_name = "canister_update set_realm_max_members"]
+fn set_realm_max_members() {
+    let Some((name, max_members)) = parse_args::<(String, Option<u64>)>() else {
+        return;
+    };
+    mutate(|state| reply(state.set_realm_max_members(caller(), &name, max_members)));
+}
+
+#[export_name = "canister_update grant_from_realm_treasury"]
+fn grant_from_realm_treasury() {
+    let Some((name, receiver, amount)) = parse_args::<(String, UserId, Cycles)>() else {
+        return;
+    };
+    mutate(|state| reply(state.grant_from_realm_treasury(caller(), &name, receiver, amount)));
+}
+
 #[export_name = "canister_update edit_realm"]
 fn edit_realm() {
     mutate(|state| {
-        let (name, logo, label_color, theme, description, controllers): (
-            String,
-            String,
-            String,
-            String,
-            String,
-            Vec<UserId>,
-        ) = parse(&arg_data_raw());
+        let Some((name, logo, label_color, theme, description, controllers)) =
+            parse_args::<(String, String, String, String, String, Vec<UserId>)>()
+        else {
+            return;
+        };
         reply(state.edit_realm(
             caller(),
             name,
@@ -534,7 +1021,9 @@ fn edit_realm() {
 #[export_name = "canister_update realm_clean_up"]
 fn realm_clean_up() {
     mutate(|state| {
-        let post_id: PostId = parse(&arg_data_raw());
+        let Some(post_id) = parse_args::<PostId>() else {
+            return;
+        };
         reply(state.clean_up_realm(caller(), post_id))
     });
 }
@@ -542,14 +1031,11 @@ fn realm_clean_up() {
 #[export_name = "canister_update create_realm"]
 fn create_realm() {
     mutate(|state| {
-        let (name, logo, label_color, theme, description, controllers): (
-            String,
-            String,
-            String,
-            String,
-            String,
-            Vec<UserId>,
-        ) = parse(&arg_data_raw());
+        let Some((name, logo, label_color, theme, description, controllers)) =
+            parse_args::<(String, String, String, String, String, Vec<UserId>)>()
+        else {
+            return;
+        };
         reply(state.create_realm(
             caller(),
             name,
@@ -565,38 +1051,36 @@ fn create_realm() {
 #[export_name = "canister_update toggle_realm_membership"]
 fn toggle_realm_membership() {
     mutate(|state| {
-        let name: String = parse(&arg_data_raw());
+        let Some(name) = parse_args::<String>() else {
+            return;
+        };
         reply(state.toggle_realm_membership(caller(), name))
     })
 }
 
+#[export_name = "canister_update toggle_following_realm"]
+fn toggle_following_realm() {
+    let Some(name) = parse_args::<String>() else {
+        return;
+    };
+    mutate(|state| reply(state.toggle_following_realm(caller(), name)))
+}
+
 #[update]
-async fn set_emergency_release(binary: ByteBuf) {
-    mutate(|state| {
-        if binary.is_empty()
-            || !state
-                .principal_to_user(caller())
-                .map(|user| user.stalwart)
-                .unwrap_or_default()
-        {
-            return;
-        }
-        state.emergency_binary = binary.to_vec();
-        state.emergency_votes.clear();
-    });
+fn set_emergency_release(binary: ByteBuf, replaced_hash: Option<String>) -> Result<(), String> {
+    mutate(|state| state.set_emergency_release(caller(), binary.to_vec(), replaced_hash))
 }
 
 #[export_name = "canister_update confirm_emergency_release"]
 fn confirm_emergency_release() {
     mutate(|state| {
         let principal = caller();
-        if let Some(balance) = state.balances.get(&account(principal)) {
-            let hash: String = parse(&arg_data_raw());
-            use sha2::{Digest, Sha256};
-            let mut hasher = Sha256::new();
-            hasher.update(&state.emergency_binary);
-            if hash == format!("{:x}", hasher.finalize()) {
-                state.emergency_votes.insert(principal, *balance);
+        if let Some(balance) = state.balances.get(&account(principal)).copied() {
+            let Some(hash) = parse_args::<String>() else {
+                return;
+            };
+            if hash == state.emergency_binary_hash() {
+                state.emergency_votes.insert(principal, balance);
             }
         }
         reply_raw(&[]);
@@ -615,8 +1099,18 @@ fn force_emergency_upgrade() {
 
 #[export_name = "canister_query check_invite"]
 fn check_invite() {
-    let code: String = parse(&arg_data_raw());
-    read(|state| reply(state.invites.contains_key(&code)))
+    let Some(code) = parse_args::<String>() else {
+        return;
+    };
+    read(|state| {
+        reply(
+            state
+                .invites
+                .get(&code)
+                .map(|invite| invite.validate(time()).is_ok())
+                .unwrap_or_default(),
+        )
+    })
 }
 
 #[export_name = "canister_query balances"]
@@ -647,13 +1141,20 @@ fn balances() {
 
 #[export_name = "canister_query transaction"]
 fn transaction() {
-    let id: usize = parse(&arg_data_raw());
+    let Some(id) = parse_args::<usize>() else {
+        return;
+    };
     read(|state| reply(state.ledger.get(id).ok_or("not found")));
 }
 
 #[export_name = "canister_query transactions"]
 fn transactions() {
-    let (page, search_term): (usize, String) = parse(&arg_data_raw());
+    let Some((page, search_term, page_size)) =
+        parse_args_with_optional_last::<(usize, String, Option<usize>)>()
+    else {
+        return;
+    };
+    let page_size = feed_page_size(page_size);
     read(|state| {
         let iter = state.ledger.iter().enumerate();
         let iter: Box<dyn DoubleEndedIterator<Item = _>> = if search_term.is_empty() {
@@ -665,8 +1166,8 @@ fn transactions() {
         };
         reply(
             iter.rev()
-                .skip(page * CONFIG.feed_page_size)
-                .take(CONFIG.feed_page_size)
+                .skip(page * page_size)
+                .take(page_size)
                 .collect::<Vec<(usize, _)>>(),
         );
     });
@@ -675,21 +1176,55 @@ fn transactions() {
 #[export_name = "canister_query proposal"]
 fn proposal() {
     read(|state| {
-        let id: u32 = parse(&arg_data_raw());
+        let Some(id) = parse_args::<u32>() else {
+            return;
+        };
         reply(
             state
                 .proposals
                 .iter()
                 .find(|proposal| proposal.id == id)
+                .cloned()
+                .map(|mut proposal| {
+                    proposal.voting_rewards_total = Some(proposal.voting_rewards_spent());
+                    proposal.refundable = Some(proposal.refundable(time()));
+                    proposal
+                })
                 .ok_or("no proposal found"),
         )
     })
 }
 
+#[export_name = "canister_query release_candidate"]
+fn release_candidate() {
+    let Some(id) = parse_args::<u32>() else {
+        return;
+    };
+    read(|state| reply(proposals::release_candidate(state, id)));
+}
+
+#[export_name = "canister_query preview_reward_outcome"]
+fn preview_reward_outcome() {
+    let Some(id) = parse_args::<u32>() else {
+        return;
+    };
+    read(|state| reply(proposals::preview_reward_outcome(state, id)));
+}
+
+#[export_name = "canister_query proposal_events"]
+fn proposal_events() {
+    let Some(since) = parse_args::<u64>() else {
+        return;
+    };
+    read(|state| reply(proposals::status_changes(state, since)));
+}
+
 #[export_name = "canister_query proposals"]
 fn proposals() {
     let page_size = 10;
-    let page: usize = parse(&arg_data_raw());
+    let Some(page) = parse_args::<usize>() else {
+        return;
+    };
     read(|state| {
         reply(
             state
@@ -733,15 +1268,27 @@ fn realms_data() {
 
 #[export_name = "canister_query realm"]
 fn realm() {
-    let name: String = parse(&arg_data_raw());
+    let Some(name) = parse_args::<String>() else {
+        return;
+    };
     read(|state| reply(state.realms.get(&name).ok_or("no realm found")));
 }
 
+#[export_name = "canister_query realm_stats"]
+fn realm_stats() {
+    let Some(name) = parse_args::<String>() else {
+        return;
+    };
+    read(|state| reply(state.realm_stats(&name)));
+}
+
 #[export_name = "canister_query realms"]
 fn realms() {
     read(|state| {
         let page_size = 8;
-        let page: usize = parse(&arg_data_raw());
+        let Some(page) = parse_args::<usize>() else {
+            return;
+        };
         reply(
             sorted_realms(state)
                 .iter()
@@ -754,38 +1301,58 @@ fn realms() {
 
 #[export_name = "canister_query user_posts"]
 fn user_posts() {
-    let (handle, page): (String, usize) = parse(&arg_data_raw());
+    let Some((handle, page, page_size)) =
+        parse_args_with_optional_last::<(String, usize, Option<usize>)>()
+    else {
+        return;
+    };
+    let page_size = feed_page_size(page_size);
     read(|state| {
-        resolve_handle(Some(handle)).map(|user| {
-            reply(
-                user.posts(state)
-                    .skip(CONFIG.feed_page_size * page)
-                    .take(CONFIG.feed_page_size)
-                    .collect::<Vec<_>>(),
-            )
-        })
+        reply(
+            resolve_handle(Some(handle))
+                .map(|user| {
+                    with_engagement(
+                        state,
+                        state
+                            .with_pinned(user.pinned, user.posts(state))
+                            .skip(page_size * page)
+                            .take(page_size)
+                            .cloned(),
+                    )
+                })
+                .unwrap_or_default(),
+        )
     });
 }
 
 #[export_name = "canister_query rewarded_posts"]
 fn rewarded_posts() {
-    let (handle, page): (String, usize) = parse(&arg_data_raw());
+    let Some((handle, page)) = parse_args::<(String, usize)>() else {
+        return;
+    };
     read(|state| {
-        resolve_handle(Some(handle)).map(|user| {
-            reply(
-                user.posts(state)
-                    .filter(|post| !post.reactions.is_empty())
-                    .skip(CONFIG.feed_page_size * page)
-                    .take(CONFIG.feed_page_size)
-                    .collect::<Vec<_>>(),
-            )
-        })
+        reply(
+            resolve_handle(Some(handle))
+                .map(|user| {
+                    with_engagement(
+                        state,
+                        user.posts(state)
+                            .filter(|post| !post.reactions.is_empty())
+                            .skip(CONFIG.feed_page_size * page)
+                            .take(CONFIG.feed_page_size)
+                            .cloned(),
+                    )
+                })
+                .unwrap_or_default(),
+        )
     });
 }
 
 #[export_name = "canister_query user_tags"]
 fn user_tags() {
-    let (handle, page): (String, usize) = parse(&arg_data_raw());
+    let Some((handle, page)) = parse_args::<(String, usize)>() else {
+        return;
+    };
     let tag = format!("@{}", handle);
     read(|state| {
         reply(
@@ -801,7 +1368,9 @@ fn user_tags() {
 
 #[export_name = "canister_query user"]
 fn user() {
-    let input: Vec<String> = parse(&arg_data_raw());
+    let Some(input) = parse_args::<Vec<String>>() else {
+        return;
+    };
     let own_profile_fetch = input.is_empty();
     reply(resolve_handle(input.into_iter().next()).map(|mut user| {
         read(|state| {
@@ -810,19 +1379,45 @@ fn user() {
                 .get(&token::account(user.principal))
                 .copied()
                 .unwrap_or_default();
+            let unvested_tokens = state
+                .vesting_grants
+                .iter()
+                .filter(|grant| grant.receiver_id(state) == Some(user.id))
+                .map(|grant| grant.unvested())
+                .sum::<u64>();
+            user.unvested_tokens = (unvested_tokens > 0).then_some(unvested_tokens);
+            // the karma history is served by a separate query
+            user.karma_history.clear();
+            user.cycles_history.clear();
+            user.controlled_realms = state.controlled_realms(user.id);
             if own_profile_fetch {
                 user.accounting.clear();
+                user.devices = user.devices();
+                user.realm_quota = Some(state.realm_quota(user.id));
             } else {
-                let karma = reaction_karma();
+                user.devices.clear();
+                user.pending_devices.clear();
+                user.viewer = state
+                    .principal_to_user(caller())
+                    .map(|viewer| UserViewerContext {
+                        following: viewer.followees.contains(&user.id),
+                        followed_by: user.followees.contains(&viewer.id),
+                    });
+                let catalog = state.reactions();
                 user.bookmarks.clear();
                 user.settings.clear();
                 user.inbox.clear();
+                user.webhook = None;
+                user.outbox.clear();
                 user.karma_from_last_posts = user
                     .posts(state)
                     .take(CONFIG.feed_page_size * 3)
                     .flat_map(|post| post.reactions.iter())
                     .flat_map(|(r_id, users)| {
-                        let cost = karma.get(r_id).copied().unwrap_or_default();
+                        let cost = catalog
+                            .get(r_id)
+                            .map(|reaction| reaction.karma)
+                            .unwrap_or_default();
                         users
                             .iter()
                             .filter(|user_id| {
@@ -845,124 +1440,460 @@ fn user() {
     }));
 }
 
+#[export_name = "canister_query followers"]
+fn followers() {
+    let Some((handle, page)) = parse_args::<(String, usize)>() else {
+        return;
+    };
+    read(|state| reply(state.follow_list(&handle, true, page)));
+}
+
+#[export_name = "canister_query following"]
+fn following() {
+    let Some((handle, page)) = parse_args::<(String, usize)>() else {
+        return;
+    };
+    read(|state| reply(state.follow_list(&handle, false, page)));
+}
+
+// Returns the effective voting power of all token holders.
+#[export_name = "canister_query voting_powers"]
+fn voting_powers() {
+    read(|state| reply(state.voting_powers(time())));
+}
+
 #[export_name = "canister_query invites"]
 fn invites() {
-    read(|state| reply(state.invites(caller())));
+    read(|state| reply(state.invites(caller(), time())));
+}
+
+// Returns the minimal funding of invites and the number of invite uses the caller can still create.
+#[export_name = "canister_query invite_quota"]
+fn invite_quota() {
+    read(|state| {
+        reply((
+            CONFIG.min_cycles_for_inviting,
+            state
+                .principal_to_user(caller())
+                .and_then(|user| state.invite_quota(user.id, time())),
+        ))
+    });
 }
 
 #[export_name = "canister_query posts"]
 fn posts() {
-    let ids: Vec<PostId> = parse(&arg_data_raw());
+    let Some(ids) = parse_args::<Vec<PostId>>() else {
+        return;
+    };
     read(|state| {
-        reply(
+        reply(with_engagement(
+            state,
             ids.into_iter()
                 .filter_map(|id| Post::get(state, &id))
-                .collect::<Vec<&Post>>(),
-        );
+                .cloned(),
+        ));
     })
 }
 
+#[export_name = "canister_query post_history"]
+fn post_history() {
+    let Some(id) = parse_args::<PostId>() else {
+        return;
+    };
+    read(|state| reply(Post::get(state, &id).map(|post| post.history())));
+}
+
+// Returns the caller's bookmarked posts, newest bookmarks first. Deleted posts are returned as
+// well, so that the clients can render them as tombstones.
+#[export_name = "canister_query bookmarks"]
+fn bookmarks() {
+    let Some(page) = parse_args::<usize>() else {
+        return;
+    };
+    read(|state| {
+        reply(
+            state
+                .principal_to_user(caller())
+                .map(|user| {
+                    with_engagement(
+                        state,
+                        user.bookmarks
+                            .iter()
+                            .filter_map(|id| Post::get(state, id))
+                            .skip(page * CONFIG.feed_page_size)
+                            .take(CONFIG.feed_page_size)
+                            .cloned(),
+                    )
+                })
+                .unwrap_or_default(),
+        )
+    });
+}
+
+// Exports all bookmarks of the caller as `(post id, link, author, body, deleted)` tuples.
+#[export_name = "canister_query export_bookmarks"]
+fn export_bookmarks() {
+    read(|state| {
+        let domain = CONFIG.domains.first().expect("no domains");
+        reply(
+            state
+                .principal_to_user(caller())
+                .map(|user| {
+                    user.bookmarks
+                        .iter()
+                        .map(|id| match Post::get(state, id) {
+                            Some(post) if !post.is_deleted() => (
+                                *id,
+                                format!("https://{}/#/post/{}", domain, id),
+                                state
+                                    .users
+                                    .get(&post.user)
+                                    .map(|user| user.name.clone())
+                                    .unwrap_or_default(),
+                                post.body.clone(),
+                                false,
+                            ),
+                            _ => (
+                                *id,
+                                format!("https://{}/#/post/{}", domain, id),
+                                Default::default(),
+                                Default::default(),
+                                true,
+                            ),
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default(),
+        )
+    });
+}
+
 #[export_name = "canister_query journal"]
 fn journal() {
-    let (handle, page): (String, usize) = parse(&arg_data_raw());
+    let Some((handle, page)) = parse_args::<(String, usize)>() else {
+        return;
+    };
     read(|state| {
         reply(
             state
                 .user(&handle)
                 .map(|user| {
-                    user.posts(state)
-                        // we filter out responses, root posts starting with tagging another user
-                        // and deletet posts
-                        .filter(|post| {
-                            !post.is_deleted()
-                                && post.parent.is_none()
-                                && !post.body.starts_with('@')
-                        })
-                        .skip(page * CONFIG.feed_page_size)
-                        .take(CONFIG.feed_page_size)
-                        .cloned()
-                        .collect::<Vec<Post>>()
+                    with_engagement(
+                        state,
+                        state
+                            .with_pinned(user.pinned, user.journal(state))
+                            .skip(page * CONFIG.feed_page_size)
+                            .take(CONFIG.feed_page_size)
+                            .cloned(),
+                    )
                 })
                 .unwrap_or_default(),
         );
     })
 }
 
+#[export_name = "canister_query notifications"]
+fn notifications() {
+    let Some(kind) = parse_args::<Option<NotificationType>>() else {
+        return;
+    };
+    read(|state| {
+        reply(
+            state
+                .principal_to_user(caller())
+                .map(|user| user.notifications(kind))
+                .unwrap_or_default(),
+        )
+    });
+}
+
+// Returns the next page of the caller's posts for the export.
+#[export_name = "canister_query export_posts"]
+fn export_posts() {
+    let Some(cursor) = parse_args::<PostId>() else {
+        return;
+    };
+    read(|state| reply(state.export_posts(caller(), cursor)));
+}
+
+// Returns the next page of post changes in the order they happened for external indexers.
+#[export_name = "canister_query posts_since"]
+fn posts_since() {
+    let Some((cursor, limit)) = parse_args::<(Option<u64>, usize)>() else {
+        return;
+    };
+    read(|state| reply(state.posts_since(cursor, limit)));
+}
+
+// Returns the caller's reacted posts, optionally filtered by the reaction id. Only the caller can
+// see their reactions.
+#[export_name = "canister_query reacted_posts"]
+fn reacted_posts() {
+    let Some((reaction, page)) = parse_args::<(Option<u16>, usize)>() else {
+        return;
+    };
+    read(|state| {
+        reply(with_engagement(
+            state,
+            state
+                .reacted_posts(caller(), reaction, page)
+                .into_iter()
+                .cloned(),
+        ))
+    });
+}
+
 #[export_name = "canister_query hot_posts"]
 fn hot_posts() {
-    let (realm, page): (String, usize) = parse(&arg_data_raw());
-    read(|state| reply(state.hot_posts(optional(realm), page)));
+    let Some((realm, page, page_size)) =
+        parse_args_with_optional_last::<(String, usize, Option<usize>)>()
+    else {
+        return;
+    };
+    let page_size = feed_page_size(page_size);
+    read(|state| {
+        reply(with_engagement(
+            state,
+            state
+                .hot_posts(
+                    optional(realm),
+                    caller_languages(state),
+                    caller_hides_flagged(state),
+                    page,
+                    page_size,
+                )
+                .into_iter(),
+        ))
+    });
+}
+
+#[export_name = "canister_query feed"]
+fn feed() {
+    let Some((mode, realm, page)) = parse_args::<(FeedMode, String, usize)>() else {
+        return;
+    };
+    read(|state| {
+        reply(with_engagement(
+            state,
+            state
+                .feed(
+                    mode,
+                    optional(realm),
+                    caller_languages(state),
+                    caller_hides_flagged(state),
+                    page,
+                    CONFIG.feed_page_size,
+                    api::time(),
+                )
+                .into_iter(),
+        ))
+    });
+}
+
+#[export_name = "canister_query leaderboard"]
+fn leaderboard() {
+    let Some((metric, window, page)) =
+        parse_args::<(leaderboards::Metric, TopWindow, usize)>()
+    else {
+        return;
+    };
+    read(|state| reply(state.leaderboards.page(state, metric, window, page)));
 }
 
 #[export_name = "canister_query last_posts"]
 fn last_posts() {
-    let (realm, page, with_comments): (String, usize, bool) = parse(&arg_data_raw());
+    let Some((realm, page, with_comments, page_size)) =
+        parse_args_with_optional_last::<(String, usize, bool, Option<usize>)>()
+    else {
+        return;
+    };
+    let page_size = feed_page_size(page_size);
     read(|state| {
-        reply(
+        let realm = optional(realm);
+        let pinned = realm
+            .as_deref()
+            .filter(|_| !with_comments)
+            .and_then(|name| state.realm_pinned_post(name));
+        let languages = caller_languages(state);
+        let hide_flagged = caller_hides_flagged(state);
+        let posts = state
+            .last_posts(realm, with_comments)
+            .take_while(|post| !post.archived)
+            .filter(|post| post.in_languages(languages) && !post.hidden_by_warning(hide_flagged));
+        reply(with_engagement(
+            state,
             state
-                .last_posts(optional(realm), with_comments)
-                .skip(page * CONFIG.feed_page_size)
-                .take(CONFIG.feed_page_size)
-                .cloned()
-                .collect::<Vec<Post>>(),
-        )
+                .with_pinned(pinned, Box::new(posts))
+                .skip(page * page_size)
+                .take(page_size)
+                .cloned(),
+        ))
     });
 }
 
 #[export_name = "canister_query posts_by_tags"]
 fn posts_by_tags() {
-    let (realm, tags, users, page): (String, Vec<String>, Vec<UserId>, usize) =
-        parse(&arg_data_raw());
+    let Some((realm, tags, users, page, match_any)) =
+        parse_args_with_optional_last::<(
+            String,
+            Vec<String>,
+            Vec<UserId>,
+            usize,
+            Option<bool>,
+        )>()
+    else {
+        return;
+    };
     read(|state| {
-        reply(
+        reply(with_engagement(
+            state,
             state
-                .posts_by_tags(optional(realm), tags, users, page)
-                .into_iter()
-                .collect::<Vec<Post>>(),
-        )
+                .posts_by_tags(
+                    optional(realm),
+                    tags,
+                    users,
+                    match_any.unwrap_or_default(),
+                    page,
+                )
+                .into_iter(),
+        ))
     });
 }
 
 #[export_name = "canister_query personal_feed"]
 fn personal_feed() {
-    let (id, page, with_comments): (UserId, usize, bool) = parse(&arg_data_raw());
+    let Some((id, page, with_comments, page_size)) =
+        parse_args_with_optional_last::<(UserId, usize, bool, Option<usize>)>()
+    else {
+        return;
+    };
+    let page_size = feed_page_size(page_size);
     read(|state| {
         reply(match state.user(id.to_string().as_str()) {
             None => Default::default(),
-            Some(user) => user
-                .personal_feed(state, page, with_comments)
-                .cloned()
-                .collect::<Vec<Post>>(),
+            Some(user) => with_engagement(
+                state,
+                user.personal_feed(state, page, page_size, with_comments)
+                    .cloned(),
+            ),
         })
     });
 }
 
 #[export_name = "canister_query thread"]
 fn thread() {
-    let id: PostId = parse(&arg_data_raw());
+    let Some(id) = parse_args::<PostId>() else {
+        return;
+    };
     read(|state| {
-        reply(
+        reply(with_engagement(
+            state,
             state
                 .thread(id)
                 .filter_map(|id| Post::get(state, &id))
-                .cloned()
-                .collect::<Vec<Post>>(),
-        )
+                .cloned(),
+        ))
     })
 }
 
+#[export_name = "canister_query tree"]
+fn tree() {
+    let Some((id, sort)) = parse_args::<(PostId, Option<TreeSort>)>() else {
+        return;
+    };
+    read(|state| {
+        reply(with_engagement(
+            state,
+            state
+                .tree(id, sort.unwrap_or_default())
+                .into_iter()
+                .filter_map(|id| Post::get(state, &id))
+                .cloned(),
+        ))
+    })
+}
+
+#[export_name = "canister_query emergency_release_status"]
+fn emergency_release_status() {
+    read(|state| reply(state.emergency_release_status(caller(), api::time())));
+}
+
+#[export_name = "canister_query stalwart_status"]
+fn stalwart_status() {
+    read(|state| reply(state.stalwart_status(caller(), api::time())));
+}
+
+#[export_name = "canister_query reward_preview"]
+fn reward_preview() {
+    read(|state| reply(state.reward_preview(caller())));
+}
+
+#[export_name = "canister_query karma_history"]
+fn karma_history() {
+    read(|state| {
+        reply(
+            state
+                .principal_to_user(caller())
+                .map(|user| user.karma_history.clone())
+                .unwrap_or_default(),
+        )
+    });
+}
+
+#[export_name = "canister_query cycles_history"]
+fn cycles_history() {
+    read(|state| {
+        reply(
+            state
+                .principal_to_user(caller())
+                .map(|user| user.cycles_history.clone())
+                .unwrap_or_default(),
+        )
+    });
+}
+
 #[export_name = "canister_query validate_username"]
 fn validate_username() {
-    let name: String = parse(&arg_data_raw());
+    let Some(name) = parse_args::<String>() else {
+        return;
+    };
     read(|state| reply(state.validate_username(&name)));
 }
 
+#[export_name = "canister_query realm_name_policy"]
+fn realm_name_policy() {
+    read(|state| reply(state.realm_name_policy()));
+}
+
+#[export_name = "canister_query handle_policy"]
+fn handle_policy() {
+    read(|state| reply(state.handle_policy()));
+}
+
+#[export_name = "canister_query search_users"]
+fn search_users() {
+    let Some((prefix, limit)) = parse_args::<(String, usize)>() else {
+        return;
+    };
+    read(|state| reply(state.search_users(&prefix, limit, api::time())));
+}
+
 #[export_name = "canister_query recent_tags"]
 fn recent_tags() {
-    let (realm, n): (String, u64) = parse(&arg_data_raw());
+    let Some((realm, n)) = parse_args::<(String, u64)>() else {
+        return;
+    };
     read(|state| reply(state.recent_tags(optional(realm), n)));
 }
 
+#[export_name = "canister_query realm_tags"]
+fn realm_tags() {
+    let Some((realm, n)) = parse_args::<(String, usize)>() else {
+        return;
+    };
+    read(|state| reply(state.realm_tags(realm, n, api::time())));
+}
+
 #[export_name = "canister_query users"]
 fn users() {
     read(|state| {
@@ -976,14 +1907,51 @@ fn users() {
     });
 }
 
+// Returns the id, the handle and the karma of the given users; unknown ids are skipped.
+#[export_name = "canister_query users_by_ids"]
+fn users_by_ids() {
+    let Some(ids) = parse_args::<Vec<UserId>>() else {
+        return;
+    };
+    read(|state| {
+        reply(
+            ids.into_iter()
+                .take(CONFIG.max_users_per_batch)
+                .filter_map(|id| state.users.get(&id))
+                .map(|user| (user.id, user.name.clone(), user.karma()))
+                .collect::<Vec<(UserId, String, Karma)>>(),
+        )
+    });
+}
+
+#[export_name = "canister_query chores_dry_run"]
+fn chores_dry_run() {
+    let Some(now) = parse_args::<u64>() else {
+        return;
+    };
+    read(|state| reply(state.plan_chores(now)));
+}
+
 #[export_name = "canister_query config"]
 fn config() {
-    reply(CONFIG);
+    // the static reactions are replaced by the catalog currently adopted by governance
+    let mut config = serde_json::json!(CONFIG);
+    config["reactions"] = serde_json::json!(read(|state| state
+        .reactions()
+        .into_values()
+        .collect::<Vec<_>>()));
+    reply(config);
 }
 
 #[export_name = "canister_query logs"]
 fn logs() {
-    read(|state| reply(state.logs()));
+    let Some((level, page, page_size)) =
+        parse_args_with_optional_last::<(Option<String>, usize, Option<usize>)>()
+    else {
+        return;
+    };
+    let page_size = feed_page_size(page_size);
+    read(|state| reply(state.logs(level.as_deref(), page, page_size)));
 }
 
 #[export_name = "canister_query stats"]
@@ -991,10 +1959,56 @@ fn stats() {
     read(|state| reply(state.stats(api::time())));
 }
 
+#[export_name = "canister_query health"]
+fn health() {
+    #[cfg(target_arch = "wasm32")]
+    let heap_size = (core::arch::wasm32::memory_size(0) as u64) << 16;
+    #[cfg(not(target_arch = "wasm32"))]
+    let heap_size = 0;
+    read(|state| reply(state.health(api::canister_balance(), heap_size, api::time())));
+}
+
+#[export_name = "canister_update refresh_stats"]
+fn refresh_stats() {
+    mutate(|state| reply(state.force_stats_refresh(caller(), api::time())));
+}
+
+#[export_name = "canister_query posts_in_range"]
+fn posts_in_range() {
+    let Some((from, to, page)) = parse_args::<(u64, u64, usize)>() else {
+        return;
+    };
+    read(|state| {
+        reply(with_engagement(
+            state,
+            state.posts_in_range(from..=to, page).into_iter(),
+        ))
+    });
+}
+
 #[export_name = "canister_query search"]
 fn search() {
-    let term: String = parse(&arg_data_raw());
-    read(|state| reply(state.search(term)));
+    let Some((term, page, realm, author, from, to)) =
+        parse_args::<(String, usize, String, String, Option<u64>, Option<u64>)>()
+    else {
+        return;
+    };
+    read(|state| {
+        let author = match optional(author) {
+            Some(handle) => match state.user(&handle) {
+                Some(user) => Some(user.id),
+                None => return reply(Vec::<SearchResult>::new()),
+            },
+            None => None,
+        };
+        reply(state.search(
+            term,
+            page,
+            optional(realm),
+            author,
+            from.unwrap_or_default()..=to.unwrap_or(u64::MAX),
+        ))
+    });
 }
 
 #[query]
@@ -1015,14 +2029,102 @@ fn stable_mem_read(page: u64) -> Vec<(u64, Blob)> {
     vec![(page, ByteBuf::from(buf))]
 }
 
-fn parse<'a, T: serde::Deserialize<'a>>(bytes: &'a [u8]) -> T {
-    serde_json::from_slice(bytes).expect("couldn't parse the input")
+/// Returns the length and the SHA256 digest of the last heap snapshot, so that the restore
+/// operator can verify the integrity of a backup.
+#[query]
+fn backup_digest() -> (u64, String) {
+    read(|state| state.backup_digest.clone())
+}
+
+// Parses the JSON arguments of the call. Malformed arguments are replied to with an error
+// instead of trapping, in which case the endpoint has to return without replying.
+fn parse_args<T: serde::de::DeserializeOwned>() -> Option<T> {
+    match serde_json::from_slice(&arg_data_raw()) {
+        Ok(args) => Some(args),
+        Err(err) => {
+            reply(Err::<(), _>(format!("couldn't parse the input: {}", err)));
+            None
+        }
+    }
+}
+
+// Parses the arguments like `parse_args`, but also accepts them without the trailing optional
+// element, so that clients predating it keep working.
+fn parse_args_with_optional_last<T: serde::de::DeserializeOwned>() -> Option<T> {
+    let arg = arg_data_raw();
+    let err = match serde_json::from_slice(&arg) {
+        Ok(args) => return Some(args),
+        Err(err) => err,
+    };
+    if let Ok(serde_json::Value::Array(mut args)) = serde_json::from_slice(&arg) {
+        args.push(serde_json::Value::Null);
+        if let Ok(args) = serde_json::from_value(serde_json::Value::Array(args)) {
+            return Some(args);
+        }
+    }
+    reply(Err::<(), _>(format!("couldn't parse the input: {}", err)));
+    None
 }
 
 fn reply<T: serde::Serialize>(data: T) {
     reply_raw(serde_json::json!(data).to_string().as_bytes());
 }
 
+// Attaches the engagement metrics, the realm colors and, for authenticated callers, the viewer
+// context to the posts returned to the clients.
+fn with_engagement(state: &State, posts: impl Iterator<Item = Post>) -> Vec<Post> {
+    let catalog = state.reactions();
+    let viewer = state.principal_to_user(caller());
+    posts
+        .map(|post| {
+            let realm = post.realm.as_ref().and_then(|name| state.realms.get(name));
+            let mut post = post.with_reaction_summary(&catalog);
+            post.impressions =
+                if viewer.map_or(false, |user| post.impressions_visible_to(user.id, realm)) {
+                    state.impressions(&post)
+                } else {
+                    None
+                };
+            let post = match realm {
+                Some(realm) => post.with_realm_style(realm),
+                None => post,
+            };
+            match viewer {
+                Some(user) => {
+                    let counts_impressions = state.counts_impressions(user.id, &post);
+                    post.with_viewer_context(user, counts_impressions)
+                }
+                None => post,
+            }
+        })
+        .collect()
+}
+
+// Returns the languages accepted by the caller; empty for anonymous callers and users accepting
+// all languages.
+fn caller_languages(state: &State) -> &[String] {
+    state
+        .principal_to_user(caller())
+        .map(|user| user.languages.as_slice())
+        .unwrap_or_default()
+}
+
+// Returns true if the caller doesn't want to see posts with content warnings in feeds.
+fn caller_hides_flagged(state: &State) -> bool {
+    state
+        .principal_to_user(caller())
+        .map(|user| user.content_warnings == ContentWarnings::Hide)
+        .unwrap_or_default()
+}
+
+// Returns the requested page size or the default one if none was requested. Sizes outside of
+// the allowed range are clamped to it.
+fn feed_page_size(requested: Option<usize>) -> usize {
+    requested
+        .map(|size| size.clamp(1, CONFIG.max_feed_page_size))
+        .unwrap_or(CONFIG.feed_page_size)
+}
+
 fn resolve_handle(handle: Option<String>) -> Option<User> {
     read(|state| match handle {
         Some(handle) => state.user(&handle).cloned(),
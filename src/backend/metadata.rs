@@ -10,6 +10,7 @@ pub fn set_index_metadata(body: &[u8]) -> Vec<u8> {
         CONFIG.name,
         "The first FULLY decentralized social network powered by the Internet Computer.",
         "website",
+        None,
     )
 }
 
@@ -31,8 +32,12 @@ pub fn set_metadata(
     title: &str,
     desc: &str,
     page_type: &str,
+    image: Option<&str>,
 ) -> Vec<u8> {
     let desc = truncate(desc, 160).replace('\n', " ");
+    let image = image
+        .map(|url| format!(r#"<meta content="{}" property="og:image" />"#, url))
+        .unwrap_or_default();
 
     let metadata = format!(
         r#"<meta content="https://{0}/#/{1}" property="og:url" />
@@ -43,8 +48,9 @@ pub fn set_metadata(
             <meta content="{3}" property="og:description" />
             <meta content="{2}" property="twitter:title" />
             <meta content="{3}" property="twitter:description" />
-            <meta content="{4}" property="og:type" />"#,
-        host, path, title, desc, page_type
+            <meta content="{4}" property="og:type" />
+            {5}"#,
+        host, path, title, desc, page_type, image
     )
     .replace('\n', "");
 
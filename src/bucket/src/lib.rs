@@ -1,11 +1,14 @@
-use candid::Principal;
+use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::api::{
     self,
-    call::{arg_data_raw, reply_raw},
+    call::{arg_data, arg_data_raw, reply, reply_raw},
     canister_balance,
     stable::*,
 };
 
+// Mirrors `max_blob_size_bytes` of the backend config; larger reads are never served over HTTP.
+const MAX_BLOB_SIZE_BYTES: u64 = 460800;
+
 static mut CONTROLLER: Option<Principal> = None;
 
 fn set_controller() {
@@ -50,6 +53,72 @@ fn read() {
     reply_raw(&buf);
 }
 
+#[derive(CandidType, Deserialize)]
+struct HttpRequest {
+    url: String,
+}
+
+#[derive(CandidType)]
+struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+// Serves blobs under `/image/<offset>/<len>`, so that they can be linked from outside, e.g. in
+// the Open Graph tags of posts.
+#[export_name = "canister_query http_request"]
+fn http_request() {
+    let (req,): (HttpRequest,) = arg_data();
+    let mut parts = req.url.split('/').skip(1);
+    let response = match (parts.next(), parts.next(), parts.next()) {
+        (Some("image"), Some(offset), Some(len)) => {
+            match (offset.parse::<u64>(), len.parse::<u64>()) {
+                (Ok(offset), Ok(len))
+                    if len <= MAX_BLOB_SIZE_BYTES
+                        && offset.saturating_add(len) <= stable64_size() << 16 =>
+                {
+                    let mut body = vec![0; len as usize];
+                    stable64_read(offset, &mut body);
+                    HttpResponse {
+                        status_code: 200,
+                        headers: vec![
+                            ("Content-Type".to_string(), content_type(&body).to_string()),
+                            (
+                                "Cache-Control".to_string(),
+                                "public, max-age=31536000, immutable".to_string(),
+                            ),
+                        ],
+                        body,
+                    }
+                }
+                _ => not_found(),
+            }
+        }
+        _ => not_found(),
+    };
+    reply((response,));
+}
+
+// Detects the image format from the magic bytes of the blob.
+fn content_type(blob: &[u8]) -> &'static str {
+    match blob {
+        [0xff, 0xd8, 0xff, ..] => "image/jpeg",
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [b'G', b'I', b'F', b'8', ..] => "image/gif",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse {
+        status_code: 404,
+        headers: Default::default(),
+        body: b"not found".to_vec(),
+    }
+}
+
 #[export_name = "canister_update update_pointer"]
 fn update_pointer() {
     assert_controller();
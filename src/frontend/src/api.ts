@@ -44,8 +44,9 @@ export const Api = (
         arg2: unknown,
         arg3: unknown,
         arg4: unknown,
+        arg5: unknown,
     ): Promise<T | null> => {
-        let effParams = getEffParams([arg0, arg1, arg2, arg3, arg4]);
+        let effParams = getEffParams([arg0, arg1, arg2, arg3, arg4, arg5]);
         const arg = Buffer.from(JSON.stringify(effParams));
 
         const response = await query_raw(undefined, methodName, arg);
@@ -104,8 +105,12 @@ export const Api = (
         call,
         set_emergency_release: async (
             blob: Uint8Array,
+            replacedHash: string | null,
         ): Promise<JsonValue | null> => {
-            const arg = IDL.encode([IDL.Vec(IDL.Nat8)], [blob]);
+            const arg = IDL.encode(
+                [IDL.Vec(IDL.Nat8), IDL.Opt(IDL.Text)],
+                [blob, replacedHash ? [replacedHash] : []],
+            );
             const response = await call_raw(
                 undefined,
                 "set_emergency_release",
@@ -114,7 +119,10 @@ export const Api = (
             if (!response) {
                 return null;
             }
-            return IDL.decode([], response)[0];
+            return IDL.decode(
+                [IDL.Variant({ Ok: IDL.Null, Err: IDL.Text })],
+                response,
+            )[0];
         },
         propose_release: async (
             text: string,
@@ -140,6 +148,9 @@ export const Api = (
             parent?: number,
             realm?: string,
             extension?: Uint8Array,
+            idempotencyKey: string[] = [],
+            language: string[] = [],
+            contentWarning: string[] = [],
         ): Promise<JsonValue | null> => {
             const arg = IDL.encode(
                 [
@@ -148,8 +159,22 @@ export const Api = (
                     IDL.Opt(IDL.Nat64),
                     IDL.Opt(IDL.Text),
                     IDL.Opt(IDL.Vec(IDL.Nat8)),
+                    IDL.Opt(IDL.Text),
+                    IDL.Opt(IDL.Text),
+                    IDL.Opt(
+                        IDL.Variant({ Nsfw: IDL.Null, Spoiler: IDL.Null }),
+                    ),
+                ],
+                [
+                    text,
+                    blobs,
+                    parent,
+                    realm,
+                    extension,
+                    idempotencyKey,
+                    language,
+                    contentWarning.map((warning) => ({ [warning]: null })),
                 ],
-                [text, blobs, parent, realm, extension],
             );
             const response = await call_raw(undefined, "add_post", arg);
             if (!response) {
@@ -340,6 +340,16 @@ export const Loading = ({ classNameArg, spaced = true }) => {
 export const loadPosts = async (ids) =>
     (await api.query("posts", ids)).map(expandUser);
 
+// Counts impressions of loaded posts in one batched call, keeping queries
+// read-only. Only posts whose impressions by the caller are counted get sent.
+export const recordImpressions = (posts) => {
+    if (!api._user) return;
+    const ids = posts
+        .filter((post) => post.viewer && post.viewer.counts_impressions)
+        .map((post) => post.id);
+    if (ids.length > 0) api.call("record_impressions", ids);
+};
+
 export const expandUser = (post) => {
     const id = post.user;
     const { users, karma } = window.backendCache;
@@ -443,8 +453,8 @@ export const applyPatch = (text, patch) =>
     dmp.patch_apply(dmp.patch_fromText(patch), text);
 
 export const reactionCosts = () =>
-    backendCache.config.reactions.reduce((acc, [id, cost, _]) => {
-        acc[id] = cost;
+    backendCache.config.reactions.reduce((acc, { id, karma }) => {
+        acc[id] = karma;
         return acc;
     }, {});
 
@@ -536,6 +546,7 @@ export const ReportBanner = ({ id, reportArg, domain }) => {
                 . Please confirm the deletion or reject the report.
             </h3>
             <h4>Reason: {report.reason}</h4>
+            {report.appeal && <h4>Appeal: {report.appeal.text}</h4>}
             {tookAction && (
                 <div className="monospace medium_text">
                     {confirmed_by.length > 0 && (
@@ -580,6 +591,20 @@ export const ReportBanner = ({ id, reportArg, domain }) => {
                             label={label}
                         />
                     ))}
+                    {domain == "post" && (
+                        <ButtonWithLoading
+                            onClick={async () => {
+                                const result = await api.call(
+                                    "add_content_warning",
+                                    id,
+                                    "Nsfw",
+                                );
+                                if ("Err" in result)
+                                    alert(`Error: ${result.Err}`);
+                            }}
+                            label="⚠️ ADD WARNING"
+                        />
+                    )}
                 </div>
             )}
         </div>
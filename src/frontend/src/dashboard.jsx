@@ -49,13 +49,20 @@ const show = (number, unit = null) => (
 export const Dashboard = ({ fullMode }) => {
     const stats = window.backendCache.stats;
     const [logs, setLogs] = React.useState([]);
+    const [logLevel, setLogLevel] = React.useState("");
+    const [logPage, setLogPage] = React.useState(0);
+    const [noMoreLogs, setNoMoreLogs] = React.useState(false);
+
+    const loadLogs = async (level, page) => {
+        const events = await api.query("logs", level || null, page, 100);
+        setNoMoreLogs(events.length < 100);
+        setLogs(page == 0 ? events : logs.concat(events));
+        setLogPage(page);
+    };
 
     React.useEffect(() => {
-        api.query("logs").then((logs) => {
-            logs.reverse();
-            setLogs(logs);
-        });
-    }, []);
+        loadLogs(logLevel, 0);
+    }, [logLevel]);
 
     const {
         stats: { last_weekly_chores },
@@ -232,6 +239,14 @@ export const Dashboard = ({ fullMode }) => {
                                 {token(stats.circulating_supply)}
                             </code>
                         </div>
+                        <div className="db_cell">
+                            <label>
+                                <Gem /> MINTING HEADROOM
+                            </label>
+                            <code className="xx_large_text">
+                                {token(stats.mint_headroom)}
+                            </code>
+                        </div>
                         <div className="db_cell">
                             <label>
                                 <CashCoin /> REWARDS SHARED
@@ -304,6 +319,12 @@ export const Dashboard = ({ fullMode }) => {
                                 </label>
                                 <code>{timeAgo(stats.last_upgrade)}</code>
                             </div>
+                            <div className="db_cell bottom_spaced">
+                                <label>
+                                    <Gear /> STATS UPDATED
+                                </label>
+                                <code>{timeAgo(stats.computed_at)}</code>
+                            </div>
                             <div className="db_cell">
                                 <label>
                                     <Binary /> VERSION
@@ -363,6 +384,15 @@ export const Dashboard = ({ fullMode }) => {
                     <h2>
                         <Document /> Logs
                     </h2>
+                    <select
+                        value={logLevel}
+                        onChange={(e) => setLogLevel(e.target.value)}
+                    >
+                        <option value="">ALL</option>
+                        <option value="INFO">INFO</option>
+                        <option value="ERROR">ERROR</option>
+                        <option value="CRITICAL">CRITICAL</option>
+                    </select>
                     <hr />
                     <Content
                         value={logs
@@ -377,6 +407,14 @@ export const Dashboard = ({ fullMode }) => {
                             .join("\n- - -\n")}
                         classNameArg="monospace"
                     />
+                    {!noMoreLogs && (
+                        <button
+                            className="top_spaced"
+                            onClick={() => loadLogs(logLevel, logPage + 1)}
+                        >
+                            MORE
+                        </button>
+                    )}
                 </div>
             )}
         </>
@@ -31,6 +31,7 @@ export const Feed = ({ params }) => {
                         tags,
                         users,
                         page,
+                        null,
                     );
                 }}
                 heartbeat={filter + params}
@@ -82,6 +83,21 @@ const FeedExtender = ({ filterVal, setFilterVal, refilter, filter }) => {
                     }
                 />
             )}
+            {!extending && api._user && contains(api._user.feeds, filter) && (
+                <ToggleButton
+                    classNameArg="max_width_col left_half_spaced"
+                    offLabel="NOTIFY"
+                    onLabel="DON'T NOTIFY"
+                    currState={() =>
+                        contains(api._user.notified_feeds || [], filter)
+                    }
+                    toggler={() =>
+                        api
+                            .call("toggle_feed_notifications", filter)
+                            .then(api._reloadUser)
+                    }
+                />
+            )}
         </div>
     );
 };
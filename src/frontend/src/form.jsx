@@ -55,8 +55,8 @@ export const Form = ({
     const [cursor, setCursor] = React.useState(0);
     const textarea = React.useRef();
     const tags = window.backendCache.recent_tags;
-    const users = Object.values(window.backendCache.users);
-    const { max_post_length, max_blob_size_bytes } = backendCache.config;
+    const { max_post_length, max_blob_size_bytes, max_blobs_per_post } =
+        backendCache.config;
 
     const handleSubmit = async () => {
         if (
@@ -73,7 +73,9 @@ export const Form = ({
             alert("The cut is not placed after the introductory part.");
             return false;
         }
-        if (value.length == 0 || value.length > max_post_length) {
+        // the backend counts characters, not UTF-16 code units
+        const length = [...value].length;
+        if (length == 0 || length > max_post_length) {
             alert(
                 `Post length should be larger than 0 and shorter than ${max_post_length} characters.`,
             );
@@ -95,6 +97,10 @@ export const Form = ({
             );
             setSubmitting(false);
             return;
+        } else if (blobArrays.length > max_blobs_per_post) {
+            alert(`Please attach not more than ${max_blobs_per_post} pictures.`);
+            setSubmitting(false);
+            return;
         } else {
             let extension;
             if (poll) {
@@ -181,8 +187,18 @@ export const Form = ({
         const cursor = textarea.current?.selectionStart - 1;
         const suggestedTags = suggestTokens(cursor, value, tags, "#");
         setSuggestedTags(suggestedTags);
-        const suggestedUsers = suggestTokens(cursor, value, users, "@");
-        setSuggestedUsers(suggestedUsers);
+        const mention = currentToken(cursor, value, "@");
+        if (mention)
+            api.query("search_users", mention, MAX_SUGGESTED_TAGS).then(
+                (users) =>
+                    setSuggestedUsers(
+                        users.map(
+                            ([_id, name]) =>
+                                mention + name.slice(mention.length),
+                        ),
+                    ),
+            );
+        else setSuggestedUsers([]);
         setChoresTimer(
             setTimeout(() => localStorage.setItem(draftKey, value), 1500),
         );
@@ -195,7 +211,6 @@ export const Form = ({
         if (event.charCode == 13) {
             const cursor = pos - 1;
             const suggestedTags = suggestTokens(cursor, value, tags, "#");
-            const suggestedUsers = suggestTokens(cursor, value, users, "@");
             if (suggestedTags.length) {
                 insertSuggestion(event, "#", suggestedTags[0]);
             } else if (suggestedUsers.length) {
@@ -593,13 +608,19 @@ const resize = async (blob, scale) => {
     return await canvasToBlob(canvas);
 };
 
-const suggestTokens = (cursor, value, tokens, trigger) => {
-    let currentTag = "";
+// Returns the token typed right before the cursor if it starts with the trigger.
+const currentToken = (cursor, value, trigger) => {
+    let token = "";
     let i;
     for (i = cursor; i >= 0 && value[i].match(/(\p{L}|-|\d)/gu); i--) {
-        currentTag = value[i] + currentTag;
+        token = value[i] + token;
     }
-    if (value[i] == trigger) {
+    return value[i] == trigger ? token : null;
+};
+
+const suggestTokens = (cursor, value, tokens, trigger) => {
+    const currentTag = currentToken(cursor, value, trigger);
+    if (currentTag != null) {
         const result = tokens
             .filter((tag) => tag.length > currentTag.length)
             .filter((tag) =>
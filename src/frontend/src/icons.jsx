@@ -929,5 +929,8 @@ export const reaction2icon = (id) => {
         case 10:
             return <Heart />;
     }
-    return <NotFound />;
+    const reaction = window.backendCache?.config?.reactions.find(
+        (reaction) => reaction.id == id,
+    );
+    return reaction ? <span>{reaction.emoji}</span> : <NotFound />;
 };
@@ -10,12 +10,16 @@ export const Inbox = () => {
     if (ids.length == 0) {
         location.href = "#/";
     }
+    // conditional notifications (open proposals and reports) are pinned to the top
+    const pinned = (id) => "Conditional" in inbox[id];
+    // ids are prefixed with the notification type, so only their numbers are compared
+    const counter = (id) => parseInt(id.split("_").pop());
     ids.sort((a, b) => {
-        if (a.startsWith("condition") && !b.startsWith("condition")) return -1;
-        if (!a.startsWith("condition") && b.startsWith("condition")) return 1;
+        if (pinned(a) && !pinned(b)) return -1;
+        if (!pinned(a) && pinned(b)) return 1;
         if (a.startsWith("watched") && !b.startsWith("watched")) return 1;
         if (!a.startsWith("watched") && b.startsWith("watched")) return -1;
-        return b.localeCompare(a);
+        return counter(b) - counter(a);
     });
     return (
         <>
@@ -47,6 +51,9 @@ export const Inbox = () => {
                             ? payload.ReportOpen
                             : payload.Proposal;
                         msg = message.Conditional[0];
+                    } else if ("Digest" in message) {
+                        id = message.Digest[1];
+                        msg = message.Digest[0];
                     } else if ("WatchedPostEntries" in message) {
                         id = parseInt(k.split("_")[1]);
                         msg = `\`${
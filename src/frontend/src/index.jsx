@@ -23,7 +23,7 @@ import {
     setTitle,
     currentRealm,
 } from "./common";
-import { Settings } from "./settings";
+import { ConfirmDevice, Settings } from "./settings";
 import { Api } from "./api";
 import { Wallet, WelcomeInvited } from "./wallet";
 import { applyTheme, themes } from "./theme";
@@ -117,6 +117,8 @@ const App = () => {
         ) : (
             <WelcomeInvited />
         );
+    } else if (handler == "device") {
+        content = auth(<ConfirmDevice userId={param} />);
     } else if (handler == "wallet" || (api._principalId && !api._user)) {
         content = <Wallet />;
     } else if (handler == "post") {
@@ -155,8 +157,8 @@ const App = () => {
             <PostFeed
                 title={<HeadBar title="Bookmarks" shareLink="bookmarks" />}
                 includeComments={true}
-                feedLoader={async () =>
-                    await api.query("posts", api._user.bookmarks)
+                feedLoader={async (page) =>
+                    await api.query("bookmarks", page)
                 }
             />,
         );
@@ -3,15 +3,37 @@ import { CopyToClipboard, HeadBar, Loading } from "./common";
 import { Cycles } from "./icons";
 import { trusted } from "./profile";
 
+const DAY = 24 * 3600 * 1000000000;
+
+const handlesToIds = (handles) =>
+    handles
+        .split(",")
+        .map((handle) => handle.trim().replace("@", "").toLowerCase())
+        .filter((handle) => handle.length > 0)
+        .map((handle) =>
+            Object.keys(backendCache.users).find(
+                (id) => backendCache.users[id].toLowerCase() == handle,
+            ),
+        )
+        .filter((id) => id != undefined)
+        .map((id) => parseInt(id));
+
 export const Invites = () => {
     const [cycles, setCycles] = React.useState(
         backendCache.config.min_cycles_for_inviting,
     );
+    const [maxUses, setMaxUses] = React.useState(1);
+    const [expiryDays, setExpiryDays] = React.useState(0);
+    const [realm, setRealm] = React.useState("");
+    const [follow, setFollow] = React.useState("");
     const [invites, setInvites] = React.useState([]);
+    const [quota, setQuota] = React.useState(null);
     const [busy, setBusy] = React.useState(false);
 
     const loadInvites = async () => {
         setInvites(await api.query("invites"));
+        const [, remaining] = await api.query("invite_quota");
+        setQuota(remaining);
     };
 
     React.useEffect(() => {
@@ -56,7 +78,21 @@ export const Invites = () => {
                         The invite will not work if your invite budget or cycle
                         balance drops below the amount attached to the invite.
                     </li>
+                    <li>
+                        An invite can be used multiple times and can expire
+                        after a number of days (<code>0</code> for no expiry).
+                    </li>
+                    <li>
+                        Invited users can automatically join a realm and follow
+                        a starter set of users (comma-separated handles).
+                    </li>
                     <li>Invites are not cancelable.</li>
+                    {quota != null && (
+                        <li>
+                            You can create invites for <code>{quota}</code>{" "}
+                            more users; expired invites don't count.
+                        </li>
+                    )}
                 </ul>
                 <div className="vcentered">
                     <input
@@ -67,6 +103,38 @@ export const Invites = () => {
                             setCycles(parseInt(event.target.value))
                         }
                     />
+                    <input
+                        type="number"
+                        value={maxUses}
+                        className="max_width_col left_half_spaced"
+                        onChange={(event) =>
+                            setMaxUses(parseInt(event.target.value))
+                        }
+                    />
+                    <input
+                        type="number"
+                        value={expiryDays}
+                        className="max_width_col left_half_spaced"
+                        onChange={(event) =>
+                            setExpiryDays(parseInt(event.target.value))
+                        }
+                    />
+                    <input
+                        type="text"
+                        value={realm}
+                        placeholder="REALM"
+                        className="max_width_col left_half_spaced"
+                        onChange={(event) =>
+                            setRealm(event.target.value.toUpperCase())
+                        }
+                    />
+                    <input
+                        type="text"
+                        value={follow}
+                        placeholder="FOLLOW"
+                        className="max_width_col left_half_spaced"
+                        onChange={(event) => setFollow(event.target.value)}
+                    />
                     {!busy && (
                         <button
                             className="vertically_spaced active"
@@ -75,6 +143,13 @@ export const Invites = () => {
                                 const result = await api.call(
                                     "create_invite",
                                     cycles,
+                                    maxUses,
+                                    expiryDays > 0
+                                        ? Number(new Date()) * 1000000 +
+                                              expiryDays * DAY
+                                        : null,
+                                    realm || null,
+                                    handlesToIds(follow),
                                 );
                                 if ("Err" in result)
                                     alert(`Failed: ${result.Err}`);
@@ -95,28 +170,42 @@ export const Invites = () => {
                                 <th align="right">
                                     <Cycles />
                                 </th>
+                                <th align="right">USES LEFT</th>
+                                <th align="right">EXPIRES IN</th>
                                 <th align="right">CODE</th>
                                 <th align="right">URL</th>
                             </tr>
                         </thead>
                         <tbody>
-                            {invites.map(([code, cycles]) => (
-                                <tr key={code}>
-                                    <td align="right">
-                                        <code>{cycles}</code>
-                                    </td>
-                                    <td align="right">
-                                        <CopyToClipboard
-                                            value={code.toUpperCase()}
-                                        />
-                                    </td>
-                                    <td align="right">
-                                        <CopyToClipboard
-                                            value={`${location.protocol}//${location.host}/#/welcome/${code}`}
-                                        />
-                                    </td>
-                                </tr>
-                            ))}
+                            {invites.map(
+                                ([code, cycles, usesLeft, expiresIn]) => (
+                                    <tr key={code}>
+                                        <td align="right">
+                                            <code>{cycles}</code>
+                                        </td>
+                                        <td align="right">
+                                            <code>{usesLeft}</code>
+                                        </td>
+                                        <td align="right">
+                                            {expiresIn == null
+                                                ? "NEVER"
+                                                : `${Math.ceil(
+                                                      expiresIn / DAY,
+                                                  )} DAYS`}
+                                        </td>
+                                        <td align="right">
+                                            <CopyToClipboard
+                                                value={code.toUpperCase()}
+                                            />
+                                        </td>
+                                        <td align="right">
+                                            <CopyToClipboard
+                                                value={`${location.protocol}//${location.host}/#/welcome/${code}`}
+                                            />
+                                        </td>
+                                    </tr>
+                                ),
+                            )}
                         </tbody>
                     </table>
                 )}
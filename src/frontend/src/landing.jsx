@@ -88,15 +88,22 @@ export const Landing = () => {
                             user.id,
                             page,
                             false,
+                            null,
                         );
                     if (feed == "HOT")
-                        return await api.query("hot_posts", realm, page);
+                        return await api.query(
+                            "hot_posts",
+                            realm,
+                            page,
+                            null,
+                        );
                     else
                         return await api.query(
                             "last_posts",
                             realm,
                             page,
                             false,
+                            null,
                         );
                 }}
             />
@@ -131,7 +138,9 @@ const RealmsDashboard = () => {
 export const TagCloud = ({ size, heartbeat, realm }) => {
     const [tags, setTags] = React.useState(null);
     const loadTags = async () => {
-        let tags = await api.query("recent_tags", realm, size);
+        let tags = realm
+            ? await api.query("realm_tags", realm, size)
+            : await api.query("recent_tags", "", size);
         const occurences = tags.map(([_, N]) => parseInt(N));
         const min = Math.min(...occurences);
         const max = Math.max(...occurences);
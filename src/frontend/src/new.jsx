@@ -11,6 +11,11 @@ import {
 export const PostSubmissionForm = ({ id, repost }) => {
     const [post, setPost] = React.useState(null);
     const [blobs, setBlobs] = React.useState({});
+    const [contentWarning, setContentWarning] = React.useState("");
+    // Lets the backend ignore repeated submissions of the same post.
+    const [idempotencyKey] = React.useState(
+        () => Date.now().toString(36) + Math.random().toString(36).slice(2),
+    );
 
     const load = async () => {
         if (!id) return;
@@ -69,6 +74,10 @@ export const PostSubmissionForm = ({ id, repost }) => {
                     [],
                     optionalRealm,
                     encodeExtension(extension),
+                    [idempotencyKey],
+                    // new posts are in the first language of the user
+                    api._user.languages.slice(0, 1),
+                    contentWarning ? [contentWarning] : [],
                 );
             }
             if ("Err" in result) {
@@ -95,6 +104,22 @@ export const PostSubmissionForm = ({ id, repost }) => {
                 repost={repost}
                 realmArg={post?.realm || currentRealm()}
             />
+            {!post && (
+                <div className="vcentered top_spaced">
+                    CONTENT WARNING
+                    <select
+                        className="left_half_spaced"
+                        value={contentWarning}
+                        onChange={(event) =>
+                            setContentWarning(event.target.value)
+                        }
+                    >
+                        <option value="">NONE</option>
+                        <option value="Nsfw">NSFW</option>
+                        <option value="Spoiler">SPOILER</option>
+                    </select>
+                </div>
+            )}
             <h3>Tipps</h3>
             <ul>
                 <li>
@@ -1,7 +1,7 @@
 import * as React from "react";
 import { userList } from "./common";
 import { Content } from "./content";
-import { Gem, YinYan } from "./icons";
+import { Gem, Realm, YinYan } from "./icons";
 
 export const Poll = ({ poll, post_id, created }) => {
     const [data, setData] = React.useState(poll);
@@ -141,6 +141,22 @@ export const Poll = ({ poll, post_id, created }) => {
                                 ]
                             }
                         </div>
+                        {Object.keys(data.weighted_by_realm_reputation || {})
+                            .length > 0 && (
+                            <div className="top_half_spaced">
+                                <Realm />{" "}
+                                <span className="left_spaced monospace">
+                                    REALM:{" "}
+                                </span>{" "}
+                                {
+                                    data.options[
+                                        keyWithMaxVal(
+                                            data.weighted_by_realm_reputation,
+                                        )
+                                    ]
+                                }
+                            </div>
+                        )}
                     </div>
                 </div>
             )}
@@ -16,6 +16,7 @@ import {
     objectReduce,
     reactionCosts,
     loadPosts,
+    recordImpressions,
     ReactionToggleButton,
     RealmRibbon,
     setTitle,
@@ -88,6 +89,7 @@ export const Post = ({
             // This is needed, becasue reactions are updated optimistically and we might have new ones in-flight.
             data.reactions = post.reactions;
         }
+        if (!preloadedData) recordImpressions([data]);
         setPost(data);
         setBlobs(await loadPostBlobs(data.files));
     };
@@ -148,29 +150,33 @@ export const Post = ({
             post.reactions[id] = [];
         }
         let users = post.reactions[id];
-        if (
-            Object.values(post.reactions)
-                .reduce((acc, users) => acc.concat(users), [])
-                .includes(userId)
-        ) {
-            if (reactionTimer) {
-                clearTimeout(reactionTimer);
-                post.reactions[id] = users.filter((id) => id != userId);
-                setPost({ ...post });
-            }
+        const previousId = Object.keys(post.reactions).find((id) =>
+            post.reactions[id].includes(userId),
+        );
+        // a reaction that wasn't sent yet is just dropped
+        if (previousId != undefined && reactionTimer) {
+            clearTimeout(reactionTimer);
+            setReactionTimer(null);
+            post.reactions[previousId] = post.reactions[previousId].filter(
+                (id) => id != userId,
+            );
+            setPost({ ...post });
             return;
         }
-        clearTimeout(reactionTimer);
-        const timer = setTimeout(
-            () =>
-                api.call("react", post.id, parseInt(id)).then((response) => {
-                    if ("Err" in response) alert(`Error: ${response.Err}`);
-                    api._reloadUser();
-                }),
-            4000,
-        );
+        // the same reaction removes it, a different one replaces it
+        const timer = setTimeout(() => {
+            setReactionTimer(null);
+            api.call("react", post.id, parseInt(id)).then((response) => {
+                if ("Err" in response) alert(`Error: ${response.Err}`);
+                api._reloadUser();
+            });
+        }, 4000);
         setReactionTimer(timer);
-        users.push(userId);
+        if (previousId != undefined)
+            post.reactions[previousId] = post.reactions[previousId].filter(
+                (id) => id != userId,
+            );
+        if (previousId != id) users.push(userId);
         setPost({ ...post });
         toggleInfo(commentIncoming);
     };
@@ -186,6 +192,7 @@ export const Post = ({
     const showReport =
         post.report && !post.report.closed && user && user.stalwart;
     const deleted = post.hashes.length > 0;
+    const deletionPending = !deleted && post.deletion_scheduled != null;
     const deletedByModeration =
         post.report &&
         post.report.closed &&
@@ -199,10 +206,18 @@ export const Post = ({
     const isGallery = post.effBody.startsWith("![");
     const postCreated =
         post.patches.length > 0 ? post.patches[0][0] : post.timestamp;
+    const warned =
+        post.content_warning && api._user?.content_warnings != "Reveal";
     const isNSFW =
-        post.effBody.toLowerCase().includes("#nsfw") &&
+        (post.effBody.toLowerCase().includes("#nsfw") || warned) &&
         isFeedItem &&
         !safeToOpen;
+    // authors always see their own posts hidden by a pending report
+    const hiddenByReport =
+        post.report &&
+        post.report.hidden &&
+        post.user.id != api._user?.id &&
+        !safeToOpen;
     const versionSpecified = !isNaN(version);
     version =
         isNaN(version) && post.patches.length > 0
@@ -213,15 +228,40 @@ export const Post = ({
         setTitle(`Post #${post.id} by @${backendCache.users[post.user.id]}`);
 
     if (deletedByModeration)
-        return <h4 className="banner">DELETED VIA MODERATION</h4>;
+        return (
+            <div className="banner">
+                <h4>DELETED VIA MODERATION</h4>
+                {post.user.id == api._user?.id && !post.report.appeal && (
+                    <ButtonWithLoading
+                        onClick={async () => {
+                            const appeal = prompt(
+                                "Why should the decision be reversed?",
+                            );
+                            if (!appeal) return;
+                            const response = await api.call(
+                                "appeal_report",
+                                "post",
+                                post.id,
+                                appeal,
+                            );
+                            if ("Err" in response) {
+                                alert(`Error: ${response.Err}`);
+                            }
+                        }}
+                        label="APPEAL"
+                    />
+                )}
+            </div>
+        );
 
     let cls = "";
-    if (!deleted && !isNSFW && !showReport) {
+    if (!deleted && !isNSFW && !hiddenByReport && !showReport) {
         if (realmPost) cls = "realm_post";
         cls += isGallery ? " gallery_post" : " text_post";
     }
 
-    const showExtension = !isNSFW && post.extension && !repost;
+    const showExtension =
+        !isNSFW && !hiddenByReport && post.extension && !repost;
     const postIsClickable =
         post.children.length > 0 || post.effBody.includes(CUT);
 
@@ -253,12 +293,19 @@ export const Post = ({
                         className="post_head banner2 x_large_text"
                         onClick={() => setSafeToOpen(true)}
                     >
-                        #NSFW
+                        {post.content_warning == "Spoiler"
+                            ? "SPOILER"
+                            : "#NSFW"}
                     </div>
                 )}
                 {deleted && (
                     <div className="post_head banner3 small_text monospace">
-                        <h3>Post deleted</h3>
+                        <h3>
+                            Post deleted
+                            {post.deleted_by == "Author" && " by the author"}
+                            {post.deleted_by == "Moderators" &&
+                                " by moderators"}
+                        </h3>
                         <ol>
                             {post.hashes.map((hash) => (
                                 <li key={hash}>
@@ -270,7 +317,44 @@ export const Post = ({
                         </ol>
                     </div>
                 )}
-                {realmPost && <RealmRibbon name={post.realm} />}
+                {post.media_pending && (
+                    <div className="post_head banner3 small_text monospace">
+                        SOME PICTURES ARE STILL BEING UPLOADED
+                    </div>
+                )}
+                {deletionPending && (
+                    <div className="post_head banner3 small_text monospace">
+                        {user && user.id == post.user.id ? (
+                            <h3>
+                                deleted (
+                                <a
+                                    href="#"
+                                    onClick={async (e) => {
+                                        e.preventDefault();
+                                        let response = await api.call(
+                                            "restore_post",
+                                            post.id,
+                                        );
+                                        if ("Err" in response) {
+                                            alert(`Error: ${response.Err}`);
+                                        } else await loadData();
+                                    }}
+                                >
+                                    restore
+                                </a>
+                                )
+                            </h3>
+                        ) : (
+                            <h3>Post deleted</h3>
+                        )}
+                    </div>
+                )}
+                {realmPost && (
+                    <RealmRibbon
+                        col={post.realm_style?.label_color}
+                        name={post.realm}
+                    />
+                )}
                 {commentAsPost && (
                     <a
                         className="reply_tag external monospace"
@@ -287,7 +371,15 @@ export const Post = ({
                         <CommentArrow classNameArg="action" />
                     </span>
                 )}
-                {!isNSFW && (
+                {hiddenByReport && (
+                    <div
+                        className="post_head banner2 x_large_text"
+                        onClick={() => setSafeToOpen(true)}
+                    >
+                        REPORTED, CLICK TO VIEW
+                    </div>
+                )}
+                {!isNSFW && !hiddenByReport && (
                     <article
                         onClick={expand}
                         className={prime ? "prime" : null}
@@ -408,6 +500,8 @@ export const Post = ({
 const PostInfo = ({ post, version, postCreated, callback }) => {
     const postAuthor = api._user?.id == post.user.id;
     const realmController = post.realm && backendCache.realms[post.realm][1];
+    const deletable =
+        post.hashes.length == 0 && post.deletion_scheduled == null;
     return (
         <>
             {api._user && (
@@ -443,24 +537,94 @@ const PostInfo = ({ post, version, postCreated, callback }) => {
                         }
                         testId="bookmark-post"
                     />
+                    {isRoot(post) && (
+                        <ToggleButton
+                            classNameArg="max_width_col"
+                            offLabel="MUTE"
+                            onLabel="UNMUTE"
+                            currState={() =>
+                                api._user.muted_threads.includes(post.id)
+                            }
+                            toggler={() =>
+                                api
+                                    .call("toggle_muting_thread", post.id)
+                                    .then(api._reloadUser)
+                            }
+                        />
+                    )}
                     <ButtonWithLoading
                         classNameArg="max_width_col"
                         onClick={async () => {
+                            const { min_tip_e8s, tip_fee_percentage } =
+                                backendCache.config;
                             const amount = prompt(
-                                `Tip @${post.user.name} with ICP:`,
+                                `Tip @${post.user.name} with ICP or split ` +
+                                    `the tip, e.g. "@alice 0.5, @bob 0.2":`,
                             );
+                            if (amount == null) return;
+                            let shares = null;
+                            try {
+                                if (amount.includes("@"))
+                                    shares = parseTipShares(amount);
+                            } catch (err) {
+                                alert(`Error: ${err}`);
+                                return;
+                            }
+                            const amounts = shares
+                                ? shares.map(([_, share]) => share)
+                                : [amount];
+                            if (
+                                amounts.some(
+                                    (share) =>
+                                        Number(share) * 1e8 < min_tip_e8s,
+                                )
+                            ) {
+                                alert(
+                                    `Tips must be at least ${icp(min_tip_e8s)} ICP.`,
+                                );
+                                return;
+                            }
+                            const fee =
+                                tip_fee_percentage > 0
+                                    ? ` (${tip_fee_percentage}% go to the treasury)`
+                                    : "";
+                            const recipients = shares
+                                ? shares
+                                      .map(
+                                          ([id, share]) =>
+                                              `${share} ICP to @${backendCache.users[id]}`,
+                                      )
+                                      .join(", ")
+                                : `${amount} ICP to @${post.user.name}`;
                             if (
-                                amount == null ||
                                 !confirm(
-                                    `Transfer ${amount} ICP to @${post.user.name} as a tip?`,
+                                    `Transfer ${recipients} as a tip${fee}?`,
                                 )
                             )
                                 return;
-                            let response = await api.call(
-                                "tip",
-                                post.id,
-                                amount,
+                            const message =
+                                prompt(
+                                    `Attach a message to your tip (optional):`,
+                                ) || "";
+                            const total = amounts.reduce(
+                                (acc, share) =>
+                                    acc + Math.round(Number(share) * 1e8),
+                                0,
                             );
+                            let response = shares
+                                ? await api.call(
+                                      "tip_split",
+                                      post.id,
+                                      (total / 1e8).toFixed(8),
+                                      shares,
+                                      message,
+                                  )
+                                : await api.call(
+                                      "tip",
+                                      post.id,
+                                      amount,
+                                      message,
+                                  );
                             if ("Err" in response) {
                                 alert(`Error: ${response.Err}`);
                             } else await callback();
@@ -483,9 +647,49 @@ const PostInfo = ({ post, version, postCreated, callback }) => {
                             label={<Close />}
                         />
                     )}
+                    {realmController && isRoot(post) && (
+                        <ButtonWithLoading
+                            classNameArg="max_width_col"
+                            onClick={async () => {
+                                if (
+                                    !confirm(
+                                        "Do you want to pin the post to the top of this realm?",
+                                    )
+                                )
+                                    return;
+                                let response = await api.call(
+                                    "pin_post",
+                                    post.id,
+                                    post.realm,
+                                );
+                                if ("Err" in response)
+                                    alert(`Error: ${response.Err}`);
+                            }}
+                            label="📌"
+                        />
+                    )}
+                    {postAuthor && isRoot(post) && (
+                        <ToggleButton
+                            classNameArg="max_width_col"
+                            offLabel="📌"
+                            onLabel="UNPIN"
+                            currState={() => api._user.pinned == post.id}
+                            toggler={() =>
+                                api
+                                    .call(
+                                        "pin_post",
+                                        api._user.pinned == post.id
+                                            ? null
+                                            : post.id,
+                                        "",
+                                    )
+                                    .then(api._reloadUser)
+                            }
+                        />
+                    )}
                     {postAuthor && (
                         <>
-                            {post.hashes.length == 0 && (
+                            {deletable && (
                                 <ButtonWithLoading
                                     classNameArg="max_width_col"
                                     onClick={async () => {
@@ -563,6 +767,11 @@ const PostInfo = ({ post, version, postCreated, callback }) => {
                     <b>CREATED</b>:{" "}
                     {new Date(parseInt(postCreated) / 1000000).toLocaleString()}
                 </div>
+                {post.impressions != null && (
+                    <div>
+                        <b>IMPRESSIONS</b>: {post.impressions}
+                    </div>
+                )}
                 {post.patches.length > 0 && (
                     <div>
                         <b>VERSIONS</b>:{" "}
@@ -598,10 +807,13 @@ const PostInfo = ({ post, version, postCreated, callback }) => {
                     <div>
                         <b>ICP TIPS</b>:{" "}
                         {commaSeparated(
-                            post.tips.map(([id, tip]) => (
+                            post.tips.map(([id, tip], i) => (
                                 <span key={id + tip}>
                                     <code>{icp(tip, "with_decimals")}</code>{" "}
                                     from {<UserLink id={id} />}
+                                    {post.tip_messages[i] && (
+                                        <i>: "{post.tip_messages[i]}"</i>
+                                    )}
                                 </span>
                             )),
                         )}
@@ -728,10 +940,13 @@ const PostBar = ({
 
 export const ReactionsPicker = ({ react }) => (
     <>
-        {backendCache.config.reactions.map(([id, _]) => (
+        {backendCache.config.reactions.map(({ id, karma, cost }) => (
             <button
                 key={id}
                 className="left_half_spaced"
+                title={`Costs ${
+                    cost + (karma > 0 ? backendCache.config.reaction_fee : 0)
+                } cycles, changes the author's karma by ${karma}`}
                 onClick={() => react(id)}
                 data-testid={"give-" + id + "-reaction"}
             >
@@ -767,6 +982,23 @@ export const Reactions = ({ reactionsMap, react }) => {
     );
 };
 
+// Parses a tip split like "@alice 0.5, @bob 0.2" into [user id, amount] pairs.
+const parseTipShares = (input) => {
+    const name2Id = Object.entries(backendCache.users).reduce(
+        (acc, [id, name]) => {
+            acc[name.toLowerCase()] = parseInt(id);
+            return acc;
+        },
+        {},
+    );
+    return input.split(",").map((entry) => {
+        const [handle, amount] = entry.trim().split(/\s+/);
+        const id = name2Id[handle.replace("@", "").toLowerCase()];
+        if (id == undefined || !amount) throw `can't parse "${entry.trim()}"`;
+        return [id, amount];
+    });
+};
+
 const skipClicks = (elem) =>
     elem &&
     (elem.dataset["meta"] == "skipClicks" || skipClicks(elem.parentElement));
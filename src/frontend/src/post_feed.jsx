@@ -1,5 +1,11 @@
 import * as React from "react";
-import { bigScreen, isRoot, Loading, expandUser } from "./common";
+import {
+    bigScreen,
+    isRoot,
+    Loading,
+    expandUser,
+    recordImpressions,
+} from "./common";
 import { Post } from "./post";
 
 export const PostFeed = ({
@@ -28,6 +34,7 @@ export const PostFeed = ({
             expandUser,
         );
         const loaded = new Set(posts.map((post) => post.id));
+        recordImpressions(nextPosts.filter((post) => !loaded.has(post.id)));
         setPosts(page == 0 ? nextPosts : posts.concat(nextPosts));
         if (nextPosts.length < backendCache.config.feed_page_size)
             setPageVlipperVisibility(false);
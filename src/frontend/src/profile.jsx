@@ -7,7 +7,6 @@ import {
     Loading,
     RealmSpan,
     HeadBar,
-    userList,
     bigScreen,
     tokenBalance,
     FlagButton,
@@ -16,6 +15,7 @@ import {
     percentage,
     ShareButton,
     ButtonWithLoading,
+    icp,
 } from "./common";
 import { Content } from "./content";
 import { Journal } from "./icons";
@@ -27,6 +27,9 @@ export const Profile = ({ handle }) => {
     const [profile, setProfile] = React.useState({ loadingStatus: 0 });
     const [allEndorsememnts, setAllEndorsements] = React.useState(false);
     const [fullAccounting, setFullAccounting] = React.useState(false);
+    const [karmaHistory, setKarmaHistory] = React.useState([]);
+    const [cyclesHistory, setCyclesHistory] = React.useState([]);
+    const [stalwartStatus, setStalwartStatus] = React.useState(null);
     const [tab, setTab] = React.useState("LAST");
 
     const updateState = async () => {
@@ -37,6 +40,12 @@ export const Profile = ({ handle }) => {
         }
         profile.loadingStatus = 1;
         setProfile(profile);
+        const ownProfile = api._user && api._user.id == profile.id;
+        setKarmaHistory(ownProfile ? await api.query("karma_history") : []);
+        setCyclesHistory(ownProfile ? await api.query("cycles_history") : []);
+        setStalwartStatus(
+            ownProfile ? await api.query("stalwart_status") : null,
+        );
     };
 
     React.useEffect(() => {
@@ -246,6 +255,73 @@ export const Profile = ({ handle }) => {
                     <hr />
                 </>
             )}
+            {karmaHistory.length > 0 && (
+                <>
+                    <div className="spaced">
+                        <h2>Karma History</h2>
+                        <table
+                            style={{ width: "100%" }}
+                            className={`monospace ${
+                                bigScreen() ? "" : "small_text"
+                            }`}
+                        >
+                            <tbody>
+                                {karmaHistory.map(([time, delta, log], i) => (
+                                    <tr className="stands_out" key={log + i}>
+                                        <td>{timeAgo(time)}</td>
+                                        <td
+                                            style={{
+                                                color:
+                                                    delta > 0 ? "green" : "red",
+                                                textAlign: "right",
+                                            }}
+                                            className="no_wrap"
+                                        >
+                                            {delta > 0 ? "+" : ""}
+                                            {delta} <YinYan />
+                                        </td>
+                                        <td style={{ textAlign: "right" }}>
+                                            {linkPost(log)}
+                                        </td>
+                                    </tr>
+                                ))}
+                            </tbody>
+                        </table>
+                    </div>
+                    <hr />
+                </>
+            )}
+            {cyclesHistory.length > 0 && (
+                <>
+                    <div className="spaced">
+                        <h2>Cycle Spendings</h2>
+                        <table
+                            style={{ width: "100%" }}
+                            className={`monospace ${
+                                bigScreen() ? "" : "small_text"
+                            }`}
+                        >
+                            <tbody>
+                                {cyclesHistory.map(([time, amount, log], i) => (
+                                    <tr className="stands_out" key={log + i}>
+                                        <td>{timeAgo(time)}</td>
+                                        <td
+                                            style={{ textAlign: "right" }}
+                                            className="no_wrap"
+                                        >
+                                            -{amount} <Cycles />
+                                        </td>
+                                        <td style={{ textAlign: "right" }}>
+                                            {linkPost(log)}
+                                        </td>
+                                    </tr>
+                                ))}
+                            </tbody>
+                        </table>
+                    </div>
+                    <hr />
+                </>
+            )}
             {trusted(profile) && !stalwart(profile) && !isBot(profile) && (
                 <>
                     <div className="spaced">
@@ -292,6 +368,12 @@ export const Profile = ({ handle }) => {
                                     WEEKS
                                 </code>
                             </div>
+                            {stalwartStatus && (
+                                <div className="db_cell monospace">
+                                    ELIGIBLE IN
+                                    <code>{eligibleIn(stalwartStatus)}</code>
+                                </div>
+                            )}
                         </div>
                     </div>
                     <hr />
@@ -352,6 +434,7 @@ export const Profile = ({ handle }) => {
                         "user_posts",
                         profile.id.toString(),
                         page,
+                        null,
                     );
                 }}
                 heartbeat={profile.id + tab}
@@ -363,6 +446,14 @@ export const Profile = ({ handle }) => {
 export const UserInfo = ({ profile }) => {
     const [followeesVisible, setFolloweesVisibility] = React.useState(false);
     const [followersVisible, setFollowersVisibility] = React.useState(false);
+    const [rewardPreview, setRewardPreview] = React.useState(null);
+
+    React.useEffect(() => {
+        if (api._user && api._user.id == profile.id)
+            api.query("reward_preview").then(setRewardPreview);
+        else setRewardPreview(null);
+    }, [profile.id]);
+
     const placeholder = (status, unfold, label, content) =>
         status ? (
             <div className="small_text">{content}</div>
@@ -384,7 +475,7 @@ export const UserInfo = ({ profile }) => {
                     followeesVisible,
                     setFolloweesVisibility,
                     profile.followees.length,
-                    userList(profile.followees),
+                    <FollowList id={profile.id} method="following" />,
                 )}
             </div>
         ) : null;
@@ -396,7 +487,7 @@ export const UserInfo = ({ profile }) => {
                     followersVisible,
                     setFollowersVisibility,
                     profile.followers.length,
-                    userList(profile.followers),
+                    <FollowList id={profile.id} method="followers" />,
                 )}
             </div>
         ) : null;
@@ -429,6 +520,7 @@ export const UserInfo = ({ profile }) => {
                 ))}
             </div>
         ) : null;
+    const controlledRealms = profile.controlled_realms || [];
     const inviter = profile.invited_by;
 
     return (
@@ -449,10 +541,32 @@ export const UserInfo = ({ profile }) => {
                         {profile.rewarded_karma.toLocaleString()}
                     </code>
                 </div>
+                {rewardPreview && (
+                    <div
+                        className="db_cell"
+                        title={`Minting ratio ${rewardPreview.minting_ratio}:1`}
+                    >
+                        NEXT MINT
+                        <code>
+                            {rewardPreview.eligible
+                                ? tokenBalance(rewardPreview.projected_tokens)
+                                : "INELIGIBLE"}
+                        </code>
+                        {!rewardPreview.trusted && (
+                            <span className="small_text">UNTRUSTED</span>
+                        )}
+                    </div>
+                )}
                 <div className="db_cell">
                     CYCLES
                     <code>{`${profile.cycles.toLocaleString()}`}</code>
                 </div>
+                {profile.realm_quota != null && (
+                    <div className="db_cell">
+                        REALMS LEFT
+                        <code>{profile.realm_quota}</code>
+                    </div>
+                )}
                 <div className="db_cell">
                     JOINED
                     <span>{`${timeAgo(profile.timestamp)}`}</span>
@@ -469,10 +583,38 @@ export const UserInfo = ({ profile }) => {
                     POSTS
                     <code>{profile.num_posts.toLocaleString()}</code>
                 </div>
+                <div className="db_cell">
+                    COMMENTS
+                    <code>{profile.engagement.comments.toLocaleString()}</code>
+                </div>
+                <div className="db_cell">
+                    REACTIONS
+                    <code>
+                        {profile.engagement.reactions_received.toLocaleString()}
+                    </code>
+                </div>
+                {"ICP" in profile.engagement.tips_received && (
+                    <div className="db_cell">
+                        TIPS
+                        <code>
+                            {icp(
+                                profile.engagement.tips_received.ICP,
+                                true,
+                            )}{" "}
+                            ICP
+                        </code>
+                    </div>
+                )}
                 <div className="db_cell">
                     TOKENS
                     <code>{tokenBalance(profile.balance)}</code>
                 </div>
+                {profile.unvested_tokens > 0 && (
+                    <div className="db_cell">
+                        VESTING
+                        <code>{tokenBalance(profile.unvested_tokens)}</code>
+                    </div>
+                )}
                 {followees}
                 {followers}
                 {inviter && (
@@ -492,6 +634,21 @@ export const UserInfo = ({ profile }) => {
                     <h2>INTERESTS</h2>
                     {feeds}
                     {realms}
+                    {controlledRealms.length > 0 && (
+                        <div className="top_spaced">
+                            CONTROLLER OF{" "}
+                            {controlledRealms.map((name) => (
+                                <RealmSpan
+                                    key={name}
+                                    name={name}
+                                    onClick={() =>
+                                        (location.href = `/#/realm/${name}`)
+                                    }
+                                    classNameArg="clickable padded_rounded left_half_spaced"
+                                />
+                            ))}
+                        </div>
+                    )}
                     <hr />
                 </>
             )}
@@ -499,6 +656,43 @@ export const UserInfo = ({ profile }) => {
     );
 };
 
+const FollowList = ({ id, method }) => {
+    const [users, setUsers] = React.useState([]);
+    const [total, setTotal] = React.useState(0);
+    const [page, setPage] = React.useState(0);
+
+    const loadPage = async (page) => {
+        const response = await api.query(method, id.toString(), page);
+        if (!response || "Err" in response) return;
+        const [total, entries] = response.Ok;
+        setTotal(total);
+        setUsers((users) => users.concat(entries));
+    };
+
+    React.useEffect(() => {
+        loadPage(page);
+    }, [page]);
+
+    return (
+        <>
+            {commaSeparated(
+                users.map(([id, name, karma]) => (
+                    <a key={id} href={`#/user/${id}`}>
+                        {name} ({karma})
+                    </a>
+                )),
+            )}
+            {users.length < total && (
+                <ButtonWithLoading
+                    classNameArg="top_half_spaced"
+                    onClick={async () => setPage(page + 1)}
+                    label="MORE"
+                />
+            )}
+        </>
+    );
+};
+
 export const getLabels = (profile) => {
     const labels = [];
     // Account created before end of 2022
@@ -552,6 +746,11 @@ export const getLabels = (profile) => {
 
 const daySeconds = 24 * 3600;
 
+const eligibleIn = ({ time_to_eligibility }) =>
+    time_to_eligibility == null
+        ? "N/A"
+        : `${Math.ceil(time_to_eligibility / 1e9 / daySeconds)} DAYS`;
+
 const secondsSince = (val) =>
     (Number(new Date()) - parseInt(val) / 1000000) / 1000;
 
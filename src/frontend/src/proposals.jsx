@@ -8,6 +8,7 @@ import {
     percentage,
     FileUploadInput,
     tokenBalance,
+    icp,
 } from "./common";
 import * as React from "react";
 import { Content } from "./content";
@@ -21,6 +22,8 @@ export const Proposals = () => {
     const [currentMask, setCurrentMask] = React.useState(null);
     const [receiver, setReceiver] = React.useState(null);
     const [fundingAmount, setFundingAmount] = React.useState(0);
+    const [vestingWeeks, setVestingWeeks] = React.useState(0);
+    const [cliffWeeks, setCliffWeeks] = React.useState(0);
     const [binary, setBinary] = React.useState(null);
     const [commit, setCommit] = React.useState("");
     const [proposal, setProposal] = React.useState(null);
@@ -47,6 +50,18 @@ export const Proposals = () => {
                         >
                             REWARD
                         </button>
+                        <button
+                            className="max_width_col"
+                            onClick={() => setCurrentMask("icp_transfer")}
+                        >
+                            ICP TRANSFER
+                        </button>
+                        <button
+                            className="max_width_col"
+                            onClick={() => setCurrentMask("ban")}
+                        >
+                            BAN
+                        </button>
                         <button
                             className="max_width_col"
                             onClick={() => setCurrentMask("release")}
@@ -69,6 +84,26 @@ export const Proposals = () => {
                                 }}
                             />
                         </div>
+                        <div className="vcentered bottom_half_spaced">
+                            VESTING WEEKS
+                            <input
+                                type="number"
+                                className="monospace left_spaced max_width_col"
+                                value={vestingWeeks}
+                                onChange={(ev) =>
+                                    setVestingWeeks(ev.target.value)
+                                }
+                            />
+                        </div>
+                        <div className="vcentered bottom_half_spaced">
+                            CLIFF WEEKS
+                            <input
+                                type="number"
+                                className="monospace left_spaced max_width_col"
+                                value={cliffWeeks}
+                                onChange={(ev) => setCliffWeeks(ev.target.value)}
+                            />
+                        </div>
                         <div className="bottom_half_spaced monospace">
                             DESCRIPTION
                         </div>
@@ -98,6 +133,13 @@ export const Proposals = () => {
                                     "propose_reward",
                                     description,
                                     receiver,
+                                    parseInt(vestingWeeks) > 0
+                                        ? {
+                                              weeks: parseInt(vestingWeeks),
+                                              cliff_weeks:
+                                                  parseInt(cliffWeeks) || 0,
+                                          }
+                                        : null,
                                 );
                                 if ("Err" in response) {
                                     alert(`Error: ${response.Err}`);
@@ -178,6 +220,142 @@ export const Proposals = () => {
                         />
                     </div>
                 )}
+                {currentMask == "icp_transfer" && (
+                    <div className="spaced column_container monospace">
+                        <div className="vcentered bottom_half_spaced">
+                            ACCOUNT
+                            <input
+                                type="text"
+                                className="monospace left_spaced max_width_col"
+                                onChange={async (ev) => {
+                                    setReceiver(ev.target.value);
+                                }}
+                            />
+                        </div>
+                        <div className="vcentered bottom_half_spaced">
+                            ICP AMOUNT
+                            <input
+                                type="text"
+                                className="monospace left_spaced max_width_col"
+                                onChange={async (ev) => {
+                                    setFundingAmount(ev.target.value);
+                                }}
+                            />
+                        </div>
+                        <div className="bottom_half_spaced monospace">
+                            DESCRIPTION
+                        </div>
+                        <textarea
+                            className="monospace bottom_spaced"
+                            rows={10}
+                            value={description}
+                            onChange={(event) =>
+                                setDescription(event.target.value)
+                            }
+                        ></textarea>
+                        {description && (
+                            <Content
+                                value={description}
+                                preview={true}
+                                classNameArg="bottom_spaced framed"
+                            />
+                        )}
+                        <ButtonWithLoading
+                            classNameArg="active"
+                            onClick={async () => {
+                                const e8s = Math.round(
+                                    parseFloat(fundingAmount) * 1e8,
+                                );
+                                if (!description || !receiver || !(e8s > 0)) {
+                                    alert("Error: incomplete data.");
+                                    return;
+                                }
+                                let response = await api.call(
+                                    "propose_icp_transfer",
+                                    description,
+                                    receiver,
+                                    e8s,
+                                );
+                                if ("Err" in response) {
+                                    alert(`Error: ${response.Err}`);
+                                    return;
+                                }
+                                setCurrentMask(null);
+                                setProposal(response.Ok);
+                            }}
+                            label="SUBMIT"
+                        />
+                    </div>
+                )}
+                {currentMask == "ban" && (
+                    <div className="spaced column_container monospace">
+                        <div className="vcentered bottom_half_spaced">
+                            USER
+                            <input
+                                type="text"
+                                className="monospace left_spaced max_width_col"
+                                onChange={async (ev) => {
+                                    setReceiver(ev.target.value);
+                                }}
+                            />
+                        </div>
+                        <div className="vcentered bottom_half_spaced">
+                            DAYS
+                            <input
+                                type="number"
+                                className="monospace left_spaced max_width_col"
+                                onChange={async (ev) => {
+                                    setFundingAmount(ev.target.value);
+                                }}
+                            />
+                        </div>
+                        <div className="bottom_half_spaced monospace">
+                            DESCRIPTION
+                        </div>
+                        <textarea
+                            className="monospace bottom_spaced"
+                            rows={10}
+                            value={description}
+                            onChange={(event) =>
+                                setDescription(event.target.value)
+                            }
+                        ></textarea>
+                        {description && (
+                            <Content
+                                value={description}
+                                preview={true}
+                                classNameArg="bottom_spaced framed"
+                            />
+                        )}
+                        <ButtonWithLoading
+                            classNameArg="active"
+                            onClick={async () => {
+                                const user =
+                                    receiver &&
+                                    (await api.query("user", [
+                                        receiver.replace("@", ""),
+                                    ]));
+                                if (!description || !user || !fundingAmount) {
+                                    alert("Error: incomplete data.");
+                                    return;
+                                }
+                                let response = await api.call(
+                                    "propose_ban",
+                                    description,
+                                    user.id,
+                                    parseInt(fundingAmount),
+                                );
+                                if ("Err" in response) {
+                                    alert(`Error: ${response.Err}`);
+                                    return;
+                                }
+                                setCurrentMask(null);
+                                setProposal(response.Ok);
+                            }}
+                            label="SUBMIT"
+                        />
+                    </div>
+                )}
                 {currentMask == "release" && (
                     <div className="spaced column_container monospace">
                         <div className="vcentered bottom_half_spaced">
@@ -250,6 +428,7 @@ export const Proposals = () => {
 export const Proposal = ({ id, postId }) => {
     const users = backendCache.users;
     const [proposal, setProposal] = React.useState(null);
+    const [projectedReward, setProjectedReward] = React.useState(null);
 
     const loadState = async () => {
         const result = await api.query("proposal", id);
@@ -257,6 +436,10 @@ export const Proposal = ({ id, postId }) => {
             setProposal(404);
         }
         setProposal(result.Ok);
+        if (result.Ok && "Reward" in result.Ok.payload) {
+            const preview = await api.query("preview_reward_outcome", id);
+            if ("Ok" in preview) setProjectedReward(preview.Ok);
+        }
         return result.Ok;
     };
 
@@ -356,6 +539,16 @@ export const Proposal = ({ id, postId }) => {
             <div className="bottom_spaced">
                 STATUS: {statusEmoji(propStatus)}{" "}
                 <span className={open ? "accent" : null}>{propStatus}</span>
+                {["Executed", "Rejected"].includes(proposal.status) && (
+                    <>
+                        {" "}
+                        <code>{proposal.approval_percentage}%</code> YES /{" "}
+                        <code>{proposal.rejection_percentage}%</code> NO
+                        {proposal.controversial && (
+                            <span className="accent"> (CLOSE CALL)</span>
+                        )}
+                    </>
+                )}
             </div>
             {!!proposal.payload.Release && (
                 <div className="monospace bottom_spaced">
@@ -390,10 +583,25 @@ export const Proposal = ({ id, postId }) => {
                         RECEIVER:{" "}
                         <code>{proposal.payload.Reward.receiver}</code>
                     </div>
+                    {proposal.payload.Reward.vesting && (
+                        <div className="bottom_half_spaced">
+                            VESTING: {proposal.payload.Reward.vesting.weeks}{" "}
+                            weeks, cliff of{" "}
+                            {proposal.payload.Reward.vesting.cliff_weeks} weeks
+                        </div>
+                    )}
+                    {proposal.status == "Open" && projectedReward != null && (
+                        <div className="bottom_spaced">
+                            PROJECTED REWARD: {tokenBalance(projectedReward)}
+                        </div>
+                    )}
                     {proposal.status == "Executed" && (
                         <div className="bottom_spaced">
-                            TOKENS MINTED:{" "}
-                            {tokenBalance(proposal.payload.Reward.minted)}
+                            TOKENS{" "}
+                            {proposal.payload.Reward.vesting
+                                ? "GRANTED"
+                                : "MINTED"}
+                            : {tokenBalance(proposal.payload.Reward.minted)}
                         </div>
                     )}
                 </>
@@ -408,9 +616,97 @@ export const Proposal = ({ id, postId }) => {
                     </div>
                 </>
             )}
+            {!!proposal.payload.TransferICP && (
+                <>
+                    <div className="bottom_half_spaced">
+                        ACCOUNT: <code>{proposal.payload.TransferICP[0]}</code>
+                    </div>
+                    <div className="bottom_spaced">
+                        AMOUNT:{" "}
+                        <code>
+                            {icp(proposal.payload.TransferICP[1], true)} ICP
+                        </code>
+                    </div>
+                </>
+            )}
+            {!!proposal.payload.Ban && (
+                <div className="bottom_spaced">
+                    BAN: {userList([proposal.payload.Ban[0]])} for{" "}
+                    {proposal.payload.Ban[1]} days
+                </div>
+            )}
+            {proposal.payload.NewUserCooldown != undefined && (
+                <div className="bottom_spaced">
+                    NEW USER COOLDOWN:{" "}
+                    <code>{proposal.payload.NewUserCooldown}</code> HOURS
+                </div>
+            )}
+            {proposal.payload.VotingReward != undefined && (
+                <div className="bottom_spaced">
+                    VOTING REWARD:{" "}
+                    <code>{proposal.payload.VotingReward}</code> CYCLES
+                </div>
+            )}
+            {!!proposal.payload.VotingPowerDecay && (
+                <div className="bottom_spaced">
+                    VOTING POWER DECAY: from{" "}
+                    <code>{proposal.payload.VotingPowerDecay[0]}</code> to{" "}
+                    <code>{proposal.payload.VotingPowerDecay[1]}</code> WEEKS OF
+                    INACTIVITY
+                </div>
+            )}
+            {proposal.payload.ProposalDeposit != undefined && (
+                <div className="bottom_spaced">
+                    PROPOSAL DEPOSIT:{" "}
+                    <code>{proposal.payload.ProposalDeposit}</code> CYCLES
+                </div>
+            )}
+            {!!proposal.payload.ReservedHandles && (
+                <div className="bottom_spaced">
+                    ADDITIONAL RESERVED HANDLES:{" "}
+                    <code>{proposal.payload.ReservedHandles.join(", ")}</code>
+                </div>
+            )}
+            {!!proposal.payload.ReservedRealmNames && (
+                <div className="bottom_spaced">
+                    ADDITIONAL RESERVED REALM NAMES:{" "}
+                    <code>
+                        {proposal.payload.ReservedRealmNames.join(", ")}
+                    </code>
+                </div>
+            )}
+            {!!proposal.payload.Reactions && (
+                <div className="bottom_spaced">
+                    REACTIONS:
+                    <ul>
+                        {proposal.payload.Reactions.map(
+                            ({ id, emoji, karma, positive, cost }) => (
+                                <li key={id}>
+                                    {emoji} (ID: <code>{id}</code>, KARMA:{" "}
+                                    <code>{karma}</code>, COST:{" "}
+                                    <code>{cost || Math.abs(karma)}</code>,{" "}
+                                    {positive ? "POSITIVE" : "NEGATIVE"})
+                                </li>
+                            ),
+                        )}
+                    </ul>
+                </div>
+            )}
             <div className="bottom_spaced">
                 EFFECTIVE VOTING POWER: {token(proposal.voting_power)}
             </div>
+            {proposal.voting_rewards_total > 0 && (
+                <div className="bottom_spaced">
+                    VOTING REWARDS SPENT:{" "}
+                    <code>{proposal.voting_rewards_total}</code> CYCLES
+                </div>
+            )}
+            {proposal.deposit_status && (
+                <div className="bottom_spaced">
+                    DEPOSIT: <code>{proposal.deposit}</code> CYCLES (
+                    {proposal.deposit_status.toUpperCase()})
+                </div>
+            )}
             {open && !isNaN(days) && (
                 <div className="bottom_spaced">
                     EXECUTION DEADLINE: {days} DAYS
@@ -469,7 +765,14 @@ export const Proposal = ({ id, postId }) => {
             {api._user && api._user.id == proposal.proposer && open && (
                 <ButtonWithLoading
                     onClick={async () => {
-                        if (!confirm("Do you want to cancel your proposal?"))
+                        const refund = proposal.refundable
+                            ? " The creation costs will be refunded."
+                            : "";
+                        if (
+                            !confirm(
+                                `Do you want to cancel your proposal?${refund}`,
+                            )
+                        )
                             return;
                         await api.call("cancel_proposal", proposal.id);
                         location.reload();
@@ -478,6 +781,25 @@ export const Proposal = ({ id, postId }) => {
                     label="CANCEL"
                 />
             )}
+            {api._user &&
+                (api._user.id == proposal.proposer || api._user.stalwart) &&
+                ["Cancelled", "Rejected"].includes(proposal.status) && (
+                    <ButtonWithLoading
+                        onClick={async () => {
+                            const response = await api.call(
+                                "resubmit_proposal",
+                                proposal.id,
+                            );
+                            if ("Err" in response) {
+                                alert(`Error: ${response.Err}`);
+                                return;
+                            }
+                            location.href = "#/proposals";
+                        }}
+                        classNameArg="top_spaced max_width_col large_text"
+                        label="RESUBMIT"
+                    />
+                )}
         </div>
     );
 };
@@ -6,6 +6,7 @@ import {
     BurgerButton,
     ButtonWithLoading,
     HeadBar,
+    icpCode,
     Loading,
     NotFound,
     realmColors,
@@ -35,6 +36,13 @@ export const RealmForm = ({ existingName }) => {
         users[userId],
     );
     const [controllers, setControllers] = React.useState([userId]);
+    const [minVotingReputation, setMinVotingReputation] = React.useState(0);
+    const [postCost, setPostCost] = React.useState(0);
+    const [maxMembers, setMaxMembers] = React.useState("");
+    const [treasury, setTreasury] = React.useState(0);
+    const [grantReceiver, setGrantReceiver] = React.useState("");
+    const [grantAmount, setGrantAmount] = React.useState(0);
+    const [policy, setPolicy] = React.useState(null);
 
     const loadRealm = async () => {
         let result = await api.query("realm", existingName);
@@ -46,6 +54,10 @@ export const RealmForm = ({ existingName }) => {
         setName(existingName);
         setDescription(realm.description);
         setControllers(realm.controllers);
+        setMinVotingReputation(realm.min_voting_reputation);
+        setPostCost(realm.post_cost);
+        setMaxMembers(realm.max_members ?? "");
+        setTreasury(realm.treasury);
         if (realm.theme) setTheme(JSON.parse(realm.theme));
         setLabelColor(realm.label_color || "#ffffff");
         setControllersString(
@@ -54,9 +66,20 @@ export const RealmForm = ({ existingName }) => {
     };
     React.useEffect(() => {
         if (editing) loadRealm();
+        else api.query("realm_name_policy").then(setPolicy);
     }, []);
 
-    const valid = name && description && controllers.length > 0;
+    const nameError = (() => {
+        if (editing || !policy || !name) return null;
+        if (name.length < policy.min_length || name.length > policy.max_length)
+            return `MUST BE ${policy.min_length}-${policy.max_length} LONG`;
+        if (!/^[A-Z0-9_-]+$/.test(name))
+            return `ONLY ${policy.allowed_chars.toUpperCase()}`;
+        if (policy.reserved.includes(name.toLowerCase())) return "RESERVED";
+        return null;
+    })();
+
+    const valid = name && !nameError && description && controllers.length > 0;
     return (
         <div className="spaced">
             <h2 className="vcentered">
@@ -121,13 +144,8 @@ export const RealmForm = ({ existingName }) => {
                     <div className="column_container bottom_spaced monospace">
                         <div className="bottom_half_spaced">
                             REALM NAME
-                            {name.length >
-                                backendCache.config.max_realm_name && (
-                                <span>
-                                    &nbsp;[⚠️ MUST BE{" "}
-                                    {backendCache.config.max_realm_name}{" "}
-                                    CHARACTERS OR LESS!]
-                                </span>
+                            {nameError && (
+                                <span>&nbsp;[⚠️ {nameError}!]</span>
                             )}
                         </div>
                         <input
@@ -198,6 +216,104 @@ export const RealmForm = ({ existingName }) => {
                         </div>
                     </div>
                 )}
+                {editing && (
+                    <div className="column_container bottom_spaced monospace">
+                        <div className="bottom_half_spaced">
+                            MINIMAL REALM REPUTATION OF POLL VOTERS (posts and
+                            reactions within the realm)
+                        </div>
+                        <input
+                            type="number"
+                            min="0"
+                            value={minVotingReputation}
+                            onChange={(event) =>
+                                setMinVotingReputation(event.target.value)
+                            }
+                        />
+                    </div>
+                )}
+                {editing && (
+                    <div className="column_container bottom_spaced monospace">
+                        <div className="bottom_half_spaced">
+                            POST COST (extra cycles per post going to the realm
+                            treasury)
+                        </div>
+                        <input
+                            type="number"
+                            min="0"
+                            value={postCost}
+                            onChange={(event) =>
+                                setPostCost(event.target.value)
+                            }
+                        />
+                    </div>
+                )}
+                {editing && (
+                    <div className="column_container bottom_spaced monospace">
+                        <div className="bottom_half_spaced">
+                            MAXIMUM MEMBERS (empty for no limit)
+                        </div>
+                        <input
+                            type="number"
+                            min="1"
+                            value={maxMembers}
+                            onChange={(event) =>
+                                setMaxMembers(event.target.value)
+                            }
+                        />
+                    </div>
+                )}
+                {editing && treasury > 0 && (
+                    <div className="column_container bottom_spaced monospace">
+                        <div className="bottom_half_spaced">
+                            GRANT FROM THE TREASURY (
+                            <code>{treasury}</code> cycles available)
+                        </div>
+                        <div className="row_container">
+                            <input
+                                type="text"
+                                className="max_width_col"
+                                placeholder="member handle"
+                                value={grantReceiver}
+                                onChange={(event) =>
+                                    setGrantReceiver(event.target.value)
+                                }
+                            />
+                            <input
+                                type="number"
+                                min="0"
+                                className="left_half_spaced"
+                                value={grantAmount}
+                                onChange={(event) =>
+                                    setGrantAmount(event.target.value)
+                                }
+                            />
+                            <ButtonWithLoading
+                                classNameArg="left_half_spaced"
+                                onClick={async () => {
+                                    const receiver =
+                                        name2Id[grantReceiver.replace("@", "")];
+                                    if (receiver == undefined) {
+                                        alert("Error: no user found");
+                                        return;
+                                    }
+                                    const result = await api.call(
+                                        "grant_from_realm_treasury",
+                                        name,
+                                        parseInt(receiver),
+                                        parseInt(grantAmount) || 0,
+                                    );
+                                    if ("Err" in result) {
+                                        alert(`Error: ${result.Err}`);
+                                        return;
+                                    }
+                                    await loadRealm();
+                                }}
+                                label="GRANT"
+                            />
+                        </div>
+                    </div>
+                )}
                 <h2>Color Theme</h2>
                 <div className="vcentered">
                     <input
@@ -299,6 +415,34 @@ export const RealmForm = ({ existingName }) => {
                         }
                         if (!editing) {
                             await api.call("toggle_realm_membership", name);
+                        } else {
+                            const result = await api.call(
+                                "set_realm_min_voting_reputation",
+                                name,
+                                parseInt(minVotingReputation) || 0,
+                            );
+                            if ("Err" in result) {
+                                alert(`Error: ${result.Err}`);
+                                return;
+                            }
+                            const costResult = await api.call(
+                                "set_realm_post_cost",
+                                name,
+                                parseInt(postCost) || 0,
+                            );
+                            if ("Err" in costResult) {
+                                alert(`Error: ${costResult.Err}`);
+                                return;
+                            }
+                            const membersResult = await api.call(
+                                "set_realm_max_members",
+                                name,
+                                parseInt(maxMembers) || null,
+                            );
+                            if ("Err" in membersResult) {
+                                alert(`Error: ${membersResult.Err}`);
+                                return;
+                            }
                         }
                         await Promise.all([
                             window.reloadCache(),
@@ -317,6 +461,7 @@ export const RealmForm = ({ existingName }) => {
 
 export const RealmHeader = ({ name }) => {
     const [realm, setRealm] = React.useState(null);
+    const [stats, setStats] = React.useState(null);
     const [showInfo, toggleInfo] = React.useState(false);
 
     const loadRealm = async () => {
@@ -328,11 +473,21 @@ export const RealmHeader = ({ name }) => {
         setRealm(result.Ok);
     };
 
+    const loadStats = async () => {
+        const result = await api.query("realm_stats", name);
+        if ("Ok" in result) setStats(result.Ok);
+    };
+
     React.useEffect(() => {
         loadRealm();
+        setStats(null);
         toggleInfo(false);
     }, [name]);
 
+    React.useEffect(() => {
+        if (showInfo && !stats) loadStats();
+    }, [showInfo]);
+
     setTitle(`realm ${name}`);
 
     if (!realm) return <Loading />;
@@ -388,8 +543,31 @@ export const RealmHeader = ({ name }) => {
                 <div className="stands_out">
                     <Content value={realm.description} />
                     <code>{realm.num_posts}</code> posts,{" "}
-                    <code>{realm.num_members}</code> members, controlled by:{" "}
+                    <code>{realm.num_members}</code>
+                    {realm.max_members != null && (
+                        <> of <code>{realm.max_members}</code></>
+                    )}{" "}
+                    members, controlled by: 
                     {userList(realm.controllers)}
+                    <p>
+                        {realm.post_cost > 0 && (
+                            <>
+                                Every post costs{" "}
+                                <code>{realm.post_cost}</code> extra
+                                cycles.{" "}
+                            </>
+                        )}
+                        Realm treasury: <code>{realm.treasury}</code> cycles.
+                    </p>
+                    {stats && (
+                        <p>
+                            <code>{stats.posts_last_week}</code> posts in the
+                            last 7 days,{" "}
+                            <code>{stats.posts_last_month}</code> in the last 30
+                            days by <code>{stats.active_authors}</code> active
+                            authors, {icpCode(stats.tips, 2)} tipped in total.
+                        </p>
+                    )}
                     {user && (
                         <div className="row_container top_spaced flex_ended">
                             {realm.controllers.includes(user.id) && (
@@ -403,6 +581,25 @@ export const RealmHeader = ({ name }) => {
                                     EDIT
                                 </button>
                             )}
+                            {!user.realms.includes(name) && (
+                                <ButtonWithLoading
+                                    classNameArg="right_half_spaced"
+                                    label={
+                                        user.followed_realms.includes(name)
+                                            ? "UNFOLLOW"
+                                            : "FOLLOW"
+                                    }
+                                    onClick={async () =>
+                                        api
+                                            .call(
+                                                "toggle_following_realm",
+                                                name,
+                                            )
+                                            .then(api._reloadUser)
+                                            .then(loadRealm)
+                                    }
+                                />
+                            )}
                             {!user.realms.includes(name) && (
                                 <ButtonWithLoading
                                     label="JOIN"
@@ -417,12 +614,16 @@ export const RealmHeader = ({ name }) => {
                                             )
                                         )
                                             return false;
+                                        const result = await api.call(
+                                            "toggle_realm_membership",
+                                            name,
+                                        );
+                                        if ("Err" in result) {
+                                            alert(`Error: ${result.Err}`);
+                                            return;
+                                        }
                                         return api
-                                            .call(
-                                                "toggle_realm_membership",
-                                                name,
-                                            )
-                                            .then(api._reloadUser)
+                                            ._reloadUser()
                                             .then(loadRealm);
                                     }}
                                 />
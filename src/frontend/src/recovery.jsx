@@ -1,15 +1,40 @@
 import * as React from "react";
-import { ButtonWithLoading, FileUploadInput, HeadBar } from "./common";
+import {
+    ButtonWithLoading,
+    FileUploadInput,
+    HeadBar,
+    tokenBalance,
+} from "./common";
 
 export const Recovery = () => {
     const [hash, setHash] = React.useState("");
+    const [status, setStatus] = React.useState(null);
     const { emergency_release, emergency_votes } = backendCache.stats;
+
+    React.useEffect(() => {
+        api.query("emergency_release_status").then(setStatus);
+    }, []);
     return (
         <>
             <HeadBar title="Recovery" />
             <div className="spaced">
                 <h2>Status</h2>
                 <code>{emergency_release || "No emergencies today! ☀️"}</code>
+                {status && (
+                    <ul className="monospace">
+                        <li>
+                            HASH: <code>{status.hash}</code>
+                        </li>
+                        <li>
+                            VOTES: {tokenBalance(status.votes)} /{" "}
+                            {tokenBalance(status.required)}
+                        </li>
+                        <li>
+                            CONFIRMED BY YOU:{" "}
+                            {status.confirmed_by_caller ? "YES" : "NO"}
+                        </li>
+                    </ul>
+                )}
                 <h2>Emergency binary</h2>
                 <FileUploadInput
                     callback={async (binary) => {
@@ -19,7 +44,21 @@ export const Recovery = () => {
                             )
                         )
                             return;
-                        await api.set_emergency_release(binary);
+                        let replacedHash = null;
+                        if (status && status.votes > 0) {
+                            replacedHash = prompt(
+                                "The current binary has votes. Please enter its hash to confirm the replacement:",
+                            );
+                            if (!replacedHash) return;
+                        }
+                        const response = await api.set_emergency_release(
+                            binary,
+                            replacedHash,
+                        );
+                        if ("Err" in response) {
+                            alert(`Error: ${response.Err}`);
+                            return;
+                        }
                         alert("Done!");
                     }}
                 />
@@ -26,7 +26,9 @@ export const Search = () => {
                                 return;
                             }
                             setSearching(true);
-                            setResults(await api.query("search", term));
+                            setResults(
+                                await api.query("search", term, 0, "", "", null, null),
+                            );
                             setSearching(false);
                         }, 300),
                     );
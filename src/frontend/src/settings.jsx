@@ -8,18 +8,29 @@ export const Settings = ({ invite = null }) => {
     const [about, setAbout] = React.useState("");
     const [settings, setSettings] = React.useState({});
     const [controllers, setControllers] = React.useState("");
+    const [languages, setLanguages] = React.useState("");
+    const [contentWarnings, setContentWarnings] = React.useState("Cover");
+    const [impressions, setImpressions] = React.useState(false);
     const [label, setLabel] = React.useState(null);
+    const [policy, setPolicy] = React.useState(null);
     const [timer, setTimer] = React.useState(null);
     const [uiRefresh, setUIRefresh] = React.useState(false);
 
     const updateData = (user) => {
         if (!user) return;
+        setName(user.name);
         setAbout(user.about);
         setControllers(user.controllers.join("\n"));
+        setLanguages(user.languages.join(" "));
+        setContentWarnings(user.content_warnings);
+        setImpressions(user.impressions_enabled);
         setSettings(user.settings);
     };
 
     React.useEffect(() => updateData(user), [user]);
+    React.useEffect(() => {
+        api.query("handle_policy").then(setPolicy);
+    }, []);
 
     const setSetting = (key, e) => {
         const newSettings = {};
@@ -35,6 +46,11 @@ export const Settings = ({ invite = null }) => {
             if ("Err" in response) {
                 return alert(`Error: ${response.Err}`);
             }
+        } else if (name != user.name) {
+            let response = await api.call("change_username", name);
+            if ("Err" in response) {
+                return alert(`Error: ${response.Err}`);
+            }
         }
         const principal_ids = controllers
             .split("\n")
@@ -50,6 +66,33 @@ export const Settings = ({ invite = null }) => {
             alert(`Error: ${response.Err}`);
             return;
         }
+        const languageResponse = await api.call(
+            "set_languages",
+            languages
+                .split(/[\s,]+/)
+                .map((code) => code.trim().toLowerCase())
+                .filter((code) => code.length > 0),
+        );
+        if ("Err" in languageResponse) {
+            alert(`Error: ${languageResponse.Err}`);
+            return;
+        }
+        const warningsResponse = await api.call(
+            "set_content_warnings",
+            contentWarnings,
+        );
+        if ("Err" in warningsResponse) {
+            alert(`Error: ${warningsResponse.Err}`);
+            return;
+        }
+        const impressionsResponse = await api.call(
+            "set_impressions_enabled",
+            impressions,
+        );
+        if ("Err" in impressionsResponse) {
+            alert(`Error: ${impressionsResponse.Err}`);
+            return;
+        }
         if (!user) location.href = "/";
         else if (uiRefresh) {
             await api._reloadUser();
@@ -63,44 +106,51 @@ export const Settings = ({ invite = null }) => {
         <>
             <HeadBar title="Settings" shareLink="setting" />
             <div className="spaced monospace column_container">
-                {!user && (
-                    <div className="column_container bottom_spaced">
-                        <div className="bottom_half_spaced">
-                            USER NAME <span className="accent">[required]</span>
-                        </div>
-                        <input
-                            type="text"
-                            value={name}
-                            className="monospace"
-                            placeholder="alphanumeric"
-                            onChange={(event) => {
-                                clearTimeout(timer);
-                                const name = event.target.value;
-                                if (name)
-                                    setTimer(
-                                        setTimeout(
-                                            () =>
-                                                api
-                                                    .query(
-                                                        "validate_username",
-                                                        name,
-                                                    )
-                                                    .then((result) =>
-                                                        setLabel(
-                                                            "Err" in result
-                                                                ? result.Err
-                                                                : "free!",
-                                                        ),
-                                                    ),
-                                            300,
-                                        ),
-                                    );
-                                setName(name);
-                            }}
-                        />
-                        <code>{label && `Status: ${label}`}</code>
+                <div className="column_container bottom_spaced">
+                    <div className="bottom_half_spaced">
+                        USER NAME <span className="accent">[required]</span>
                     </div>
-                )}
+                    <input
+                        type="text"
+                        value={name}
+                        className="monospace"
+                        placeholder={
+                            policy
+                                ? `${policy.min_length}-${policy.max_length} ${policy.allowed_chars}`
+                                : "alphanumeric"
+                        }
+                        onChange={(event) => {
+                            clearTimeout(timer);
+                            const name = event.target.value;
+                            if (name && name != user?.name)
+                                setTimer(
+                                    setTimeout(
+                                        () =>
+                                            api
+                                                .query(
+                                                    "validate_username",
+                                                    name,
+                                                )
+                                                .then((result) =>
+                                                    setLabel(
+                                                        "Err" in result
+                                                            ? result.Err
+                                                            : "free!",
+                                                    ),
+                                                ),
+                                        300,
+                                    ),
+                                );
+                            setName(name);
+                        }}
+                    />
+                    <code>{label && `Status: ${label}`}</code>
+                    {user && (
+                        <div className="top_half_spaced small_text">
+                            Handles can be changed only once in 30 days.
+                        </div>
+                    )}
+                </div>
                 <div className="column_container bottom_spaced">
                     <div className="bottom_half_spaced">ABOUT YOU</div>
                     <input
@@ -151,6 +201,48 @@ export const Settings = ({ invite = null }) => {
                         rows="4"
                     ></textarea>
                 </div>
+                <div className="column_container bottom_spaced">
+                    <div className="bottom_half_spaced">
+                        FEED LANGUAGES (two-letter codes like "en de", the
+                        first one is used for your posts)
+                    </div>
+                    <input
+                        placeholder="all languages"
+                        className="monospace"
+                        type="text"
+                        value={languages}
+                        onChange={(event) => setLanguages(event.target.value)}
+                    />
+                </div>
+                <div className="column_container bottom_spaced">
+                    <div className="bottom_half_spaced">
+                        POSTS WITH CONTENT WARNINGS
+                    </div>
+                    <select
+                        value={contentWarnings}
+                        onChange={(event) =>
+                            setContentWarnings(event.target.value)
+                        }
+                    >
+                        <option value="Cover">COVER</option>
+                        <option value="Reveal">REVEAL</option>
+                        <option value="Hide">HIDE</option>
+                    </select>
+                </div>
+                <div className="column_container bottom_spaced">
+                    <div className="bottom_half_spaced">
+                        COUNT IMPRESSIONS OF MY POSTS
+                    </div>
+                    <select
+                        value={impressions ? "true" : "false"}
+                        onChange={(event) =>
+                            setImpressions(event.target.value == "true")
+                        }
+                    >
+                        <option value="false">NO</option>
+                        <option value="true">YES</option>
+                    </select>
+                </div>
                 <ButtonWithLoading
                     classNameArg="active"
                     onClick={submit}
@@ -198,9 +290,146 @@ export const Settings = ({ invite = null }) => {
                                 label="CHANGE PRINCIPAL"
                             />
                         }
+                        <hr />
+                        <div className="column_container top_spaced">
+                            <div className="bottom_half_spaced">DEVICES</div>
+                            {api._user.devices.map((device) => (
+                                <div
+                                    key={device.principal}
+                                    className="row_container vcentered bottom_half_spaced"
+                                >
+                                    <span className="max_width_col">
+                                        <b>{device.label}</b>:{" "}
+                                        <code className="small_text">
+                                            {device.principal}
+                                        </code>
+                                    </span>
+                                    {device.principal !=
+                                        api._user.principal && (
+                                        <ButtonWithLoading
+                                            onClick={async () => {
+                                                let response = await api.call(
+                                                    "remove_device",
+                                                    device.principal,
+                                                );
+                                                if ("Err" in response) {
+                                                    alert(
+                                                        `Error: ${response.Err}`,
+                                                    );
+                                                    return;
+                                                }
+                                                await api._reloadUser();
+                                            }}
+                                            label="REMOVE"
+                                        />
+                                    )}
+                                </div>
+                            ))}
+                            {api._user.pending_devices.map((device) => (
+                                <div
+                                    key={device.principal}
+                                    className="row_container vcentered bottom_half_spaced"
+                                >
+                                    <span className="max_width_col">
+                                        <b>{device.label}</b>: open{" "}
+                                        <code className="small_text">
+                                            {`${location.origin}/#/device/${api._user.id}`}
+                                        </code>{" "}
+                                        on the device to confirm it
+                                    </span>
+                                    <ButtonWithLoading
+                                        onClick={async () => {
+                                            let response = await api.call(
+                                                "remove_device",
+                                                device.principal,
+                                            );
+                                            if ("Err" in response) {
+                                                alert(`Error: ${response.Err}`);
+                                                return;
+                                            }
+                                            await api._reloadUser();
+                                        }}
+                                        label="REMOVE"
+                                    />
+                                </div>
+                            ))}
+                        </div>
+                        <ButtonWithLoading
+                            onClick={async () => {
+                                const device = prompt(
+                                    "Principal of the new device:",
+                                );
+                                if (!device) return;
+                                const label = prompt("Device label:");
+                                if (!label) return;
+                                let response = await api.call(
+                                    "add_device",
+                                    device.trim(),
+                                    label,
+                                );
+                                if ("Err" in response) {
+                                    alert(`Error: ${response.Err}`);
+                                    return;
+                                }
+                                await api._reloadUser();
+                            }}
+                            label="ADD DEVICE"
+                        />
+                        <hr />
+                        <div className="vertically_spaced">
+                            ⚠️ Deleting your account erases your profile and
+                            the contents of all your posts. This cannot be
+                            undone!
+                        </div>
+                        <ButtonWithLoading
+                            onClick={async () => {
+                                const handle = prompt(
+                                    "Please enter your handle to confirm the account deletion:",
+                                );
+                                if (!handle) return;
+                                let response = await api.call(
+                                    "delete_account",
+                                    handle,
+                                );
+                                if ("Err" in response) {
+                                    alert(`Error: ${response.Err}`);
+                                    return;
+                                }
+                                localStorage.clear();
+                                location.href = "/";
+                            }}
+                            label="DELETE ACCOUNT"
+                        />
                     </>
                 )}
             </div>
         </>
     );
 };
+
+export const ConfirmDevice = ({ userId }) => (
+    <>
+        <HeadBar title="CONFIRM DEVICE" shareLink="device" />
+        <div className="spaced">
+            <p>
+                User #{userId} wants to let this device control their account.
+                Confirm it only if it's your own account.
+            </p>
+            <ButtonWithLoading
+                classNameArg="active"
+                onClick={async () => {
+                    let response = await api.call(
+                        "confirm_device",
+                        parseInt(userId),
+                    );
+                    if ("Err" in response) {
+                        alert(`Error: ${response.Err}`);
+                        return;
+                    }
+                    location.href = "/";
+                }}
+                label="CONFIRM"
+            />
+        </div>
+    </>
+);
@@ -36,6 +36,7 @@ export const Tokens = () => {
             "transactions",
             txPage,
             userToPrincipal[term.toLowerCase()] || term,
+            null,
         );
         if (txs.length == 0) {
             setNoMoreData(true);
@@ -191,6 +191,7 @@ export const Wallet = () => {
             "transactions",
             0,
             api._user.principal,
+            null,
         );
         setTransactions(txs);
     };